@@ -0,0 +1,12 @@
+fn main() {
+    // The gRPC service is generated from proto/payments.proto, with a vendored protoc so builds
+    // don't depend on one being installed
+    #[cfg(feature = "grpc")]
+    {
+        let mut config = tonic_prost_build::Config::new();
+        config.protoc_executable(protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this platform"));
+        tonic_prost_build::configure()
+            .compile_with_config(config, &["proto/payments.proto"], &["proto"])
+            .expect("failed to compile proto/payments.proto");
+    }
+}
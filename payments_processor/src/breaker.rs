@@ -0,0 +1,107 @@
+use std::collections::VecDeque;
+use std::fmt;
+use serde::Deserialize;
+
+// `[circuit_breaker]` in the config. A source is stopped when the same failing record arrives
+// `max_repeats` times in a row, or when more than `max_reject_rate` of the last `window` records
+// failed.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BreakerConfig {
+    pub max_repeats: u32,
+    pub window: usize,
+    pub max_reject_rate: f64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig { max_repeats: 3, window: 100, max_reject_rate: 0.5 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trip {
+    RepeatedRecord { record: String, repeats: u32 },
+    RejectRate { rejected: usize, window: usize },
+}
+
+impl fmt::Display for Trip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trip::RepeatedRecord { record, repeats } => write!(f, "same failing record {} times in a row: {}", repeats, record),
+            Trip::RejectRate { rejected, window } => write!(f, "{} of the last {} records failed", rejected, window),
+        }
+    }
+}
+
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    last_failure: Option<String>,
+    repeats: u32,
+    recent: VecDeque<bool>,
+    rejected: usize,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        CircuitBreaker { config, last_failure: None, repeats: 0, recent: VecDeque::new(), rejected: 0 }
+    }
+
+    // Feeds one record's outcome: None if it was accepted, otherwise a key identifying the failing
+    // record. Returns why the breaker tripped, if it did.
+    pub fn record(&mut self, failure: Option<String>) -> Option<Trip> {
+        self.recent.push_back(failure.is_some());
+        self.rejected += failure.is_some() as usize;
+        if self.recent.len() > self.config.window && self.recent.pop_front() == Some(true) {
+            self.rejected -= 1;
+        }
+
+        match failure {
+            Some(key) if self.last_failure.as_ref() == Some(&key) => self.repeats += 1,
+            Some(key) => {
+                self.last_failure = Some(key);
+                self.repeats = 1;
+            }
+            None => {
+                self.last_failure = None;
+                self.repeats = 0;
+            }
+        }
+
+        if self.config.max_repeats > 0 && self.repeats >= self.config.max_repeats {
+            let record = self.last_failure.clone().unwrap_or_default();
+            return Some(Trip::RepeatedRecord { record, repeats: self.repeats });
+        }
+        let full = self.config.window > 0 && self.recent.len() == self.config.window;
+        if full && self.rejected as f64 > self.config.max_reject_rate * self.config.window as f64 {
+            return Some(Trip::RejectRate { rejected: self.rejected, window: self.config.window });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_trips_on_repeated_record() {
+        let mut breaker = CircuitBreaker::new(BreakerConfig { max_repeats: 3, window: 0, max_reject_rate: 1.0 });
+        assert_eq!(breaker.record(Some("a".into())), None);
+        assert_eq!(breaker.record(Some("a".into())), None);
+        assert_eq!(breaker.record(None), None);
+        assert_eq!(breaker.record(Some("a".into())), None);
+        assert_eq!(breaker.record(Some("a".into())), None);
+        assert_eq!(breaker.record(Some("a".into())), Some(Trip::RepeatedRecord { record: "a".into(), repeats: 3 }));
+    }
+
+    #[test]
+    fn test_breaker_trips_on_reject_rate_over_window() {
+        let mut breaker = CircuitBreaker::new(BreakerConfig { max_repeats: 0, window: 4, max_reject_rate: 0.5 });
+        for outcome in [Some("a"), None, Some("b"), None, Some("c"), None] {
+            assert_eq!(breaker.record(outcome.map(String::from)), None);
+        }
+        assert_eq!(breaker.record(Some("d".into())), None);
+        assert_eq!(breaker.record(Some("e".into())), Some(Trip::RejectRate { rejected: 3, window: 4 }));
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::checkpoint::{self, CheckpointError};
+use crate::journal::{self, JournalError};
+use crate::manifest::Checksum;
+use crate::output::AtomicFile;
+use crate::schema::EXPECTED_COLUMNS;
+
+// Format of the bundle itself, i.e. of its index and the entries it may hold
+pub const VERSION: u32 = 1;
+
+const INDEX: &str = "bundle.json";
+const CHECKPOINT: &str = "checkpoint.jsonl";
+const JOURNAL: &str = "journal.jsonl";
+const MANIFEST: &str = "manifest.json";
+const CONFIG: &str = "config.toml";
+
+#[derive(Debug)]
+pub enum BundleError {
+    Io(io::Error),
+    Checkpoint(CheckpointError),
+    Journal(JournalError),
+    Invalid(String),
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::Io(e) => write!(f, "Bundle I/O error: {}", e),
+            BundleError::Checkpoint(e) => write!(f, "{}", e),
+            BundleError::Journal(e) => write!(f, "{}", e),
+            BundleError::Invalid(e) => write!(f, "Invalid bundle: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+impl From<io::Error> for BundleError {
+    fn from(e: io::Error) -> Self {
+        BundleError::Io(e)
+    }
+}
+
+impl From<CheckpointError> for BundleError {
+    fn from(e: CheckpointError) -> Self {
+        BundleError::Checkpoint(e)
+    }
+}
+
+impl From<JournalError> for BundleError {
+    fn from(e: JournalError) -> Self {
+        BundleError::Journal(e)
+    }
+}
+
+// The files a bundle is made from, or written back to on import; only the checkpoint is required
+#[derive(Debug, Default)]
+pub struct BundleFiles {
+    pub checkpoint: PathBuf,
+    pub journal: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    pub config: Option<PathBuf>,
+}
+
+impl BundleFiles {
+    fn target(&self, entry: &str) -> Option<&Path> {
+        match entry {
+            CHECKPOINT => Some(&self.checkpoint),
+            JOURNAL => self.journal.as_deref(),
+            MANIFEST => self.manifest.as_deref(),
+            CONFIG => self.config.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+// The first entry of a bundle: what wrote it, the formats of the other entries and their checksums
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BundleIndex {
+    pub version: u32,
+    pub crate_version: String,
+    pub created_at: u64,
+    pub checkpoint_version: u32,
+    pub journal_version: u32,
+    // The CSV columns the ledger reads, for whoever has to produce the next inputs
+    pub input_columns: Vec<String>,
+    // How many journal entries the bundle holds, if it holds a journal
+    pub journal_entries: Option<u64>,
+    pub files: BTreeMap<String, Checksum>,
+}
+
+// Writes a zstd-compressed tar of the ledger state: the checkpoint in the current format, the last
+// `journal_tail` entries of the journal (all of them for None), the manifest and the config, behind an
+// index. The parts are staged next to `out` first so the index can carry their checksums.
+pub fn export(files: &BundleFiles, journal_tail: Option<u64>, out: &Path) -> Result<BundleIndex, BundleError> {
+    let staging = staging_dir(out);
+    fs::create_dir_all(&staging)?;
+    let result = export_staged(files, journal_tail, out, &staging);
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn export_staged(files: &BundleFiles, journal_tail: Option<u64>, out: &Path, staging: &Path) -> Result<BundleIndex, BundleError> {
+    let mut parts = vec![(CHECKPOINT, staging.join(CHECKPOINT))];
+    checkpoint::migrate(&files.checkpoint, &parts[0].1)?;
+    let mut journal_entries = None;
+    if let Some(journal) = &files.journal {
+        let path = staging.join(JOURNAL);
+        let mut tail = AtomicFile::create(&path)?;
+        journal_entries = Some(journal::write_tail(journal, journal_tail, &mut tail)?);
+        tail.commit()?;
+        parts.push((JOURNAL, path));
+    }
+    for (name, path) in [(MANIFEST, &files.manifest), (CONFIG, &files.config)] {
+        if let Some(path) = path {
+            parts.push((name, path.clone()));
+        }
+    }
+
+    let mut checksums = BTreeMap::new();
+    for (name, path) in &parts {
+        checksums.insert(name.to_string(), Checksum::of(path)?);
+    }
+    let index = BundleIndex {
+        version: VERSION,
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        checkpoint_version: checkpoint::VERSION,
+        journal_version: journal::VERSION,
+        input_columns: EXPECTED_COLUMNS.iter().map(|c| c.to_string()).collect(),
+        journal_entries,
+        files: checksums,
+    };
+
+    let mut tar = tar::Builder::new(zstd::Encoder::new(AtomicFile::create(out)?, 0)?);
+    let json = serde_json::to_vec_pretty(&index).map_err(io::Error::from)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(index.created_at);
+    header.set_cksum();
+    tar.append_data(&mut header, INDEX, json.as_slice())?;
+    for (name, path) in &parts {
+        tar.append_path_with_name(path, name)?;
+    }
+    tar.into_inner()?.finish()?.commit()?;
+    Ok(index)
+}
+
+// Unpacks a bundle to `files`, after checking its version and the checksum of every entry. Entries
+// without a target in `files` (e.g. the journal when none is given) are skipped. Nothing is written
+// unless the whole bundle checks out, and existing files are only replaced with `overwrite`.
+pub fn import(bundle: &Path, files: &BundleFiles, overwrite: bool) -> Result<BundleIndex, BundleError> {
+    let invalid = |e: String| BundleError::Invalid(e);
+    let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(bundle)?)?);
+    let mut entries = archive.entries()?;
+
+    let index: BundleIndex = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()?.to_str() != Some(INDEX) {
+                return Err(invalid(format!("the first entry is not {}", INDEX)));
+            }
+            serde_json::from_reader(&mut entry).map_err(|e| invalid(format!("{}: {}", INDEX, e)))?
+        }
+        None => return Err(invalid("empty archive".to_string())),
+    };
+    if index.version > VERSION || index.checkpoint_version > checkpoint::VERSION || index.journal_version > journal::VERSION {
+        return Err(invalid(format!("written by version {} of the processor, which is newer than this one", index.crate_version)));
+    }
+    if !index.files.contains_key(CHECKPOINT) {
+        return Err(invalid(format!("no {}", CHECKPOINT)));
+    }
+
+    let mut written = vec![];
+    let mut seen = vec![];
+    for entry in entries {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let expected = index.files.get(&name).ok_or_else(|| invalid(format!("unexpected entry {}", name)))?;
+        let Some(target) = files.target(&name) else {
+            seen.push(name);
+            continue;
+        };
+        if target.exists() && !overwrite {
+            return Err(invalid(format!("{} already exists", target.display())));
+        }
+        let mut out = AtomicFile::create(target)?;
+        let checksum = copy_hashed(&mut entry, &mut out)?;
+        if &checksum != expected {
+            return Err(invalid(format!("{} does not match its checksum", name)));
+        }
+        written.push(out);
+        seen.push(name);
+    }
+    if let Some(missing) = index.files.keys().find(|name| !seen.contains(name)) {
+        return Err(invalid(format!("{} is listed but missing", missing)));
+    }
+    for out in written {
+        out.commit()?;
+    }
+    Ok(index)
+}
+
+fn copy_hashed<R: Read, W: Write>(from: &mut R, to: &mut W) -> io::Result<Checksum> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = from.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        to.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+    Ok(Checksum { size, sha256: format!("{:x}", hasher.finalize()) })
+}
+
+fn staging_dir(out: &Path) -> PathBuf {
+    let mut name = out.as_os_str().to_owned();
+    name.push(".parts");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::Journal;
+    use crate::ledger::Ledger;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_bundle_carries_the_state_to_another_instance() {
+        let dir = std::env::temp_dir().join(format!("payments_processor_bundle_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let source = BundleFiles { checkpoint: dir.join("state.jsonl"), journal: Some(dir.join("journal.jsonl")), ..Default::default() };
+        let mut ledger = Ledger::new();
+        let (journal, hook) = Journal::open(source.journal.as_ref().unwrap()).unwrap();
+        ledger.add_hook(Box::new(hook));
+        for tx in [TxBuilder::deposit(1, 1, 10.0).build(), TxBuilder::deposit(2, 2, 5.0).build(), TxBuilder::dispute(1, 1).build()] {
+            ledger.process_transaction(&tx).unwrap();
+        }
+        journal.lock().unwrap().sync().unwrap();
+        ledger.checkpoint(&source.checkpoint, &Default::default()).unwrap();
+
+        let bundle = dir.join("state.tar.zst");
+        let exported = export(&source, Some(2), &bundle).unwrap();
+        assert_eq!(exported.journal_entries, Some(2));
+
+        let target = BundleFiles { checkpoint: dir.join("imported.jsonl"), ..Default::default() };
+        assert_eq!(import(&bundle, &target, false).unwrap(), exported);
+        let mut imported = Ledger::new();
+        imported.restore(&target.checkpoint).unwrap();
+        assert_eq!(imported.client(1).unwrap().balance(Default::default()).held, 10.0);
+        // A second import doesn't replace what the first one wrote
+        assert!(matches!(import(&bundle, &target, false), Err(BundleError::Invalid(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
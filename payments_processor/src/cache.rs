@@ -0,0 +1,139 @@
+use std::cell::{Cell, RefCell};
+use std::num::NonZeroUsize;
+use lru::LruCache;
+
+use crate::client::{Client, OperatorAccount};
+use crate::store::{LedgerStore, StoreError};
+use crate::transaction::Transaction;
+
+// Lookups of stored transactions answered from the cache and from the store behind it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+// An LRU cache of the most recently stored or looked-up transactions in front of a disk-backed
+// store, so that the common dispute shortly after its deposit doesn't go to disk. Writes go through
+// to the store, which stays the one holding everything; the cache only ever has copies of its rows.
+// Only `get_tx` is counted in the stats: the duplicate check of every new transaction is a miss by
+// nature and would drown out the lookups the cache is for.
+pub struct CachedStore<S> {
+    store: S,
+    cache: RefCell<LruCache<u32, Transaction>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl<S: LedgerStore> CachedStore<S> {
+    pub fn new(store: S, capacity: NonZeroUsize) -> Self {
+        CachedStore { store, cache: RefCell::new(LruCache::new(capacity)), hits: Cell::new(0), misses: Cell::new(0) }
+    }
+}
+
+impl<S: LedgerStore> LedgerStore for CachedStore<S> {
+    fn get_tx(&self, tx_id: u32) -> Result<Option<Transaction>, StoreError> {
+        if let Some(tx) = self.cache.borrow_mut().get(&tx_id) {
+            self.hits.set(self.hits.get() + 1);
+            return Ok(Some(tx.clone()));
+        }
+        self.misses.set(self.misses.get() + 1);
+        let tx = self.store.get_tx(tx_id)?;
+        if let Some(tx) = &tx {
+            self.cache.borrow_mut().put(tx_id, tx.clone());
+        }
+        Ok(tx)
+    }
+
+    fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError> {
+        if self.cache.borrow().contains(&tx_id) {
+            return Ok(true);
+        }
+        self.store.contains_tx(tx_id)
+    }
+
+    fn put_tx(&mut self, tx: &Transaction) -> Result<(), StoreError> {
+        self.store.put_tx(tx)?;
+        self.cache.get_mut().put(tx.tx_id, tx.clone());
+        Ok(())
+    }
+
+    fn remove_tx(&mut self, tx_id: u32) -> Result<(), StoreError> {
+        self.cache.get_mut().pop(&tx_id);
+        self.store.remove_tx(tx_id)
+    }
+
+    fn get_client(&self, client_id: u16) -> Result<Option<Client>, StoreError> {
+        self.store.get_client(client_id)
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), StoreError> {
+        self.store.put_client(client)
+    }
+
+    fn clients(&self) -> Result<Vec<Client>, StoreError> {
+        self.store.clients()
+    }
+
+    fn operator(&self) -> Result<OperatorAccount, StoreError> {
+        self.store.operator()
+    }
+
+    fn put_operator(&mut self, operator: &OperatorAccount) -> Result<(), StoreError> {
+        self.store.put_operator(operator)
+    }
+
+    fn commit(&mut self) -> Result<(), StoreError> {
+        self.store.commit()
+    }
+
+    fn for_each_tx(&self, f: &mut dyn FnMut(&Transaction) -> Result<(), StoreError>) -> Result<(), StoreError> {
+        self.store.for_each_tx(f)
+    }
+
+    fn move_transactions(&mut self, other: &mut dyn LedgerStore) -> Result<(), StoreError> {
+        self.cache.get_mut().clear();
+        self.store.move_transactions(other)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(CacheStats { hits: self.hits.get(), misses: self.misses.get() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use crate::store::MemoryStore;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_disputes_of_recent_deposits_are_served_from_the_cache() {
+        let store = CachedStore::new(MemoryStore::new(), NonZeroUsize::new(2).unwrap());
+        let mut ledger = Ledger::with_store(Box::new(store)).unwrap();
+        for tx in [
+            TxBuilder::deposit(1, 1, 10.0).build(),
+            TxBuilder::deposit(1, 2, 10.0).build(),
+            TxBuilder::deposit(1, 3, 10.0).build(),
+            // Tx 3 is still cached, tx 1 was evicted by it and comes from the store
+            TxBuilder::dispute(1, 3).build(),
+            TxBuilder::dispute(1, 1).build(),
+            TxBuilder::resolve(1, 1).build(),
+        ] {
+            ledger.process_transaction(&tx).unwrap();
+        }
+        let stats = ledger.metrics().tx_cache.unwrap();
+        assert_eq!(stats, CacheStats { hits: 2, misses: 1 });
+        assert_eq!(ledger.client(1).unwrap().balance(Default::default()).held, 10.0);
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+use crate::client::{Balance, Client, Currency, OperatorAccount, Tier};
+use crate::handle::HandleError;
+use crate::ledger::Ledger;
+use crate::output::AtomicFile;
+use crate::store::{LedgerStore, MemoryStore, StoreError, StoredTx};
+
+// 2 when transactions got their full dispute status instead of a disputed flag, 3 when balances
+// were split by currency, 4 when the ids of transactions the retention dropped were added. Older
+// checkpoints are upgraded line by line as they are read (see `upgrade`).
+pub const VERSION: u32 = 4;
+// Transactions a shard's restore collects before handing them to its ledger, so a ledger that spills
+// its history to disk never has all of it in memory
+const RESTORE_BATCH: usize = 100_000;
+
+// Records read so far from each input, by the path it was given as
+pub type Offsets = BTreeMap<String, u64>;
+// The input files with records in the state, by sha256, with the path each was first given as.
+// Unlike the offsets, which are only this run's, they are carried over from the checkpoint a run
+// resumed from, so a later run can tell a file it is given again.
+pub type AppliedInputs = BTreeMap<String, String>;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    Corrupt { line: u64, error: String },
+    Store(StoreError),
+    Handle(HandleError),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::Io(e) => write!(f, "Checkpoint I/O error: {}", e),
+            CheckpointError::Corrupt { line, error } => write!(f, "Corrupt checkpoint on line {}: {}", line, error),
+            CheckpointError::Store(e) => write!(f, "{}", e),
+            CheckpointError::Handle(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<io::Error> for CheckpointError {
+    fn from(e: io::Error) -> Self {
+        CheckpointError::Io(e)
+    }
+}
+
+impl From<StoreError> for CheckpointError {
+    fn from(e: StoreError) -> Self {
+        CheckpointError::Store(e)
+    }
+}
+
+impl From<HandleError> for CheckpointError {
+    fn from(e: HandleError) -> Self {
+        CheckpointError::Handle(e)
+    }
+}
+
+// One line of a checkpoint. Unlike the summary, balances are kept at full precision.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Line {
+    Header {
+        version: u32,
+        offsets: Offsets,
+        // The last journal entry the state includes, when the run had a journal
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        journal_seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        inputs: AppliedInputs,
+    },
+    Operator {
+        fees_earned: f64,
+        chargeback_losses: f64,
+    },
+    Client {
+        id: u16,
+        balances: BTreeMap<Currency, Balance>,
+        locked: bool,
+        tier: Tier,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        overdraft_limit: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    Tx(StoredTx),
+    // The ids of transactions the retention dropped, which still count for duplicate checks
+    Retired {
+        ids: Vec<u32>,
+    },
+}
+
+// Writes a checkpoint as JSON Lines: a header with the input offsets, then the operator account,
+// clients and transaction history of one or more ledgers. The file only replaces the previous
+// checkpoint at that path once `finish` has synced it, so a crash mid-write keeps the old one.
+pub struct CheckpointWriter {
+    out: AtomicFile,
+}
+
+impl CheckpointWriter {
+    pub fn create<P: AsRef<Path>>(path: P, offsets: &Offsets, journal_seq: Option<u64>, inputs: &AppliedInputs) -> Result<Self, CheckpointError> {
+        let mut writer = CheckpointWriter { out: AtomicFile::create(path)? };
+        writer.line(&Line::Header { version: VERSION, offsets: offsets.clone(), journal_seq, inputs: inputs.clone() })?;
+        Ok(writer)
+    }
+
+    fn line(&mut self, line: &Line) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, line)?;
+        self.out.write_all(b"\n")
+    }
+
+    // Adds a ledger's state; the ledgers of one checkpoint must hold disjoint sets of clients
+    pub fn write_ledger(&mut self, ledger: &Ledger) -> Result<(), CheckpointError> {
+        let snapshot = ledger.snapshot();
+        let OperatorAccount { fees_earned, chargeback_losses } = snapshot.operator;
+        self.line(&Line::Operator { fees_earned, chargeback_losses })?;
+        for c in snapshot.clients {
+            self.line(&Line::Client { id: c.id, balances: c.balances.into_iter().collect(), locked: c.locked, tier: c.tier, overdraft_limit: c.overdraft_limit, name: c.name })?;
+        }
+        ledger.for_each_transaction(&mut |tx| self.line(&Line::Tx(tx.into())).map_err(|e| StoreError(e.to_string())))?;
+        let ids = ledger.retired_ids();
+        if !ids.is_empty() {
+            self.line(&Line::Retired { ids })?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), CheckpointError> {
+        self.out.commit()?;
+        Ok(())
+    }
+}
+
+// Loads a checkpoint into ledgers configured like the ones that wrote it, spreading the clients
+// over them the way `ShardedLedger` routes them (by client id modulo the number of ledgers), and
+// returns the input offsets to resume from. The ledgers are expected to be empty.
+pub fn restore<P: AsRef<Path>>(path: P, ledgers: &mut [Ledger]) -> Result<Offsets, CheckpointError> {
+    assert!(!ledgers.is_empty(), "restore needs at least one ledger");
+    let mut stores: Vec<MemoryStore> = ledgers.iter().map(|_| MemoryStore::new()).collect();
+    let mut batched = vec![0; ledgers.len()];
+    let shards = stores.len();
+    let shard = |client: u16| client as usize % shards;
+    let mut offsets = None;
+    let mut operator = OperatorAccount::default();
+    let mut retired = vec![];
+
+    let mut version = VERSION;
+
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let corrupt = |error: String| CheckpointError::Corrupt { line: n as u64 + 1, error };
+        let line = line?;
+        let line: Line = if version < VERSION {
+            let mut value = serde_json::from_str(&line).map_err(|e| corrupt(e.to_string()))?;
+            upgrade(&mut value, version).map_err(corrupt)?;
+            serde_json::from_value(value)
+        } else {
+            serde_json::from_str(&line)
+        }
+        .map_err(|e| corrupt(e.to_string()))?;
+        match line {
+            Line::Header { version: v, offsets: o, .. } if (1..=VERSION).contains(&v) => {
+                version = v;
+                offsets = Some(o);
+            }
+            Line::Header { version, .. } => return Err(corrupt(format!("unsupported version {}", version))),
+            _ if offsets.is_none() => return Err(corrupt("missing header".to_string())),
+            Line::Operator { fees_earned, chargeback_losses } => {
+                operator.fees_earned += fees_earned;
+                operator.chargeback_losses += chargeback_losses;
+            }
+            Line::Client { id, balances, locked, tier, overdraft_limit, name } => {
+                let client = Client { id, balances: balances.into_iter().collect(), locked, tier, overdraft_limit, name };
+                stores[shard(id)].put_client(&client)?;
+            }
+            Line::Tx(stored) => {
+                let tx = stored.into_transaction().map_err(corrupt)?;
+                let shard = shard(tx.client_id);
+                stores[shard].put_tx(&tx)?;
+                batched[shard] += 1;
+                if batched[shard] == RESTORE_BATCH {
+                    ledgers[shard].merge(Ledger::with_store(Box::new(mem::take(&mut stores[shard])))?)?;
+                    batched[shard] = 0;
+                }
+            }
+            Line::Retired { ids } => retired.extend(ids),
+        }
+    }
+
+    // The operator account is a sum over the ledgers, so it all goes to the first one, as do the
+    // retired ids, which sharded ledgers share through their `TxIds`
+    stores[0].put_operator(&operator)?;
+    ledgers[0].add_retired_ids(retired);
+    for (ledger, store) in ledgers.iter_mut().zip(stores) {
+        ledger.merge(Ledger::with_store(Box::new(store))?)?;
+    }
+    offsets.ok_or_else(|| CheckpointError::Corrupt { line: 0, error: "empty checkpoint".to_string() })
+}
+
+// The last journal entry the checkpoint's state includes, as recorded in its header
+pub fn journal_seq<P: AsRef<Path>>(path: P) -> Result<Option<u64>, CheckpointError> {
+    match header(path.as_ref())? {
+        Line::Header { journal_seq, .. } => Ok(journal_seq),
+        _ => unreachable!("header returns the header"),
+    }
+}
+
+// The input files the checkpoint's state has records of, as recorded in its header
+pub fn applied_inputs<P: AsRef<Path>>(path: P) -> Result<AppliedInputs, CheckpointError> {
+    match header(path.as_ref())? {
+        Line::Header { inputs, .. } => Ok(inputs),
+        _ => unreachable!("header returns the header"),
+    }
+}
+
+fn header(path: &Path) -> Result<Line, CheckpointError> {
+    let corrupt = |error: String| CheckpointError::Corrupt { line: 1, error };
+    let first = BufReader::new(File::open(path)?).lines().next().ok_or_else(|| corrupt("empty checkpoint".to_string()))??;
+    match serde_json::from_str(&first).map_err(|e| corrupt(e.to_string()))? {
+        header @ Line::Header { .. } => Ok(header),
+        _ => Err(corrupt("missing header".to_string())),
+    }
+}
+
+// Rewrites one line of a checkpoint of version `from` in the current format
+fn upgrade(line: &mut Value, from: u32) -> Result<(), String> {
+    let line = line.as_object_mut().ok_or("not an object")?;
+    match line.get("kind").and_then(Value::as_str) {
+        Some("header") => {
+            line.insert("version".to_string(), json!(VERSION));
+        }
+        // Version 1 had a disputed flag and the reason of an annulment instead of the status
+        Some("tx") if from < 2 => {
+            let disputed = line.remove("disputed").and_then(|d| d.as_bool()).unwrap_or(false);
+            let status = match line.remove("annulled") {
+                Some(Value::String(reason)) => {
+                    line.insert("reason".to_string(), json!(reason));
+                    "annulled"
+                }
+                _ if disputed => "disputed",
+                _ => "posted",
+            };
+            line.insert("status".to_string(), json!(status));
+        }
+        // Before version 3 a client had one balance, in the default currency
+        Some("client") if from < 3 => {
+            let mut balance = Map::new();
+            for field in ["available", "held", "total", "operator_held"] {
+                balance.insert(field.to_string(), line.remove(field).ok_or_else(|| format!("client without {}", field))?);
+            }
+            line.insert("balances".to_string(), json!(BTreeMap::from([(Currency::default(), balance)])));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Rewrites the checkpoint at `from` in the current format to `to`, which may be the same path, and
+// returns the version it had. One that is already current is copied as is.
+pub fn migrate(from: &Path, to: &Path) -> Result<u32, CheckpointError> {
+    let mut out = AtomicFile::create(to)?;
+    let mut version = None;
+    for (n, line) in BufReader::new(File::open(from)?).lines().enumerate() {
+        let corrupt = |error: String| CheckpointError::Corrupt { line: n as u64 + 1, error };
+        let line = line?;
+        let mut value: Value = serde_json::from_str(&line).map_err(|e| corrupt(e.to_string()))?;
+        let from_version = match version {
+            Some(v) => v,
+            None => {
+                let v = match serde_json::from_value(value.clone()) {
+                    Ok(Line::Header { version, .. }) => version,
+                    _ => return Err(corrupt("missing header".to_string())),
+                };
+                if !(1..=VERSION).contains(&v) {
+                    return Err(corrupt(format!("unsupported version {}", v)));
+                }
+                *version.insert(v)
+            }
+        };
+        if from_version < VERSION {
+            upgrade(&mut value, from_version).map_err(corrupt)?;
+            serde_json::to_writer(&mut out, &value).map_err(io::Error::from)?;
+        } else {
+            out.write_all(line.as_bytes())?;
+        }
+        out.write_all(b"\n")?;
+    }
+    let version = version.ok_or_else(|| CheckpointError::Corrupt { line: 0, error: "empty checkpoint".to_string() })?;
+    out.commit()?;
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::{LedgerError, Retention};
+    use crate::test_util::TxBuilder;
+    use crate::transaction::PaymentStatus;
+
+    #[test]
+    fn test_checkpoint_restores_into_any_number_of_ledgers() {
+        let path = std::env::temp_dir().join(format!("payments_processor_checkpoint_{}.jsonl", std::process::id()));
+        let mut ledger = Ledger::new();
+        ledger.set_admin_ops(true);
+        ledger.set_retention(Retention::Deposits);
+        for tx in [
+            TxBuilder::deposit(1, 1, 10.0).currency(Currency::Eur).build(),
+            TxBuilder::deposit(2, 2, 1.0 / 3.0).build(),
+            TxBuilder::dispute(1, 1).build(),
+            TxBuilder::set_tier(2, 3, Tier::Premium).build(),
+            TxBuilder::withdrawal(2, 4, 0.0001).build(),
+        ] {
+            ledger.process_transaction(&tx).unwrap();
+        }
+        let offsets = Offsets::from([("a.csv".to_string(), 4)]);
+        ledger.checkpoint(&path, &offsets).unwrap();
+
+        let mut restored = Ledger::new();
+        assert_eq!(restored.restore(&path).unwrap(), offsets);
+        let balances: Vec<(Currency, f64)> = restored.snapshot().clients.iter()
+            .flat_map(|c| c.rows())
+            .map(|row| (row.currency, row.available))
+            .collect();
+        assert_eq!(balances, vec![(Currency::Eur, 0.0), (Currency::Usd, 1.0 / 3.0 - 0.0001)]);
+        // The withdrawal wasn't kept, but its id was
+        assert_eq!(restored.process_transaction(&TxBuilder::withdrawal(2, 4, 0.0001).build()), Err(LedgerError::DuplicateTransaction(4)));
+        // The dispute carries over, in its currency, so it can still be resolved
+        restored.process_transaction(&TxBuilder::resolve(1, 1).build()).unwrap();
+        assert_eq!(restored.client(1).unwrap().balance(Currency::Eur).available, 10.0);
+
+        let mut shards = vec![Ledger::new(), Ledger::new()];
+        restore(&path, &mut shards).unwrap();
+        assert_eq!(shards[0].client(2).unwrap().tier, Tier::Premium);
+        assert!(shards[1].transaction(1).unwrap().is_some() && shards[0].transaction(1).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_header_records_the_inputs_the_state_has_records_of() {
+        let path = std::env::temp_dir().join(format!("payments_processor_checkpoint_inputs_{}.jsonl", std::process::id()));
+        let inputs = AppliedInputs::from([("9f86d081884c7d65".to_string(), "day1.csv".to_string())]);
+        let mut out = CheckpointWriter::create(&path, &Offsets::new(), Some(7), &inputs).unwrap();
+        out.write_ledger(&Ledger::new()).unwrap();
+        out.finish().unwrap();
+        assert_eq!((applied_inputs(&path).unwrap(), journal_seq(&path).unwrap()), (inputs, Some(7)));
+
+        Ledger::new().checkpoint(&path, &Offsets::new()).unwrap();
+        assert!(applied_inputs(&path).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_older_checkpoints_are_upgraded_when_restored_or_migrated() {
+        let path = std::env::temp_dir().join(format!("payments_processor_checkpoint_v1_{}.jsonl", std::process::id()));
+        let v1 = [
+            r#"{"kind":"header","version":1,"offsets":{"a.csv":3}}"#,
+            r#"{"kind":"operator","fees_earned":0.0,"chargeback_losses":0.0}"#,
+            r#"{"kind":"client","id":1,"available":5.0,"held":10.0,"total":15.0,"locked":false,"tier":"basic","operator_held":0.0}"#,
+            r#"{"kind":"tx","tx_type":"deposit","client_id":1,"tx_id":1,"amount":10.0,"disputed":true}"#,
+            r#"{"kind":"tx","tx_type":"deposit","client_id":1,"tx_id":2,"amount":5.0,"disputed":false}"#,
+        ];
+        std::fs::write(&path, v1.join("\n") + "\n").unwrap();
+
+        let mut restored = Ledger::new();
+        assert_eq!(restored.restore(&path).unwrap(), Offsets::from([("a.csv".to_string(), 3)]));
+        assert_eq!(restored.client(1).unwrap().balance(Currency::Usd).held, 10.0);
+        // The dispute carries over, so it can still be resolved
+        restored.process_transaction(&TxBuilder::resolve(1, 1).build()).unwrap();
+        assert_eq!(restored.client(1).unwrap().balance(Currency::Usd).available, 15.0);
+
+        assert_eq!(migrate(&path, &path).unwrap(), 1);
+        assert_eq!(migrate(&path, &path).unwrap(), VERSION);
+        let mut migrated = Ledger::new();
+        migrated.restore(&path).unwrap();
+        assert_eq!(migrated.transaction(1).unwrap().unwrap().status, PaymentStatus::Disputed);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,33 +1,234 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize, Serializer};
+use serde::ser::SerializeStruct;
 
+use crate::search::{self, NameIndex, SearchHit};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    #[default]
+    Basic,
+    Verified,
+    Premium,
+}
+
+impl FromStr for Tier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Tier, String> {
+        match s.trim().to_lowercase().as_str() {
+            "basic" => Ok(Tier::Basic),
+            "verified" => Ok(Tier::Verified),
+            "premium" => Ok(Tier::Premium),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tier::Basic => write!(f, "basic"),
+            Tier::Verified => write!(f, "verified"),
+            Tier::Premium => write!(f, "premium"),
+        }
+    }
+}
+
+// Currencies balances are kept in. Records without a currency are in the default, USD.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Currency {
+    #[default]
+    Usd,
+    Eur,
+    Gbp,
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Currency, String> {
+        match s.trim().to_uppercase().as_str() {
+            "USD" => Ok(Currency::Usd),
+            "EUR" => Ok(Currency::Eur),
+            "GBP" => Ok(Currency::Gbp),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Currency::Usd => write!(f, "USD"),
+            Currency::Eur => write!(f, "EUR"),
+            Currency::Gbp => write!(f, "GBP"),
+        }
+    }
+}
+
+// Limits enforced by the ledger for every client of a tier; unset limits don't apply.
+// Configured per tier in the `[tiers.<name>]` sections of the config file.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TierLimits {
+    pub max_balance: Option<f64>,
+    pub max_withdrawal: Option<f64>,
+    pub disputes: bool,
+}
+
+impl Default for TierLimits {
+    fn default() -> Self {
+        TierLimits { max_balance: None, max_withdrawal: None, disputes: true }
+    }
+}
+
+// A client's funds in one currency
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    // Ring-fenced by the operator with a hold record, e.g. pending a fraud review. Part of `total`
+    // but not of `available`, and independent of disputes.
+    pub operator_held: f64,
+}
+
+impl Balance {
+    const EMPTY: Balance = Balance { available: 0.0, held: 0.0, total: 0.0, operator_held: 0.0 };
+}
+
+// Locking (by a chargeback) and the tier apply to the client as a whole, across currencies
+#[derive(Clone, Debug, PartialEq)]
 pub struct Client {
     pub id: u16,
+    pub balances: HashMap<Currency, Balance>,
+    pub locked: bool,
+    pub tier: Tier,
+    // Pre-approved credit line: withdrawals may take `available` down to minus this much. None
+    // falls back to the ledger's default (`Ledger::set_overdraft_limit`, zero unless set).
+    pub overdraft_limit: Option<f64>,
+    // Account holder, as given in the `--clients` file
+    pub name: Option<String>,
+}
+
+// One summary row: a client's balance in one currency. The currency comes last so readers of
+// summaries written before currencies keep their column positions.
+#[derive(Clone, Debug, Serialize)]
+pub struct AccountRow {
+    pub client: u16,
+    #[serde(serialize_with = "four_decimals")]
     pub available: f64,
+    #[serde(serialize_with = "four_decimals")]
     pub held: f64,
+    #[serde(serialize_with = "four_decimals")]
     pub total: f64,
     pub locked: bool,
+    pub tier: Tier,
+    #[serde(serialize_with = "four_decimals")]
+    pub operator_held: f64,
+    pub currency: Currency,
+}
+
+// The operator's own position, kept apart from client balances: fees charged by the business rules,
+// and the part of each chargeback the client couldn't cover because the funds were already gone
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OperatorAccount {
+    pub fees_earned: f64,
+    pub chargeback_losses: f64,
+}
+
+impl OperatorAccount {
+    pub fn net(&self) -> f64 {
+        self.fees_earned - self.chargeback_losses
+    }
+}
+
+impl Serialize for OperatorAccount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let round = |v: f64| (v * 10_000.0).round() / 10_000.0;
+        let mut state = serializer.serialize_struct("OperatorAccount", 3)?;
+        state.serialize_field("fees_earned", &round(self.fees_earned))?;
+        state.serialize_field("chargeback_losses", &round(self.chargeback_losses))?;
+        state.serialize_field("net", &round(self.net()))?;
+        state.end()
+    }
+}
+
+// Keep serialized amounts at the same 4 decimal precision as the CSV summary
+pub(crate) fn four_decimals<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64((value * 10_000.0).round() / 10_000.0)
 }
 
 impl Client {
     pub fn new(id: u16) -> Client {
         Client {
             id,
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
+            balances: HashMap::new(),
             locked: false,
+            tier: Tier::Basic,
+            overdraft_limit: None,
+            name: None,
+        }
+    }
+
+    // Empty for a currency the client never held
+    pub fn balance(&self, currency: Currency) -> &Balance {
+        self.balances.get(&currency).unwrap_or(&Balance::EMPTY)
+    }
+
+    pub fn balance_mut(&mut self, currency: Currency) -> &mut Balance {
+        self.balances.entry(currency).or_default()
+    }
+
+    pub fn row(&self, currency: Currency) -> AccountRow {
+        let balance = self.balance(currency);
+        AccountRow {
+            client: self.id,
+            available: balance.available,
+            held: balance.held,
+            total: balance.total,
+            locked: self.locked,
+            tier: self.tier,
+            operator_held: balance.operator_held,
+            currency,
         }
     }
+
+    // The summary rows, ordered by currency; a client that never held funds gets an empty one in the
+    // default currency, so it still shows up
+    pub fn rows(&self) -> Vec<AccountRow> {
+        let mut currencies: Vec<Currency> = self.balances.keys().copied().collect();
+        if currencies.is_empty() {
+            currencies.push(Currency::default());
+        }
+        currencies.sort();
+        currencies.into_iter().map(|currency| self.row(currency)).collect()
+    }
 }
 
+// Names are set through `set_name` and whole clients put back through `insert` and `remove`, which
+// keep the search index in step with them
 pub struct Clients  {
     pub clients: HashMap<u16, Client>,
+    names: NameIndex,
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Clients {
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
+            names: NameIndex::default(),
         }
     }
 
@@ -38,4 +239,35 @@ impl Clients {
     pub fn find_client(&mut self, client_id: u16) -> Option<&mut Client> {
         self.clients.get_mut(&client_id)
     }
+
+    pub fn set_name(&mut self, client_id: u16, name: Option<String>) {
+        let client = self.add_client(client_id);
+        let old = std::mem::replace(&mut client.name, name.clone());
+        if let Some(old) = old {
+            self.names.remove(client_id, &old);
+        }
+        if let Some(name) = name {
+            self.names.insert(client_id, &name);
+        }
+    }
+
+    pub fn insert(&mut self, client: Client) {
+        self.remove(client.id);
+        if let Some(name) = &client.name {
+            self.names.insert(client.id, name);
+        }
+        self.clients.insert(client.id, client);
+    }
+
+    pub fn remove(&mut self, client_id: u16) -> Option<Client> {
+        let client = self.clients.remove(&client_id)?;
+        if let Some(name) = &client.name {
+            self.names.remove(client_id, name);
+        }
+        Some(client)
+    }
+
+    pub fn search(&self, query: &str) -> Vec<SearchHit> {
+        search::search(&self.clients, &self.names, query)
+    }
 }
@@ -0,0 +1,117 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+use crate::client::{Currency, Tier};
+
+// One row of the `--clients` file: a client's account state to start from and its metadata. Fields
+// left out (or empty in CSV) keep the ledger's defaults. A client can have a row per currency.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct ClientSettings {
+    pub client: u16,
+    // Opening balance, available in `currency` (USD when not given); only seeded into a client that
+    // has no balance in that currency yet, so a run continuing a store doesn't reset it
+    #[serde(default)]
+    pub balance: Option<f64>,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    // True locks the account; a file never unlocks one
+    #[serde(default)]
+    pub locked: Option<bool>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tier: Option<Tier>,
+    // Pre-approved credit line; see `Client::overdraft_limit`
+    #[serde(default)]
+    pub overdraft_limit: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum ClientsFileError {
+    Io { path: PathBuf, source: io::Error },
+    Csv { path: PathBuf, source: csv::Error },
+    Json { path: PathBuf, source: serde_json::Error },
+    Invalid { path: PathBuf, client: u16, reason: String },
+}
+
+impl fmt::Display for ClientsFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientsFileError::Io { path, source } => write!(f, "Failed to read clients file {}: {}", path.display(), source),
+            ClientsFileError::Csv { path, source } => write!(f, "Invalid clients file {}: {}", path.display(), source),
+            ClientsFileError::Json { path, source } => write!(f, "Invalid clients file {}: {}", path.display(), source),
+            ClientsFileError::Invalid { path, client, reason } => write!(f, "Clients file {}: client {}: {}", path.display(), client, reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientsFileError {}
+
+// A JSON array of objects for .json files, CSV with a header row naming the columns used otherwise
+// (client,balance,currency,locked,name,tier,overdraft_limit)
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<ClientSettings>, ClientsFileError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|source| ClientsFileError::Io { path: path.to_path_buf(), source })?;
+    let clients: Vec<ClientSettings> = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_reader(BufReader::new(file)).map_err(|source| ClientsFileError::Json { path: path.to_path_buf(), source })?,
+        _ => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(file)
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(|source| ClientsFileError::Csv { path: path.to_path_buf(), source })?,
+    };
+    for settings in &clients {
+        let reason = if settings.overdraft_limit.is_some_and(|limit| !limit.is_finite() || limit < 0.0) {
+            "overdraft_limit must be a non-negative amount"
+        } else if settings.balance.is_some_and(|balance| !balance.is_finite()) {
+            "balance must be a number"
+        } else {
+            continue;
+        };
+        return Err(ClientsFileError::Invalid { path: path.to_path_buf(), client: settings.client, reason: reason.to_string() });
+    }
+    Ok(clients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_clients_file_is_read_from_csv_or_json() {
+        let dir = std::env::temp_dir();
+        let csv_path = dir.join(format!("payments_processor_clients_{}.csv", std::process::id()));
+        let json_path = dir.join(format!("payments_processor_clients_{}.json", std::process::id()));
+        fs::write(&csv_path, "client,balance,currency,locked,name,tier,overdraft_limit\n1,25.5,EUR,,Acme Ltd, premium, 100\n2,,,true,,,\n").unwrap();
+        fs::write(
+            &json_path,
+            r#"[{"client":1,"balance":25.5,"currency":"EUR","name":"Acme Ltd","tier":"premium","overdraft_limit":100},{"client":2,"locked":true}]"#,
+        )
+        .unwrap();
+
+        let expected = vec![
+            ClientSettings {
+                client: 1,
+                balance: Some(25.5),
+                currency: Some(Currency::Eur),
+                name: Some("Acme Ltd".to_string()),
+                tier: Some(Tier::Premium),
+                overdraft_limit: Some(100.0),
+                ..ClientSettings::default()
+            },
+            ClientSettings { client: 2, locked: Some(true), ..ClientSettings::default() },
+        ];
+        assert_eq!(load(&csv_path).unwrap(), expected);
+        assert_eq!(load(&json_path).unwrap(), expected);
+
+        fs::write(&csv_path, "client,overdraft_limit\n3,-5\n").unwrap();
+        assert!(matches!(load(&csv_path), Err(ClientsFileError::Invalid { client: 3, .. })));
+        fs::remove_file(&csv_path).unwrap();
+        fs::remove_file(&json_path).unwrap();
+    }
+}
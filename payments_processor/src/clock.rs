@@ -0,0 +1,41 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// Wall-clock time for time-based features (overdue disputes, held-funds monitors), so they can be
+// driven deterministically in tests instead of depending on when the test runs
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// A clock that only moves when told to. Clones share the same time, so a test can keep one and
+// hand another to the component under test.
+#[derive(Clone)]
+pub struct TestClock(Arc<Mutex<SystemTime>>);
+
+impl TestClock {
+    pub fn new(start: SystemTime) -> Self {
+        TestClock(Arc::new(Mutex::new(start)))
+    }
+
+    pub fn set(&self, time: SystemTime) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = time;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) += by;
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+use crate::breaker::BreakerConfig;
+use crate::client::{Tier, TierLimits};
+use crate::enrichment::ReferenceSource;
+use crate::late::LateRecordPolicy;
+use crate::ledger::{DisputeFundsPolicy, LedgerConfig, LockedAccountPolicy, Retention, UnknownRecordPolicy};
+use crate::notifications::NotificationRule;
+use crate::rates::RateTable;
+use crate::rules::BusinessRules;
+use crate::segments::JournalRetention;
+
+// Settings loaded from the TOML file passed with `--config`. Every section is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // WASM business-rule plugins (needs the `wasm` feature)
+    pub plugins: Vec<PathBuf>,
+    // Rhai fee/validation scripts (needs the `scripting` feature)
+    pub scripts: Vec<PathBuf>,
+    // `[[notifications]]` condition -> action rules evaluated as transactions apply
+    pub notifications: Vec<NotificationRule>,
+    // `[tiers.basic]`, `[tiers.verified]`, `[tiers.premium]` limits
+    pub tiers: HashMap<Tier, TierLimits>,
+    // `[[reference]]` data files joined onto transactions at ingest
+    pub reference: Vec<ReferenceSource>,
+    // "reject" (default), "skip" or "plugin"
+    pub unknown_records: UnknownRecordPolicy,
+    // Deposits/withdrawals on accounts locked by a chargeback: "reject" (default) or "allow"
+    pub locked_accounts: LockedAccountPolicy,
+    // Disputes for more than the client has available: "allow" (default), "reject" or "cap"
+    pub dispute_funds: DisputeFundsPolicy,
+    // Transactions kept after they apply: "all" (default), "deposits" or { window = N }
+    pub retention: Retention,
+    // Records seq'd inside a period a `--watch` summary already reported: "apply" (default), "restate"
+    // or "review"
+    pub late_records: LateRecordPolicy,
+    // Transactions taking longer than this to apply are logged with their context
+    pub latency_budget_ms: Option<u64>,
+    // Stops reading an input that keeps failing; off unless the section is present
+    pub circuit_breaker: Option<BreakerConfig>,
+    // `[journal]` segment rotation, compression and deletion for `--journal`
+    pub journal: JournalRetention,
+    // `[reporting]` currency of record and rates for `--consolidated`
+    pub reporting: Option<RateTable>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, source: std::io::Error },
+    Parse { path: PathBuf, source: toml::de::Error },
+    // A plugin or script that couldn't be loaded
+    Rules { path: PathBuf, error: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => write!(f, "Failed to read config {}: {}", path.display(), source),
+            ConfigError::Parse { path, source } => write!(f, "Invalid config {}: {}", path.display(), source),
+            ConfigError::Rules { path, error } => write!(f, "Failed to load rules {}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|source| ConfigError::Io { path: path.to_path_buf(), source })?;
+        Config::parse(&text).map_err(|source| ConfigError::Parse { path: path.to_path_buf(), source })
+    }
+
+    pub fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    // The ledger options the file sets, the others at their defaults
+    pub fn ledger_config(&self) -> LedgerConfig {
+        LedgerConfig {
+            unknown_records: self.unknown_records,
+            locked_accounts: self.locked_accounts,
+            dispute_funds: self.dispute_funds,
+            retention: self.retention,
+            tiers: self.tiers.clone(),
+            ..LedgerConfig::default()
+        }
+    }
+
+    // The plugins' and scripts' business rules, in that order; every ledger needs its own
+    pub fn rules(&self) -> Result<Vec<Box<dyn BusinessRules>>, ConfigError> {
+        let plugins = self.plugins.iter().map(|path| load_plugin(path));
+        let scripts = self.scripts.iter().map(|path| load_script(path));
+        plugins.chain(scripts).collect()
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn load_plugin(path: &Path) -> Result<Box<dyn BusinessRules>, ConfigError> {
+    match crate::wasm::WasmPlugin::from_file(path) {
+        Ok(plugin) => Ok(Box::new(plugin)),
+        Err(e) => Err(ConfigError::Rules { path: path.to_path_buf(), error: e.to_string() }),
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+fn load_plugin(path: &Path) -> Result<Box<dyn BusinessRules>, ConfigError> {
+    Err(ConfigError::Rules { path: path.to_path_buf(), error: "WASM plugins need a build with the `wasm` feature".to_string() })
+}
+
+#[cfg(feature = "scripting")]
+fn load_script(path: &Path) -> Result<Box<dyn BusinessRules>, ConfigError> {
+    match crate::scripting::ScriptRules::from_file(path) {
+        Ok(script) => Ok(Box::new(script)),
+        Err(e) => Err(ConfigError::Rules { path: path.to_path_buf(), error: e.to_string() }),
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn load_script(path: &Path) -> Result<Box<dyn BusinessRules>, ConfigError> {
+    Err(ConfigError::Rules { path: path.to_path_buf(), error: "rule scripts need a build with the `scripting` feature".to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        let config = Config::parse("").unwrap();
+        assert!(config.plugins.is_empty());
+        assert!(config.scripts.is_empty());
+    }
+
+    #[test]
+    fn test_config_parses_script_and_plugin_paths() {
+        let config = Config::parse("scripts = [\"fees.rhai\"]\nplugins = [\"rules.wasm\"]\n").unwrap();
+        assert_eq!(config.scripts, vec![PathBuf::from("fees.rhai")]);
+        assert_eq!(config.plugins, vec![PathBuf::from("rules.wasm")]);
+    }
+
+    #[test]
+    fn test_config_rejects_wrong_types() {
+        assert!(Config::parse("scripts = 3").is_err());
+    }
+
+    #[test]
+    fn test_config_parses_tier_limits() {
+        let config = Config::parse("[tiers.basic]\nmax_withdrawal = 500.0\ndisputes = false\n").unwrap();
+        assert_eq!(config.tiers[&Tier::Basic], TierLimits { max_balance: None, max_withdrawal: Some(500.0), disputes: false });
+    }
+
+    #[test]
+    fn test_config_parses_retention() {
+        assert_eq!(Config::parse("").unwrap().retention, Retention::All);
+        assert_eq!(Config::parse("retention = \"deposits\"").unwrap().retention, Retention::Deposits);
+        assert_eq!(Config::parse("retention = { window = 1000 }").unwrap().retention, Retention::Window(1000));
+    }
+}
@@ -0,0 +1,204 @@
+// Per-client, per-currency comparison of two summaries or checkpoints written by this processor, for
+// `diff old.csv new.csv`
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use csv::{ReaderBuilder, StringRecord};
+use serde::{Deserialize, Serialize};
+
+use crate::client::Currency;
+use crate::ledger::Ledger;
+use crate::summary::OutputFormat;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SummaryRow {
+    pub client: u16,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+    // Summaries written before currencies have no such column and are all USD
+    #[serde(default)]
+    pub currency: Currency,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl Change {
+    fn as_str(self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Changed => "changed",
+        }
+    }
+}
+
+// new - old for each amount; a client missing on one side counts as all zeroes there
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ClientDelta {
+    pub client: u16,
+    pub change: Change,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub newly_locked: bool,
+    pub currency: Currency,
+}
+
+// Reads the client rows of a CSV summary, keyed by client and currency; extra columns (tier) are
+// ignored and the optional totals and operator sections at the end are skipped
+pub fn read_summary<R: Read>(reader: R) -> Result<BTreeMap<(u16, Currency), SummaryRow>, csv::Error> {
+    let mut reader = ReaderBuilder::new().flexible(true).trim(csv::Trim::All).from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let mut rows = BTreeMap::new();
+    let mut record = StringRecord::new();
+    while reader.read_record(&mut record)? {
+        if matches!(record.get(0), Some("totals" | "operator")) {
+            break;
+        }
+        let row: SummaryRow = record.deserialize(Some(&headers))?;
+        rows.insert((row.client, row.currency), row);
+    }
+    Ok(rows)
+}
+
+// A CSV summary, or a checkpoint (JSON Lines, so starting with `{`) read as the summary of the
+// ledger it holds
+pub fn load<P: AsRef<Path>>(path: P) -> Result<BTreeMap<(u16, Currency), SummaryRow>, Box<dyn Error>> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'{') {
+        return Ok(read_summary(reader)?);
+    }
+    let mut ledger = Ledger::new();
+    ledger.restore(path)?;
+    Ok(rows(&ledger))
+}
+
+// A ledger's balances as the rows of its summary
+pub fn rows(ledger: &Ledger) -> BTreeMap<(u16, Currency), SummaryRow> {
+    let rows = ledger.snapshot().clients.into_iter().flat_map(|c| c.rows()).map(|row| {
+        let summary = SummaryRow { client: row.client, available: row.available, held: row.held, total: row.total, locked: row.locked, currency: row.currency };
+        ((row.client, row.currency), summary)
+    });
+    rows.collect()
+}
+
+// Balances or lock states that differ, ordered by client id and currency. Amounts are compared at
+// the summary's 4 decimal precision.
+pub fn diff(old: &BTreeMap<(u16, Currency), SummaryRow>, new: &BTreeMap<(u16, Currency), SummaryRow>) -> Vec<ClientDelta> {
+    let ids: BTreeSet<(u16, Currency)> = old.keys().chain(new.keys()).copied().collect();
+    let delta = |new: f64, old: f64| ((new - old) * 10_000.0).round() / 10_000.0;
+    ids.into_iter()
+        .filter_map(|(id, currency)| {
+            let (before, after) = (old.get(&(id, currency)), new.get(&(id, currency)));
+            let change = match (before, after) {
+                (None, Some(_)) => Change::Added,
+                (Some(_), None) => Change::Removed,
+                _ => Change::Changed,
+            };
+            let zero = SummaryRow { client: id, available: 0.0, held: 0.0, total: 0.0, locked: false, currency };
+            let (before, after) = (before.unwrap_or(&zero), after.unwrap_or(&zero));
+            let d = ClientDelta {
+                client: id,
+                change,
+                available: delta(after.available, before.available),
+                held: delta(after.held, before.held),
+                total: delta(after.total, before.total),
+                newly_locked: after.locked && !before.locked,
+                currency,
+            };
+            let unchanged = d.available == 0.0 && d.held == 0.0 && d.total == 0.0 && after.locked == before.locked;
+            (change != Change::Changed || !unchanged).then_some(d)
+        })
+        .collect()
+}
+
+pub fn write_deltas<W: Write>(deltas: &[ClientDelta], format: OutputFormat, mut out: W) -> Result<(), Box<dyn Error>> {
+    match format {
+        OutputFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(out);
+            wtr.write_record(["client", "change", "available", "held", "total", "newly_locked", "currency"])?;
+            for d in deltas {
+                wtr.write_record(&[
+                    d.client.to_string(),
+                    d.change.as_str().to_string(),
+                    format!("{:.4}", d.available),
+                    format!("{:.4}", d.held),
+                    format!("{:.4}", d.total),
+                    d.newly_locked.to_string(),
+                    d.currency.to_string(),
+                ])?;
+            }
+            wtr.flush()?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut out, deltas)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Jsonl => {
+            for d in deltas {
+                serde_json::to_writer(&mut out, d)?;
+                writeln!(out)?;
+            }
+        }
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => return Err("diff output is csv, json or jsonl".into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_reports_changed_added_removed_and_newly_locked() {
+        let old = read_summary("client,available,held,total,locked\n1,10.0,0.0,10.0,false\n2,5.0,0.0,5.0,false\n3,1.0,0.0,1.0,false\n".as_bytes()).unwrap();
+        let new = read_summary(
+            "client,available,held,total,locked,tier\n1,10.0000,0.0000,10.0000,false,basic\n2,0.0000,0.0000,0.0000,true,basic\n4,2.5000,0.5000,3.0000,false,basic\ntotals,12.5000,0.5000,13.0000,,\noperator,fees_earned,chargeback_losses,net\noperator,0.0000,5.0000,-5.0000\n".as_bytes(),
+        ).unwrap();
+
+        let deltas = diff(&old, &new);
+        assert_eq!(deltas, vec![
+            ClientDelta { client: 2, change: Change::Changed, available: -5.0, held: 0.0, total: -5.0, newly_locked: true, currency: Currency::Usd },
+            ClientDelta { client: 3, change: Change::Removed, available: -1.0, held: 0.0, total: -1.0, newly_locked: false, currency: Currency::Usd },
+            ClientDelta { client: 4, change: Change::Added, available: 2.5, held: 0.5, total: 3.0, newly_locked: false, currency: Currency::Usd },
+        ]);
+
+        let mut out = Vec::new();
+        write_deltas(&deltas[..1], OutputFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,change,available,held,total,newly_locked,currency\n2,changed,-5.0000,0.0000,-5.0000,true,USD\n"
+        );
+    }
+
+    #[test]
+    fn test_checkpoints_are_compared_like_summaries() {
+        use crate::test_util::TxBuilder;
+
+        let dir = std::env::temp_dir();
+        let (summary, checkpoint) = (dir.join(format!("payments_processor_diff_{}.csv", std::process::id())), dir.join(format!("payments_processor_diff_{}.jsonl", std::process::id())));
+        std::fs::write(&summary, "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n").unwrap();
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(&TxBuilder::deposit(1, 1, 10.0).build()).unwrap();
+        ledger.process_transaction(&TxBuilder::dispute(1, 1).amount(2.5).build()).unwrap();
+        ledger.checkpoint(&checkpoint, &Default::default()).unwrap();
+
+        let deltas = diff(&load(&summary).unwrap(), &load(&checkpoint).unwrap());
+        assert_eq!(deltas, vec![ClientDelta { client: 1, change: Change::Changed, available: -2.5, held: 2.5, total: 0.0, newly_locked: false, currency: Currency::Usd }]);
+        std::fs::remove_file(&summary).unwrap();
+        std::fs::remove_file(&checkpoint).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use csv::Reader;
+use serde::Deserialize;
+
+use crate::transaction::Transaction;
+
+// One `[[reference]]` table from the config: `value_column` of the CSV at `path` is looked up by
+// `key_column` and stored on each transaction as the `name` attribute. `join_on` is "client", "tx",
+// or the name of an attribute added by an earlier table, so lookups can be chained
+// (client -> merchant -> merchant category).
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ReferenceSource {
+    pub name: String,
+    pub path: PathBuf,
+    pub key_column: String,
+    pub value_column: String,
+    #[serde(default = "default_join")]
+    pub join_on: String,
+}
+
+fn default_join() -> String {
+    "client".to_string()
+}
+
+#[derive(Debug)]
+pub enum ReferenceError {
+    Csv { path: PathBuf, source: csv::Error },
+    MissingColumn { path: PathBuf, column: String },
+}
+
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReferenceError::Csv { path, source } => write!(f, "Failed to read reference data {}: {}", path.display(), source),
+            ReferenceError::MissingColumn { path, column } => write!(f, "Reference data {} has no column {}", path.display(), column),
+        }
+    }
+}
+
+impl std::error::Error for ReferenceError {}
+
+struct ReferenceTable {
+    name: String,
+    join_on: String,
+    values: HashMap<String, String>,
+}
+
+#[derive(Default)]
+pub struct Enricher {
+    tables: Vec<ReferenceTable>,
+}
+
+impl Enricher {
+    pub fn load(sources: &[ReferenceSource]) -> Result<Self, ReferenceError> {
+        let tables = sources.iter().map(load_table).collect::<Result<_, _>>()?;
+        Ok(Self { tables })
+    }
+
+    // Adds an attribute for every table whose join key has a match; unmatched lookups add nothing
+    pub fn enrich(&self, tx: &mut Transaction) {
+        for table in &self.tables {
+            let key = match table.join_on.as_str() {
+                "client" => Some(tx.client_id.to_string()),
+                "tx" => Some(tx.tx_id.to_string()),
+                attribute => tx.attributes.get(attribute).cloned(),
+            };
+            if let Some(value) = key.and_then(|k| table.values.get(&k)) {
+                tx.attributes.insert(table.name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn load_table(source: &ReferenceSource) -> Result<ReferenceTable, ReferenceError> {
+    let csv_err = |e| ReferenceError::Csv { path: source.path.clone(), source: e };
+    let mut reader = Reader::from_path(&source.path).map_err(csv_err)?;
+    let headers = reader.headers().map_err(csv_err)?.clone();
+    let column = |name: &str| {
+        headers.iter().position(|h| h.trim() == name).ok_or_else(|| ReferenceError::MissingColumn {
+            path: source.path.clone(),
+            column: name.to_string(),
+        })
+    };
+    let (key, value) = (column(&source.key_column)?, column(&source.value_column)?);
+
+    let mut values = HashMap::new();
+    for record in reader.records() {
+        let record = record.map_err(csv_err)?;
+        if let (Some(k), Some(v)) = (record.get(key), record.get(value)) {
+            values.insert(k.trim().to_string(), v.trim().to_string());
+        }
+    }
+    Ok(ReferenceTable { name: source.name.clone(), join_on: source.join_on.clone(), values })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::test_util::TxBuilder;
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("payments_processor_{}_{}.csv", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn source(name: &str, path: &Path, key_column: &str, value_column: &str, join_on: &str) -> ReferenceSource {
+        ReferenceSource {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            key_column: key_column.to_string(),
+            value_column: value_column.to_string(),
+            join_on: join_on.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_enricher_joins_on_client_and_chains_attributes() {
+        let clients = write_temp("clients", "client_id,country,merchant\n1,DE,m1\n2,FR,m2\n");
+        let merchants = write_temp("merchants", "id, category\nm1, groceries\n");
+        let enricher = Enricher::load(&[
+            source("country", &clients, "client_id", "country", "client"),
+            source("merchant", &clients, "client_id", "merchant", "client"),
+            source("category", &merchants, "id", "category", "merchant"),
+        ]).unwrap();
+
+        let mut tx = TxBuilder::deposit(1, 10, 1.0).build();
+        enricher.enrich(&mut tx);
+        assert_eq!(tx.attributes["country"], "DE");
+        assert_eq!(tx.attributes["category"], "groceries");
+
+        let mut tx = TxBuilder::deposit(3, 11, 1.0).build();
+        enricher.enrich(&mut tx);
+        assert!(tx.attributes.is_empty());
+
+        std::fs::remove_file(clients).unwrap();
+        std::fs::remove_file(merchants).unwrap();
+    }
+
+    #[test]
+    fn test_enricher_reports_missing_column() {
+        let path = write_temp("bad_reference", "client,country\n1,DE\n");
+        let err = Enricher::load(&[source("region", &path, "client", "region", "client")]).err().unwrap();
+        assert!(matches!(err, ReferenceError::MissingColumn { ref column, .. } if column == "region"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
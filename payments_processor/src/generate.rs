@@ -0,0 +1,278 @@
+// Synthetic CSV inputs for load testing and fuzzing, for `generate`: deposits, withdrawals and
+// disputes with their resolves and chargebacks over a set of clients, reproducible from a seed
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+// Posted deposits remembered as dispute targets, so memory stays flat however many rows are asked for;
+// older ones are no longer disputed
+const DISPUTABLE: usize = 100_000;
+
+// SplitMix64, so runs are reproducible from the seed alone
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+// Relative weights of the record types, e.g. `deposit=60,withdrawal=25,dispute=8,resolve=5,chargeback=2`
+// (the default); types left out get 0. A resolve or chargeback needs an open dispute and a dispute a
+// deposit, so when there is none a deposit is written instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mix {
+    pub deposit: u32,
+    pub withdrawal: u32,
+    pub dispute: u32,
+    pub resolve: u32,
+    pub chargeback: u32,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Mix { deposit: 60, withdrawal: 25, dispute: 8, resolve: 5, chargeback: 2 }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidMix(String);
+
+impl fmt::Display for InvalidMix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid mix '{}', expected e.g. deposit=60,withdrawal=25,dispute=8,resolve=5,chargeback=2", self.0)
+    }
+}
+
+impl Error for InvalidMix {}
+
+impl FromStr for Mix {
+    type Err = InvalidMix;
+
+    fn from_str(s: &str) -> Result<Mix, InvalidMix> {
+        let invalid = || InvalidMix(s.to_string());
+        let mut mix = Mix { deposit: 0, withdrawal: 0, dispute: 0, resolve: 0, chargeback: 0 };
+        for part in s.split(',') {
+            let (name, weight) = part.split_once('=').ok_or_else(invalid)?;
+            let weight = weight.trim().parse().map_err(|_| invalid())?;
+            match name.trim().to_lowercase().as_str() {
+                "deposit" => mix.deposit = weight,
+                "withdrawal" => mix.withdrawal = weight,
+                "dispute" => mix.dispute = weight,
+                "resolve" => mix.resolve = weight,
+                "chargeback" => mix.chargeback = weight,
+                _ => return Err(invalid()),
+            }
+        }
+        // Everything else falls back to deposits, so there must be some weight to start from
+        if mix.deposit == 0 {
+            return Err(invalid());
+        }
+        Ok(mix)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl Mix {
+    fn pick(&self, rng: &mut Rng) -> Kind {
+        let weights = [
+            (Kind::Deposit, self.deposit),
+            (Kind::Withdrawal, self.withdrawal),
+            (Kind::Dispute, self.dispute),
+            (Kind::Resolve, self.resolve),
+            (Kind::Chargeback, self.chargeback),
+        ];
+        let mut roll = rng.below(weights.iter().map(|(_, w)| u64::from(*w)).sum());
+        for (kind, weight) in weights {
+            if roll < u64::from(weight) {
+                return kind;
+            }
+            roll -= u64::from(weight);
+        }
+        Kind::Deposit
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GenerateOptions {
+    pub clients: u16,
+    pub rows: u64,
+    pub seed: u64,
+    pub mix: Mix,
+    // Share of rows replaced by a malformed one (missing or unparsable fields, unknown types, negative
+    // amounts), between 0 and 1
+    pub malformed: f64,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        GenerateOptions { clients: 100, rows: 10_000, seed: 0, mix: Mix::default(), malformed: 0.0 }
+    }
+}
+
+// What was written, by kind
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GenerateReport {
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+    pub malformed: u64,
+}
+
+// Amounts are in ten-thousandths, the precision records allow
+fn amount(value: u64) -> String {
+    format!("{}.{:04}", value / 10_000, value % 10_000)
+}
+
+// Writes a CSV input with a header and `options.rows` records. Every deposit, withdrawal and malformed
+// row has a tx id of its own, counting up from 1; disputes, resolves and chargebacks refer to an
+// earlier deposit of the same client. Withdrawals are sized against what the generator expects the
+// client to have available and now and then exceed it, so the ledger rejects some, as it does
+// disputes on deposits that were partly withdrawn. Clients keep transacting after a chargeback, so
+// unless the ledger runs with `lock_on_chargeback` off, their later records are rejected too.
+pub fn generate<W: Write>(options: &GenerateOptions, out: W) -> Result<GenerateReport, Box<dyn Error>> {
+    let mut rng = Rng(options.seed);
+    let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(out);
+    wtr.write_record(["type", "client", "tx", "amount"])?;
+    let clients = options.clients.max(1);
+    let mut available = vec![0u64; usize::from(clients) + 1];
+    // (client, tx, amount) of posted deposits and of disputed ones
+    let mut disputable: VecDeque<(u16, u32, u64)> = VecDeque::new();
+    let mut disputed: Vec<(u16, u32, u64)> = vec![];
+    let mut report = GenerateReport::default();
+    let mut next_tx = 1u32;
+
+    for _ in 0..options.rows {
+        let client = rng.below(u64::from(clients)) as u16 + 1;
+        if rng.chance(options.malformed) {
+            let (tx, id) = (next_tx.to_string(), next_tx);
+            next_tx += 1;
+            let row: Vec<String> = match rng.below(6) {
+                0 => vec!["deposit".into(), client.to_string(), tx, String::new()],
+                1 => vec!["deposit".into(), client.to_string(), tx, "12,50".into()],
+                2 => vec!["withdrawal".into(), client.to_string(), tx, format!("-{}", amount(rng.below(1_000_000) + 1))],
+                3 => vec!["refund".into(), client.to_string(), tx, amount(rng.below(1_000_000) + 1)],
+                4 => vec!["deposit".into(), client.to_string()],
+                _ => vec!["deposit".into(), "client".into(), id.to_string(), "1.0".into()],
+            };
+            wtr.write_record(&row)?;
+            report.malformed += 1;
+            continue;
+        }
+        let kind = match options.mix.pick(&mut rng) {
+            Kind::Dispute if disputable.is_empty() => Kind::Deposit,
+            Kind::Resolve | Kind::Chargeback if disputed.is_empty() => Kind::Deposit,
+            kind => kind,
+        };
+        match kind {
+            Kind::Deposit => {
+                let value = rng.below(1_000_000) + 1;
+                wtr.write_record(["deposit", &client.to_string(), &next_tx.to_string(), &amount(value)])?;
+                available[usize::from(client)] += value;
+                disputable.push_back((client, next_tx, value));
+                if disputable.len() > DISPUTABLE {
+                    disputable.pop_front();
+                }
+                next_tx += 1;
+                report.deposits += 1;
+            }
+            Kind::Withdrawal => {
+                // Up to a tenth more than available, so about one in eleven overdraws
+                let balance = available[usize::from(client)];
+                let value = rng.below(balance + balance / 10 + 1) + 1;
+                wtr.write_record(["withdrawal", &client.to_string(), &next_tx.to_string(), &amount(value)])?;
+                if value <= balance {
+                    available[usize::from(client)] -= value;
+                }
+                next_tx += 1;
+                report.withdrawals += 1;
+            }
+            Kind::Dispute => {
+                let index = rng.below(disputable.len() as u64) as usize;
+                let target = disputable.swap_remove_back(index).expect("index is in range");
+                wtr.write_record(["dispute", &target.0.to_string(), &target.1.to_string(), ""])?;
+                let balance = &mut available[usize::from(target.0)];
+                *balance = balance.saturating_sub(target.2);
+                disputed.push(target);
+                report.disputes += 1;
+            }
+            Kind::Resolve | Kind::Chargeback => {
+                let index = rng.below(disputed.len() as u64) as usize;
+                let (client, tx, value) = disputed.swap_remove(index);
+                let name = if kind == Kind::Resolve { "resolve" } else { "chargeback" };
+                wtr.write_record([name, &client.to_string(), &tx.to_string(), ""])?;
+                if kind == Kind::Resolve {
+                    available[usize::from(client)] += value;
+                    report.resolves += 1;
+                } else {
+                    report.chargebacks += 1;
+                }
+            }
+        }
+    }
+    wtr.flush()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::{Ledger, LedgerConfig};
+    use crate::source::{CsvSource, SourceError, TransactionSource};
+
+    #[test]
+    fn test_mix_parses_weights_and_needs_deposits() {
+        assert_eq!("deposit=1, dispute=2".parse::<Mix>().unwrap(), Mix { deposit: 1, withdrawal: 0, dispute: 2, resolve: 0, chargeback: 0 });
+        assert!("withdrawal=1".parse::<Mix>().is_err());
+        assert!("deposit=1,refund=2".parse::<Mix>().is_err());
+    }
+
+    #[test]
+    fn test_generated_input_is_reproducible_and_feeds_the_ledger() {
+        let options = GenerateOptions { clients: 20, rows: 5_000, seed: 7, malformed: 0.02, ..GenerateOptions::default() };
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        let report = generate(&options, &mut first).unwrap();
+        generate(&options, &mut second).unwrap();
+        assert_eq!(first, second);
+        assert!(report.disputes > 0 && report.resolves > 0 && report.chargebacks > 0 && report.malformed > 0);
+
+        let mut source = CsvSource::from_reader(first.as_slice());
+        let mut ledger = Ledger::with_config(LedgerConfig { lock_on_chargeback: false, ..LedgerConfig::default() });
+        let (mut unreadable, mut applied) = (0, 0);
+        while let Some(result) = source.next() {
+            match result {
+                Ok(tx) => applied += ledger.process_transaction(&tx).is_ok() as u64,
+                Err(SourceError::UnknownRecord(_) | SourceError::Transaction(_) | SourceError::Csv(_)) => unreadable += 1,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert!(unreadable <= report.malformed);
+        let written = report.deposits + report.withdrawals + report.disputes + report.resolves + report.chargebacks;
+        assert!(applied > written * 8 / 10, "{} of {} applied", applied, written);
+    }
+}
@@ -0,0 +1,165 @@
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::client::AccountRow;
+use crate::enrichment::Enricher;
+use crate::handle::HandleError;
+use crate::shard::ShardedLedger;
+use crate::transaction::{self, Transaction, TxType};
+
+// Messages, server and client generated from proto/payments.proto
+pub mod proto {
+    tonic::include_proto!("payments");
+}
+
+use proto::payments_server::{Payments, PaymentsServer};
+use proto::{Account, GetAccountRequest, GetSummaryRequest, Rejection, SubmitResponse, Summary, TransactionRequest};
+
+// The `Payments` gRPC service over a running `ShardedLedger`, like the HTTP server of `serve`
+pub struct PaymentsService {
+    ledger: ShardedLedger,
+    enricher: Arc<Enricher>,
+}
+
+impl PaymentsService {
+    pub fn new(ledger: ShardedLedger, enricher: Arc<Enricher>) -> PaymentsServer<Self> {
+        PaymentsServer::new(PaymentsService { ledger, enricher })
+    }
+}
+
+// Serves until `shutdown` resolves, letting calls in flight finish
+pub async fn serve(
+    listener: TcpListener,
+    ledger: ShardedLedger,
+    enricher: Arc<Enricher>,
+    shutdown: impl Future<Output = ()> + Send,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(PaymentsService::new(ledger, enricher))
+        .serve_with_incoming_shutdown(TcpIncoming::from(listener), shutdown)
+        .await
+}
+
+// Validated like a record of an input file
+fn to_transaction(request: TransactionRequest) -> Result<Transaction, String> {
+    let client = u16::try_from(request.client).map_err(|_| format!("client id {} out of range", request.client))?;
+    let value = request.tier.as_deref().or(request.reason.as_deref());
+    let destination = request.destination.map(|d| d.to_string());
+    let tx_type = TxType::parse_input(&request.r#type, value, destination.as_deref()).map_err(|e| e.to_string())?;
+    let amount = if tx_type.carries_value() { None } else { request.amount };
+    let currency = transaction::parse_currency(request.currency.as_deref()).map_err(|e| e.to_string())?;
+    let mut tx = Transaction::new(tx_type, client, request.tx, amount);
+    tx.currency = currency;
+    tx.validate().map_err(|e| e.to_string())
+}
+
+fn account(row: AccountRow) -> Account {
+    Account {
+        client: u32::from(row.client),
+        available: row.available,
+        held: row.held,
+        total: row.total,
+        locked: row.locked,
+        tier: row.tier.to_string(),
+        operator_held: row.operator_held,
+        currency: row.currency.to_string(),
+    }
+}
+
+fn status(e: HandleError) -> Status {
+    match e {
+        HandleError::Closed => Status::unavailable(e.to_string()),
+        e => Status::failed_precondition(e.to_string()),
+    }
+}
+
+#[tonic::async_trait]
+impl Payments for PaymentsService {
+    async fn submit_transactions(&self, request: Request<Streaming<TransactionRequest>>) -> Result<Response<SubmitResponse>, Status> {
+        let mut stream = request.into_inner();
+        let mut response = SubmitResponse::default();
+        let mut index = 0;
+        while let Some(request) = stream.message().await? {
+            let tx_id = request.tx;
+            let result = match to_transaction(request) {
+                Ok(mut tx) => {
+                    self.enricher.enrich(&mut tx);
+                    match self.ledger.apply(tx).await {
+                        Err(HandleError::Closed) => return Err(status(HandleError::Closed)),
+                        result => result.map_err(|e| e.to_string()),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => response.applied += 1,
+                Err(error) => response.rejections.push(Rejection { index, tx: tx_id, error }),
+            }
+            index += 1;
+        }
+        Ok(Response::new(response))
+    }
+
+    async fn get_account(&self, request: Request<GetAccountRequest>) -> Result<Response<Account>, Status> {
+        let request = request.into_inner();
+        let id = request.client;
+        let currency = transaction::parse_currency(request.currency.as_deref()).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let client = u16::try_from(id).map_err(|_| Status::invalid_argument(format!("client id {} out of range", id)))?;
+        match self.ledger.shard(client).client(client).await.map_err(status)? {
+            Some(client) => Ok(Response::new(account(client.row(currency.unwrap_or_default())))),
+            None => Err(Status::not_found(format!("Client {} not found", id))),
+        }
+    }
+
+    async fn get_summary(&self, request: Request<GetSummaryRequest>) -> Result<Response<Summary>, Status> {
+        let snapshot = self.ledger.snapshot().await.map_err(status)?;
+        let operator = request.into_inner().operator.then(|| proto::OperatorAccount {
+            fees_earned: snapshot.operator.fees_earned,
+            chargeback_losses: snapshot.operator.chargeback_losses,
+            net: snapshot.operator.net(),
+        });
+        Ok(Response::new(Summary { accounts: snapshot.clients.iter().flat_map(|c| c.rows()).map(account).collect(), operator }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use proto::payments_client::PaymentsClient;
+
+    fn request(tx_type: &str, client: u32, tx: u32, amount: Option<f64>) -> TransactionRequest {
+        TransactionRequest { r#type: tx_type.to_string(), client, tx, amount, tier: None, reason: None, destination: None, currency: None }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_transactions_are_applied_and_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let ledger = ShardedLedger::spawn(vec![Ledger::new(), Ledger::new()]).unwrap();
+        let enricher = Arc::new(Enricher::load(&[]).unwrap());
+        tokio::spawn(serve(listener, ledger, enricher, std::future::pending()));
+
+        let mut client = PaymentsClient::connect(url).await.unwrap();
+        let stream = tonic::codegen::tokio_stream::iter(vec![
+            request("deposit", 1, 1, Some(3.0)),
+            request("withdrawal", 1, 2, Some(5.0)),
+            request("deposit", 70_000, 3, Some(1.0)),
+            request("deposit", 2, 4, Some(1.5)),
+        ]);
+        let response = client.submit_transactions(stream).await.unwrap().into_inner();
+        assert_eq!(response.applied, 2);
+        assert_eq!(response.rejections.iter().map(|r| (r.index, r.tx)).collect::<Vec<_>>(), vec![(1, 2), (2, 3)]);
+
+        let account = client.get_account(GetAccountRequest { client: 1, currency: None }).await.unwrap().into_inner();
+        assert_eq!((account.available, account.tier.as_str(), account.currency.as_str()), (3.0, "basic", "USD"));
+        let account = client.get_account(GetAccountRequest { client: 1, currency: Some("eur".to_string()) }).await.unwrap().into_inner();
+        assert_eq!((account.available, account.currency.as_str()), (0.0, "EUR"));
+        assert_eq!(client.get_account(GetAccountRequest { client: 9, currency: None }).await.unwrap_err().code(), tonic::Code::NotFound);
+        let summary = client.get_summary(GetSummaryRequest { operator: true }).await.unwrap().into_inner();
+        assert_eq!(summary.accounts.iter().map(|a| a.client).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(summary.operator.map(|o| o.net), Some(0.0));
+    }
+}
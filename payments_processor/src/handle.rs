@@ -0,0 +1,370 @@
+use std::collections::HashSet;
+use std::fmt;
+use tokio::sync::{mpsc, oneshot};
+use tracing::Span;
+
+use crate::checkpoint::{CheckpointError, CheckpointWriter};
+use crate::client::Client;
+use crate::ledger::{Ledger, LedgerConfig, LedgerError, LedgerSnapshot, SimulationResult};
+use crate::metrics::Metrics;
+use crate::rules::BusinessRules;
+use crate::search::SearchHit;
+use crate::store::StoreError;
+use crate::transaction::{Transaction, UnknownRecord};
+
+// Commands queued before callers start waiting on the actor
+const QUEUE_DEPTH: usize = 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum HandleError {
+    Ledger(LedgerError),
+    // Rejected by a typed constructor before reaching the ledger, e.g. a negative amount
+    Invalid(String),
+    // The ledger task has stopped, either through `shutdown` or a panic
+    Closed,
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandleError::Ledger(e) => write!(f, "{}", e),
+            HandleError::Invalid(e) => write!(f, "{}", e),
+            HandleError::Closed => write!(f, "Ledger task has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for HandleError {}
+
+// Server-side filter for `LedgerHandle::subscribe`; unset fields match everything
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    pub clients: Option<HashSet<u16>>,
+    // Record type names as written in the input, e.g. "chargeback"
+    pub tx_types: Option<HashSet<String>>,
+    // Compared with the transaction's amount, or for dispute/resolve/chargeback the disputed one's
+    pub min_amount: Option<f64>,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &LedgerEvent) -> bool {
+        self.clients.as_ref().is_none_or(|c| c.contains(&event.tx.client_id))
+            && self.tx_types.as_ref().is_none_or(|t| t.contains(event.tx.tx_type.name()))
+            && self.min_amount.is_none_or(|min| event.amount.is_some_and(|a| a >= min))
+    }
+}
+
+// One processed transaction as seen by subscribers, accepted or not
+#[derive(Clone, Debug)]
+pub struct LedgerEvent {
+    pub tx: Transaction,
+    pub amount: Option<f64>,
+    pub result: Result<(), LedgerError>,
+    // The client's balances after the transaction
+    pub client: Option<Client>,
+}
+
+enum Command {
+    // With the caller's span, so what the ledger and its hooks log is tied to the input and transaction
+    Apply(Transaction, Span, oneshot::Sender<Result<(), LedgerError>>),
+    // The steps of a transfer between shards; see `ShardedLedger::apply`
+    ReserveCredit(Transaction, oneshot::Sender<Result<(), LedgerError>>),
+    ApplyTransfer(Transaction, Result<(), LedgerError>, Span, oneshot::Sender<Result<(), LedgerError>>),
+    Credit(Transaction, Span, oneshot::Sender<Result<(), LedgerError>>),
+    CancelCredit(Transaction),
+    Unknown(UnknownRecord, Span, oneshot::Sender<Result<(), LedgerError>>),
+    Client(u16, oneshot::Sender<Option<Client>>),
+    Search(String, oneshot::Sender<Vec<SearchHit>>),
+    Metrics(oneshot::Sender<Metrics>),
+    Simulate(Vec<Transaction>, oneshot::Sender<Result<SimulationResult, StoreError>>),
+    // With a resume signal, the ledger task holds further commands until it fires (or is dropped)
+    Snapshot(oneshot::Sender<LedgerSnapshot>, Option<oneshot::Receiver<()>>),
+    Subscribe(EventFilter, mpsc::UnboundedSender<LedgerEvent>),
+    Checkpoint(CheckpointWriter, oneshot::Sender<Result<CheckpointWriter, CheckpointError>>),
+    Reload(LedgerConfig, Vec<Box<dyn BusinessRules>>, oneshot::Sender<()>),
+    Shutdown(oneshot::Sender<Ledger>),
+}
+
+// Async access to a `Ledger` owned by its own task. Handles are cheap to clone and can be used from
+// any number of tasks; commands are applied one at a time in the order they reach the ledger task,
+// so embedding services never touch a lock.
+#[derive(Clone)]
+pub struct LedgerHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+impl LedgerHandle {
+    // Moves the ledger into a new task on the current tokio runtime
+    pub fn spawn(mut ledger: Ledger) -> LedgerHandle {
+        let (commands, mut rx) = mpsc::channel(QUEUE_DEPTH);
+        tokio::spawn(async move {
+            let mut subscribers: Vec<(EventFilter, mpsc::UnboundedSender<LedgerEvent>)> = vec![];
+            while let Some(command) = rx.recv().await {
+                match command {
+                    Command::Apply(tx, span, reply) => {
+                        let result = span.in_scope(|| ledger.process_transaction(&tx));
+                        if !subscribers.is_empty() {
+                            publish(&mut subscribers, &ledger, &tx, &result);
+                        }
+                        let _ = reply.send(result);
+                    }
+                    Command::ReserveCredit(tx, reply) => {
+                        let _ = reply.send(ledger.reserve_credit(&tx));
+                    }
+                    Command::ApplyTransfer(tx, credit, span, reply) => {
+                        let result = span.in_scope(|| ledger.process_outgoing_transfer(&tx, credit));
+                        if !subscribers.is_empty() {
+                            publish(&mut subscribers, &ledger, &tx, &result);
+                        }
+                        let _ = reply.send(result);
+                    }
+                    Command::Credit(tx, span, reply) => {
+                        let _ = reply.send(span.in_scope(|| ledger.credit_transfer(&tx)));
+                    }
+                    Command::CancelCredit(tx) => ledger.cancel_credit(&tx),
+                    Command::Unknown(record, span, reply) => {
+                        let _ = reply.send(span.in_scope(|| ledger.handle_unknown(&record)));
+                    }
+                    Command::Subscribe(filter, events) => subscribers.push((filter, events)),
+                    Command::Client(id, reply) => {
+                        let _ = reply.send(ledger.client(id).cloned());
+                    }
+                    Command::Search(query, reply) => {
+                        let _ = reply.send(ledger.search_clients(&query));
+                    }
+                    Command::Metrics(reply) => {
+                        let _ = reply.send(ledger.metrics());
+                    }
+                    Command::Simulate(txs, reply) => {
+                        let _ = reply.send(ledger.simulate(&txs));
+                    }
+                    Command::Snapshot(reply, resume) => {
+                        let _ = reply.send(ledger.snapshot());
+                        if let Some(resume) = resume {
+                            let _ = resume.await;
+                        }
+                    }
+                    Command::Checkpoint(mut out, reply) => {
+                        let _ = reply.send(out.write_ledger(&ledger).map(|()| out));
+                    }
+                    Command::Reload(config, rules, reply) => {
+                        ledger.reload(&config, rules);
+                        let _ = reply.send(());
+                    }
+                    Command::Shutdown(reply) => {
+                        let _ = reply.send(ledger);
+                        return;
+                    }
+                }
+            }
+        });
+        LedgerHandle { commands }
+    }
+
+    pub async fn apply(&self, tx: Transaction) -> Result<(), HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Apply(tx, Span::current(), reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)?.map_err(HandleError::Ledger)
+    }
+
+    // See `Ledger::reserve_credit`
+    pub(crate) async fn reserve_credit(&self, tx: Transaction) -> Result<(), HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::ReserveCredit(tx, reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)?.map_err(HandleError::Ledger)
+    }
+
+    // See `Ledger::process_outgoing_transfer`
+    pub(crate) async fn apply_transfer(&self, tx: Transaction, credit: Result<(), LedgerError>) -> Result<(), HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::ApplyTransfer(tx, credit, Span::current(), reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)?.map_err(HandleError::Ledger)
+    }
+
+    pub(crate) async fn cancel_credit(&self, tx: Transaction) -> Result<(), HandleError> {
+        self.send(Command::CancelCredit(tx)).await
+    }
+
+    // See `Ledger::credit_transfer`
+    pub(crate) async fn credit_transfer(&self, tx: Transaction) -> Result<(), HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Credit(tx, Span::current(), reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)?.map_err(HandleError::Ledger)
+    }
+
+    // Applies the ledger's unknown-record policy; see `Ledger::handle_unknown`
+    pub async fn handle_unknown(&self, record: UnknownRecord) -> Result<(), HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Unknown(record, Span::current(), reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)?.map_err(HandleError::Ledger)
+    }
+
+    pub async fn deposit(&self, client: u16, tx: u32, amount: f64) -> Result<(), HandleError> {
+        let tx = Transaction::deposit(client, tx, amount).map_err(|e| HandleError::Invalid(e.to_string()))?;
+        self.apply(tx).await
+    }
+
+    pub async fn withdraw(&self, client: u16, tx: u32, amount: f64) -> Result<(), HandleError> {
+        let tx = Transaction::withdrawal(client, tx, amount).map_err(|e| HandleError::Invalid(e.to_string()))?;
+        self.apply(tx).await
+    }
+
+    pub async fn dispute(&self, client: u16, tx: u32) -> Result<(), HandleError> {
+        self.apply(Transaction::dispute(client, tx)).await
+    }
+
+    pub async fn resolve(&self, client: u16, tx: u32) -> Result<(), HandleError> {
+        self.apply(Transaction::resolve(client, tx)).await
+    }
+
+    pub async fn chargeback(&self, client: u16, tx: u32) -> Result<(), HandleError> {
+        self.apply(Transaction::chargeback(client, tx)).await
+    }
+
+    // A copy of the client's current balances
+    pub async fn client(&self, client: u16) -> Result<Option<Client>, HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Client(client, reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)
+    }
+
+    // The clients matching `query`; see `Ledger::search_clients`
+    pub async fn search(&self, query: &str) -> Result<Vec<SearchHit>, HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Search(query.to_string(), reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)
+    }
+
+    // What-if run of `txs`; see `Ledger::simulate`
+    pub async fn simulate(&self, txs: Vec<Transaction>) -> Result<SimulationResult, HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Simulate(txs, reply)).await?;
+        let result = response.await.map_err(|_| HandleError::Closed)?;
+        result.map_err(|e| HandleError::Ledger(LedgerError::Store(e)))
+    }
+
+    pub async fn metrics(&self) -> Result<Metrics, HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Metrics(reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)
+    }
+
+    // A point-in-time copy of the balances; commands queued behind it wait only for the copy
+    pub async fn snapshot(&self) -> Result<LedgerSnapshot, HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Snapshot(reply, None)).await?;
+        response.await.map_err(|_| HandleError::Closed)
+    }
+
+    // Like `snapshot`, but the ledger task then pauses until `resume` fires, so several ledgers can
+    // be copied at the same cut
+    pub(crate) async fn snapshot_paused(&self, resume: oneshot::Receiver<()>) -> Result<oneshot::Receiver<LedgerSnapshot>, HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Snapshot(reply, Some(resume))).await?;
+        Ok(response)
+    }
+
+    // Every transaction applied through any handle from now on that matches `filter`, accepted or
+    // rejected. Dropping the receiver ends the subscription.
+    pub async fn subscribe(&self, filter: EventFilter) -> Result<mpsc::UnboundedReceiver<LedgerEvent>, HandleError> {
+        let (events, receiver) = mpsc::unbounded_channel();
+        self.send(Command::Subscribe(filter, events)).await?;
+        Ok(receiver)
+    }
+
+    // Adds the ledger's state to a checkpoint being written, once the commands queued before are applied
+    pub async fn write_checkpoint(&self, out: CheckpointWriter) -> Result<CheckpointWriter, CheckpointError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Checkpoint(out, reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)?
+    }
+
+    // See `Ledger::reload`; transactions queued before this one are applied under the old config
+    pub async fn reload(&self, config: LedgerConfig, rules: Vec<Box<dyn BusinessRules>>) -> Result<(), HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Reload(config, rules, reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)
+    }
+
+    // Stops the ledger task once the commands queued before this one are applied and hands the
+    // ledger back, e.g. for writing the summary. Other handles get `Closed` afterwards.
+    pub async fn shutdown(self) -> Result<Ledger, HandleError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Shutdown(reply)).await?;
+        response.await.map_err(|_| HandleError::Closed)
+    }
+
+    async fn send(&self, command: Command) -> Result<(), HandleError> {
+        self.commands.send(command).await.map_err(|_| HandleError::Closed)
+    }
+}
+
+fn publish(
+    subscribers: &mut Vec<(EventFilter, mpsc::UnboundedSender<LedgerEvent>)>,
+    ledger: &Ledger,
+    tx: &Transaction,
+    result: &Result<(), LedgerError>,
+) {
+    let amount = tx.amount.or_else(|| ledger.transaction(tx.tx_id).ok().flatten().and_then(|t| t.amount));
+    let event = LedgerEvent { tx: tx.clone(), amount, result: result.clone(), client: ledger.client(tx.client_id).cloned() };
+    subscribers.retain(|(filter, events)| !filter.matches(&event) || events.send(event.clone()).is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Currency;
+
+    #[tokio::test]
+    async fn test_handle_serializes_concurrent_callers() {
+        let handle = LedgerHandle::spawn(Ledger::new());
+        let mut tasks = vec![];
+        for i in 0..50u32 {
+            let handle = handle.clone();
+            tasks.push(tokio::spawn(async move { handle.deposit((i % 5) as u16, i, 1.0).await }));
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        assert_eq!(handle.withdraw(0, 100, 20.0).await, Err(HandleError::Ledger(LedgerError::NotEnoughFunds {
+            client: 0,
+            requested: 20.0,
+            available: 10.0,
+        })));
+        let what_if = handle.simulate(vec![Transaction::withdrawal(0, 101, 10.0).unwrap()]).await.unwrap();
+        assert_eq!(what_if.clients[0].balance(Currency::Usd).available, 0.0);
+        assert_eq!(handle.client(0).await.unwrap().unwrap().balance(Currency::Usd).available, 10.0);
+        handle.dispute(1, 1).await.unwrap();
+        assert_eq!(handle.client(1).await.unwrap().unwrap().balance(Currency::Usd).held, 1.0);
+
+        let other = handle.clone();
+        let ledger = handle.shutdown().await.unwrap();
+        assert_eq!(ledger.clients().count(), 5);
+        assert_eq!(other.deposit(1, 200, 1.0).await, Err(HandleError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_only_receives_matching_events() {
+        let handle = LedgerHandle::spawn(Ledger::new());
+        let filter = EventFilter {
+            clients: Some(HashSet::from([1, 2])),
+            tx_types: Some(HashSet::from(["deposit".to_string(), "dispute".to_string()])),
+            min_amount: Some(50.0),
+        };
+        let mut events = handle.subscribe(filter).await.unwrap();
+
+        handle.deposit(1, 1, 100.0).await.unwrap();
+        handle.deposit(1, 2, 10.0).await.unwrap();
+        handle.deposit(3, 3, 100.0).await.unwrap();
+        handle.withdraw(2, 4, 60.0).await.unwrap_err();
+        handle.dispute(1, 1).await.unwrap();
+        drop(handle);
+
+        let mut seen = vec![];
+        while let Some(event) = events.recv().await {
+            seen.push((event.tx.tx_type.name(), event.tx.tx_id, event.amount));
+        }
+        assert_eq!(seen, [("deposit", 1, Some(100.0)), ("dispute", 1, Some(100.0))]);
+    }
+}
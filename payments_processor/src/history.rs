@@ -0,0 +1,151 @@
+// Per-transaction audit trail: every accepted change to a transaction's state, for `export-history`
+
+use std::error::Error;
+use std::io::Write;
+use serde::Serialize;
+
+use crate::summary::OutputFormat;
+use crate::transaction::{Transaction, TxType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxEventKind {
+    Deposited,
+    Withdrawn,
+    Held,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Annulled,
+    Released,
+    Transferred,
+}
+
+impl TxEventKind {
+    // None for records that don't act on a transaction (tier changes, locks and unlocks)
+    pub fn of(tx_type: &TxType) -> Option<TxEventKind> {
+        match tx_type {
+            TxType::Deposit => Some(TxEventKind::Deposited),
+            TxType::Withdrawal => Some(TxEventKind::Withdrawn),
+            TxType::Hold => Some(TxEventKind::Held),
+            TxType::Dispute => Some(TxEventKind::Disputed),
+            TxType::Resolve => Some(TxEventKind::Resolved),
+            TxType::Chargeback => Some(TxEventKind::ChargedBack),
+            TxType::Annul(_) => Some(TxEventKind::Annulled),
+            TxType::Release => Some(TxEventKind::Released),
+            TxType::Transfer(_) => Some(TxEventKind::Transferred),
+            TxType::SetTier(_) | TxType::Lock | TxType::Unlock => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            TxEventKind::Deposited => "deposited",
+            TxEventKind::Withdrawn => "withdrawn",
+            TxEventKind::Held => "held",
+            TxEventKind::Disputed => "disputed",
+            TxEventKind::Resolved => "resolved",
+            TxEventKind::ChargedBack => "charged_back",
+            TxEventKind::Annulled => "annulled",
+            TxEventKind::Released => "released",
+            TxEventKind::Transferred => "transferred",
+        }
+    }
+}
+
+// `seq` counts the events of one ledger, so it orders the events of a transaction (whose client
+// always lives in the same shard) but not those of different shards. The amount is on the event that
+// created the transaction and on disputes, resolves and chargebacks of part of it, the reason only on
+// annulments.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TxEvent {
+    pub seq: u64,
+    pub tx: u32,
+    pub client: u16,
+    pub event: TxEventKind,
+    pub amount: Option<f64>,
+    pub reason: Option<String>,
+}
+
+impl TxEvent {
+    pub fn new(seq: u64, tx: &Transaction) -> Option<TxEvent> {
+        let event = TxEventKind::of(&tx.tx_type)?;
+        let reason = match &tx.tx_type {
+            TxType::Annul(reason) => Some(reason.clone()),
+            _ => None,
+        };
+        let amount = if tx.tx_type.moves_funds() || tx.tx_type.is_dispute() { tx.amount } else { None };
+        Some(TxEvent { seq, tx: tx.tx_id, client: tx.client_id, event, amount, reason })
+    }
+}
+
+// Ordered by transaction, then sequence
+pub fn write_history<W: Write>(events: &[TxEvent], format: OutputFormat, mut out: W) -> Result<(), Box<dyn Error>> {
+    let mut events: Vec<&TxEvent> = events.iter().collect();
+    events.sort_by_key(|e| (e.tx, e.seq));
+    match format {
+        OutputFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(out);
+            wtr.write_record(["seq", "tx", "client", "event", "amount", "reason"])?;
+            for e in events {
+                wtr.write_record(&[
+                    e.seq.to_string(),
+                    e.tx.to_string(),
+                    e.client.to_string(),
+                    e.event.as_str().to_string(),
+                    e.amount.map(|a| format!("{:.4}", a)).unwrap_or_default(),
+                    e.reason.clone().unwrap_or_default(),
+                ])?;
+            }
+            wtr.flush()?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut out, &events)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Jsonl => {
+            for e in events {
+                serde_json::to_writer(&mut out, e)?;
+                writeln!(out)?;
+            }
+        }
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => return Err("history output is csv, json or jsonl".into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_history_lists_each_transactions_transitions_in_order() {
+        let mut ledger = Ledger::new();
+        ledger.set_history(true);
+        ledger.set_admin_ops(true);
+        for tx in [
+            TxBuilder::deposit(1, 1, 10.0).build(),
+            TxBuilder::deposit(1, 2, 5.0).build(),
+            TxBuilder::dispute(1, 1).build(),
+            TxBuilder::withdrawal(1, 3, 50.0).build(),
+            TxBuilder::resolve(1, 1).build(),
+            TxBuilder::annul(1, 2, "duplicate").build(),
+        ] {
+            let _ = ledger.process_transaction(&tx);
+        }
+        // Neither the rejected withdrawal nor a simulated deposit leave a trace
+        let simulated = ledger.simulate(&[TxBuilder::deposit(1, 4, 1.0).build()]).unwrap();
+        assert!(simulated.rejections.is_empty());
+        assert_eq!(ledger.history().len(), 5);
+
+        let mut out = Vec::new();
+        ledger.export_history(OutputFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "seq,tx,client,event,amount,reason\n1,1,1,deposited,10.0000,\n3,1,1,disputed,,\n4,1,1,resolved,,\n2,2,1,deposited,5.0000,\n5,2,1,annulled,,duplicate\n"
+        );
+    }
+}
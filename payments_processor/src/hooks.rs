@@ -0,0 +1,53 @@
+use crate::client::Client;
+use crate::ledger::LedgerError;
+use crate::transaction::Transaction;
+
+// Extension points called by `Ledger` around every transaction. All methods default to no-ops,
+// so a hook only implements what it cares about.
+pub trait LedgerHook: Send {
+    // Returning Err(reason) rejects the transaction before it touches any balance
+    fn before_apply(&mut self, _tx: &Transaction, _client: Option<&Client>) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn after_apply(&mut self, _tx: &Transaction, _client: Option<&Client>) {}
+
+    fn on_reject(&mut self, _tx: &Transaction, _error: &LedgerError) {}
+
+    // The destination side of a transfer whose source client is in another shard of a
+    // `ShardedLedger`; the other calls were made there. `client` is the destination.
+    fn after_credit(&mut self, _tx: &Transaction, _client: Option<&Client>) {}
+}
+
+pub(crate) struct BeforeApplyFn<F>(pub F);
+
+impl<F> LedgerHook for BeforeApplyFn<F>
+where
+    F: FnMut(&Transaction, Option<&Client>) -> Result<(), String> + Send,
+{
+    fn before_apply(&mut self, tx: &Transaction, client: Option<&Client>) -> Result<(), String> {
+        (self.0)(tx, client)
+    }
+}
+
+pub(crate) struct AfterApplyFn<F>(pub F);
+
+impl<F> LedgerHook for AfterApplyFn<F>
+where
+    F: FnMut(&Transaction, Option<&Client>) + Send,
+{
+    fn after_apply(&mut self, tx: &Transaction, client: Option<&Client>) {
+        (self.0)(tx, client)
+    }
+}
+
+pub(crate) struct OnRejectFn<F>(pub F);
+
+impl<F> LedgerHook for OnRejectFn<F>
+where
+    F: FnMut(&Transaction, &LedgerError) + Send,
+{
+    fn on_reject(&mut self, tx: &Transaction, error: &LedgerError) {
+        (self.0)(tx, error)
+    }
+}
@@ -0,0 +1,138 @@
+// One client's balances and transactions, for the `inspect` subcommand
+
+use std::error::Error;
+use std::io::Write;
+use serde::Serialize;
+
+use crate::client::{AccountRow, Currency};
+use crate::ledger::Ledger;
+use crate::store::StoreError;
+use crate::summary::OutputFormat;
+use crate::transaction::Transaction;
+
+// A transaction as `inspect` lists it. `value` is what the record type adds (the tier, the transfer's
+// destination or the annulment's reason), `disputed` the part of a deposit under dispute.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TxRow {
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub tx_type: &'static str,
+    pub amount: Option<f64>,
+    pub currency: Currency,
+    pub status: &'static str,
+    pub disputed: Option<f64>,
+    pub value: Option<String>,
+}
+
+impl From<&Transaction> for TxRow {
+    fn from(tx: &Transaction) -> Self {
+        TxRow {
+            tx: tx.tx_id,
+            tx_type: tx.tx_type.name(),
+            amount: tx.amount,
+            currency: tx.currency.unwrap_or_default(),
+            status: tx.status.name(),
+            disputed: tx.disputed,
+            value: tx.tx_type.value(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientReport {
+    pub client: u16,
+    // One row per currency, as in the summary
+    pub balances: Vec<AccountRow>,
+    pub transactions: Vec<TxRow>,
+}
+
+impl ClientReport {
+    // None if the ledger has no such client
+    pub fn of(ledger: &Ledger, client_id: u16) -> Result<Option<ClientReport>, StoreError> {
+        let Some(client) = ledger.client(client_id) else { return Ok(None) };
+        let transactions = ledger.client_transactions(client_id)?.iter().map(TxRow::from).collect();
+        Ok(Some(ClientReport { client: client_id, balances: client.rows(), transactions }))
+    }
+
+    // CSV is the balances as summary rows, then a blank line and the transactions; JSON (and JSON
+    // Lines) one object with both
+    pub fn write<W: Write>(&self, format: OutputFormat, mut out: W) -> Result<(), Box<dyn Error>> {
+        match format {
+            OutputFormat::Csv => {
+                let mut wtr = csv::Writer::from_writer(&mut out);
+                wtr.write_record(["client", "available", "held", "total", "locked", "tier", "operator_held", "currency"])?;
+                for row in &self.balances {
+                    wtr.write_record(&[
+                        row.client.to_string(),
+                        format!("{:.4}", row.available),
+                        format!("{:.4}", row.held),
+                        format!("{:.4}", row.total),
+                        row.locked.to_string(),
+                        row.tier.to_string(),
+                        format!("{:.4}", row.operator_held),
+                        row.currency.to_string(),
+                    ])?;
+                }
+                wtr.flush()?;
+                drop(wtr);
+                writeln!(out)?;
+                let mut wtr = csv::Writer::from_writer(out);
+                wtr.write_record(["tx", "type", "amount", "currency", "status", "disputed", "value"])?;
+                for tx in &self.transactions {
+                    wtr.write_record(&[
+                        tx.tx.to_string(),
+                        tx.tx_type.to_string(),
+                        tx.amount.map(|a| format!("{:.4}", a)).unwrap_or_default(),
+                        tx.currency.to_string(),
+                        tx.status.to_string(),
+                        tx.disputed.map(|a| format!("{:.4}", a)).unwrap_or_default(),
+                        tx.value.clone().unwrap_or_default(),
+                    ])?;
+                }
+                wtr.flush()?;
+            }
+            OutputFormat::Json | OutputFormat::Jsonl => {
+                serde_json::to_writer(&mut out, self)?;
+                writeln!(out)?;
+            }
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => return Err("inspect output is csv, json or jsonl".into()),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_report_lists_a_clients_balances_and_transactions() {
+        let mut ledger = Ledger::new();
+        for tx in [
+            TxBuilder::deposit(1, 2, 10.0).build(),
+            TxBuilder::deposit(2, 3, 1.0).build(),
+            TxBuilder::deposit(1, 1, 5.0).currency(Currency::Eur).build(),
+            TxBuilder::dispute(1, 2).amount(4.0).build(),
+        ] {
+            ledger.process_transaction(&tx).unwrap();
+        }
+        assert_eq!(ledger.client_balance(1, Currency::Usd).unwrap().held, 4.0);
+        assert!(ledger.client_balance(2, Currency::Eur).is_none());
+        assert!(ClientReport::of(&ledger, 3).unwrap().is_none());
+
+        let mut out = Vec::new();
+        ClientReport::of(&ledger, 1).unwrap().unwrap().write(OutputFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked,tier,operator_held,currency\n\
+             1,6.0000,4.0000,10.0000,false,basic,0.0000,USD\n\
+             1,5.0000,0.0000,5.0000,false,basic,0.0000,EUR\n\
+             \n\
+             tx,type,amount,currency,status,disputed,value\n\
+             1,deposit,5.0000,EUR,posted,,\n\
+             2,deposit,10.0000,USD,disputed,4.0000,\n"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+// Consistency checks over a whole ledger, for `Ledger::check_invariants` and `--check-invariants`.
+// Balances are compared to half the smallest amount records can carry, so float rounding isn't a violation.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::client::Currency;
+use crate::ledger::Ledger;
+use crate::store::StoreError;
+use crate::transaction::{PaymentStatus, TxType};
+
+const TOLERANCE: f64 = 0.00005;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InvariantViolation {
+    // total != available + held + operator_held
+    Unbalanced { client: u16, currency: Currency, available: f64, held: f64, operator_held: f64, total: f64 },
+    // `held` or `operator_held` below zero
+    NegativeHold { client: u16, currency: Currency, field: &'static str, amount: f64 },
+    // `available` below the client's overdraft limit (zero without one)
+    Overdrawn { client: u16, currency: Currency, available: f64, limit: f64 },
+    // `held` differs from what the client's open disputes hold
+    DisputedMismatch { client: u16, currency: Currency, held: f64, disputed: f64 },
+    // `operator_held` differs from the client's active holds
+    HoldMismatch { client: u16, currency: Currency, operator_held: f64, holds: f64 },
+    // A client with a charged-back deposit that isn't locked, although chargebacks lock and nothing unlocks
+    NotLocked { client: u16, tx: u32 },
+    Store(StoreError),
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvariantViolation::Unbalanced { client, currency, available, held, operator_held, total } => write!(
+                f,
+                "Client {} {}: total {} is not available {} + held {} + operator held {}",
+                client, currency, total, available, held, operator_held
+            ),
+            InvariantViolation::NegativeHold { client, currency, field, amount } => write!(f, "Client {} {}: {} is {}", client, currency, field, amount),
+            InvariantViolation::Overdrawn { client, currency, available, limit } =>
+                write!(f, "Client {} {}: available {} is below the overdraft limit of {}", client, currency, available, limit),
+            InvariantViolation::DisputedMismatch { client, currency, held, disputed } =>
+                write!(f, "Client {} {}: held {} but {} is under dispute", client, currency, held, disputed),
+            InvariantViolation::HoldMismatch { client, currency, operator_held, holds } =>
+                write!(f, "Client {} {}: operator held {} but the active holds add up to {}", client, currency, operator_held, holds),
+            InvariantViolation::NotLocked { client, tx } => write!(f, "Client {} is not locked after the chargeback of tx {}", client, tx),
+            InvariantViolation::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+impl From<StoreError> for InvariantViolation {
+    fn from(e: StoreError) -> Self {
+        InvariantViolation::Store(e)
+    }
+}
+
+fn differs(a: f64, b: f64) -> bool {
+    (a - b).abs() > TOLERANCE
+}
+
+// Returns the first violation, going through clients by id and their balances by currency. Reads the
+// whole transaction history, so a check costs as much as the ledger is large.
+pub fn check(ledger: &Ledger) -> Result<(), InvariantViolation> {
+    // (disputed, held by the operator) per client and currency, and the first charged-back tx per client
+    let mut expected: BTreeMap<(u16, Currency), (f64, f64)> = BTreeMap::new();
+    let mut charged_back: BTreeMap<u16, u32> = BTreeMap::new();
+    ledger.for_each_transaction(&mut |tx| {
+        let key = (tx.client_id, tx.currency.unwrap_or_default());
+        match (&tx.tx_type, &tx.status) {
+            (TxType::Deposit, PaymentStatus::Disputed) => expected.entry(key).or_default().0 += tx.disputed.or(tx.amount).unwrap_or(0.0),
+            (TxType::Deposit, PaymentStatus::ChargedBack) => {
+                let first = charged_back.entry(tx.client_id).or_insert(tx.tx_id);
+                *first = (*first).min(tx.tx_id);
+            }
+            (TxType::Hold, _) => expected.entry(key).or_default().1 += tx.amount.unwrap_or(0.0),
+            _ => {}
+        }
+        Ok(())
+    })?;
+
+    let config = ledger.config();
+    let keys: BTreeSet<(u16, Currency)> = ledger.clients()
+        .flat_map(|c| c.balances.keys().map(move |currency| (c.id, *currency)))
+        .chain(expected.keys().copied())
+        .collect();
+    for (client, currency) in keys {
+        let balance = ledger.client_balance(client, currency).unwrap_or_default();
+        let (disputed, holds) = expected.get(&(client, currency)).copied().unwrap_or_default();
+        if differs(balance.total, balance.available + balance.held + balance.operator_held) {
+            let (available, held, operator_held, total) = (balance.available, balance.held, balance.operator_held, balance.total);
+            return Err(InvariantViolation::Unbalanced { client, currency, available, held, operator_held, total });
+        }
+        for (field, amount) in [("held", balance.held), ("operator held", balance.operator_held)] {
+            if amount < -TOLERANCE {
+                return Err(InvariantViolation::NegativeHold { client, currency, field, amount });
+            }
+        }
+        let limit = ledger.client(client).and_then(|c| c.overdraft_limit).unwrap_or(config.overdraft_limit);
+        if balance.available < -limit - TOLERANCE {
+            return Err(InvariantViolation::Overdrawn { client, currency, available: balance.available, limit });
+        }
+        if differs(balance.held, disputed) {
+            return Err(InvariantViolation::DisputedMismatch { client, currency, held: balance.held, disputed });
+        }
+        if differs(balance.operator_held, holds) {
+            return Err(InvariantViolation::HoldMismatch { client, currency, operator_held: balance.operator_held, holds });
+        }
+    }
+
+    // With admin operations a lock record could have been undone since
+    if config.lock_on_chargeback && !config.admin_ops {
+        for (client, tx) in charged_back {
+            if !ledger.client(client).is_some_and(|c| c.locked) {
+                return Err(InvariantViolation::NotLocked { client, tx });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_invariants_catch_a_dispute_taking_available_below_zero() {
+        let mut ledger = Ledger::new();
+        for tx in [
+            TxBuilder::deposit(1, 1, 10.0).build(),
+            TxBuilder::deposit(1, 2, 5.0).build(),
+            TxBuilder::dispute(1, 2).build(),
+            TxBuilder::resolve(1, 2).build(),
+            TxBuilder::deposit(2, 3, 1.0).build(),
+            TxBuilder::dispute(2, 3).build(),
+            TxBuilder::chargeback(2, 3).build(),
+            TxBuilder::withdrawal(1, 4, 12.0).build(),
+        ] {
+            ledger.process_transaction(&tx).unwrap();
+        }
+        assert_eq!(ledger.check_invariants(), Ok(()));
+
+        // The withdrawal took most of the deposit, so disputing it overdraws the account
+        ledger.process_transaction(&TxBuilder::dispute(1, 1).build()).unwrap();
+        assert_eq!(
+            ledger.check_invariants(),
+            Err(InvariantViolation::Overdrawn { client: 1, currency: Currency::Usd, available: -7.0, limit: 0.0 })
+        );
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+
+use crate::client::{Client, Currency};
+use crate::hooks::LedgerHook;
+use crate::ledger::{Ledger, LedgerError};
+use crate::segments::{self, JournalRetention};
+use crate::transaction::{Transaction, TxType};
+
+// Entries between fsyncs; every entry is flushed to the OS as soon as it is written, so a crash of
+// the process loses nothing, and a crash of the machine at most this many entries
+const SYNC_EVERY: u64 = 1_000;
+// Format of the entries, in the header line a new journal starts with; journals without one were
+// written before versions and are version 1. Version 2 added the reload entries.
+pub const VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    Corrupt { line: u64, error: String },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "Journal I/O error: {}", e),
+            JournalError::Corrupt { line, error } => write!(f, "Corrupt journal entry on line {}: {}", line, error),
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+// One line of the journal: a transaction as the ledger was about to apply it, the marker that the
+// transaction with that sequence number was rejected after all, a config reload, or the header
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Rejected {
+        seq: u64,
+        rejected: String,
+    },
+    Applied {
+        seq: u64,
+        #[serde(rename = "type")]
+        tx_type: String,
+        client: u16,
+        tx: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        amount: Option<f64>,
+        // The tier of a tier record, or the reason of an annul
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        attributes: BTreeMap<String, String>,
+    },
+    // The config file serve was reloaded from, once the entries up to `after` were written
+    Reloaded {
+        reloaded: String,
+        sha256: String,
+        after: u64,
+    },
+    Header {
+        version: u32,
+    },
+}
+
+impl Entry {
+    fn applied(seq: u64, tx: &Transaction) -> Entry {
+        Entry::Applied {
+            seq,
+            tx_type: tx.tx_type.name().to_string(),
+            client: tx.client_id,
+            tx: tx.tx_id,
+            amount: tx.amount,
+            value: tx.tx_type.value(),
+            currency: tx.currency,
+            attributes: tx.attributes.clone(),
+        }
+    }
+}
+
+// Append-only write-ahead log of what the ledger applied. Every transaction is written (and flushed)
+// before the ledger touches any balance, and followed by a rejection marker if the ledger turned it
+// down, so `replay` can rebuild the ledger from the accepted ones. Opening an existing journal
+// appends to it, continuing its sequence numbers. With a `JournalRetention` the file rolls over into
+// segments (see segments.rs).
+pub struct Journal {
+    out: BufWriter<File>,
+    next_seq: u64,
+    unsynced: u64,
+    path: PathBuf,
+    // Size of the file at `path`, and whether it holds a transaction yet; a segment always ends with one
+    bytes: u64,
+    has_applied: bool,
+    retention: JournalRetention,
+    // The thread compressing and deleting the rolled-over segments, and how to wake it up
+    maintenance: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl Journal {
+    // Returns the shared journal plus the hook to register on the ledger
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Arc<Mutex<Journal>>, JournalHook), JournalError> {
+        let path = path.as_ref();
+        // The sequence continues from the last entry, which is in the last segment if the file
+        // doesn't hold one yet; a torn final line from a crash is ignored
+        let mut next_seq = segments::segments(path)?.last().map_or(1, |segment| segment.last_seq + 1);
+        let mut empty = true;
+        let mut has_applied = false;
+        if path.exists() {
+            for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+                empty = false;
+                match serde_json::from_str(&line?) {
+                    Ok(Entry::Applied { seq, .. }) => {
+                        next_seq = seq + 1;
+                        has_applied = true;
+                    }
+                    Ok(Entry::Header { version }) if version > VERSION => {
+                        return Err(JournalError::Corrupt { line: n as u64 + 1, error: format!("unsupported version {}", version) });
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let bytes = file.metadata()?.len();
+        let mut journal = Journal {
+            out: BufWriter::new(file),
+            next_seq,
+            unsynced: 0,
+            path: path.to_path_buf(),
+            bytes,
+            has_applied,
+            retention: JournalRetention::default(),
+            maintenance: None,
+        };
+        if empty {
+            journal.append(&Entry::Header { version: VERSION })?;
+        }
+        let state = Arc::new(Mutex::new(journal));
+        let hook = Journal::hook(&state);
+        Ok((state, hook))
+    }
+
+    // Another hook writing to the same journal, for when the transactions go through several ledgers
+    pub fn hook(state: &Arc<Mutex<Journal>>) -> JournalHook {
+        JournalHook { state: Arc::clone(state), pending: None }
+    }
+
+    // Rolls over the file at `path` once it reaches `segment_bytes`, and compresses and deletes
+    // segments as configured; segments left over from an earlier run are taken care of right away
+    pub fn set_retention(&mut self, retention: JournalRetention) {
+        // The thread has its own copy of the old retention
+        self.wait_for_retention();
+        self.retention = retention;
+        if self.retention != JournalRetention::default() {
+            self.maintain();
+        }
+    }
+
+    fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        // Rolls over before a transaction rather than after it, so its rejection marker stays with it
+        if let Entry::Applied { .. } = entry {
+            if self.has_applied && self.retention.segment_bytes.is_some_and(|max| self.bytes >= max) {
+                self.rotate()?;
+            }
+            self.has_applied = true;
+        }
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.out.write_all(&line)?;
+        self.out.flush()?;
+        self.bytes += line.len() as u64;
+        self.unsynced += 1;
+        if self.unsynced >= SYNC_EVERY {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Records that the config was reloaded from `path`, with its checksum, after the last entry
+    pub fn record_reload(&mut self, path: &Path, sha256: &str) -> io::Result<()> {
+        let entry = Entry::Reloaded { reloaded: path.display().to_string(), sha256: sha256.to_string(), after: self.last_seq() };
+        self.append(&entry)?;
+        self.sync()
+    }
+
+    // Sequence number of the last entry written, 0 before the first
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    // Forces everything written so far to disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.out.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // Moves the file to the segment named after its last entry and starts a new one at `path`
+    fn rotate(&mut self) -> io::Result<()> {
+        self.sync()?;
+        fs::rename(&self.path, segments::segment_path(&self.path, self.last_seq()))?;
+        self.out = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        self.bytes = 0;
+        self.has_applied = false;
+        self.append(&Entry::Header { version: VERSION })?;
+        self.maintain();
+        Ok(())
+    }
+
+    // Runs the retention on the segments in the background. Every run goes over all of them, so
+    // rotations while one is running are taken care of by a single run after it.
+    fn maintain(&mut self) {
+        let (wake, _) = self.maintenance.get_or_insert_with(|| {
+            let (wake, woken) = mpsc::channel();
+            let (path, retention) = (self.path.clone(), self.retention.clone());
+            let thread = thread::spawn(move || {
+                while woken.recv().is_ok() {
+                    while woken.try_recv().is_ok() {}
+                    match segments::enforce(&path, &retention, SystemTime::now()) {
+                        Ok(deleted) => {
+                            for segment in deleted {
+                                tracing::info!("Deleted journal segment {}", segment.display());
+                            }
+                        }
+                        Err(e) => tracing::warn!("Journal retention failed for {}: {}", path.display(), e),
+                    }
+                }
+            });
+            (wake, thread)
+        });
+        let _ = wake.send(());
+    }
+
+    // Waits for the segments being compressed or deleted, for a run about to exit; the journal may
+    // outlive it, held by tasks that aren't joined
+    pub fn wait_for_retention(&mut self) {
+        if let Some((wake, thread)) = self.maintenance.take() {
+            drop(wake);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        self.wait_for_retention();
+    }
+}
+
+pub struct JournalHook {
+    state: Arc<Mutex<Journal>>,
+    // Sequence number of the transaction in flight, once it is in the journal
+    pending: Option<u64>,
+}
+
+impl LedgerHook for JournalHook {
+    // A transaction that can't be journaled is rejected, so the ledger never gets ahead of the journal
+    fn before_apply(&mut self, tx: &Transaction, _client: Option<&Client>) -> Result<(), String> {
+        let mut journal = self.state.lock().map_err(|_| "journal poisoned".to_string())?;
+        let seq = journal.next_seq;
+        journal.append(&Entry::applied(seq, tx)).map_err(|e| format!("journal write failed: {}", e))?;
+        journal.next_seq += 1;
+        self.pending = Some(seq);
+        Ok(())
+    }
+
+    fn after_apply(&mut self, _tx: &Transaction, _client: Option<&Client>) {
+        self.pending = None;
+    }
+
+    fn on_reject(&mut self, _tx: &Transaction, error: &LedgerError) {
+        // Nothing to mark when an earlier hook rejected the transaction before it was journaled
+        let Some(seq) = self.pending.take() else { return };
+        if let Ok(mut journal) = self.state.lock()
+            && let Err(e) = journal.append(&Entry::Rejected { seq, rejected: error.to_string() })
+        {
+            // Replay re-validates, so a missing marker only costs a warning there
+            tracing::warn!("Failed to journal rejection of entry {}: {}", seq, e);
+        }
+    }
+}
+
+// Outcome of `replay`: how many journaled transactions were applied, and any that the ledger
+// rejected this time although the journal recorded them as accepted (e.g. different rules)
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    pub applied: u64,
+    pub skipped_rejected: u64,
+    pub diverged: Vec<(u64, LedgerError)>,
+}
+
+// Rebuilds a ledger by applying the journal's accepted transactions in order. The ledger should be
+// configured like the one that wrote the journal (same rules and limits) to end up in the same state.
+pub fn replay<P: AsRef<Path>>(path: P, ledger: &mut Ledger) -> Result<ReplayReport, JournalError> {
+    replay_to(path, ledger, None)
+}
+
+// Like `replay`, stopping after entry `last`, e.g. the one a checkpoint was taken at
+pub fn replay_to<P: AsRef<Path>>(path: P, ledger: &mut Ledger, last: Option<u64>) -> Result<ReplayReport, JournalError> {
+    let path = path.as_ref();
+    let mut rejected = HashSet::new();
+    for_each_entry(path, |entry| {
+        match entry {
+            Entry::Rejected { seq, .. } => {
+                rejected.insert(seq);
+            }
+            // Replay applies everything under the one config it is given
+            Entry::Reloaded { reloaded, after, .. } if last.is_none_or(|last| after < last) => {
+                tracing::warn!("The entries after {} were applied under the config reloaded from {}", after, reloaded);
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+
+    let mut report = ReplayReport::default();
+    let mut first = true;
+    for_each_entry(path, |entry| {
+        let Entry::Applied { seq, tx_type, client, tx, amount, value, currency, attributes } = entry else {
+            return Ok(());
+        };
+        if std::mem::take(&mut first) && seq > 1 {
+            tracing::warn!("The journal starts at entry {}; the segments before it were deleted by the retention", seq);
+        }
+        if last.is_some_and(|last| seq > last) {
+            return Ok(());
+        }
+        if rejected.contains(&seq) {
+            report.skipped_rejected += 1;
+            return Ok(());
+        }
+        let tx_type = TxType::parse(&tx_type, value.as_deref()).map_err(|e| e.to_string())?;
+        let mut tx = Transaction::new(tx_type, client, tx, amount);
+        tx.currency = currency;
+        tx.attributes = attributes;
+        match ledger.process_transaction(&tx) {
+            Ok(()) => report.applied += 1,
+            Err(e) => report.diverged.push((seq, e)),
+        }
+        Ok(())
+    })?;
+    Ok(report)
+}
+
+// Writes the journal's last `entries` entries (all of them for None) to `out` as a journal of its own,
+// under the current header, and returns how many it wrote. The sequence numbers are kept, so the
+// tail still lines up with the full journal.
+pub fn write_tail<P: AsRef<Path>, W: Write>(path: P, entries: Option<u64>, mut out: W) -> Result<u64, JournalError> {
+    let path = path.as_ref();
+    let mut total = 0;
+    for_each_entry(path, |entry| {
+        if !matches!(entry, Entry::Header { .. }) {
+            total += 1;
+        }
+        Ok(())
+    })?;
+    let skip = total - entries.unwrap_or(total).min(total);
+    let mut write = |entry: &Entry| -> io::Result<()> {
+        serde_json::to_writer(&mut out, entry)?;
+        out.write_all(b"\n")
+    };
+    write(&Entry::Header { version: VERSION })?;
+    let (mut seen, mut failed) = (0, None);
+    for_each_entry(path, |entry| {
+        if !matches!(entry, Entry::Header { .. }) {
+            seen += 1;
+            if seen > skip && failed.is_none() {
+                failed = write(&entry).err();
+            }
+        }
+        Ok(())
+    })?;
+    match failed {
+        Some(e) => Err(e.into()),
+        None => Ok(total - skip),
+    }
+}
+
+// Calls `f` for every entry, going through the segments and then the file at `path`; a final line
+// that doesn't parse is the torn write of a crash and is skipped. Line numbers count across the
+// files. A journal of a later version than this build's is refused rather than misread.
+fn for_each_entry(path: &Path, mut f: impl FnMut(Entry) -> Result<(), String>) -> Result<(), JournalError> {
+    let segments = segments::segments(path)?;
+    let active = || -> io::Result<Box<dyn BufRead>> { Ok(Box::new(BufReader::new(File::open(path)?))) };
+    let mut line_no = 0;
+    for file in segments.iter().map(|segment| segment.open()).chain(std::iter::once_with(active)) {
+        let mut lines = file?.lines().peekable();
+        while let Some(line) = lines.next() {
+            line_no += 1;
+            let line = line?;
+            let entry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(_) if lines.peek().is_none() => break,
+                Err(e) => return Err(JournalError::Corrupt { line: line_no, error: e.to_string() }),
+            };
+            if let Entry::Header { version } = entry
+                && version > VERSION
+            {
+                return Err(JournalError::Corrupt { line: line_no, error: format!("unsupported version {}", version) });
+            }
+            f(entry).map_err(|error| JournalError::Corrupt { line: line_no, error })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Tier;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_replay_rebuilds_the_ledger_from_accepted_entries() {
+        let path = std::env::temp_dir().join(format!("payments_processor_journal_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let txs = [
+            TxBuilder::deposit(1, 1, 10.0).currency(Currency::Eur).build(),
+            TxBuilder::withdrawal(1, 2, 50.0).currency(Currency::Eur).build(),
+            TxBuilder::set_tier(1, 3, Tier::Premium).build(),
+            TxBuilder::dispute(1, 1).build(),
+        ];
+
+        let mut ledger = Ledger::new();
+        ledger.set_admin_ops(true);
+        let (journal, hook) = Journal::open(&path).unwrap();
+        ledger.add_hook(Box::new(hook));
+        for tx in &txs {
+            let _ = ledger.process_transaction(tx);
+        }
+        journal.lock().unwrap().sync().unwrap();
+        drop(ledger);
+        // A crash in the middle of writing an entry
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"seq\":5,\"ty").unwrap();
+
+        let mut replayed = Ledger::new();
+        replayed.set_admin_ops(true);
+        let report = replay(&path, &mut replayed).unwrap();
+        assert_eq!(report, ReplayReport { applied: 3, skipped_rejected: 1, diverged: vec![] });
+        let client = replayed.client(1).unwrap();
+        let balance = client.balance(Currency::Eur);
+        assert_eq!((balance.available, balance.held, client.tier), (0.0, 10.0, Tier::Premium));
+
+        // Reopening continues the sequence
+        let (journal, _) = Journal::open(&path).unwrap();
+        assert_eq!(journal.lock().unwrap().next_seq, 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rotated_and_compressed_segments_are_replayed_in_order() {
+        let dir = std::env::temp_dir().join(format!("payments_processor_journal_segments_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("journal.jsonl");
+
+        let mut ledger = Ledger::new();
+        let (journal, hook) = Journal::open(&path).unwrap();
+        journal.lock().unwrap().set_retention(JournalRetention { segment_bytes: Some(1), compress: true, ..Default::default() });
+        ledger.add_hook(Box::new(hook));
+        for tx in 1..=3 {
+            ledger.process_transaction(&TxBuilder::deposit(1, tx, 10.0).build()).unwrap();
+        }
+        // Rejected, and the marker stays in the segment of its transaction
+        let _ = ledger.process_transaction(&TxBuilder::withdrawal(1, 4, 100.0).build());
+        drop(ledger);
+        drop(journal);
+
+        let rotated = segments::segments(&path).unwrap();
+        assert_eq!(rotated.iter().map(|s| (s.last_seq, s.compressed)).collect::<Vec<_>>(), [(1, true), (2, true), (3, true)]);
+        let mut replayed = Ledger::new();
+        assert_eq!(replay(&path, &mut replayed).unwrap(), ReplayReport { applied: 3, skipped_rejected: 1, diverged: vec![] });
+        assert_eq!(replayed.client(1).unwrap().balance(Currency::Usd).available, 30.0);
+        let (journal, _) = Journal::open(&path).unwrap();
+        assert_eq!(journal.lock().unwrap().next_seq, 5);
+
+        // Deleting the oldest segments leaves the later entries replayable
+        drop(journal);
+        let deleted = segments::enforce(&path, &JournalRetention { max_total_bytes: Some(0), ..Default::default() }, SystemTime::now()).unwrap();
+        assert_eq!(deleted.len(), 3);
+        let mut replayed = Ledger::new();
+        assert_eq!(replay(&path, &mut replayed).unwrap(), ReplayReport { applied: 0, skipped_rejected: 1, diverged: vec![] });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_journals_of_a_later_version_are_refused() {
+        let path = std::env::temp_dir().join(format!("payments_processor_journal_v2_{}.jsonl", std::process::id()));
+        std::fs::write(&path, format!("{{\"version\":{}}}\n", VERSION + 1)).unwrap();
+        assert!(matches!(Journal::open(&path), Err(JournalError::Corrupt { line: 1, .. })));
+        assert!(matches!(replay(&path, &mut Ledger::new()), Err(JournalError::Corrupt { line: 1, .. })));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
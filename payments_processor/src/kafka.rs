@@ -0,0 +1,149 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::Message;
+
+use crate::source::{self, SourceError, TransactionSource};
+use crate::transaction::Transaction;
+
+// How long a poll waits for a message before checking the stop flag again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KafkaOptions {
+    pub brokers: String,
+    pub topic: String,
+    pub group: String,
+    // Any other key=value, handed to librdkafka as is, e.g. security.protocol=ssl
+    pub extra: Vec<(String, String)>,
+}
+
+impl KafkaOptions {
+    // From `--kafka brokers=host:9092 topic=payments [group=<consumer group>] [<librdkafka key>=<value>...]`
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (mut brokers, mut topic, mut group, mut extra) = (None, None, None, vec![]);
+        for arg in args {
+            let (key, value) = arg.split_once('=').ok_or_else(|| format!("expected key=value, got {}", arg))?;
+            match key {
+                "brokers" => brokers = Some(value.to_string()),
+                "topic" => topic = Some(value.to_string()),
+                "group" => group = Some(value.to_string()),
+                key => extra.push((key.to_string(), value.to_string())),
+            }
+        }
+        Ok(KafkaOptions {
+            brokers: brokers.ok_or("--kafka needs brokers=<host:port,...>")?,
+            topic: topic.ok_or("--kafka needs topic=<name>")?,
+            group: group.unwrap_or_else(|| "payments_processor".to_string()),
+            extra,
+        })
+    }
+}
+
+// Consumes one topic as a never-ending input: each message is a record (a JSON object or a headerless
+// CSV line, see `source::parse_record`). A message's offset is only stored for commit once the caller
+// asks for the next record, i.e. after it applied (or rejected) this one, so a crash redelivers
+// whatever wasn't applied. Delivery is at least once: run the ledger with `--idempotent` to skip
+// redelivered transactions. `next` returns None once `stop` is set.
+pub struct KafkaSource {
+    consumer: BaseConsumer,
+    stop: Arc<AtomicBool>,
+    // Topic, partition and offset of the message `next` returned last
+    last: Option<(String, i32, i64)>,
+    payload: Option<String>,
+}
+
+impl KafkaSource {
+    pub fn connect(options: &KafkaOptions, stop: Arc<AtomicBool>) -> Result<Self, KafkaError> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &options.brokers)
+            .set("group.id", &options.group)
+            .set("auto.offset.reset", "earliest")
+            .set("enable.auto.commit", "true")
+            .set("enable.auto.offset.store", "false");
+        for (key, value) in &options.extra {
+            config.set(key, value);
+        }
+        let consumer: BaseConsumer = config.create()?;
+        consumer.subscribe(&[&options.topic])?;
+        Ok(KafkaSource { consumer, stop, last: None, payload: None })
+    }
+
+    fn store_last(&mut self) {
+        if let Some((topic, partition, offset)) = self.last.take()
+            && let Err(e) = self.consumer.store_offset(&topic, partition, offset)
+        {
+            tracing::error!("Failed to store Kafka offset {} of {}/{}: {}", offset, topic, partition, e);
+        }
+    }
+}
+
+impl TransactionSource for KafkaSource {
+    fn next(&mut self) -> Option<Result<Transaction, SourceError>> {
+        self.store_last();
+        loop {
+            if self.stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            let message = match self.consumer.poll(POLL_INTERVAL)? {
+                Ok(message) => message,
+                // librdkafka retries broker and network errors itself, so they are only logged
+                Err(e) => {
+                    tracing::warn!("Kafka error: {}", e);
+                    continue;
+                }
+            };
+            self.last = Some((message.topic().to_string(), message.partition(), message.offset()));
+            let payload = match message.payload_view::<str>() {
+                Some(Ok(payload)) => payload,
+                None => "",
+                Some(Err(e)) => {
+                    self.payload = None;
+                    return Some(Err(SourceError::Io(io::Error::new(io::ErrorKind::InvalidData, e))));
+                }
+            };
+            self.payload = Some(payload.to_string());
+            return Some(source::parse_record(payload));
+        }
+    }
+
+    // The offset of the last message within its partition
+    fn line(&self) -> Option<u64> {
+        self.last.as_ref().map(|(_, _, offset)| *offset as u64)
+    }
+
+    fn raw(&self) -> Option<String> {
+        self.payload.clone()
+    }
+}
+
+impl Drop for KafkaSource {
+    // Commits what was stored, rather than leaving it to the next auto-commit
+    fn drop(&mut self) {
+        match self.consumer.commit_consumer_state(CommitMode::Sync) {
+            Ok(()) | Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => {}
+            Err(e) => tracing::error!("Failed to commit Kafka offsets: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_need_brokers_and_topic_and_pass_the_rest_on() {
+        let args: Vec<String> = ["brokers=a:9092,b:9092", "topic=payments", "security.protocol=ssl"].map(String::from).into();
+        let options = KafkaOptions::parse(&args).unwrap();
+        assert_eq!((options.brokers.as_str(), options.topic.as_str(), options.group.as_str()), ("a:9092,b:9092", "payments", "payments_processor"));
+        assert_eq!(options.extra, vec![("security.protocol".to_string(), "ssl".to_string())]);
+
+        assert_eq!(KafkaOptions::parse(&args[1..]), Err("--kafka needs brokers=<host:port,...>".to_string()));
+        assert_eq!(KafkaOptions::parse(&["topic".to_string()]), Err("expected key=value, got topic".to_string()));
+    }
+}
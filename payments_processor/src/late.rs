@@ -0,0 +1,98 @@
+use std::io::{self, Write};
+use serde::{Deserialize, Serialize};
+
+// What happens to a record whose seq or timestamp is no later than the last one a `--watch` summary
+// already reported (`late_records` in the config)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LateRecordPolicy {
+    // Apply it like any other record
+    #[default]
+    Apply,
+    // Apply it and list it in the `--late-records` restatement report, as the summaries it falls
+    // into no longer hold
+    Restate,
+    // Hold it back for review: list it in `--late-records` without applying it
+    Review,
+}
+
+// A line of the `--late-records` file
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LateRecord {
+    pub input: String,
+    pub line: Option<u64>,
+    pub seq: u64,
+    // The highest seq of the records the last summary included
+    pub reported_through: u64,
+    // "restated" or "held"
+    pub action: &'static str,
+    // The record as it appears in the input, so a held one can be resubmitted
+    pub record: Option<String>,
+}
+
+// Tracks how far the summaries have reported, and writes the late records as JSON Lines
+pub struct LateRecords<W: Write> {
+    policy: LateRecordPolicy,
+    out: W,
+    latest: Option<u64>,
+    reported: Option<u64>,
+}
+
+impl<W: Write> LateRecords<W> {
+    pub fn new(policy: LateRecordPolicy, out: W) -> Self {
+        LateRecords { policy, out, latest: None, reported: None }
+    }
+
+    // Whether to apply a record with this seq. Records without one are never late.
+    pub fn admit(&mut self, input: &str, line: Option<u64>, seq: Option<u64>, record: Option<String>) -> io::Result<bool> {
+        let Some(seq) = seq else { return Ok(true) };
+        let action = match (self.policy, self.reported) {
+            (LateRecordPolicy::Restate, Some(reported)) if seq <= reported => "restated",
+            (LateRecordPolicy::Review, Some(reported)) if seq <= reported => "held",
+            _ => {
+                self.latest = self.latest.max(Some(seq));
+                return Ok(true);
+            }
+        };
+        let late = LateRecord { input: input.to_string(), line, seq, reported_through: self.reported.unwrap_or_default(), action, record };
+        serde_json::to_writer(&mut self.out, &late)?;
+        writeln!(self.out)?;
+        Ok(self.policy == LateRecordPolicy::Restate)
+    }
+
+    // Everything admitted so far went into a summary
+    pub fn reported(&mut self) {
+        self.reported = self.latest;
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_inside_reported_periods_are_restated_or_held() {
+        for (policy, applied) in [(LateRecordPolicy::Restate, true), (LateRecordPolicy::Review, false)] {
+            let mut late = LateRecords::new(policy, Vec::new());
+            assert!(late.admit("a.csv", Some(2), Some(10), None).unwrap());
+            late.reported();
+            assert!(late.admit("b.csv", Some(2), Some(20), None).unwrap());
+            assert!(late.admit("b.csv", Some(3), None, None).unwrap());
+            assert_eq!(late.admit("b.csv", Some(4), Some(10), Some("deposit,1,3,1.0,10".to_string())).unwrap(), applied);
+
+            let report: serde_json::Value = serde_json::from_slice(&late.out).unwrap();
+            assert_eq!((report["line"].as_u64(), report["reported_through"].as_u64()), (Some(4), Some(10)));
+            assert_eq!(report["action"], if applied { "restated" } else { "held" });
+        }
+
+        let mut late = LateRecords::new(LateRecordPolicy::Apply, Vec::new());
+        late.admit("a.csv", Some(2), Some(10), None).unwrap();
+        late.reported();
+        assert!(late.admit("b.csv", Some(2), Some(5), None).unwrap());
+        assert!(late.out.is_empty());
+    }
+}
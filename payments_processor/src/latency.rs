@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::client::Client;
+use crate::hooks::LedgerHook;
+use crate::ledger::LedgerError;
+use crate::transaction::Transaction;
+
+// Per-transaction apply latency, measured from the hook's `before_apply` to its `after_apply` or
+// `on_reject`. Register it first so the time spent in the other hooks and the rules is included.
+// Transactions over the budget are logged as warnings with the transaction and client state.
+pub struct LatencyTracker {
+    budget: Option<Duration>,
+    // Microseconds, sorted when the summary is taken
+    samples: Vec<u32>,
+    slow: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub p50_us: u32,
+    pub p99_us: u32,
+    pub max_us: u32,
+    pub over_budget: u64,
+}
+
+impl LatencyTracker {
+    // Returns the shared tracker plus the hook to register on the ledger
+    pub fn new(budget: Option<Duration>) -> (Arc<Mutex<LatencyTracker>>, LatencyHook) {
+        let state = Arc::new(Mutex::new(LatencyTracker { budget, samples: vec![], slow: 0 }));
+        let hook = LatencyTracker::hook(&state);
+        (state, hook)
+    }
+
+    // Another hook feeding the same tracker, for when the transactions go through several ledgers
+    pub fn hook(state: &Arc<Mutex<LatencyTracker>>) -> LatencyHook {
+        LatencyHook { state: Arc::clone(state), started: None }
+    }
+
+    fn record(&mut self, elapsed: Duration, tx: &Transaction, client: Option<&Client>, error: Option<&LedgerError>) {
+        self.samples.push(elapsed.as_micros().min(u32::MAX as u128) as u32);
+        if self.budget.is_some_and(|budget| elapsed > budget) {
+            self.slow += 1;
+            tracing::warn!(
+                "Slow transaction: {:?} took {:?} (budget {:?}); client: {:?}; rejected: {}",
+                tx,
+                elapsed,
+                self.budget.unwrap_or_default(),
+                client,
+                error.map_or("no".to_string(), |e| e.to_string()),
+            );
+        }
+    }
+
+    pub fn summary(&mut self) -> LatencySummary {
+        self.samples.sort_unstable();
+        let percentile = |p: usize| match self.samples.len() {
+            0 => 0,
+            n => self.samples[((n - 1) * p).div_ceil(100)],
+        };
+        LatencySummary {
+            count: self.samples.len(),
+            p50_us: percentile(50),
+            p99_us: percentile(99),
+            max_us: self.samples.last().copied().unwrap_or(0),
+            over_budget: self.slow,
+        }
+    }
+}
+
+pub struct LatencyHook {
+    state: Arc<Mutex<LatencyTracker>>,
+    started: Option<Instant>,
+}
+
+impl LatencyHook {
+    fn finish(&mut self, tx: &Transaction, client: Option<&Client>, error: Option<&LedgerError>) {
+        if let (Some(started), Ok(mut state)) = (self.started.take(), self.state.lock()) {
+            state.record(started.elapsed(), tx, client, error);
+        }
+    }
+}
+
+impl LedgerHook for LatencyHook {
+    fn before_apply(&mut self, _tx: &Transaction, _client: Option<&Client>) -> Result<(), String> {
+        self.started = Some(Instant::now());
+        Ok(())
+    }
+
+    fn after_apply(&mut self, tx: &Transaction, client: Option<&Client>) {
+        self.finish(tx, client, None);
+    }
+
+    fn on_reject(&mut self, tx: &Transaction, error: &LedgerError) {
+        self.finish(tx, None, Some(error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_latency_tracker_counts_every_transaction() {
+        let (state, hook) = LatencyTracker::new(Some(Duration::ZERO));
+        let mut ledger = Ledger::new();
+        ledger.add_hook(Box::new(hook));
+        ledger.before_apply(|_, _| {
+            std::thread::sleep(Duration::from_millis(2));
+            Ok(())
+        });
+
+        ledger.process_transaction(&TxBuilder::deposit(1, 1, 5.0).build()).unwrap();
+        ledger.process_transaction(&TxBuilder::withdrawal(1, 2, 50.0).build()).unwrap_err();
+
+        let summary = state.lock().unwrap().summary();
+        assert_eq!(summary.count, 2);
+        assert_eq!(summary.over_budget, 2);
+        assert!(summary.p50_us >= 2_000);
+        assert!(summary.max_us >= summary.p99_us && summary.p99_us >= summary.p50_us);
+    }
+}
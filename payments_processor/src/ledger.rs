@@ -1,17 +1,65 @@
-use std::collections::HashMap;
-use csv::{StringRecord, Writer};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+use csv::StringRecord;
 use std::error::Error;
 use std::fmt;
+use std::io::Write;
+use std::time::Instant;
 
-use crate::transaction::{Transaction, TxType, PaymentStatus};
-use crate::client::Clients;
+use serde::Deserialize;
 
-#[derive(Debug, PartialEq)]
+use crate::checkpoint::{self, CheckpointError, CheckpointWriter, Offsets};
+use crate::transaction::{Transaction, TxType, PaymentStatus, UnknownRecord};
+use crate::client::{Balance, Client, Clients, Currency, OperatorAccount, Tier, TierLimits};
+use crate::clients_file::ClientSettings;
+use crate::history::{self, TxEvent};
+use crate::invariants::{self, InvariantViolation};
+use crate::hooks::{AfterApplyFn, BeforeApplyFn, LedgerHook, OnRejectFn};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::rules::BusinessRules;
+use crate::search::SearchHit;
+use crate::shard::TxIds;
+use crate::source::{SourceError, TransactionSource};
+use crate::store::{LedgerStore, MemoryStore, StoreError};
+use crate::summary::{self, CsvSummaryWriter, OutputFormat, SummaryOptions, SummaryWriter};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum LedgerError {
     ClientNotFound(u16),
     MalformedRequest,
     NotEnoughFunds { client: u16, requested: f64, available: f64 },
     InvalidDispute(u32),
+    // A dispute, resolve or chargeback the dispute lifecycle doesn't allow from the tx's status,
+    // e.g. disputing a charged-back tx
+    InvalidStateTransition { tx: u32, from: PaymentStatus, to: PaymentStatus },
+    // A dispute, resolve or chargeback naming a different client than the transaction it refers to
+    ClientMismatch { tx: u32, expected: u16, got: u16 },
+    // A dispute for more than the deposit, or a resolve or chargeback for more than is under dispute
+    DisputeAmountTooLarge { tx: u32, requested: f64, outstanding: f64 },
+    // A dispute for more than the client has available, under `DisputeFundsPolicy::Reject`
+    DisputeExceedsAvailable { tx: u32, requested: f64, available: f64 },
+    // Deposit, withdrawal or outgoing transfer on an account locked by a chargeback, under
+    // `LockedAccountPolicy::Reject`
+    AccountLocked(u16),
+    // A deposit, withdrawal, hold or transfer reusing the tx id of an earlier one
+    DuplicateTransaction(u32),
+    // An annul naming a tx that is not a posted or resolved deposit or withdrawal
+    InvalidAnnulment(u32),
+    // A release naming a tx id that isn't an active hold
+    UnknownHold(u32),
+    // A transfer whose destination is its own client
+    SelfTransfer(u32),
+    // A record naming another currency than the transaction it refers to
+    CurrencyMismatch { tx: u32, expected: Currency, got: Currency },
+    // An admin record (tier, hold, release, annul, lock or unlock) on a ledger that doesn't allow admin
+    // operations (`set_admin_ops`)
+    AdminOpsDisabled(u32),
+    RejectedByHook { tx: u32, reason: String },
+    RejectedByRule { tx: u32, reason: String },
+    TierLimit { client: u16, tier: Tier, limit: &'static str },
+    UnknownRecordType { tx_type: String, reason: String },
+    Store(StoreError),
 }
 impl fmt::Display for LedgerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -21,85 +69,1096 @@ impl fmt::Display for LedgerError {
             LedgerError::NotEnoughFunds { client, requested, available } =>
                 write!(f, "Client {}: insufficient funds (requested {}, available {})", client, requested, available),
             LedgerError::InvalidDispute(tx) => write!(f, "Invalid dispute for tx {}", tx),
+            LedgerError::InvalidStateTransition { tx, from, to } => write!(f, "Tx {} cannot go from {} to {}", tx, from, to),
+            LedgerError::ClientMismatch { tx, expected, got } =>
+                write!(f, "Tx {} belongs to client {}, not client {}", tx, expected, got),
+            LedgerError::DisputeAmountTooLarge { tx, requested, outstanding } =>
+                write!(f, "Tx {}: {} is more than the {} that can be disputed", tx, requested, outstanding),
+            LedgerError::DisputeExceedsAvailable { tx, requested, available } =>
+                write!(f, "Tx {}: disputing {} would take available funds of {} below zero", tx, requested, available),
+            LedgerError::AccountLocked(client) => write!(f, "Client {} is locked", client),
+            LedgerError::DuplicateTransaction(tx) => write!(f, "Duplicate transaction id {}", tx),
+            LedgerError::InvalidAnnulment(tx) => write!(f, "Tx {} cannot be annulled", tx),
+            LedgerError::UnknownHold(tx) => write!(f, "No active hold with tx {}", tx),
+            LedgerError::SelfTransfer(tx) => write!(f, "Tx {} transfers to its own client", tx),
+            LedgerError::CurrencyMismatch { tx, expected, got } => write!(f, "Tx {} is in {}, not {}", tx, expected, got),
+            LedgerError::AdminOpsDisabled(tx) => write!(f, "Tx {} is an admin operation, which this ledger doesn't allow", tx),
+            LedgerError::RejectedByHook { tx, reason } => write!(f, "Tx {} rejected by hook: {}", tx, reason),
+            LedgerError::RejectedByRule { tx, reason } => write!(f, "Tx {} rejected by business rules: {}", tx, reason),
+            LedgerError::TierLimit { client, tier, limit } => write!(f, "Client {}: {} tier does not allow this ({})", client, tier, limit),
+            LedgerError::UnknownRecordType { tx_type, reason } => write!(f, "Unknown transaction type {}: {}", tx_type, reason),
+            LedgerError::Store(e) => write!(f, "{}", e),
         }
     }
 }
 impl std::error::Error for LedgerError {}
 
+impl LedgerError {
+    // A stable name for the kind of error, e.g. for the `kind` label of the error metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LedgerError::ClientNotFound(_) => "client_not_found",
+            LedgerError::MalformedRequest => "malformed_request",
+            LedgerError::NotEnoughFunds { .. } => "not_enough_funds",
+            LedgerError::InvalidDispute(_) => "invalid_dispute",
+            LedgerError::InvalidStateTransition { .. } => "invalid_state_transition",
+            LedgerError::ClientMismatch { .. } => "client_mismatch",
+            LedgerError::DisputeAmountTooLarge { .. } => "dispute_amount_too_large",
+            LedgerError::DisputeExceedsAvailable { .. } => "dispute_exceeds_available",
+            LedgerError::AccountLocked(_) => "account_locked",
+            LedgerError::DuplicateTransaction(_) => "duplicate_transaction",
+            LedgerError::InvalidAnnulment(_) => "invalid_annulment",
+            LedgerError::UnknownHold(_) => "unknown_hold",
+            LedgerError::SelfTransfer(_) => "self_transfer",
+            LedgerError::CurrencyMismatch { .. } => "currency_mismatch",
+            LedgerError::AdminOpsDisabled(_) => "admin_ops_disabled",
+            LedgerError::RejectedByHook { .. } => "rejected_by_hook",
+            LedgerError::RejectedByRule { .. } => "rejected_by_rule",
+            LedgerError::TierLimit { .. } => "tier_limit",
+            LedgerError::UnknownRecordType { .. } => "unknown_record_type",
+            LedgerError::Store(_) => "store",
+        }
+    }
+}
+
+impl From<StoreError> for LedgerError {
+    fn from(e: StoreError) -> Self {
+        LedgerError::Store(e)
+    }
+}
+
+// Accepted or rejected transactions between automatic `Ledger::flush`es
+const COMMIT_EVERY: u64 = 10_000;
+
+// What to do with records of a type this build doesn't know (`unknown_records` in the config), so
+// producers can add record types without breaking older deployments
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownRecordPolicy {
+    #[default]
+    Reject,
+    // Drop the record; it is still counted per type in the manifest
+    Skip,
+    // Offer it to the business rules' `handle_unknown`; rejected if none takes it
+    Plugin,
+}
+
+// Whether deposits and withdrawals still go through on accounts locked by a chargeback
+// (`locked_accounts` in the config)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockedAccountPolicy {
+    #[default]
+    Reject,
+    Allow,
+}
+
+// What a dispute does when the client has already spent part of the deposit, so that holding all of it
+// would take `available` below zero (`dispute_funds` in the config). Disputes can't use an overdraft line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisputeFundsPolicy {
+    // Hold it all anyway, leaving available negative
+    #[default]
+    Allow,
+    // Reject the dispute with `LedgerError::DisputeExceedsAvailable`
+    Reject,
+    // Hold what is available and record the rest as the transaction's `shortfall`
+    Cap,
+}
+
+// Which applied transactions the ledger keeps (`retention` in the config). Only deposits can be
+// disputed; withdrawals and transfers are kept for annulments alone, and active holds are always kept
+// for their release. Of the transactions not kept only the id is, so a replay is still a duplicate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Retention {
+    #[default]
+    All,
+    Deposits,
+    // Deposits, but only the latest N; older ones can no longer be disputed or annulled. A deposit
+    // under dispute stays until the dispute ends.
+    Window(usize),
+}
+
+// The semantics a ledger applies transactions with, for `Ledger::with_config`. The defaults are those
+// of `Ledger::new`, and the `set_*` methods change single options later.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LedgerConfig {
+    pub unknown_records: UnknownRecordPolicy,
+    pub locked_accounts: LockedAccountPolicy,
+    pub dispute_funds: DisputeFundsPolicy,
+    pub retention: Retention,
+    pub tiers: HashMap<Tier, TierLimits>,
+    // Skip duplicate tx ids instead of rejecting them, so replaying an input is harmless
+    pub idempotent: bool,
+    // Apply admin records (`TxType::is_admin`); off by default so an ordinary input can't unfreeze an
+    // account, annul its own withdrawals or raise its tier
+    pub admin_ops: bool,
+    // How far below zero a withdrawal may take `available` for clients without a credit line of
+    // their own: 0 (the default) rejects withdrawals the funds don't cover, infinity never does
+    pub overdraft_limit: f64,
+    // Whether a resolved deposit can be disputed again; by default resolving ends its lifecycle
+    pub redispute_resolved: bool,
+    // Whether a chargeback locks the account (the default) or only takes the funds back
+    pub lock_on_chargeback: bool,
+    // Run `check_invariants` after every transaction and panic on a violation; for debugging, as each
+    // check reads the whole ledger
+    pub check_invariants: bool,
+}
+
+impl Default for LedgerConfig {
+    fn default() -> Self {
+        LedgerConfig {
+            unknown_records: UnknownRecordPolicy::default(),
+            locked_accounts: LockedAccountPolicy::default(),
+            dispute_funds: DisputeFundsPolicy::default(),
+            retention: Retention::default(),
+            tiers: HashMap::new(),
+            idempotent: false,
+            admin_ops: false,
+            overdraft_limit: 0.0,
+            redispute_resolved: false,
+            lock_on_chargeback: true,
+            check_invariants: false,
+        }
+    }
+}
+
+// Outcome of `Ledger::simulate`: the resulting balances of every client the transactions touched,
+// ordered by id, and the transactions that would be rejected, by their index in the batch and id
+#[derive(Debug, Default)]
+pub struct SimulationResult {
+    pub clients: Vec<Client>,
+    pub rejections: Vec<(usize, u32, LedgerError)>,
+}
+
+// A copy of every client's balances and the operator account at one point in time, ordered by
+// client id, for reports taken while the ledger keeps processing
+#[derive(Clone, Debug, Default)]
+pub struct LedgerSnapshot {
+    pub clients: Vec<Client>,
+    pub operator: OperatorAccount,
+}
+
+impl LedgerSnapshot {
+    pub fn write_summary(&self, out: &mut dyn SummaryWriter, options: &SummaryOptions) -> Result<(), Box<dyn Error>> {
+        summary::write(out, &self.clients, &self.operator, options)
+    }
+
+    // Combines snapshots of ledgers holding disjoint sets of clients
+    pub fn merge(snapshots: Vec<LedgerSnapshot>) -> LedgerSnapshot {
+        let mut merged = LedgerSnapshot::default();
+        for snapshot in snapshots {
+            merged.clients.extend(snapshot.clients);
+            merged.operator.fees_earned += snapshot.operator.fees_earned;
+            merged.operator.chargeback_losses += snapshot.operator.chargeback_losses;
+        }
+        merged.clients.sort_by_key(|c| c.id);
+        merged
+    }
+}
+
 pub struct Ledger {
-    ledger: HashMap<u32, Transaction>,
+    // Transaction history, including active operator holds (which can't be disputed, being holds)
+    store: Box<dyn LedgerStore>,
     clients: Clients,
+    // Clients changed since the last flush, and how many transactions that flush is behind
+    dirty: HashSet<u16>,
+    unflushed: u64,
+    // Off while simulating, so simulated state is never committed
+    autoflush: bool,
+    hooks: Vec<Box<dyn LedgerHook>>,
+    rules: Vec<Box<dyn BusinessRules>>,
+    operator: OperatorAccount,
+    config: LedgerConfig,
+    // Deposits kept under `Retention::Window`, oldest first
+    window: VecDeque<u32>,
+    // The ids of transactions the retention didn't keep (or no longer keeps), for duplicate checks
+    retired: HashSet<u32>,
+    // Every accepted change to a transaction, in order, when enabled with `set_history`
+    history: Option<Vec<TxEvent>>,
+    // (index, count) when this ledger is one shard of a `ShardedLedger` and so holds only the clients
+    // routed to it
+    shard: Option<(usize, usize)>,
+    // The ids of every shard, for duplicate checks across them; see `share_tx_ids`
+    tx_ids: Option<TxIds>,
+    // Transfers from other shards this one has made room for, by tx id: (destination, currency, amount)
+    incoming: HashMap<u32, (u16, Currency, f64)>,
+    // Why the destination's shard refused the transfer being applied; see `process_outgoing_transfer`
+    credit_refusal: Option<LedgerError>,
+    metrics: Metrics,
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Ledger {
     pub fn new() -> Ledger {
+        Ledger::with_config(LedgerConfig::default())
+    }
+
+    pub fn with_config(config: LedgerConfig) -> Ledger {
         Ledger { 
-            ledger: HashMap::new(),
+            store: Box::new(MemoryStore::new()),
             clients: Clients::new(), 
+            dirty: HashSet::new(),
+            unflushed: 0,
+            autoflush: true,
+            hooks: Vec::new(),
+            rules: Vec::new(),
+            operator: OperatorAccount::default(),
+            config,
+            window: VecDeque::new(),
+            retired: HashSet::new(),
+            history: None,
+            shard: None,
+            tx_ids: None,
+            incoming: HashMap::new(),
+            credit_refusal: None,
+            metrics: Metrics::default(),
+        }
+    }
+
+    // A ledger on the given store, starting from the clients and operator account it already holds
+    pub fn with_store(store: Box<dyn LedgerStore>) -> Result<Ledger, StoreError> {
+        let mut ledger = Ledger::new();
+        for client in store.clients()? {
+            ledger.clients.insert(client);
+        }
+        ledger.operator = store.operator()?;
+        ledger.store = store;
+        let mut disputes = 0;
+        ledger.store.for_each_tx(&mut |tx| {
+            disputes += (tx.status == PaymentStatus::Disputed) as u64;
+            Ok(())
+        })?;
+        ledger.metrics.disputes_open = disputes;
+        Ok(ledger)
+    }
+
+    // Writes the changed clients and the operator account to the store and commits, so a persistent
+    // store holds a consistent state. Also done every `COMMIT_EVERY` transactions.
+    pub fn flush(&mut self) -> Result<(), StoreError> {
+        for id in self.dirty.drain() {
+            if let Some(client) = self.clients.clients.get(&id) {
+                self.store.put_client(client)?;
+            }
         }
+        self.store.put_operator(&self.operator)?;
+        self.store.commit()?;
+        self.unflushed = 0;
+        Ok(())
+    }
+
+    pub fn operator(&self) -> &OperatorAccount {
+        &self.operator
+    }
+
+    pub fn config(&self) -> &LedgerConfig {
+        &self.config
     }
 
-    pub fn print_summary(&self) -> Result<(), Box<dyn Error>> {
-        let mut wtr = Writer::from_writer(std::io::stdout());
+    // Replaces every option at once, e.g. on a ledger opened with `with_store`
+    pub fn set_config(&mut self, config: LedgerConfig) {
+        self.config = config;
+    }
 
-        wtr.write_record(&["client", "available", "held", "total", "locked"])?;
+    pub fn set_tier_limits(&mut self, tier: Tier, limits: TierLimits) {
+        self.config.tiers.insert(tier, limits);
+    }
+
+    pub fn set_unknown_policy(&mut self, policy: UnknownRecordPolicy) {
+        self.config.unknown_records = policy;
+    }
+
+    pub fn set_locked_policy(&mut self, policy: LockedAccountPolicy) {
+        self.config.locked_accounts = policy;
+    }
+
+    // Applies to transactions from then on; ones already kept stay
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.config.retention = retention;
+    }
 
-        for client in self.clients.clients.values() {
-            wtr.write_record(&[
-                client.id.to_string(),
-                format!("{:.4}", client.available),
-                format!("{:.4}", client.held),
-                format!("{:.4}", client.total),
-                client.locked.to_string(),
-            ])?;
+    pub fn set_idempotent(&mut self, idempotent: bool) {
+        self.config.idempotent = idempotent;
+    }
+
+    pub fn set_admin_ops(&mut self, allowed: bool) {
+        self.config.admin_ops = allowed;
+    }
+
+    pub fn set_overdraft_limit(&mut self, limit: f64) {
+        self.config.overdraft_limit = limit;
+    }
+
+    pub fn set_check_invariants(&mut self, enabled: bool) {
+        self.config.check_invariants = enabled;
+    }
+
+    // Balances add up, holds match the open disputes and active operator holds, nothing is overdrawn
+    // past its limit and charged-back clients are locked; see `invariants::check`
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        invariants::check(self)
+    }
+
+    // Applies a row of a `--clients` file, creating the client if needed: seeds its opening balance
+    // unless it already has one in that currency, and sets what else the row gives. A shard leaves
+    // clients routed to other shards alone.
+    pub fn configure_client(&mut self, settings: &ClientSettings) {
+        if !self.owns(settings.client) {
+            return;
+        }
+        let client = self.clients.add_client(settings.client);
+        if let Some(opening) = settings.balance {
+            let currency = settings.currency.unwrap_or_default();
+            if !client.balances.contains_key(&currency) {
+                let balance = client.balance_mut(currency);
+                balance.available = opening;
+                balance.total = opening;
+            }
+        }
+        if settings.locked == Some(true) {
+            client.locked = true;
+        }
+        if let Some(tier) = settings.tier {
+            client.tier = tier;
+        }
+        if let Some(limit) = settings.overdraft_limit {
+            client.overdraft_limit = Some(limit);
         }
+        if let Some(name) = &settings.name {
+            self.clients.set_name(settings.client, Some(name.clone()));
+        }
+        self.dirty.insert(settings.client);
+    }
+
+    // Keeps the audit trail of every transaction from now on; see `history::TxEvent`
+    pub fn set_history(&mut self, enabled: bool) {
+        match (enabled, &self.history) {
+            (true, None) => self.history = Some(vec![]),
+            (false, _) => self.history = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    pub fn history(&self) -> &[TxEvent] {
+        self.history.as_deref().unwrap_or_default()
+    }
+
+    // The audit trail as CSV, JSON or JSON Lines, ordered by transaction
+    pub fn export_history<W: std::io::Write>(&self, format: OutputFormat, out: W) -> Result<(), Box<dyn Error>> {
+        history::write_history(self.history(), format, out)
+    }
+
+    // Makes this ledger shard `index` of `count`: transfers then only credit destinations it holds,
+    // leaving the others to `credit_transfer` on their shard
+    pub fn set_shard(&mut self, index: usize, count: usize) {
+        self.shard = Some((index, count));
+    }
+
+    // Checks tx ids against every ledger sharing `ids` rather than only this one's store, claiming the
+    // ids it already holds; `ShardedLedger::spawn` does this for its shards
+    pub fn share_tx_ids(&mut self, ids: TxIds) -> Result<(), StoreError> {
+        self.store.for_each_tx(&mut |tx| {
+            ids.claim(tx.tx_id);
+            Ok(())
+        })?;
+        for &tx_id in &self.retired {
+            ids.claim(tx_id);
+        }
+        self.tx_ids = Some(ids);
+        Ok(())
+    }
 
-        wtr.flush()?;
+    // Whether the client is routed to this ledger; always true unless it's a shard
+    pub fn owns(&self, client_id: u16) -> bool {
+        self.shard.is_none_or(|(index, count)| client_id as usize % count == index)
+    }
+
+    pub fn add_rules(&mut self, rules: Box<dyn BusinessRules>) {
+        self.rules.push(rules);
+    }
+
+    // Swaps in the policies and tier limits of `file` and a new set of business rules, e.g. from a
+    // reloaded config file, between two transactions. The options a run sets on the command line
+    // (idempotency, admin records, overdraft, ...) and the retention stay as they are.
+    pub fn reload(&mut self, file: &LedgerConfig, rules: Vec<Box<dyn BusinessRules>>) {
+        self.config.unknown_records = file.unknown_records;
+        self.config.locked_accounts = file.locked_accounts;
+        self.config.dispute_funds = file.dispute_funds;
+        self.config.tiers = file.tiers.clone();
+        self.rules = rules;
+    }
+
+    pub fn add_hook(&mut self, hook: Box<dyn LedgerHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn before_apply<F>(&mut self, f: F)
+    where
+        F: FnMut(&Transaction, Option<&Client>) -> Result<(), String> + Send + 'static,
+    {
+        self.add_hook(Box::new(BeforeApplyFn(f)));
+    }
+
+    pub fn after_apply<F>(&mut self, f: F)
+    where
+        F: FnMut(&Transaction, Option<&Client>) + Send + 'static,
+    {
+        self.add_hook(Box::new(AfterApplyFn(f)));
+    }
+
+    pub fn on_reject<F>(&mut self, f: F)
+    where
+        F: FnMut(&Transaction, &LedgerError) + Send + 'static,
+    {
+        self.add_hook(Box::new(OnRejectFn(f)));
+    }
+
+    pub fn client(&self, client_id: u16) -> Option<&Client> {
+        self.clients.clients.get(&client_id)
+    }
+
+    // None if there is no such client or it never held the currency
+    pub fn client_balance(&self, client_id: u16, currency: Currency) -> Option<Balance> {
+        self.client(client_id)?.balances.get(&currency).cloned()
+    }
+
+    // The client's transactions the ledger keeps (see `Retention`), by id. Reads through the whole
+    // history, so meant for tooling rather than per transaction.
+    pub fn client_transactions(&self, client_id: u16) -> Result<Vec<Transaction>, StoreError> {
+        let mut txs = vec![];
+        self.store.for_each_tx(&mut |tx| {
+            if tx.client_id == client_id {
+                txs.push(tx.clone());
+            }
+            Ok(())
+        })?;
+        txs.sort_by_key(|tx| tx.tx_id);
+        Ok(txs)
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = &Client> {
+        self.clients.clients.values()
+    }
+
+    // Clients whose id or name matches `query`, best matches first
+    pub fn search_clients(&self, query: &str) -> Vec<SearchHit> {
+        self.clients.search(query)
+    }
+
+    pub fn transaction(&self, tx_id: u32) -> Result<Option<Transaction>, StoreError> {
+        self.store.get_tx(tx_id)
+    }
+
+    // Folds in a ledger holding a disjoint set of clients, e.g. one shard of a `ShardedLedger`.
+    // The other ledger's hooks and rules are dropped, and its transactions move into this store.
+    pub fn merge(&mut self, mut other: Ledger) -> Result<(), StoreError> {
+        other.store.move_transactions(self.store.as_mut())?;
+        self.retired.extend(other.retired);
+        self.dirty.extend(other.clients.clients.keys());
+        for client in other.clients.clients.into_values() {
+            self.clients.insert(client);
+        }
+        self.operator.fees_earned += other.operator.fees_earned;
+        self.operator.chargeback_losses += other.operator.chargeback_losses;
+        if let (Some(history), Some(other)) = (&mut self.history, other.history) {
+            history.extend(other);
+        }
+        self.metrics.merge(&other.metrics);
         Ok(())
     }
 
+    // The counters so far, with the gauges as of now
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.metrics.clone();
+        metrics.accounts_locked = self.clients.clients.values().filter(|c| c.locked).count() as u64;
+        metrics.tx_cache = self.store.cache_stats();
+        metrics
+    }
+
+    pub fn snapshot(&self) -> LedgerSnapshot {
+        let mut clients: Vec<Client> = self.clients.clients.values().cloned().collect();
+        clients.sort_by_key(|c| c.id);
+        LedgerSnapshot { clients, operator: self.operator.clone() }
+    }
+
+    // Writes the full state (clients, operator account and transaction history) with the input
+    // offsets it corresponds to; see `checkpoint::CheckpointWriter`
+    pub fn checkpoint<P: AsRef<std::path::Path>>(&self, path: P, offsets: &Offsets) -> Result<(), CheckpointError> {
+        let mut out = CheckpointWriter::create(path, offsets, None, &Default::default())?;
+        out.write_ledger(self)?;
+        out.finish()
+    }
+
+    // Loads a checkpoint into this (empty, configured) ledger and returns its input offsets
+    pub fn restore<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<Offsets, CheckpointError> {
+        checkpoint::restore(path, std::slice::from_mut(self))
+    }
+
+    pub(crate) fn for_each_transaction(&self, f: &mut dyn FnMut(&Transaction) -> Result<(), StoreError>) -> Result<(), StoreError> {
+        self.store.for_each_tx(f)
+    }
+
+    // See `retired`; sorted, so checkpoints of the same state are the same
+    pub(crate) fn retired_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.retired.iter().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub(crate) fn add_retired_ids(&mut self, ids: impl IntoIterator<Item = u32>) {
+        self.retired.extend(ids);
+    }
+
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn clients_mut(&mut self) -> &mut Clients {
+        &mut self.clients
+    }
+
+    // The CSV summary, to a file, a buffer or any other writer, with the header and sections the
+    // options ask for. Ordered by client id, so the same state always prints the same summary.
+    pub fn write_summary<W: Write>(&self, w: W, options: &SummaryOptions) -> Result<(), Box<dyn Error>> {
+        let mut out = CsvSummaryWriter::new(w).header(options.header);
+        summary::write_ledgers(std::slice::from_ref(self), &mut out, options)
+    }
+
     pub fn process(&mut self, record: StringRecord) {
         match Transaction::create_transaction(&record) {
             Ok(tx) => {
-                if let Err(e) = self.process_transaction(&tx) {
-                    eprintln!("Error applying transaction: {}", e);
+                self.apply(&tx);
+            }
+            Err(e) => tracing::warn!("Unreadable record: {}", e),
+        }
+    }
+
+    // Applies the transaction, logging any error; returns whether it was accepted
+    pub fn apply(&mut self, tx: &Transaction) -> bool {
+        let _span = logging::tx_span(tx).entered();
+        match self.process_transaction(tx) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("Rejected: {}", e);
+                false
+            }
+        }
+    }
+
+    pub fn process_source<S: TransactionSource + ?Sized>(&mut self, source: &mut S) {
+        while let Some(result) = source.next() {
+            match result {
+                Ok(tx) => {
+                    self.apply(&tx);
+                }
+                Err(SourceError::UnknownRecord(record)) => {
+                    if let Err(e) = self.handle_unknown(&record) {
+                        tracing::warn!("Rejected record: {}", e);
+                    }
+                }
+                Err(e) => tracing::warn!("Unreadable record: {}", e),
+            }
+        }
+    }
+
+    // Dry run: applies the transactions through the business rules as usual, but without running
+    // hooks, then puts back every client and transaction entry they touched, so the ledger ends up
+    // exactly as it was. Only those entries are copied, not the whole ledger. No flush happens
+    // in between, so a persistent store never commits the simulated state.
+    pub fn simulate(&mut self, txs: &[Transaction]) -> Result<SimulationResult, StoreError> {
+        let mut clients: HashMap<u16, Option<Client>> = HashMap::new();
+        let mut transactions: HashMap<u32, Option<Transaction>> = HashMap::new();
+        let operator = self.operator.clone();
+        let events = self.history().len();
+        let metrics = self.metrics.clone();
+        let hooks = std::mem::take(&mut self.hooks);
+        self.autoflush = false;
+
+        let mut rejections = vec![];
+        let mut failure = None;
+        // Ids that may get retired by the simulation, and have to be given back afterwards
+        let mut unretired = vec![];
+        for (index, tx) in txs.iter().enumerate() {
+            clients.entry(tx.client_id).or_insert_with(|| self.clients.clients.get(&tx.client_id).cloned());
+            // A transfer also credits its destination
+            if let TxType::Transfer(destination) = tx.tx_type {
+                clients.entry(destination).or_insert_with(|| self.clients.clients.get(&destination).cloned());
+            }
+            if let Entry::Vacant(entry) = transactions.entry(tx.tx_id) {
+                if !self.retired.contains(&tx.tx_id) {
+                    unretired.push(tx.tx_id);
+                }
+                match self.store.get_tx(tx.tx_id) {
+                    Ok(before) => entry.insert(before),
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
+                };
+            }
+            if let Err(e) = self.process_transaction(tx) {
+                rejections.push((index, tx.tx_id, e));
+            }
+        }
+
+        let mut result: Vec<Client> = clients.keys().filter_map(|id| self.clients.clients.get(id).cloned()).collect();
+        result.sort_by_key(|c| c.id);
+
+        self.operator = operator;
+        self.metrics = metrics;
+        if let Some(history) = &mut self.history {
+            history.truncate(events);
+        }
+        self.hooks = hooks;
+        self.autoflush = true;
+        for (id, before) in clients {
+            match before {
+                Some(client) => self.clients.insert(client),
+                None => drop(self.clients.remove(id)),
+            }
+        }
+        for id in unretired {
+            if self.retired.remove(&id) && let Some(ids) = &self.tx_ids {
+                ids.release(id);
+            }
+        }
+        for (id, before) in transactions {
+            let restored = match before {
+                Some(tx) => self.store.put_tx(&tx),
+                None => self.forget_tx(id),
+            };
+            if let Err(e) = restored {
+                failure.get_or_insert(e);
+            }
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(SimulationResult { clients: result, rejections }),
+        }
+    }
+
+    // Applies the unknown-record policy; Ok means the record was skipped or taken by a plugin
+    pub fn handle_unknown(&mut self, record: &UnknownRecord) -> Result<(), LedgerError> {
+        let result = self.apply_unknown_policy(record);
+        if let Err(e) = &result {
+            self.metrics.record_error(e);
+        }
+        result
+    }
+
+    fn apply_unknown_policy(&mut self, record: &UnknownRecord) -> Result<(), LedgerError> {
+        let rejected = |reason: String| LedgerError::UnknownRecordType { tx_type: record.tx_type.clone(), reason };
+        match self.config.unknown_records {
+            UnknownRecordPolicy::Reject => Err(rejected("rejected by policy".to_string())),
+            UnknownRecordPolicy::Skip => Ok(()),
+            UnknownRecordPolicy::Plugin => {
+                for rules in self.rules.iter_mut() {
+                    if rules.handle_unknown(record).map_err(rejected)? {
+                        return Ok(());
+                    }
+                }
+                Err(rejected("no plugin handled it".to_string()))
+            }
+        }
+    }
+
+    // Runs the hooks and rules and applies the transaction, returning why it was rejected if it was
+    pub fn process_transaction(&mut self, tx: &Transaction) -> Result<(), LedgerError> {
+        let started = Instant::now();
+        if self.autoflush && self.unflushed >= COMMIT_EVERY {
+            self.flush()?;
+        }
+        if self.config.idempotent && self.is_duplicate(tx)? {
+            return Ok(());
+        }
+        self.unflushed += 1;
+        self.dirty.insert(tx.client_id);
+        let client = self.clients.clients.get(&tx.client_id);
+        let mut result = Ok(());
+        for hook in self.hooks.iter_mut() {
+            if let Err(reason) = hook.before_apply(tx, client) {
+                result = Err(LedgerError::RejectedByHook { tx: tx.tx_id, reason });
+                break;
+            }
+        }
+
+        if result.is_ok() {
+            result = self.apply_with_rules(tx);
+        }
+        if let (Ok(()), Some(history)) = (&result, &mut self.history) {
+            // Numbered from 1, and still increasing after merging in another ledger's events
+            history.extend(TxEvent::new(history.len() as u64 + 1, tx));
+        }
+
+        let client = self.clients.clients.get(&tx.client_id);
+        for hook in self.hooks.iter_mut() {
+            match &result {
+                Ok(()) => hook.after_apply(tx, client),
+                Err(e) => hook.on_reject(tx, e),
+            }
+        }
+        self.metrics.record(&tx.tx_type, &result, started.elapsed());
+        // A violation is a bug in the ledger rather than bad input, so it stops everything like a failed assertion
+        if self.config.check_invariants && let Err(violation) = self.check_invariants() {
+            panic!("Invariant violated after tx {} ({:?}): {}", tx.tx_id, tx.tx_type, violation);
+        }
+        result
+    }
+
+    // Only records that create a transaction have ids of their own; disputes, resolves, chargebacks
+    // and releases refer to an earlier one
+    fn is_duplicate(&self, tx: &Transaction) -> Result<bool, StoreError> {
+        if !matches!(tx.tx_type, TxType::Deposit | TxType::Withdrawal | TxType::Hold | TxType::Transfer(_)) {
+            return Ok(false);
+        }
+        Ok(self.retired.contains(&tx.tx_id)
+            || self.store.contains_tx(tx.tx_id)?
+            || self.tx_ids.as_ref().is_some_and(|ids| ids.contains(tx.tx_id)))
+    }
+
+    // Stores a transaction with an id of its own. The id is claimed in the shared registry at the same
+    // time, so of two shards applying the same id at once only one gets it.
+    fn store_new_tx(&mut self, t: &Transaction) -> Result<(), LedgerError> {
+        if let Some(ids) = &self.tx_ids && !ids.claim(t.tx_id) {
+            return Err(LedgerError::DuplicateTransaction(t.tx_id));
+        }
+        let stored = self.store.put_tx(t);
+        if let (Err(_), Some(ids)) = (&stored, &self.tx_ids) {
+            ids.release(t.tx_id);
+        }
+        Ok(stored?)
+    }
+
+    // A withdrawal or transfer: stored under `Retention::All`, otherwise only its id is kept (and
+    // claimed in the shared registry like a stored one)
+    fn record_new_tx(&mut self, t: &Transaction) -> Result<(), LedgerError> {
+        if self.config.retention == Retention::All {
+            return self.store_new_tx(t);
+        }
+        if let Some(ids) = &self.tx_ids && !ids.claim(t.tx_id) {
+            return Err(LedgerError::DuplicateTransaction(t.tx_id));
+        }
+        self.retired.insert(t.tx_id);
+        Ok(())
+    }
+
+    // Drops a transaction from the store, giving its id back to the shared registry if it held it
+    fn forget_tx(&mut self, tx_id: u32) -> Result<(), StoreError> {
+        if self.tx_ids.is_some() && !self.store.contains_tx(tx_id)? {
+            return Ok(());
+        }
+        self.store.remove_tx(tx_id)?;
+        if let Some(ids) = &self.tx_ids {
+            ids.release(tx_id);
+        }
+        Ok(())
+    }
+
+    fn apply_with_rules(&mut self, tx: &Transaction) -> Result<(), LedgerError> {
+        if self.is_duplicate(tx)? {
+            return Err(LedgerError::DuplicateTransaction(tx.tx_id));
+        }
+        let client = self.clients.clients.get(&tx.client_id);
+        let rejected = |reason| LedgerError::RejectedByRule { tx: tx.tx_id, reason };
+        let mut fee = 0.0;
+        for rules in self.rules.iter_mut() {
+            rules.validate_transaction(tx, client).map_err(rejected)?;
+            if matches!(tx.tx_type, TxType::Deposit | TxType::Withdrawal) {
+                let f = rules.compute_fee(tx, client).map_err(rejected)?;
+                if !f.is_finite() || f < 0.0 {
+                    return Err(rejected(format!("invalid fee {}", f)));
                 }
+                fee += f;
+            }
+        }
+
+        let currency = tx.currency.unwrap_or_default();
+        if fee > 0.0 {
+            // The fee must be covered by the funds left after the transaction itself
+            let available = client.map_or(0.0, |c| c.balance(currency).available);
+            let amount = tx.amount.unwrap_or(0.0);
+            let (requested, available) = match tx.tx_type {
+                TxType::Deposit => (fee, available + amount),
+                _ => (amount + fee, available),
+            };
+            if available < requested {
+                return Err(LedgerError::NotEnoughFunds { client: tx.client_id, requested, available });
             }
-            Err(e) => eprintln!("Error processing record: {}", e),
         }
+
+        self.apply_transaction(tx)?;
+
+        if fee > 0.0 {
+            let balance = self.clients.add_client(tx.client_id).balance_mut(currency);
+            balance.available -= fee;
+            balance.total -= fee;
+            self.operator.fees_earned += fee;
+        }
+        Ok(())
     }
 
-    fn process_transaction(&mut self, tx: &Transaction) -> Result<(), LedgerError> {
+    fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), LedgerError> {
+        if tx.tx_type.is_admin() && !self.config.admin_ops {
+            return Err(LedgerError::AdminOpsDisabled(tx.tx_id));
+        }
         match tx.tx_type {
             TxType::Deposit => self.deposit(tx),
             TxType::Withdrawal => self.withdraw( tx),
             TxType::Dispute => self.dispute(tx),
             TxType::Resolve => self.resolve(tx),
             TxType::Chargeback => self.chargeback(tx),
+            TxType::SetTier(tier) => {
+                self.clients.add_client(tx.client_id).tier = tier;
+                Ok(())
+            }
+            TxType::Hold => self.hold(tx),
+            TxType::Release => self.release(tx),
+            TxType::Annul(ref reason) => self.annul(tx, reason),
+            TxType::Transfer(destination) => self.transfer(tx, destination),
+            TxType::Lock => self.set_locked(tx, true),
+            TxType::Unlock => self.set_locked(tx, false),
+        }
+    }
+
+    fn set_locked(&mut self, t: &Transaction, locked: bool) -> Result<(), LedgerError> {
+        let client = self.clients.find_client(t.client_id).ok_or(LedgerError::ClientNotFound(t.client_id))?;
+        client.locked = locked;
+        Ok(())
+    }
+
+    // The first step of a transfer from a client of another shard: checks that the destination's tier
+    // allows the credit and holds the room for it until `credit_transfer` or `cancel_credit`
+    pub fn reserve_credit(&mut self, tx: &Transaction) -> Result<(), LedgerError> {
+        let (TxType::Transfer(destination), Some(amount)) = (&tx.tx_type, tx.amount) else {
+            return Err(LedgerError::MalformedRequest);
+        };
+        let currency = tx.currency.unwrap_or_default();
+        self.check_max_balance(*destination, currency, amount)?;
+        self.incoming.insert(tx.tx_id, (*destination, currency, amount));
+        Ok(())
+    }
+
+    // Gives back the room `reserve_credit` held, once the source's shard rejected the transfer
+    pub fn cancel_credit(&mut self, tx: &Transaction) {
+        self.incoming.remove(&tx.tx_id);
+    }
+
+    // Applies a transfer to a client of another shard, whose shard already answered `reserve_credit`:
+    // a refusal rejects the transfer where a single ledger would have found the destination over its limit
+    pub fn process_outgoing_transfer(&mut self, tx: &Transaction, credit: Result<(), LedgerError>) -> Result<(), LedgerError> {
+        self.credit_refusal = credit.err();
+        let result = self.process_transaction(tx);
+        self.credit_refusal = None;
+        result
+    }
+
+    // The destination side of a transfer applied by another shard, which holds the source client and
+    // did the checks. Hooks get `after_credit` rather than the usual calls, which the other shard made.
+    // Without a `reserve_credit` first, the destination's tier limit is checked here.
+    pub fn credit_transfer(&mut self, tx: &Transaction) -> Result<(), LedgerError> {
+        let (TxType::Transfer(destination), Some(amount)) = (&tx.tx_type, tx.amount) else {
+            return Err(LedgerError::MalformedRequest);
+        };
+        if self.incoming.remove(&tx.tx_id).is_none() {
+            self.check_max_balance(*destination, tx.currency.unwrap_or_default(), amount)?;
+        }
+        self.credit(*destination, tx.currency.unwrap_or_default(), amount);
+        let client = self.clients.clients.get(destination);
+        for hook in self.hooks.iter_mut() {
+            hook.after_credit(tx, client);
+        }
+        Ok(())
+    }
+
+    // Whether the client's tier lets `amount` more into its balance, counting the transfers this shard
+    // has made room for. A client that doesn't exist yet would be created with the default tier.
+    fn check_max_balance(&self, client_id: u16, currency: Currency, amount: f64) -> Result<(), LedgerError> {
+        let client = self.clients.clients.get(&client_id);
+        let tier = client.map_or_else(Tier::default, |c| c.tier);
+        let Some(max) = self.config.tiers.get(&tier).and_then(|l| l.max_balance) else { return Ok(()) };
+        let incoming: f64 = self.incoming.values().filter(|(id, c, _)| *id == client_id && *c == currency).map(|(_, _, a)| a).sum();
+        if client.map_or(0.0, |c| c.balance(currency).total) + incoming + amount > max {
+            return Err(LedgerError::TierLimit { client: client_id, tier, limit: "max balance" });
+        }
+        Ok(())
+    }
+
+    fn credit(&mut self, client_id: u16, currency: Currency, amount: f64) {
+        let balance = self.clients.add_client(client_id).balance_mut(currency);
+        balance.available += amount;
+        balance.total += amount;
+        self.dirty.insert(client_id);
+    }
+
+    // Moves funds between two clients atomically. The source is checked like a withdrawal, the
+    // destination against its tier's max balance. In a shard that doesn't hold the destination only the
+    // source is debited, see `ShardedLedger::apply`.
+    fn transfer(&mut self, t: &Transaction, destination: u16) -> Result<(), LedgerError> {
+        if destination == t.client_id {
+            return Err(LedgerError::SelfTransfer(t.tx_id));
+        }
+        let amount = t.amount.ok_or(LedgerError::MalformedRequest)?;
+        let currency = t.currency.unwrap_or_default();
+        let client = self.clients.find_client(t.client_id).ok_or(LedgerError::ClientNotFound(t.client_id))?;
+        if client.locked && self.config.locked_accounts == LockedAccountPolicy::Reject {
+            return Err(LedgerError::AccountLocked(t.client_id));
+        }
+        let limits = self.config.tiers.get(&client.tier);
+        if limits.and_then(|l| l.max_withdrawal).is_some_and(|max| amount > max) {
+            return Err(LedgerError::TierLimit { client: t.client_id, tier: client.tier, limit: "max withdrawal" });
+        }
+        // Checked before `balance_mut`, so a rejection doesn't leave an empty balance behind. The same
+        // funds check as a withdrawal, overdraft line included
+        let available = client.balance(currency).available;
+        let overdraft = client.overdraft_limit.unwrap_or(self.config.overdraft_limit);
+        if available + overdraft < amount {
+            return Err(LedgerError::NotEnoughFunds { client: t.client_id, requested: amount, available });
+        }
+        if self.owns(destination) {
+            self.check_max_balance(destination, currency, amount)?;
+        } else if let Some(refusal) = self.credit_refusal.take() {
+            return Err(refusal);
+        }
+        self.record_new_tx(t)?;
+        let balance = self.clients.add_client(t.client_id).balance_mut(currency);
+        balance.available -= amount;
+        balance.total -= amount;
+        if self.owns(destination) {
+            self.credit(destination, currency, amount);
+        }
+        Ok(())
+    }
+
+    // Undoes the balance effect of a deposit or withdrawal but keeps the record, marked with the
+    // reason. Disputed transactions have to be resolved first; charged-back ones can't be annulled.
+    fn annul(&mut self, t: &Transaction, reason: &str) -> Result<(), LedgerError> {
+        let mut tx = self.store.get_tx(t.tx_id)?.ok_or(LedgerError::InvalidAnnulment(t.tx_id))?;
+        if tx.client_id != t.client_id {
+            return Err(LedgerError::ClientMismatch { tx: t.tx_id, expected: tx.client_id, got: t.client_id });
+        }
+        let currency = same_currency(t, &tx)?;
+        if !matches!(tx.status, PaymentStatus::Posted | PaymentStatus::Resolved) {
+            return Err(LedgerError::InvalidAnnulment(t.tx_id));
+        }
+        let amount = tx.amount.ok_or(LedgerError::MalformedRequest)?;
+        let signed = match tx.tx_type {
+            TxType::Deposit => -amount,
+            TxType::Withdrawal => amount,
+            _ => return Err(LedgerError::InvalidAnnulment(t.tx_id)),
+        };
+        let client = self.clients.find_client(t.client_id).ok_or(LedgerError::ClientNotFound(t.client_id))?;
+        tx.status = PaymentStatus::Annulled(reason.to_string());
+        self.store.put_tx(&tx)?;
+        let balance = client.balance_mut(currency);
+        balance.available += signed;
+        balance.total += signed;
+        Ok(())
+    }
+
+    // Moves funds from available into the operator hold; total is unchanged
+    fn hold(&mut self, t: &Transaction) -> Result<(), LedgerError> {
+        let client = self.clients.find_client(t.client_id).ok_or(LedgerError::ClientNotFound(t.client_id))?;
+        let amount = t.amount.ok_or(LedgerError::MalformedRequest)?;
+        let currency = t.currency.unwrap_or_default();
+        let available = client.balance(currency).available;
+        if available < amount {
+            return Err(LedgerError::NotEnoughFunds { client: t.client_id, requested: amount, available });
+        }
+        self.store_new_tx(t)?;
+        let balance = self.clients.add_client(t.client_id).balance_mut(currency);
+        balance.available -= amount;
+        balance.operator_held += amount;
+        Ok(())
+    }
+
+    fn release(&mut self, t: &Transaction) -> Result<(), LedgerError> {
+        let hold = match self.store.get_tx(t.tx_id)? {
+            Some(hold) if hold.tx_type == TxType::Hold => hold,
+            _ => return Err(LedgerError::UnknownHold(t.tx_id)),
+        };
+        if hold.client_id != t.client_id {
+            return Err(LedgerError::ClientMismatch { tx: t.tx_id, expected: hold.client_id, got: t.client_id });
         }
+        let currency = same_currency(t, &hold)?;
+        let amount = hold.amount.ok_or(LedgerError::MalformedRequest)?;
+        if self.clients.find_client(t.client_id).is_none() {
+            return Err(LedgerError::ClientNotFound(t.client_id));
+        }
+        self.forget_tx(t.tx_id)?;
+        let balance = self.clients.add_client(t.client_id).balance_mut(currency);
+        balance.operator_held -= amount;
+        balance.available += amount;
+        Ok(())
     }
 
     fn deposit(&mut self, t: &Transaction) -> Result<(), LedgerError> {
         let client = self.clients.add_client(t.client_id);
+        if client.locked && self.config.locked_accounts == LockedAccountPolicy::Reject {
+            return Err(LedgerError::AccountLocked(t.client_id));
+        }
         let amount = t.amount.ok_or(LedgerError::MalformedRequest)?;
-        client.available += amount;
-        client.total += amount;
-        self.ledger.insert(t.tx_id, t.clone());
+        // Checked before `balance_mut`, so a rejection doesn't leave an empty balance behind
+        let currency = t.currency.unwrap_or_default();
+        self.check_max_balance(t.client_id, currency, amount)?;
+        self.store_new_tx(t)?;
+        let balance = self.clients.add_client(t.client_id).balance_mut(currency);
+        balance.available += amount;
+        balance.total += amount;
+        self.slide_window(t.tx_id)?;
+        Ok(())
+    }
+
+    // Under `Retention::Window`, forgets the oldest deposits past the window but for their ids, which
+    // stay claimed. One under dispute goes to the back instead, as its resolve or chargeback still
+    // needs it. Simulated deposits are left out, being taken back afterwards anyway.
+    fn slide_window(&mut self, tx_id: u32) -> Result<(), StoreError> {
+        let Retention::Window(size) = self.config.retention else { return Ok(()) };
+        if !self.autoflush {
+            return Ok(());
+        }
+        self.window.push_back(tx_id);
+        for _ in size..self.window.len() {
+            let Some(oldest) = self.window.pop_front() else { break };
+            match self.store.get_tx(oldest)? {
+                Some(tx) if tx.status == PaymentStatus::Disputed => self.window.push_back(oldest),
+                _ => {
+                    self.store.remove_tx(oldest)?;
+                    self.retired.insert(oldest);
+                }
+            }
+        }
         Ok(())
     }
 
     fn withdraw(&mut self, t: &Transaction) -> Result<(), LedgerError> {
         let client = self.clients.add_client(t.client_id);
+        if client.locked && self.config.locked_accounts == LockedAccountPolicy::Reject {
+            return Err(LedgerError::AccountLocked(t.client_id));
+        }
         let amount = t.amount.ok_or(LedgerError::MalformedRequest)?;
+        let limits = self.config.tiers.get(&client.tier);
+        if limits.and_then(|l| l.max_withdrawal).is_some_and(|max| amount > max) {
+            return Err(LedgerError::TierLimit { client: t.client_id, tier: client.tier, limit: "max withdrawal" });
+        }
 
-        // Assumption-1: Only withdraw if available > tx amount, so we don't end up with negative balances - please comment 'if statement' below if incorrect
-        if client.available >= amount {
-            client.available -= amount;
-            client.total -= amount;
-            self.ledger.insert(t.tx_id, t.clone());
-            return Ok(())
+        // Only withdraw if available covers the amount, so we don't end up with negative balances, unless
+        // the client has an overdraft line (or the config a default one), which lets available go that far below zero
+        let currency = t.currency.unwrap_or_default();
+        let available = client.balance(currency).available;
+        let overdraft = client.overdraft_limit.unwrap_or(self.config.overdraft_limit);
+        if available + overdraft >= amount {
+            self.record_new_tx(t)?;
+            let balance = self.clients.add_client(t.client_id).balance_mut(currency);
+            balance.available -= amount;
+            balance.total -= amount;
+            Ok(())
         } else {
-            return Err(LedgerError::NotEnoughFunds { client: (t.client_id), requested: (amount), available: (client.available) });
+            Err(LedgerError::NotEnoughFunds { client: (t.client_id), requested: (amount), available: (available) })
         }
     }
 
@@ -108,14 +1167,46 @@ impl Ledger {
             Some(c) => c,
             None => return Err(LedgerError::ClientNotFound(t.client_id)),
         };
-        let tx = match self.ledger.get_mut(&t.tx_id) {
+        if self.config.tiers.get(&client.tier).is_some_and(|l| !l.disputes) {
+            return Err(LedgerError::TierLimit { client: t.client_id, tier: client.tier, limit: "disputes" });
+        }
+        let mut tx = match self.store.get_tx(t.tx_id)? {
             Some(tx) => tx,
             None => return Err(LedgerError::InvalidDispute(t.tx_id)),
         };
-        let amount = tx.amount.ok_or(LedgerError::MalformedRequest)?;
-        client.held += amount;
-        client.available -= amount;
-        tx.status = PaymentStatus::Disputed;
+        if tx.client_id != t.client_id {
+            return Err(LedgerError::ClientMismatch { tx: t.tx_id, expected: tx.client_id, got: t.client_id });
+        }
+        // Only deposits can be disputed: holding back the amount of a withdrawal would take the
+        // client's funds a second time rather than return them
+        if tx.tx_type != TxType::Deposit {
+            return Err(LedgerError::InvalidDispute(t.tx_id));
+        }
+        let currency = same_currency(t, &tx)?;
+        let deposited = tx.amount.ok_or(LedgerError::MalformedRequest)?;
+        match tx.status {
+            PaymentStatus::Resolved if self.config.redispute_resolved => tx.status = PaymentStatus::Disputed,
+            _ => transition(&mut tx, PaymentStatus::Disputed)?,
+        }
+        let requested = disputed_part(t, deposited)?;
+        // Rounded to the precision of amounts, so float drift in the balance can't turn a dispute away
+        let available = (client.balance(currency).available * 10_000.0).round() / 10_000.0;
+        let (amount, shortfall) = match self.config.dispute_funds {
+            _ if requested <= available => (requested, None),
+            DisputeFundsPolicy::Allow => (requested, None),
+            DisputeFundsPolicy::Reject => return Err(LedgerError::DisputeExceedsAvailable { tx: t.tx_id, requested, available }),
+            DisputeFundsPolicy::Cap => {
+                let held = available.max(0.0);
+                (held, Some(((requested - held) * 10_000.0).round() / 10_000.0))
+            }
+        };
+        tx.disputed = Some(amount);
+        tx.shortfall = shortfall;
+        self.store.put_tx(&tx)?;
+        let balance = client.balance_mut(currency);
+        balance.held += amount;
+        balance.available -= amount;
+        self.metrics.disputes_open += 1;
         Ok(())
     }
 
@@ -124,18 +1215,22 @@ impl Ledger {
             Some(c) => c,
             None => return Err(LedgerError::ClientNotFound(t.client_id)),
         };
-        let tx = match self.ledger.get_mut(&t.tx_id) {
+        let mut tx = match self.store.get_tx(t.tx_id)? {
             Some(tx) => tx,
             None => return Err(LedgerError::InvalidDispute(t.tx_id)),
         };
-        if !matches!(tx.status, PaymentStatus::Disputed) {
-            return Err(LedgerError::InvalidDispute(t.tx_id))
+        if tx.client_id != t.client_id {
+            return Err(LedgerError::ClientMismatch { tx: t.tx_id, expected: tx.client_id, got: t.client_id });
+        }
+        let currency = same_currency(t, &tx)?;
+        let (amount, _) = settle(t, &mut tx, PaymentStatus::Resolved)?;
+        self.store.put_tx(&tx)?;
+        let balance = client.balance_mut(currency);
+        balance.held -= amount;
+        balance.available += amount;
+        if tx.status == PaymentStatus::Resolved {
+            self.metrics.disputes_open = self.metrics.disputes_open.saturating_sub(1);
         }
-        let amount = tx.amount.ok_or(LedgerError::MalformedRequest)?;
-        client.held -= amount;
-        client.available += amount;
-        // Assumption-2: Mark transaction as no longer disputed - please comment line below if incorrect
-        tx.status = PaymentStatus::Undisputed;
         Ok(())
     }
 
@@ -144,23 +1239,87 @@ impl Ledger {
             Some(c) => c,
             None => return Err(LedgerError::ClientNotFound(t.client_id)),
         };
-        let tx = match self.ledger.get_mut(&t.tx_id) {
+        let mut tx = match self.store.get_tx(t.tx_id)? {
             Some(tx) => tx,
             None => return Err(LedgerError::InvalidDispute(t.tx_id)),
         };
-        if !matches!(tx.status, PaymentStatus::Disputed) {
-            return Err(LedgerError::InvalidDispute(t.tx_id))
+        if tx.client_id != t.client_id {
+            return Err(LedgerError::ClientMismatch { tx: t.tx_id, expected: tx.client_id, got: t.client_id });
+        }
+        let currency = same_currency(t, &tx)?;
+        // A chargeback of part of the dispute still ends it, giving back the rest
+        let uncovered = tx.shortfall.unwrap_or(0.0);
+        let (amount, released) = settle(t, &mut tx, PaymentStatus::ChargedBack)?;
+        self.store.put_tx(&tx)?;
+        self.metrics.disputes_open = self.metrics.disputes_open.saturating_sub(1);
+        // Whatever the client's total can no longer cover is absorbed by the operator, as is the part of
+        // a capped dispute that was never held
+        let balance = client.balance_mut(currency);
+        let shortfall = amount - balance.total.max(0.0) + uncovered;
+        if shortfall > 0.0 {
+            self.operator.chargeback_losses += shortfall;
+        }
+        balance.held -= amount + released;
+        balance.available += released;
+        balance.total -= amount;
+        if self.config.lock_on_chargeback {
+            client.locked = true;
         }
-        let amount = tx.amount.ok_or(LedgerError::MalformedRequest)?;
-        client.held -= amount;
-        client.total -= amount;
-        client.locked = true; 
-        // my gut feeling tells me that this is still a disputed charge, so I wont do the same (switch tx.status) 
-        // as I did in resolve and change the PaymentStatus - please add if incorrect? :)
         Ok(())
     }
 }
 
+// The currency of `original` that record `t` acts on, which `t` may only repeat
+fn same_currency(t: &Transaction, original: &Transaction) -> Result<Currency, LedgerError> {
+    let expected = original.currency.unwrap_or_default();
+    match t.currency {
+        Some(got) if got != expected => Err(LedgerError::CurrencyMismatch { tx: t.tx_id, expected, got }),
+        _ => Ok(expected),
+    }
+}
+
+// How much of `outstanding` dispute record `t` acts on: the amount it gives, or all of it
+fn disputed_part(t: &Transaction, outstanding: f64) -> Result<f64, LedgerError> {
+    match t.amount {
+        Some(requested) if requested > outstanding => Err(LedgerError::DisputeAmountTooLarge { tx: t.tx_id, requested, outstanding }),
+        Some(requested) => Ok(requested),
+        None => Ok(outstanding),
+    }
+}
+
+// Settles the part of disputed `tx` that resolve or chargeback `t` acts on, returning it and what is left
+// under dispute. A resolve leaves `tx` disputed until nothing is left; a chargeback always ends the dispute.
+fn settle(t: &Transaction, tx: &mut Transaction, to: PaymentStatus) -> Result<(f64, f64), LedgerError> {
+    if tx.status != PaymentStatus::Disputed {
+        return Err(LedgerError::InvalidStateTransition { tx: tx.tx_id, from: tx.status.clone(), to });
+    }
+    let outstanding = tx.disputed.or(tx.amount).ok_or(LedgerError::MalformedRequest)?;
+    let amount = disputed_part(t, outstanding)?;
+    // Kept to the 4 decimal places of amounts, so parts adding up to the whole end the dispute
+    let left = ((outstanding - amount) * 10_000.0).round() / 10_000.0;
+    if left > 0.0 && to == PaymentStatus::Resolved {
+        tx.disputed = Some(left);
+        return Ok((amount, left));
+    }
+    transition(tx, to)?;
+    tx.disputed = None;
+    tx.shortfall = None;
+    Ok((amount, left))
+}
+
+// Moves a tx along the dispute lifecycle: Posted -> Disputed -> Resolved | ChargedBack
+fn transition(tx: &mut Transaction, to: PaymentStatus) -> Result<(), LedgerError> {
+    let allowed = matches!(
+        (&tx.status, &to),
+        (PaymentStatus::Posted, PaymentStatus::Disputed) | (PaymentStatus::Disputed, PaymentStatus::Resolved | PaymentStatus::ChargedBack)
+    );
+    if !allowed {
+        return Err(LedgerError::InvalidStateTransition { tx: tx.tx_id, from: tx.status.clone(), to });
+    }
+    tx.status = to;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -172,7 +1331,11 @@ mod tests {
             client_id,
             tx_id,
             amount,
-            status: PaymentStatus::Undisputed,
+            currency: None,
+            status: PaymentStatus::Posted,
+            disputed: None,
+            shortfall: None,
+            attributes: Default::default(),
         }
     }
 
@@ -183,8 +1346,8 @@ mod tests {
         assert!(ledger.deposit(&tx).is_ok());
 
         let client = ledger.clients.find_client(1).unwrap();
-        assert_eq!(client.available, 1.0);
-        assert_eq!(client.total, 1.0);
+        assert_eq!(client.balance(Currency::Usd).available, 1.0);
+        assert_eq!(client.balance(Currency::Usd).total, 1.0);
     }
 
     #[test]
@@ -197,8 +1360,8 @@ mod tests {
         assert!(ledger.withdraw(&tx).is_ok());
 
         let client = ledger.clients.find_client(1).unwrap();
-        assert_eq!(client.available, 6.0);
-        assert_eq!(client.total, 6.0);
+        assert_eq!(client.balance(Currency::Usd).available, 6.0);
+        assert_eq!(client.balance(Currency::Usd).total, 6.0);
     }
 
     #[test]
@@ -211,21 +1374,21 @@ mod tests {
         assert!(ledger.dispute(&tx).is_ok());
 
         let client = ledger.clients.find_client(1).unwrap();
-        let transaction = ledger.ledger.get(&1).unwrap();
+        let transaction = ledger.store.get_tx(1).unwrap().unwrap();
 
-        assert_eq!(client.available, 0.0);
-        assert_eq!(client.held, 1.0);
-        assert_eq!(client.total, 1.0);
+        assert_eq!(client.balance(Currency::Usd).available, 0.0);
+        assert_eq!(client.balance(Currency::Usd).held, 1.0);
+        assert_eq!(client.balance(Currency::Usd).total, 1.0);
         assert!(matches!(transaction.status, PaymentStatus::Disputed));
 
         let tx = create_tx(TxType::Resolve, 1, 1, None);
         assert!(ledger.resolve(&tx).is_ok());
         let client = ledger.clients.find_client(1).unwrap();
-        let transaction = ledger.ledger.get(&1).unwrap();
-        assert_eq!(client.available, 1.0);
-        assert_eq!(client.held, 0.0);
-        assert_eq!(client.total, 1.0);
-        assert!(matches!(transaction.status, PaymentStatus::Undisputed));
+        let transaction = ledger.store.get_tx(1).unwrap().unwrap();
+        assert_eq!(client.balance(Currency::Usd).available, 1.0);
+        assert_eq!(client.balance(Currency::Usd).held, 0.0);
+        assert_eq!(client.balance(Currency::Usd).total, 1.0);
+        assert!(matches!(transaction.status, PaymentStatus::Resolved));
     }
 
     #[test]
@@ -241,13 +1404,13 @@ mod tests {
         assert!(ledger.chargeback(&tx).is_ok());
 
         let client = ledger.clients.find_client(1).unwrap();
-        let transaction = ledger.ledger.get(&1).unwrap();
+        let transaction = ledger.store.get_tx(1).unwrap().unwrap();
 
-        assert_eq!(client.available, 0.0);
-        assert_eq!(client.held, 0.0);
-        assert_eq!(client.total, 0.0);
+        assert_eq!(client.balance(Currency::Usd).available, 0.0);
+        assert_eq!(client.balance(Currency::Usd).held, 0.0);
+        assert_eq!(client.balance(Currency::Usd).total, 0.0);
         assert!(client.locked);
-        assert!(matches!(transaction.status, PaymentStatus::Disputed));
+        assert!(matches!(transaction.status, PaymentStatus::ChargedBack));
     }
 
     #[test]
@@ -268,6 +1431,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_overdraft_lets_withdrawals_go_below_zero_up_to_the_limit() {
+        let mut ledger = Ledger::new();
+        ledger.set_overdraft_limit(10.0);
+        ledger.configure_client(&ClientSettings { client: 2, overdraft_limit: Some(50.0), ..ClientSettings::default() });
+        for client in [1, 2] {
+            ledger.process_transaction(&create_tx(TxType::Deposit, client, client as u32, Some(5.0))).unwrap();
+        }
+
+        assert!(ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 3, Some(15.0))).is_ok());
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, -10.0);
+        assert_eq!(
+            ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 4, Some(0.5))),
+            Err(LedgerError::NotEnoughFunds { client: 1, requested: 0.5, available: -10.0 })
+        );
+        // A client's own limit replaces the default
+        assert!(ledger.process_transaction(&create_tx(TxType::Withdrawal, 2, 5, Some(55.0))).is_ok());
+        assert!(ledger.process_transaction(&create_tx(TxType::Withdrawal, 2, 6, Some(0.5))).is_err());
+    }
+
+    #[test]
+    fn test_transfers_draw_on_the_overdraft_like_withdrawals() {
+        let mut ledger = Ledger::new();
+        ledger.set_overdraft_limit(10.0);
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(5.0))).unwrap();
+        let transfer = |tx, amount| create_tx(TxType::Transfer(2), 1, tx, Some(amount));
+
+        assert!(ledger.process_transaction(&transfer(2, 15.0)).is_ok());
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, -10.0);
+        assert_eq!(ledger.client(2).unwrap().balance(Currency::Usd).available, 15.0);
+        assert_eq!(
+            ledger.process_transaction(&transfer(3, 0.5)),
+            Err(LedgerError::NotEnoughFunds { client: 1, requested: 0.5, available: -10.0 })
+        );
+    }
+
+    #[test]
+    fn test_clients_file_seeds_balances_only_once() {
+        let mut ledger = Ledger::new();
+        let seed = ClientSettings { client: 1, balance: Some(20.0), locked: Some(true), name: Some("Acme".to_string()), ..ClientSettings::default() };
+        ledger.configure_client(&seed);
+        ledger.configure_client(&ClientSettings { client: 1, balance: Some(3.0), currency: Some(Currency::Eur), ..ClientSettings::default() });
+        ledger.set_locked_policy(LockedAccountPolicy::Allow);
+        ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 1, Some(5.0))).unwrap();
+
+        // Configuring again, as a run continuing a store would, leaves the balances alone
+        ledger.configure_client(&seed);
+        let client = ledger.client(1).unwrap();
+        assert_eq!((client.balance(Currency::Usd).available, client.balance(Currency::Eur).total), (15.0, 3.0));
+        assert_eq!((client.locked, client.name.as_deref()), (true, Some("Acme")));
+    }
+
     #[test]
     fn test_deposit_or_withdraw_with_no_amount_fails() {
         let mut ledger = Ledger::new();
@@ -309,6 +1524,289 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_dispute_resolve_chargeback_reject_other_clients_tx() {
+        let mut ledger = Ledger::new();
+        ledger.deposit(&create_tx(TxType::Deposit, 1, 1, Some(5.0))).unwrap();
+        ledger.deposit(&create_tx(TxType::Deposit, 2, 2, Some(5.0))).unwrap();
+        let mismatch = Err(LedgerError::ClientMismatch { tx: 1, expected: 1, got: 2 });
+
+        assert_eq!(ledger.dispute(&create_tx(TxType::Dispute, 2, 1, None)), mismatch);
+        ledger.dispute(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        assert_eq!(ledger.resolve(&create_tx(TxType::Resolve, 2, 1, None)), mismatch);
+        assert_eq!(ledger.chargeback(&create_tx(TxType::Chargeback, 2, 1, None)), mismatch);
+
+        let other = ledger.clients.find_client(2).unwrap();
+        assert_eq!((other.balance(Currency::Usd).available, other.balance(Currency::Usd).held, other.locked), (5.0, 0.0, false));
+    }
+
+    #[test]
+    fn test_operator_hold_and_release() {
+        let mut ledger = Ledger::new();
+        ledger.set_admin_ops(true);
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Hold, 1, 2, Some(4.0))).unwrap();
+
+        let client = ledger.client(1).unwrap();
+        assert_eq!((client.balance(Currency::Usd).available, client.balance(Currency::Usd).held, client.balance(Currency::Usd).operator_held, client.balance(Currency::Usd).total), (6.0, 0.0, 4.0, 10.0));
+        assert!(matches!(
+            ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 3, Some(8.0))),
+            Err(LedgerError::NotEnoughFunds { .. })
+        ));
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Dispute, 1, 2, None)), Err(LedgerError::InvalidDispute(2)));
+
+        ledger.process_transaction(&create_tx(TxType::Release, 1, 2, None)).unwrap();
+        let client = ledger.client(1).unwrap();
+        assert_eq!((client.balance(Currency::Usd).available, client.balance(Currency::Usd).operator_held, client.balance(Currency::Usd).total), (10.0, 0.0, 10.0));
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Release, 1, 2, None)), Err(LedgerError::UnknownHold(2)));
+    }
+
+    #[test]
+    fn test_locked_account_policy() {
+        let mut ledger = Ledger::new();
+        ledger.deposit(&create_tx(TxType::Deposit, 1, 1, Some(5.0))).unwrap();
+        ledger.deposit(&create_tx(TxType::Deposit, 1, 2, Some(5.0))).unwrap();
+        ledger.dispute(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        ledger.chargeback(&create_tx(TxType::Chargeback, 1, 1, None)).unwrap();
+
+        assert_eq!(ledger.deposit(&create_tx(TxType::Deposit, 1, 3, Some(1.0))), Err(LedgerError::AccountLocked(1)));
+        assert_eq!(ledger.withdraw(&create_tx(TxType::Withdrawal, 1, 4, Some(1.0))), Err(LedgerError::AccountLocked(1)));
+
+        ledger.set_locked_policy(LockedAccountPolicy::Allow);
+        ledger.withdraw(&create_tx(TxType::Withdrawal, 1, 4, Some(1.0))).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, 4.0);
+    }
+
+    #[test]
+    fn test_config_picks_the_dispute_and_balance_semantics() {
+        let config = LedgerConfig { redispute_resolved: true, lock_on_chargeback: false, overdraft_limit: f64::INFINITY, ..LedgerConfig::default() };
+        let mut ledger = Ledger::with_config(config);
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(5.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Resolve, 1, 1, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Chargeback, 1, 1, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 2, Some(7.0))).unwrap();
+        let client = ledger.client(1).unwrap();
+        assert!(!client.locked);
+        assert_eq!(client.balance(Currency::Usd).available, -7.0);
+
+        let mut ledger = Ledger::new();
+        assert_eq!(ledger.config(), &LedgerConfig::default());
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(5.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Resolve, 1, 1, None)).unwrap();
+        assert!(matches!(ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)), Err(LedgerError::InvalidStateTransition { .. })));
+    }
+
+    #[test]
+    fn test_dispute_funds_policy_decides_disputes_of_spent_deposits() {
+        let spent = |policy| {
+            let mut ledger = Ledger::with_config(LedgerConfig { dispute_funds: policy, ..LedgerConfig::default() });
+            ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+            ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 2, Some(6.0))).unwrap();
+            let result = ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None));
+            (ledger, result)
+        };
+
+        let (ledger, result) = spent(DisputeFundsPolicy::Allow);
+        assert_eq!(result, Ok(()));
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, -6.0);
+
+        let (ledger, result) = spent(DisputeFundsPolicy::Reject);
+        assert_eq!(result, Err(LedgerError::DisputeExceedsAvailable { tx: 1, requested: 10.0, available: 4.0 }));
+        assert_eq!(ledger.transaction(1).unwrap().unwrap().status, PaymentStatus::Posted);
+
+        let (mut ledger, result) = spent(DisputeFundsPolicy::Cap);
+        assert_eq!(result, Ok(()));
+        let tx = ledger.transaction(1).unwrap().unwrap();
+        assert_eq!((tx.disputed, tx.shortfall), (Some(4.0), Some(6.0)));
+        let balance = ledger.client(1).unwrap().balance(Currency::Usd);
+        assert_eq!((balance.available, balance.held, balance.total), (0.0, 4.0, 4.0));
+        ledger.process_transaction(&create_tx(TxType::Chargeback, 1, 1, None)).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).total, 0.0);
+        assert_eq!(ledger.operator().chargeback_losses, 6.0);
+        assert_eq!(ledger.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_retention_keeps_only_the_deposits_disputes_need() {
+        let mut ledger = Ledger::new();
+        ledger.set_retention(Retention::Window(2));
+        for tx in 1..=3 {
+            ledger.process_transaction(&create_tx(TxType::Deposit, 1, tx, Some(5.0))).unwrap();
+        }
+        ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 4, Some(1.0))).unwrap();
+        assert!(ledger.transaction(4).unwrap().is_none());
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)), Err(LedgerError::InvalidDispute(1)));
+
+        // A deposit under dispute outlives the window until the dispute ends
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 2, None)).unwrap();
+        for tx in 5..=7 {
+            ledger.process_transaction(&create_tx(TxType::Deposit, 1, tx, Some(5.0))).unwrap();
+        }
+        ledger.process_transaction(&create_tx(TxType::Resolve, 1, 2, None)).unwrap();
+        let kept: Vec<u32> = (1..=7).filter(|&tx| ledger.transaction(tx).unwrap().is_some()).collect();
+        assert_eq!(kept, vec![2, 6, 7]);
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, 29.0);
+    }
+
+    #[test]
+    fn test_ids_the_retention_drops_are_still_duplicates() {
+        let mut ledger = Ledger::new();
+        ledger.set_retention(Retention::Deposits);
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+        let withdrawal = create_tx(TxType::Withdrawal, 1, 2, Some(4.0));
+        ledger.process_transaction(&withdrawal).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Transfer(2), 1, 3, Some(1.0))).unwrap();
+        assert_eq!(ledger.process_transaction(&withdrawal), Err(LedgerError::DuplicateTransaction(2)));
+        assert_eq!(
+            ledger.process_transaction(&create_tx(TxType::Transfer(2), 1, 3, Some(1.0))),
+            Err(LedgerError::DuplicateTransaction(3)),
+        );
+        // A simulated withdrawal doesn't use up its id
+        ledger.simulate(&[create_tx(TxType::Withdrawal, 1, 4, Some(1.0))]).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 4, Some(1.0))).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, 4.0);
+
+        // Nor does a deposit's id leave with it at the end of the window
+        ledger.set_retention(Retention::Window(1));
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 5, Some(1.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 6, Some(1.0))).unwrap();
+        assert!(ledger.transaction(5).unwrap().is_none());
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Deposit, 1, 5, Some(1.0))), Err(LedgerError::DuplicateTransaction(5)));
+        ledger.set_idempotent(true);
+        ledger.process_transaction(&withdrawal).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, 6.0);
+    }
+
+    #[test]
+    fn test_transfer_moves_funds_and_checks_the_source() {
+        let mut ledger = Ledger::new();
+        let transfer = |from, tx, to, amount| create_tx(TxType::Transfer(to), from, tx, Some(amount));
+        assert_eq!(ledger.process_transaction(&transfer(1, 1, 2, 1.0)), Err(LedgerError::ClientNotFound(1)));
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 2, Some(10.0))).unwrap();
+
+        ledger.process_transaction(&transfer(1, 3, 2, 4.0)).unwrap();
+        assert_eq!(ledger.process_transaction(&transfer(1, 3, 2, 1.0)), Err(LedgerError::DuplicateTransaction(3)));
+        assert_eq!(ledger.process_transaction(&transfer(1, 4, 1, 1.0)), Err(LedgerError::SelfTransfer(4)));
+        assert_eq!(
+            ledger.process_transaction(&transfer(1, 5, 2, 7.0)),
+            Err(LedgerError::NotEnoughFunds { client: 1, requested: 7.0, available: 6.0 })
+        );
+        let balances = |ledger: &Ledger, id| ledger.client(id).map(|c| (c.balance(Currency::Usd).available, c.balance(Currency::Usd).total));
+        assert_eq!((balances(&ledger, 1), balances(&ledger, 2)), (Some((6.0, 6.0)), Some((4.0, 4.0))));
+
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 2, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Chargeback, 1, 2, None)).unwrap();
+        assert_eq!(ledger.process_transaction(&transfer(1, 7, 2, 1.0)), Err(LedgerError::AccountLocked(1)));
+    }
+
+    #[test]
+    fn test_currencies_are_kept_apart_and_disputes_stay_in_the_original_one() {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0)).in_currency(Currency::Eur)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 2, Some(3.0))).unwrap();
+        assert_eq!(
+            ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 3, Some(5.0))),
+            Err(LedgerError::NotEnoughFunds { client: 1, requested: 5.0, available: 3.0 })
+        );
+
+        assert_eq!(
+            ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None).in_currency(Currency::Gbp)),
+            Err(LedgerError::CurrencyMismatch { tx: 1, expected: Currency::Eur, got: Currency::Gbp })
+        );
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        let client = ledger.client(1).unwrap();
+        let (eur, usd) = (client.balance(Currency::Eur), client.balance(Currency::Usd));
+        assert_eq!(((eur.available, eur.held), (usd.available, usd.held)), ((0.0, 10.0), (3.0, 0.0)));
+        // A rejected withdrawal doesn't open a balance in its currency
+        ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 4, Some(1.0)).in_currency(Currency::Gbp)).unwrap_err();
+        assert!(!ledger.client(1).unwrap().balances.contains_key(&Currency::Gbp));
+    }
+
+    #[test]
+    fn test_admin_records_need_admin_ops_and_unlock_reopens_a_charged_back_account() {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 2, 6, Some(10.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Withdrawal, 2, 7, Some(4.0))).unwrap();
+        // A client's own feed can't take its withdrawal back, raise its tier or touch holds
+        for tx in [
+            create_tx(TxType::Annul("mistake".to_string()), 2, 7, None),
+            create_tx(TxType::SetTier(Tier::Premium), 2, 8, None),
+            create_tx(TxType::Hold, 2, 9, Some(1.0)),
+            create_tx(TxType::Release, 2, 9, None),
+        ] {
+            assert_eq!(ledger.process_transaction(&tx), Err(LedgerError::AdminOpsDisabled(tx.tx_id)));
+        }
+        let client = ledger.client(2).unwrap();
+        assert_eq!((client.balance(Currency::Usd).available, client.balance(Currency::Usd).operator_held, client.tier), (6.0, 0.0, Tier::Basic));
+
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Chargeback, 1, 1, None)).unwrap();
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Unlock, 1, 2, None)), Err(LedgerError::AdminOpsDisabled(2)));
+        assert!(ledger.client(1).unwrap().locked);
+
+        ledger.set_admin_ops(true);
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Unlock, 9, 2, None)), Err(LedgerError::ClientNotFound(9)));
+        ledger.process_transaction(&create_tx(TxType::Unlock, 1, 2, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 3, Some(1.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Lock, 1, 4, None)).unwrap();
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Deposit, 1, 5, Some(1.0))), Err(LedgerError::AccountLocked(1)));
+    }
+
+    #[test]
+    fn test_duplicate_tx_ids_are_rejected_or_skipped() {
+        let mut ledger = Ledger::new();
+        let deposit = create_tx(TxType::Deposit, 1, 1, Some(5.0));
+        ledger.process_transaction(&deposit).unwrap();
+        assert_eq!(ledger.process_transaction(&deposit), Err(LedgerError::DuplicateTransaction(1)));
+        assert_eq!(
+            ledger.process_transaction(&create_tx(TxType::Hold, 1, 1, Some(1.0))),
+            Err(LedgerError::DuplicateTransaction(1)),
+        );
+
+        ledger.set_idempotent(true);
+        ledger.process_transaction(&deposit).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).total, 5.0);
+    }
+
+    #[test]
+    fn test_annul_reverses_and_keeps_the_record() {
+        let mut ledger = Ledger::new();
+        ledger.set_admin_ops(true);
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 2, Some(3.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 3, Some(2.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 3, None)).unwrap();
+
+        let annul = |tx| create_tx(TxType::Annul("wrong file".to_string()), 1, tx, None);
+        ledger.process_transaction(&annul(1)).unwrap();
+        ledger.process_transaction(&annul(2)).unwrap();
+        assert_eq!(ledger.process_transaction(&annul(1)), Err(LedgerError::InvalidAnnulment(1)));
+        assert_eq!(ledger.process_transaction(&annul(3)), Err(LedgerError::InvalidAnnulment(3)));
+
+        let client = ledger.client(1).unwrap();
+        assert_eq!((client.balance(Currency::Usd).available, client.balance(Currency::Usd).held, client.balance(Currency::Usd).total), (0.0, 2.0, 2.0));
+        assert_eq!(ledger.transaction(1).unwrap().unwrap().status, PaymentStatus::Annulled("wrong file".to_string()));
+        assert_eq!(
+            ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)),
+            Err(LedgerError::InvalidStateTransition { tx: 1, from: PaymentStatus::Annulled("wrong file".to_string()), to: PaymentStatus::Disputed }),
+        );
+    }
+
+    #[test]
+    fn test_withdrawals_cannot_be_disputed() {
+        let mut ledger = Ledger::new();
+        ledger.deposit(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+        ledger.withdraw(&create_tx(TxType::Withdrawal, 1, 2, Some(4.0))).unwrap();
+
+        assert_eq!(ledger.dispute(&create_tx(TxType::Dispute, 1, 2, None)), Err(LedgerError::InvalidDispute(2)));
+        let client = ledger.client(1).unwrap();
+        assert_eq!((client.balance(Currency::Usd).available, client.balance(Currency::Usd).held), (6.0, 0.0));
+    }
+
     #[test]
     fn test_resolve_chargeback_undisputed_tx_fails() {
         let mut ledger = Ledger::new();
@@ -316,12 +1814,275 @@ mod tests {
         ledger.deposit(&tx).unwrap();
 
         let tx = create_tx(TxType::Resolve, 1, 1, None);
-        let res = ledger.chargeback(&tx);
-        assert!(matches!(res, Err(LedgerError::InvalidDispute(1))));
+        let res = ledger.resolve(&tx);
+        assert!(matches!(res, Err(LedgerError::InvalidStateTransition { tx: 1, from: PaymentStatus::Posted, to: PaymentStatus::Resolved })));
 
         let tx = create_tx(TxType::Chargeback, 1, 1, None);
         let res = ledger.chargeback(&tx);
-        assert!(matches!(res, Err(LedgerError::InvalidDispute(1))));
+        assert!(matches!(res, Err(LedgerError::InvalidStateTransition { tx: 1, from: PaymentStatus::Posted, to: PaymentStatus::ChargedBack })));
+    }
+
+    #[test]
+    fn test_disputes_end_once_resolved_or_charged_back() {
+        let mut ledger = Ledger::new();
+        for tx in [
+            create_tx(TxType::Deposit, 1, 1, Some(5.0)),
+            create_tx(TxType::Deposit, 1, 2, Some(5.0)),
+            create_tx(TxType::Dispute, 1, 1, None),
+            create_tx(TxType::Resolve, 1, 1, None),
+            create_tx(TxType::Dispute, 1, 2, None),
+            create_tx(TxType::Chargeback, 1, 2, None),
+        ] {
+            ledger.process_transaction(&tx).unwrap();
+        }
+        let invalid = |tx, from, to| Err(LedgerError::InvalidStateTransition { tx, from, to });
+
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)), invalid(1, PaymentStatus::Resolved, PaymentStatus::Disputed));
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Dispute, 1, 2, None)), invalid(2, PaymentStatus::ChargedBack, PaymentStatus::Disputed));
+        assert_eq!(ledger.process_transaction(&create_tx(TxType::Chargeback, 1, 2, None)), invalid(2, PaymentStatus::ChargedBack, PaymentStatus::ChargedBack));
+        let client = ledger.client(1).unwrap();
+        assert_eq!((client.balance(Currency::Usd).available, client.balance(Currency::Usd).held, client.balance(Currency::Usd).total), (5.0, 0.0, 5.0));
+    }
+
+    #[test]
+    fn test_partial_disputes_hold_and_settle_only_their_part() {
+        let mut ledger = Ledger::new();
+        let usd = |ledger: &Ledger| {
+            let b = ledger.client(1).unwrap().balance(Currency::Usd);
+            (b.available, b.held, b.total)
+        };
+        for tx in [
+            create_tx(TxType::Deposit, 1, 1, Some(100.0)),
+            create_tx(TxType::Deposit, 1, 2, Some(50.0)),
+            create_tx(TxType::Dispute, 1, 1, Some(30.0)),
+            create_tx(TxType::Resolve, 1, 1, Some(10.0)),
+        ] {
+            ledger.process_transaction(&tx).unwrap();
+        }
+        assert_eq!(usd(&ledger), (130.0, 20.0, 150.0));
+        assert_eq!(ledger.transaction(1).unwrap().unwrap().disputed, Some(20.0));
+        assert_eq!(
+            ledger.process_transaction(&create_tx(TxType::Resolve, 1, 1, Some(25.0))),
+            Err(LedgerError::DisputeAmountTooLarge { tx: 1, requested: 25.0, outstanding: 20.0 })
+        );
+        assert_eq!(ledger.metrics().disputes_open, 1);
+
+        // The rest of the dispute is resolved without an amount, or given back by a partial chargeback
+        ledger.process_transaction(&create_tx(TxType::Resolve, 1, 1, None)).unwrap();
+        assert_eq!(ledger.transaction(1).unwrap().unwrap().status, PaymentStatus::Resolved);
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 2, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Chargeback, 1, 2, Some(20.0))).unwrap();
+        assert_eq!(usd(&ledger), (130.0, 0.0, 130.0));
+        assert_eq!(ledger.transaction(2).unwrap().unwrap().status, PaymentStatus::ChargedBack);
+        assert_eq!(ledger.metrics().disputes_open, 0);
+    }
+
+    #[test]
+    fn test_hooks_can_reject_and_observe_transactions() {
+        use std::sync::{Arc, Mutex};
+
+        let applied = Arc::new(Mutex::new(vec![]));
+        let rejected = Arc::new(Mutex::new(vec![]));
+
+        let mut ledger = Ledger::new();
+        ledger.before_apply(|tx, _| match tx.amount {
+            Some(amount) if amount > 100.0 => Err("over limit".to_string()),
+            _ => Ok(()),
+        });
+        let applied_clone = Arc::clone(&applied);
+        ledger.after_apply(move |tx, client| {
+            applied_clone.lock().unwrap().push((tx.tx_id, client.map(|c| c.balance(Currency::Usd).available)));
+        });
+        let rejected_clone = Arc::clone(&rejected);
+        ledger.on_reject(move |tx, e| rejected_clone.lock().unwrap().push((tx.tx_id, e.clone())));
+
+        assert!(ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).is_ok());
+        let res = ledger.process_transaction(&create_tx(TxType::Deposit, 1, 2, Some(500.0)));
+        assert!(matches!(res, Err(LedgerError::RejectedByHook { tx: 2, .. })));
+        let res = ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 3, Some(20.0)));
+        assert!(matches!(res, Err(LedgerError::NotEnoughFunds { .. })));
+
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, 10.0);
+        assert_eq!(*applied.lock().unwrap(), vec![(1, Some(10.0))]);
+        let rejected = rejected.lock().unwrap();
+        assert_eq!(rejected.len(), 2);
+        assert_eq!(rejected[0].1, LedgerError::RejectedByHook { tx: 2, reason: "over limit".to_string() });
+    }
+
+    struct FlatFee(f64);
+
+    impl BusinessRules for FlatFee {
+        fn validate_transaction(&mut self, tx: &Transaction, _client: Option<&Client>) -> Result<(), String> {
+            if tx.client_id == 9 { Err("client 9 is blocked".to_string()) } else { Ok(()) }
+        }
+
+        fn compute_fee(&mut self, _tx: &Transaction, _client: Option<&Client>) -> Result<f64, String> {
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn test_business_rules_validate_and_charge_fees() {
+        let mut ledger = Ledger::new();
+        ledger.add_rules(Box::new(FlatFee(1.0)));
+
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+        let client = ledger.client(1).unwrap();
+        assert_eq!(client.balance(Currency::Usd).available, 9.0);
+        assert_eq!(client.balance(Currency::Usd).total, 9.0);
+
+        // 9.0 available can't cover an 8.5 withdrawal plus the 1.0 fee
+        let res = ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 2, Some(8.5)));
+        assert_eq!(res, Err(LedgerError::NotEnoughFunds { client: 1, requested: 9.5, available: 9.0 }));
+
+        // Disputes aren't charged
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, -1.0);
+
+        let res = ledger.process_transaction(&create_tx(TxType::Deposit, 9, 3, Some(10.0)));
+        assert!(matches!(res, Err(LedgerError::RejectedByRule { tx: 3, .. })));
+        assert!(ledger.client(9).is_none());
+    }
+
+    #[test]
+    fn test_tier_limits_are_enforced_per_client_tier() {
+        let mut ledger = Ledger::new();
+        ledger.set_admin_ops(true);
+        ledger.set_tier_limits(Tier::Basic, TierLimits { max_balance: Some(100.0), max_withdrawal: Some(10.0), disputes: false });
+        ledger.set_tier_limits(Tier::Premium, TierLimits::default());
+
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(60.0))).unwrap();
+        let res = ledger.process_transaction(&create_tx(TxType::Deposit, 1, 2, Some(50.0)));
+        assert_eq!(res, Err(LedgerError::TierLimit { client: 1, tier: Tier::Basic, limit: "max balance" }));
+        let mut eur = create_tx(TxType::Deposit, 1, 5, Some(150.0));
+        eur.currency = Some(Currency::Eur);
+        assert!(ledger.process_transaction(&eur).is_err());
+        assert!(!ledger.client(1).unwrap().balances.contains_key(&Currency::Eur));
+        let res = ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 3, Some(20.0)));
+        assert_eq!(res, Err(LedgerError::TierLimit { client: 1, tier: Tier::Basic, limit: "max withdrawal" }));
+        let res = ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None));
+        assert_eq!(res, Err(LedgerError::TierLimit { client: 1, tier: Tier::Basic, limit: "disputes" }));
+
+        ledger.process_transaction(&create_tx(TxType::SetTier(Tier::Premium), 1, 4, None)).unwrap();
+        assert_eq!(ledger.client(1).unwrap().tier, Tier::Premium);
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 2, Some(50.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 3, Some(20.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).held, 60.0);
+    }
+
+    #[test]
+    fn test_transfers_are_held_to_both_clients_tier_limits() {
+        let mut ledger = Ledger::new();
+        ledger.set_admin_ops(true);
+        ledger.set_tier_limits(Tier::Basic, TierLimits { max_balance: Some(20.0), max_withdrawal: Some(5.0), disputes: true });
+        ledger.set_tier_limits(Tier::Premium, TierLimits::default());
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(15.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 2, 2, Some(15.0))).unwrap();
+
+        let res = ledger.process_transaction(&create_tx(TxType::Transfer(2), 1, 3, Some(15.0)));
+        assert_eq!(res, Err(LedgerError::TierLimit { client: 1, tier: Tier::Basic, limit: "max withdrawal" }));
+        ledger.process_transaction(&create_tx(TxType::SetTier(Tier::Premium), 1, 4, None)).unwrap();
+        let res = ledger.process_transaction(&create_tx(TxType::Transfer(2), 1, 3, Some(15.0)));
+        assert_eq!(res, Err(LedgerError::TierLimit { client: 2, tier: Tier::Basic, limit: "max balance" }));
+        // Neither side is touched by a rejected transfer
+        let totals = |ledger: &Ledger| [1, 2].map(|id| ledger.client(id).unwrap().balance(Currency::Usd).total);
+        assert_eq!(totals(&ledger), [15.0, 15.0]);
+
+        ledger.process_transaction(&create_tx(TxType::Transfer(2), 1, 3, Some(5.0))).unwrap();
+        assert_eq!(totals(&ledger), [10.0, 20.0]);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_operator_account_tracks_fees_and_chargeback_losses() {
+        let mut ledger = Ledger::new();
+        ledger.add_rules(Box::new(FlatFee(0.5)));
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Withdrawal, 1, 2, Some(6.0))).unwrap();
+        assert_eq!(ledger.operator().fees_earned, 1.0);
+
+        // 3.0 is left when the 10.0 deposit is charged back, so the operator absorbs 7.0
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Chargeback, 1, 1, None)).unwrap();
+        assert_eq!(ledger.operator().chargeback_losses, 7.0);
+        assert_eq!(ledger.operator().net(), -6.0);
+    }
+
+    #[test]
+    fn test_unknown_record_policy() {
+        struct TakesRefunds;
+        impl BusinessRules for TakesRefunds {
+            fn validate_transaction(&mut self, _tx: &Transaction, _client: Option<&Client>) -> Result<(), String> {
+                Ok(())
+            }
+
+            fn handle_unknown(&mut self, record: &UnknownRecord) -> Result<bool, String> {
+                Ok(record.tx_type == "refund")
+            }
+        }
+
+        let record = |tx_type: &str| UnknownRecord { tx_type: tx_type.to_string(), raw: format!("{},1,1,1.0", tx_type) };
+        let mut ledger = Ledger::new();
+        assert!(matches!(ledger.handle_unknown(&record("refund")), Err(LedgerError::UnknownRecordType { .. })));
+
+        ledger.set_unknown_policy(UnknownRecordPolicy::Skip);
+        assert_eq!(ledger.handle_unknown(&record("refund")), Ok(()));
+
+        ledger.set_unknown_policy(UnknownRecordPolicy::Plugin);
+        ledger.add_rules(Box::new(TakesRefunds));
+        assert_eq!(ledger.handle_unknown(&record("refund")), Ok(()));
+        assert_eq!(ledger.handle_unknown(&record("payout")), Err(LedgerError::UnknownRecordType {
+            tx_type: "payout".to_string(),
+            reason: "no plugin handled it".to_string(),
+        }));
+    }
+
+    #[test]
+    fn test_simulate_reports_outcome_without_changing_state() {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Dispute, 1, 1, None)).unwrap();
+        let after_apply = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let counter = after_apply.clone();
+        ledger.after_apply(move |_, _| *counter.lock().unwrap() += 1);
+
+        let result = ledger.simulate(&[
+            create_tx(TxType::Chargeback, 1, 1, None),
+            create_tx(TxType::Deposit, 2, 2, Some(5.0)),
+            create_tx(TxType::Withdrawal, 2, 3, Some(8.0)),
+        ]).unwrap();
+
+        assert_eq!(result.clients.len(), 2);
+        assert!(result.clients[0].locked);
+        assert_eq!(result.clients[0].balance(Currency::Usd).total, 0.0);
+        assert_eq!(result.clients[1].balance(Currency::Usd).available, 5.0);
+        assert_eq!(result.rejections.len(), 1);
+        assert_eq!((result.rejections[0].0, result.rejections[0].1), (2, 3));
+
+        let client = ledger.client(1).unwrap();
+        assert!(!client.locked);
+        assert_eq!(client.balance(Currency::Usd).held, 10.0);
+        assert!(ledger.client(2).is_none());
+        assert!(ledger.transaction(2).unwrap().is_none());
+        assert_eq!(*after_apply.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_simulated_transfer_reports_and_restores_both_clients() {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 1, 1, Some(10.0))).unwrap();
+        ledger.process_transaction(&create_tx(TxType::Deposit, 2, 2, Some(1.0))).unwrap();
+
+        let result = ledger.simulate(&[
+            create_tx(TxType::Transfer(2), 1, 3, Some(4.0)),
+            create_tx(TxType::Transfer(3), 1, 4, Some(1.0)),
+        ]).unwrap();
+        let totals: Vec<(u16, f64)> = result.clients.iter().map(|c| (c.id, c.balance(Currency::Usd).total)).collect();
+        assert_eq!(totals, [(1, 5.0), (2, 5.0), (3, 1.0)]);
+
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).total, 10.0);
+        assert_eq!(ledger.client(2).unwrap().balance(Currency::Usd).total, 1.0);
+        assert!(ledger.client(3).is_none());
+        assert!(ledger.transaction(3).unwrap().is_none());
+    }
+}
@@ -0,0 +1,75 @@
+pub mod breaker;
+pub mod bundle;
+pub mod cache;
+pub mod checkpoint;
+pub mod config;
+pub mod transaction;
+pub mod client;
+pub mod clients_file;
+pub mod clock;
+pub mod diff;
+pub mod latency;
+pub mod lock;
+pub mod ledger;
+pub mod logging;
+pub mod enrichment;
+pub mod generate;
+pub mod handle;
+pub mod history;
+pub mod inspect;
+pub mod invariants;
+pub mod hooks;
+pub mod journal;
+pub mod late;
+pub mod manifest;
+pub mod metrics;
+pub mod notifications;
+pub mod output;
+pub mod pipeline;
+pub mod rates;
+pub mod rejects;
+pub mod reload;
+pub mod rules;
+pub mod schema;
+pub mod search;
+pub mod segments;
+pub mod shadow;
+pub mod shard;
+pub mod source;
+pub mod spill;
+pub mod stats;
+pub mod store;
+pub mod summary;
+pub mod validate;
+pub mod verify;
+pub mod watch;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "kafka")]
+pub mod kafka;
+
+#[cfg(feature = "server")]
+pub mod server;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "scripting")]
+pub mod scripting;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
+#[cfg(test)]
+mod simulation;
+
+// The engine's main types at the crate root for embedding, e.g.
+// `ledger.process_transaction(&Transaction::deposit(1, 1, 10.0)?)`
+pub use client::{Client, Clients};
+pub use config::ConfigError;
+pub use invariants::InvariantViolation;
+pub use ledger::{Ledger, LedgerConfig, LedgerError};
+pub use source::{IterSource, SourceError, TransactionSource};
+pub use transaction::{Transaction, TransactionError, TxType};
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum LockError {
+    Io(io::Error),
+    // Another run holds the lock; `owner` is the process id it wrote into the lock file, if readable
+    Held { path: PathBuf, owner: Option<String> },
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Io(e) => write!(f, "Lock file I/O error: {}", e),
+            LockError::Held { path, owner } => write!(
+                f,
+                "{} is in use by another run{}; wait for it to finish, or pass --force if it is known to be gone",
+                path.display(),
+                owner.as_ref().map_or(String::new(), |pid| format!(" (process {})", pid))
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+impl From<io::Error> for LockError {
+    fn from(e: io::Error) -> Self {
+        LockError::Io(e)
+    }
+}
+
+// Exclusive advisory locks on `<path>.lock` next to each store, journal and checkpoint a run writes, so
+// a second run on the same files is turned away instead of interleaving its writes with the first's.
+// The operating system drops the locks when the process exits, however it exits, so a crashed run
+// never leaves a stale lock behind; the lock files themselves are left in place.
+#[derive(Debug)]
+pub struct RunLock {
+    _files: Vec<File>,
+}
+
+impl RunLock {
+    // With `force`, a lock held by another run is only warned about and the run goes ahead
+    pub fn acquire<P: AsRef<Path>>(paths: &[P], force: bool) -> Result<RunLock, LockError> {
+        let mut files = vec![];
+        for path in paths {
+            let path = lock_path(path.as_ref());
+            let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+            match file.try_lock() {
+                Ok(()) => {
+                    file.set_len(0)?;
+                    file.rewind()?;
+                    write!(file, "{}", std::process::id())?;
+                    files.push(file);
+                }
+                Err(TryLockError::WouldBlock) => {
+                    let mut owner = String::new();
+                    let owner = file.read_to_string(&mut owner).ok().filter(|_| !owner.is_empty()).map(|_| owner);
+                    let held = LockError::Held { path, owner };
+                    if !force {
+                        return Err(held);
+                    }
+                    tracing::warn!("{}; going ahead because of --force", held);
+                }
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+        Ok(RunLock { _files: files })
+    }
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_run_is_turned_away_unless_forced() {
+        let path = std::env::temp_dir().join(format!("payments_processor_lock_{}.jsonl", std::process::id()));
+        let first = RunLock::acquire(&[&path], false).unwrap();
+        match RunLock::acquire(&[&path], false) {
+            Err(LockError::Held { owner, .. }) => assert_eq!(owner, Some(std::process::id().to_string())),
+            other => panic!("expected the lock to be held, got {:?}", other),
+        }
+        assert!(RunLock::acquire(&[&path], true).is_ok());
+        drop(first);
+        assert!(RunLock::acquire(&[&path], false).is_ok());
+        std::fs::remove_file(lock_path(&path)).unwrap();
+    }
+}
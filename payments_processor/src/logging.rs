@@ -0,0 +1,126 @@
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use tracing::level_filters::LevelFilter;
+use tracing::{Span, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::transaction::Transaction;
+
+// Level used when neither RUST_LOG nor --log-level is set: rejected records and failed deliveries are
+// warnings, progress is info
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::INFO;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per event, with the fields of the spans it happened in (input file, transaction)
+    Json,
+}
+
+#[derive(Debug)]
+pub struct UnknownLogFormat(String);
+
+impl fmt::Display for UnknownLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown log format '{}', expected text or json", self.0)
+    }
+}
+
+impl std::error::Error for UnknownLogFormat {}
+
+impl FromStr for LogFormat {
+    type Err = UnknownLogFormat;
+
+    fn from_str(s: &str) -> Result<LogFormat, UnknownLogFormat> {
+        match s.trim().to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(UnknownLogFormat(other.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+// The span a transaction is applied in; the ledger's handles carry the caller's spans over to the
+// ledger task, so hooks log within it as well
+pub fn tx_span(tx: &Transaction) -> Span {
+    tracing::info_span!("tx", tx = tx.tx_id, client = tx.client_id, kind = tx.tx_type.name())
+}
+
+pub fn subscriber<W>(format: LogFormat, filter: EnvFilter, writer: W, ansi: bool) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.with_ansi(ansi).finish()),
+        LogFormat::Json => Box::new(builder.json().with_span_list(true).finish()),
+    }
+}
+
+// Installs the subscriber for the process: diagnostics go to stderr, filtered by RUST_LOG or else `level`
+pub fn init(format: LogFormat, level: LevelFilter) -> Result<(), tracing::subscriber::SetGlobalDefaultError> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level.to_string()));
+    tracing::subscriber::set_global_default(subscriber(format, filter, io::stderr, io::stderr().is_terminal()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use crate::ledger::Ledger;
+    use crate::test_util::TxBuilder;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_events_carry_the_input_and_transaction_spans() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = subscriber(LogFormat::Json, EnvFilter::new(DEFAULT_LEVEL.to_string()), move || writer.clone(), false);
+        tracing::subscriber::with_default(subscriber, || {
+            let mut ledger = Ledger::new();
+            let _input = tracing::info_span!("input", file = "in.csv").entered();
+            assert!(ledger.apply(&TxBuilder::deposit(1, 1, 5.0).build()));
+            assert!(!ledger.apply(&TxBuilder::withdrawal(1, 2, 50.0).build()));
+        });
+
+        let out = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["level"], "WARN");
+        assert_eq!(lines[0]["spans"][0]["file"], "in.csv");
+        assert_eq!(lines[0]["span"]["name"], "tx");
+        assert_eq!(lines[0]["span"]["tx"], 2);
+        assert_eq!(lines[0]["span"]["client"], 1);
+    }
+
+    #[test]
+    fn test_log_format_parses() {
+        assert_eq!("JSON".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,62 +1,1507 @@
-use std::env;
 use std::error::Error;
 use std::fs::File;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use csv::ReaderBuilder;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use tokio::sync::RwLock;
+use tracing::Instrument;
+use tracing::level_filters::LevelFilter;
 
-mod transaction;
-mod client;
-mod ledger;
-use ledger::Ledger;
+use payments_processor::breaker::CircuitBreaker;
+use payments_processor::bundle::{self, BundleFiles};
+use payments_processor::checkpoint::{self, AppliedInputs, Offsets};
+use payments_processor::clients_file::{self, ClientSettings};
+use payments_processor::config::Config;
+use payments_processor::diff;
+use payments_processor::schema;
+use payments_processor::segments;
+use payments_processor::enrichment::Enricher;
+use payments_processor::generate::{self, GenerateOptions, Mix};
+use payments_processor::inspect::ClientReport;
+use payments_processor::journal::{self, Journal, JournalError};
+use payments_processor::late::{LateRecordPolicy, LateRecords};
+use payments_processor::lock::{LockError, RunLock};
+use payments_processor::latency::LatencyTracker;
+use payments_processor::ledger::{Ledger, Retention};
+use payments_processor::logging::{self, LogFormat};
+use payments_processor::manifest::{Checksum, FileProvenance, InputProvenance, Manifest};
+use payments_processor::metrics::Metrics;
+use payments_processor::notifications::NotificationHook;
+use payments_processor::output::AtomicFile;
+use payments_processor::pipeline::{Feed, Order, Parsed, Reader, SequencedMerge};
+use payments_processor::rejects::{Reject, RejectsWriter};
+use payments_processor::shadow::{ShadowComparison, ShadowDiff};
+use payments_processor::shard::{ShardedLedger, TxIds};
+use payments_processor::source::{self, CsvFormat, SourceError};
+use payments_processor::spill::SpillStore;
+use payments_processor::stats::RunStats;
+use payments_processor::store::StoreError;
+use payments_processor::summary::{self, OutputFormat, SummaryOptions};
+use payments_processor::validate;
+use payments_processor::verify;
+use payments_processor::watch::DropFolder;
+
+// EX_DATAERR from sysexits.h: the input was bad, not the invocation (--strict aborts, validate problems)
+const EXIT_BAD_INPUT: i32 = 65;
+// EX_TEMPFAIL: another run holds the store, journal or checkpoint, so trying again later may work
+const EXIT_LOCKED: i32 = 75;
+
+#[derive(Parser)]
+#[command(version, about = "Applies transaction files to client accounts and writes the account summary", args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    // Without a subcommand the arguments are those of `process`, so `payments_processor input.csv` keeps working
+    #[command(flatten)]
+    process: ProcessArgs,
+    /// Diagnostics on stderr as text or json (one object per line)
+    #[arg(long, global = true, default_value = "text")]
+    log_format: LogFormat,
+    /// Least severe diagnostics logged: off, error, warn, info, debug or trace. RUST_LOG takes precedence, e.g. RUST_LOG=payments_processor=debug
+    #[arg(long, global = true, default_value_t = logging::DEFAULT_LEVEL)]
+    log_level: LevelFilter,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Apply the inputs and write the account summary (the default)
+    Process(Box<ProcessArgs>),
+    /// Print the per-client changes between two CSV summaries or checkpoints
+    Diff {
+        #[arg(long, default_value = "csv")]
+        format: OutputFormat,
+        old: PathBuf,
+        new: PathBuf,
+    },
+    /// Rebuild the ledger from a --journal file and write its account summary
+    Replay {
+        journal: PathBuf,
+        /// The config the journal was written with, so rules and limits apply the same way
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(long, default_value = "csv")]
+        format: OutputFormat,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[arg(long)]
+        operator: bool,
+    },
+    /// Print one client's balances and the transactions kept for it, from a --checkpoint file or by replaying a --journal
+    Inspect {
+        #[arg(long)]
+        client: u16,
+        #[arg(long, required_unless_present = "journal", conflicts_with = "journal")]
+        checkpoint: Option<PathBuf>,
+        #[arg(long)]
+        journal: Option<PathBuf>,
+        /// The config the checkpoint or journal was written with
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// csv, json or jsonl
+        #[arg(long, default_value = "csv")]
+        format: OutputFormat,
+    },
+    /// Apply the inputs and write the history of every transaction (created, disputed, resolved, ...) instead of the summary
+    ExportHistory {
+        /// CSV or JSON Lines inputs, like process
+        #[arg(required = true)]
+        inputs: Vec<String>,
+        #[command(flatten)]
+        csv: CsvArgs,
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// csv, json or jsonl
+        #[arg(long, default_value = "csv")]
+        format: OutputFormat,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Apply the inputs to a throwaway ledger under the same rules as process and report every record that couldn't be
+    /// read or would be rejected, with its line; exits with status 65 if there are any. Writes no summary, store or journal
+    Validate(ValidateArgs),
+    /// Check a --checkpoint file's invariants and, with --journal, that replaying the journal up to it gives the same
+    /// balances; reports the first problem and exits with status 65 if there is one
+    Verify {
+        checkpoint: PathBuf,
+        #[arg(long)]
+        journal: Option<PathBuf>,
+        /// The config the checkpoint and journal were written with
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Rewrite a --checkpoint file written by an older version in the current format, so --resume, diff and inspect read it
+    Migrate {
+        checkpoint: PathBuf,
+        /// Write the migrated checkpoint here instead of replacing the original
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Pack a --checkpoint, with the journal, manifest and config of the run, into a versioned .tar.zst bundle
+    ExportBundle {
+        output: PathBuf,
+        #[command(flatten)]
+        files: BundleArgs,
+        /// Only include the journal's last this many entries
+        #[arg(long, requires = "journal")]
+        journal_tail: Option<u64>,
+    },
+    /// Unpack a bundle from export-bundle, checking its version and checksums; the checkpoint is ready for --resume
+    ImportBundle {
+        bundle: PathBuf,
+        #[command(flatten)]
+        files: BundleArgs,
+        /// Replace files that already exist
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write a synthetic CSV input for load tests and fuzzing, the same for the same seed and options
+    Generate {
+        #[arg(long, default_value_t = 100)]
+        clients: u16,
+        #[arg(long, visible_alias = "transactions", default_value_t = 10_000)]
+        rows: u64,
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /// Relative weights of the record types
+        #[arg(long, default_value = "deposit=60,withdrawal=25,dispute=8,resolve=5,chargeback=2")]
+        mix: Mix,
+        /// Share of rows that are malformed, between 0 and 1
+        #[arg(long, default_value_t = 0.0, value_parser = parse_share)]
+        malformed: f64,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Keep the ledger running and accept transactions over HTTP (needs the `server` feature)
+    Serve(ServeArgs),
+    /// Like serve, with the gRPC service of proto/payments.proto (needs the `grpc` feature)
+    ServeGrpc(ServeArgs),
+    /// Inspect CSV inputs
+    #[command(subcommand)]
+    Schema(SchemaCommand),
+    /// Inspect the segments of a --journal
+    #[command(subcommand)]
+    Retention(RetentionCommand),
+}
+
+#[derive(Subcommand)]
+enum SchemaCommand {
+    /// Report the columns, their detected types and any anomalies; fails if there are anomalies
+    Check {
+        input: PathBuf,
+        #[command(flatten)]
+        csv: CsvArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum RetentionCommand {
+    /// Print the journal's segments as JSON, with their sizes and ages and which ones the `[journal]` retention of the
+    /// config deletes
+    Status {
+        journal: PathBuf,
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+struct ProcessArgs {
+    /// CSV or JSON Lines (.jsonl/.ndjson) inputs, - for CSV on stdin
+    #[arg(required_unless_present_any = ["kafka", "watch"])]
+    inputs: Vec<String>,
+    /// Also consume a Kafka topic until Ctrl-C (needs the `kafka` feature): brokers=<host:port,...> topic=<name> [group=<id>] [<librdkafka setting>=<value>...]
+    #[arg(long, num_args = 1.., value_name = "KEY=VALUE", conflicts_with_all = ["checkpoint", "resume"])]
+    kafka: Vec<String>,
+    /// Process every file dropped into this folder until Ctrl-C, moving each to its processed/ or failed/ subfolder
+    #[arg(long, conflicts_with_all = ["checkpoint", "resume", "kafka"])]
+    watch: Option<PathBuf>,
+    /// Seconds between the summaries written while watching (to --output or stdout)
+    #[arg(long, requires = "watch", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    summary_every: u64,
+    /// While watching, write the records whose seq or timestamp falls inside a period a summary already reported to
+    /// this file (JSON Lines), as `late_records` in the config says: restated, or held for review
+    #[arg(long, requires = "watch")]
+    late_records: Option<PathBuf>,
+    /// Summary format: csv, json, jsonl or parquet
+    #[arg(long, visible_alias = "output-format", default_value = "csv")]
+    format: OutputFormat,
+    /// Write the summary to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// TOML config with rules, tiers, notifications, reference data and plugins
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Write a JSON run manifest (inputs, checksums, rejections, latency)
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+    /// After the summary, print a JSON report of the run (records read, accepted and rejected by error, counts per type,
+    /// clients created, duration, throughput) to stderr, or with --stats=FILE to that file
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "-", value_name = "FILE")]
+    stats: Option<PathBuf>,
+    /// Write the metrics in the Prometheus text format to this file at the end (and with every --watch summary)
+    #[arg(long)]
+    metrics: Option<PathBuf>,
+    /// WASM rule plugin, in addition to those in the config
+    #[arg(long = "plugin")]
+    plugins: Vec<PathBuf>,
+    /// Replay every transaction against a ledger built from this config and report differences
+    #[arg(long)]
+    shadow: Option<PathBuf>,
+    /// Write the shadow differences to this file instead of stderr
+    #[arg(long, requires = "shadow")]
+    shadow_report: Option<PathBuf>,
+    /// Add the operator section (funds under operator holds) to the summary
+    #[arg(long)]
+    operator: bool,
+    /// Add a totals row per currency after the clients
+    #[arg(long)]
+    totals: bool,
+    /// Add every client's balances converted to the currency of record, at the rates of the config's [reporting]
+    /// section, after the rows in their own currencies
+    #[arg(long)]
+    consolidated: bool,
+    /// Leave out the CSV header row
+    #[arg(long)]
+    no_header: bool,
+    /// Reject CSV inputs whose header isn't exactly type,client,tx,amount
+    #[arg(long)]
+    strict_schema: bool,
+    #[command(flatten)]
+    csv: CsvArgs,
+    /// Write every unreadable or rejected record with its error to this file (JSON Lines for .jsonl, CSV otherwise)
+    #[arg(long)]
+    rejects: Option<PathBuf>,
+    /// Stop at the first unreadable or rejected record and exit with status 65 without writing the summary
+    #[arg(long)]
+    strict: bool,
+    /// How records of several inputs interleave: arrival (as each input is read, which varies between runs) or
+    /// sequenced (merged by each record's seq or timestamp column, the same every run)
+    #[arg(long, default_value = "arrival", conflicts_with_all = ["kafka", "watch"])]
+    order: Order,
+    /// Skip repeated transaction ids instead of rejecting them
+    #[arg(long)]
+    idempotent: bool,
+    /// Apply admin records (lock, unlock, tier, hold, release, annul) instead of rejecting them
+    #[arg(long)]
+    allow_admin_ops: bool,
+    /// Debugging aid: check the ledger's invariants after every transaction and panic on the first violation.
+    /// Every check reads the whole ledger, so this is only for small inputs
+    #[arg(long)]
+    check_invariants: bool,
+    #[command(flatten)]
+    accounts: AccountArgs,
+    /// Keep balances and transaction history in this SQLite database (needs the `sqlite` feature); an existing one is continued
+    #[arg(long, conflicts_with = "shards")]
+    store: Option<PathBuf>,
+    /// Also keep this many of the --store's most recently used transactions in memory, so disputes of
+    /// recent deposits don't go to disk (0 for none)
+    #[arg(long, default_value_t = 10_000)]
+    store_cache: usize,
+    /// Append every transaction to this write-ahead journal before it is applied; an existing one is continued
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    /// Keep at most this many transactions per shard in memory and spill older ones to a temporary file,
+    /// where disputes still find them
+    #[arg(long, conflicts_with = "store", value_parser = clap::value_parser!(u64).range(1..))]
+    max_tx_memory: Option<u64>,
+    /// Periodically write the ledger state and input positions to this file, for --resume
+    #[arg(long, conflicts_with = "store")]
+    checkpoint: Option<PathBuf>,
+    /// Records (over all inputs) between checkpoints
+    #[arg(long, requires = "checkpoint", default_value_t = 100_000, value_parser = clap::value_parser!(u64).range(1..))]
+    checkpoint_every: u64,
+    /// Start from a --checkpoint file, skipping the records of each input it already covers
+    #[arg(long, conflicts_with_all = ["store", "shadow"])]
+    resume: Option<PathBuf>,
+    /// Leave out, with a warning, inputs the --resume checkpoint already has the records of, instead of refusing to run
+    #[arg(long, requires = "resume")]
+    skip_duplicates: bool,
+    /// Go ahead even if another run holds the lock on the --store, --journal or --checkpoint
+    #[arg(long)]
+    force: bool,
+    /// Number of ledger shards; defaults to the available parallelism
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    shards: Option<u16>,
+}
+
+impl ProcessArgs {
+    fn summary_options(&self, config: &Config) -> Result<SummaryOptions, Box<dyn Error>> {
+        let consolidated = match (self.consolidated, &config.reporting) {
+            (true, None) => return Err("--consolidated needs a [reporting] section in the config".into()),
+            (true, Some(rates)) => Some(rates.clone()),
+            (false, _) => None,
+        };
+        Ok(SummaryOptions { header: !self.no_header, totals: self.totals, operator: self.operator, consolidated })
+    }
+}
+
+// Where a bundle's parts are read from on export, or written to on import
+#[derive(Args)]
+struct BundleArgs {
+    #[arg(long)]
+    checkpoint: PathBuf,
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    /// A --manifest of the run
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+impl BundleArgs {
+    fn files(self) -> BundleFiles {
+        BundleFiles { checkpoint: self.checkpoint, journal: self.journal, manifest: self.manifest, config: self.config }
+    }
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    /// CSV or JSON Lines inputs, like process
+    #[arg(required = true)]
+    inputs: Vec<String>,
+    #[command(flatten)]
+    csv: CsvArgs,
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Write the report to this file (JSON Lines for .jsonl, CSV otherwise) instead of CSV on stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[arg(long)]
+    strict_schema: bool,
+    #[arg(long)]
+    idempotent: bool,
+    #[arg(long)]
+    allow_admin_ops: bool,
+    #[command(flatten)]
+    accounts: AccountArgs,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Address to listen on; 127.0.0.1:8080 for serve, 127.0.0.1:50051 for serve-grpc
+    #[arg(long)]
+    listen: Option<String>,
+    /// TOML config with rules, tiers, notifications and reference data
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Skip repeated transaction ids instead of rejecting them
+    #[arg(long)]
+    idempotent: bool,
+    /// Apply admin records (lock, unlock, tier, hold, release, annul) instead of rejecting them
+    #[arg(long)]
+    allow_admin_ops: bool,
+    #[command(flatten)]
+    accounts: AccountArgs,
+    /// Keep the ledger in this SQLite database (needs the `sqlite` feature), so it survives restarts
+    #[arg(long, conflicts_with = "shards")]
+    store: Option<PathBuf>,
+    /// Also keep this many of the --store's most recently used transactions in memory, so disputes of
+    /// recent deposits don't go to disk (0 for none)
+    #[arg(long, default_value_t = 10_000)]
+    store_cache: usize,
+    /// Append every transaction to this write-ahead journal before it is applied
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    /// Go ahead even if another run holds the lock on the --store or --journal
+    #[arg(long)]
+    force: bool,
+    /// Number of ledger shards; defaults to the available parallelism
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    shards: Option<u16>,
+}
+
+#[derive(Args)]
+struct AccountArgs {
+    /// Credit line of clients without their own in --clients: withdrawals may take available down to minus this much
+    #[arg(long, default_value_t = 0.0, value_parser = parse_limit)]
+    overdraft_limit: f64,
+    /// Account state to start from, as CSV or a JSON array for .json: client, and optionally balance, currency,
+    /// locked, name, tier and overdraft_limit. Balances are only seeded into clients that don't have one yet
+    #[arg(long)]
+    clients: Option<PathBuf>,
+}
+
+impl AccountArgs {
+    fn load(&self) -> Result<Vec<ClientSettings>, Box<dyn Error>> {
+        Ok(self.clients.as_ref().map(clients_file::load).transpose()?.unwrap_or_default())
+    }
+
+    // For shards, once the ledger knows which one it is
+    fn configure(&self, ledger: &mut Ledger, clients: &[ClientSettings]) {
+        ledger.set_overdraft_limit(self.overdraft_limit);
+        for settings in clients {
+            ledger.configure_client(settings);
+        }
+    }
+}
+
+// How CSV inputs are laid out, for exports that aren't comma-separated with decimal points
+#[derive(Args)]
+struct CsvArgs {
+    /// Field separator of CSV inputs: one character, or "tab". Defaults to tab for .tsv inputs and comma otherwise
+    #[arg(long, value_parser = parse_delimiter)]
+    delimiter: Option<u8>,
+    /// Decimal separator of amounts in CSV inputs, e.g. "," for 12,50
+    #[arg(long, default_value_t = '.')]
+    decimal_separator: char,
+}
+
+impl CsvArgs {
+    fn format(&self) -> CsvFormat {
+        CsvFormat { delimiter: self.delimiter, decimal_separator: self.decimal_separator }
+    }
+
+    // Exits with a usage error if the decimal separator is also the field separator of one of the CSV
+    // inputs, e.g. --decimal-separator , without --delimiter, which would read 12,50 as 12 and a stray 50
+    fn check<P: AsRef<Path>>(&self, inputs: &[P]) {
+        for input in inputs.iter().filter(|input| !source::is_json_lines(input.as_ref())) {
+            if self.format().for_path(input.as_ref()).separators_clash() {
+                let message = format!(
+                    "--decimal-separator '{}' is also the field separator of {}; name another one with --delimiter",
+                    self.decimal_separator,
+                    input.as_ref().display()
+                );
+                Cli::command().error(ErrorKind::ArgumentConflict, message).exit();
+            }
+        }
+    }
+}
+
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(format!("{} is not a single character or \"tab\"", s)),
+    }
+}
+
+fn parse_share(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(share) if (0.0..=1.0).contains(&share) => Ok(share),
+        _ => Err(format!("{} is not between 0 and 1", s)),
+    }
+}
+
+fn parse_limit(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(limit) if limit.is_finite() && limit >= 0.0 => Ok(limit),
+        _ => Err(format!("{} is not a non-negative amount", s)),
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
+    let cli = Cli::parse();
+    match &cli.command {
+        None => cli.process.csv.check(&cli.process.inputs),
+        Some(Command::Process(args)) => args.csv.check(&args.inputs),
+        Some(Command::Validate(args)) => args.csv.check(&args.inputs),
+        Some(Command::ExportHistory { inputs, csv, .. }) => csv.check(inputs),
+        Some(Command::Schema(SchemaCommand::Check { input, csv })) => csv.check(&[input]),
+        _ => {}
+    }
+    logging::init(cli.log_format, cli.log_level)?;
+    match cli.command {
+        None => run_process(cli.process).await,
+        Some(Command::Process(args)) => run_process(*args).await,
+        Some(Command::Diff { format, old, new }) => run_diff(format, &old, &new),
+        Some(Command::Replay { journal, config, format, output, operator }) => {
+            run_replay(&journal, config.as_deref(), format, output.as_deref(), operator).await
+        }
+        Some(Command::Inspect { client, checkpoint, journal, config, format }) => {
+            run_inspect(client, checkpoint.as_deref(), journal.as_deref(), config.as_deref(), format)
+        }
+        Some(Command::ExportHistory { inputs, csv, config, format, output }) => {
+            run_export_history(&inputs, csv.format(), config.as_deref(), format, output.as_deref())
+        }
+        Some(Command::Validate(args)) => run_validate(&args),
+        Some(Command::Verify { checkpoint, journal, config }) => run_verify(&checkpoint, journal.as_deref(), config.as_deref()),
+        Some(Command::Migrate { checkpoint, output }) => run_migrate(&checkpoint, output.as_deref()),
+        Some(Command::ExportBundle { output, files, journal_tail }) => {
+            let index = bundle::export(&files.files(), journal_tail, &output)?;
+            tracing::info!("Wrote {} with {}", output.display(), index.files.keys().cloned().collect::<Vec<_>>().join(", "));
+            Ok(())
+        }
+        Some(Command::ImportBundle { bundle, files, force }) => {
+            let index = bundle::import(&bundle, &files.files(), force)?;
+            tracing::info!("Imported {} from version {} of the processor", bundle.display(), index.crate_version);
+            Ok(())
+        }
+        Some(Command::Generate { clients, rows, seed, mix, malformed, output }) => {
+            run_generate(&GenerateOptions { clients, rows, seed, mix, malformed }, output.as_deref())
+        }
+        Some(Command::Serve(args)) => run_serve(args).await,
+        Some(Command::ServeGrpc(args)) => run_serve_grpc(args).await,
+        Some(Command::Schema(SchemaCommand::Check { input, csv })) => run_schema(&input, csv.format()),
+        Some(Command::Retention(RetentionCommand::Status { journal, config })) => run_retention_status(&journal, config.as_deref()),
+    }
+}
 
-    if args.len() < 2 {
-        eprintln!("Usage: cargo run -- <input1.csv> <input2.csv> ...");
-        std::process::exit(1);
+async fn run_process(args: ProcessArgs) -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+    let ProcessArgs { format, strict, strict_schema, idempotent, allow_admin_ops, .. } = args;
+    let max_tx_memory = args.max_tx_memory.map(|max| usize::try_from(max).unwrap_or(usize::MAX));
+    let mut inputs = args.inputs.clone();
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let summary_options = args.summary_options(&config)?;
+    let shadow_config = args.shadow.as_ref().map(Config::load).transpose()?;
+    // A store is one database written by one ledger, so it runs unsharded
+    let shards = match (&args.store, args.shards) {
+        (Some(_), _) => 1,
+        (None, Some(n)) => usize::from(n),
+        (None, None) => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+
+    config.plugins.extend(args.plugins.iter().cloned());
+    let (latency, _) = LatencyTracker::new(config.latency_budget_ms.map(Duration::from_millis));
+    let _lock = lock_run(&[&args.store, &args.journal, &args.checkpoint], args.force)?;
+    let journal = args.journal.as_ref().map(|path| open_journal(path, &config)).transpose()?;
+    let clients = args.accounts.load()?;
+    check_retention(&config, args.store.as_deref())?;
+    // Only --watch writes summaries while records still arrive, so only there can a record be late
+    if args.watch.is_some() && config.late_records != LateRecordPolicy::Apply && args.late_records.is_none() {
+        return Err("late_records in the config needs --late-records to write the late records to".into());
+    }
+    let mut ledgers = vec![];
+    let mut shadows = vec![];
+    // Shadow shards check tx ids across each other like the real ones
+    let shadow_tx_ids = TxIds::default();
+    for index in 0..shards {
+        let mut ledger = build_ledger(&config, args.store.as_deref().map(|path| (path, args.store_cache)), max_tx_memory)?;
+        ledger.set_idempotent(idempotent);
+        ledger.set_admin_ops(allow_admin_ops);
+        ledger.set_check_invariants(args.check_invariants);
+        // First hook, so the latency covers the other hooks as well
+        ledger.add_hook(Box::new(LatencyTracker::hook(&latency)));
+        if !config.notifications.is_empty() {
+            ledger.add_hook(Box::new(NotificationHook::new(config.notifications.clone())));
+        }
+        // Shadow ledgers only get the business rules of their config, never its notifications
+        if let Some(shadow_config) = &shadow_config {
+            let mut shadow = build_ledger(shadow_config, None, max_tx_memory)?;
+            shadow.set_shard(index, shards);
+            if shards > 1 {
+                shadow.share_tx_ids(shadow_tx_ids.clone())?;
+            }
+            shadow.set_admin_ops(allow_admin_ops);
+            args.accounts.configure(&mut shadow, &clients);
+            let (state, hook) = ShadowComparison::new(shadow);
+            ledger.add_hook(Box::new(hook));
+            shadows.push(state);
+        }
+        // Last hook, so a transaction another hook turns away never reaches the journal
+        if let Some(journal) = &journal {
+            ledger.add_hook(Box::new(Journal::hook(journal)));
+        }
+        ledgers.push(ledger);
+    }
+    let offsets = match &args.resume {
+        Some(path) => checkpoint::restore(path, &mut ledgers)?,
+        None => Offsets::new(),
+    };
+    // After restoring, so the file's settings win over the checkpoint's
+    for (index, ledger) in ledgers.iter_mut().enumerate() {
+        ledger.set_shard(index, shards);
+        args.accounts.configure(ledger, &clients);
+    }
+    // Offsets are keyed by input path
+    if (args.checkpoint.is_some() || args.resume.is_some()) && inputs.iter().collect::<std::collections::HashSet<_>>().len() < inputs.len() {
+        return Err("--checkpoint and --resume need distinct input paths".into());
+    }
+    // The input files the state already has records of, from earlier runs, and the checksums of this
+    // run's to add to them. A file among them that the checkpoint has no offset for (unlike an input of
+    // the run being resumed) was already applied, under this path or another.
+    let applied = match &args.resume {
+        Some(path) => checkpoint::applied_inputs(path)?,
+        None => AppliedInputs::new(),
+    };
+    let mut checksums = vec![];
+    if args.checkpoint.is_some() || args.resume.is_some() {
+        let mut kept = vec![];
+        for path in inputs.drain(..) {
+            // Unreadable files fail when they are opened
+            let sha256 = (path != "-").then(|| Checksum::of(&path).ok()).flatten().map(|c| c.sha256);
+            if let Some(earlier) = sha256.as_ref().filter(|_| !offsets.contains_key(&path)).and_then(|sha256| applied.get(sha256)) {
+                if !args.skip_duplicates {
+                    tracing::error!("{} has the same contents as {}, which the resumed checkpoint already has the records of; pass --skip-duplicates to leave it out", path, earlier);
+                    std::process::exit(EXIT_BAD_INPUT);
+                }
+                tracing::warn!("Skipping {}: it has the same contents as {}, which the resumed checkpoint already has the records of", path, earlier);
+                continue;
+            }
+            checksums.push(sha256);
+            kept.push(path);
+        }
+        inputs = kept;
     }
 
-    let ledger = Arc::new(Mutex::new(Ledger::new()));
+    let clients_before = ledgers.iter().map(|l| l.clients().count() as u64).sum();
+    let ledger = ShardedLedger::spawn(ledgers)?;
+    let enricher = Arc::new(Enricher::load(&config.reference)?);
 
-    let mut handles = vec![];
+    // Set by the first input to fail under --strict, so the others stop too, and by Ctrl-C when consuming Kafka
+    let stop = Arc::new(AtomicBool::new(false));
+    let kafka = open_kafka(&args.kafka, &stop)?;
+    if let Some((name, _)) = &kafka {
+        inputs.push(name.clone());
+        let stop = Arc::clone(&stop);
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                stop.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+    let mut kafka = kafka.map(|(_, source)| source);
 
-    for file_path in &args[1..] {
-        let ledger_clone = Arc::clone(&ledger);
-        let file_path = file_path.clone();
+    let rejects = match &args.rejects {
+        Some(path) => Some(Arc::new(StdMutex::new(RejectsWriter::create(path)?))),
+        None => None,
+    };
+    // Records read from each input, including those a resumed run skipped
+    let positions: Vec<Arc<AtomicU64>> = inputs.iter().map(|_| Arc::new(AtomicU64::new(0))).collect();
+    let checkpointer = args.checkpoint.clone().map(|path| Arc::new(Checkpointer {
+        path,
+        every: args.checkpoint_every,
+        records: AtomicU64::new(0),
+        gate: RwLock::new(()),
+        positions: inputs.iter().cloned().zip(positions.iter().cloned()).collect(),
+        applied,
+        checksums,
+        journal: journal.clone(),
+    }));
+    let applier = Applier { ledger: ledger.clone(), checkpointer, rejects: rejects.clone(), stop: Arc::clone(&stop), strict };
 
-        let handle = tokio::spawn(async move {
-            match File::open(&file_path) {
-                Ok(file) => {
-                    let mut reader = ReaderBuilder::new()
-                        .flexible(true)
-                        .from_reader(file);
+    // Inputs that opened, with where their records come from
+    let mut runs = vec![];
+    let mut feeds = vec![];
+    let mut aborts = vec![];
+    // By position in `inputs`, so the manifest lists them in the order given
+    let mut finished = vec![];
+    for (index, (file_path, position)) in inputs.iter().zip(positions).enumerate() {
+        let span = tracing::info_span!("input", file = %file_path);
+        // The Kafka input comes last
+        let live = kafka.is_some() && index + 1 == inputs.len();
+        let opened = match kafka.take_if(|_| live) {
+            Some(source) => Ok(source),
+            None => open_input(file_path, strict_schema, args.csv.format()).await,
+        };
+        let source = match opened {
+            Ok(source) => source,
+            Err(e) => {
+                tracing::error!(parent: &span, "Failed to open {}: {}", file_path, e);
+                finished.push((index, InputProvenance::new(file_path.clone())));
+                if strict {
+                    aborts.push(Reject { input: file_path.clone(), line: None, record: None, error: e.to_string() });
+                    stop.store(true, Ordering::Relaxed);
+                }
+                continue;
+            }
+        };
+        // Reject lines need the raw records
+        let keep_raw = rejects.is_some() || strict;
+        feeds.push(match live {
+            true => Feed::Live { source, enricher: Arc::clone(&enricher), keep_raw },
+            false => {
+                let reader = Reader { skip: offsets.get(file_path).copied().unwrap_or(0), keep_raw, ..Reader::default() };
+                Feed::Reader(reader.spawn(source, Arc::clone(&enricher), Arc::clone(&position), Arc::clone(&stop)))
+            }
+        });
+        runs.push(InputRun {
+            index,
+            input: InputProvenance::new(file_path.clone()),
+            breaker: config.circuit_breaker.clone().map(CircuitBreaker::new),
+            position,
+            span,
+            abort: None,
+        });
+    }
 
-                    for result in reader.records() {
-                        match result {
-                            Ok(record) => {
-                                let mut ledger_lock = ledger_clone.lock().await;
-                                ledger_lock.process(record);
+    let applying = match args.order {
+        Order::Arrival => {
+            let mut handles = vec![];
+            for (mut run, mut feed) in runs.into_iter().zip(feeds) {
+                let applier = applier.clone();
+                handles.push(tokio::spawn(async move {
+                    // Dropping `feed` when the input stops early stops its reader
+                    'input: while let Some(chunk) = feed.next_chunk().await {
+                        for parsed in chunk {
+                            if !applier.apply(&mut run, parsed).await {
+                                break 'input;
                             }
-                            Err(e) => eprintln!("Error reading record in {}: {}", file_path, e),
                         }
                     }
+                    vec![run]
+                }));
+            }
+            handles
+        }
+        Order::Sequenced => vec![tokio::spawn(async move {
+            let mut merge = SequencedMerge::new(feeds);
+            while let Some((index, parsed)) = merge.next().await {
+                if !applier.apply(&mut runs[index], parsed).await {
+                    merge.close(index);
                 }
-                Err(e) => eprintln!("Failed to open {}: {}", file_path, e),
             }
-        });
+            runs
+        })],
+    };
+
+    let mut manifest = Manifest::new(format.to_string());
+    if let Some(dir) = &args.watch {
+        manifest.inputs.extend(watch_folder(dir, &args, &config, &ledger, &enricher, rejects.as_deref()).await?);
+    }
+    for handle in applying {
+        for run in handle.await? {
+            finished.push((run.index, run.input));
+            aborts.extend(run.abort);
+        }
+    }
+    finished.sort_by_key(|(index, _)| *index);
+    manifest.inputs.extend(finished.into_iter().map(|(_, input)| input));
+    if let Some(rejects) = &rejects {
+        rejects.lock().map_err(|_| "rejects writer poisoned")?.flush()?;
+    }
+    if !aborts.is_empty() {
+        for abort in &aborts {
+            tracing::error!("Aborted (--strict): {}", abort);
+        }
+        std::process::exit(EXIT_BAD_INPUT);
+    }
+
+    // Shadow reports compare each shard with its own shadow ledger, so they are taken before merging
+    let mut shard_ledgers = ledger.shutdown().await?;
+    for shard in shard_ledgers.iter_mut() {
+        shard.flush()?;
+    }
+    if let Some(journal) = &journal {
+        let mut journal = journal.lock().map_err(|_| "journal poisoned")?;
+        journal.sync()?;
+        journal.wait_for_retention();
+    }
+    let mut shadow_diffs = vec![];
+    for (state, shard) in shadows.iter().zip(&shard_ledgers) {
+        shadow_diffs.extend(state.lock().map_err(|_| "shadow comparison state poisoned")?.report(shard));
+    }
+    // Rejections keep their per-shard arrival order, balance differences are ordered by client
+    shadow_diffs.sort_by_key(|diff| match diff {
+        ShadowDiff::Rejection { .. } => None,
+        ShadowDiff::Balance { client, currency, .. } => Some((*client, *currency)),
+    });
+    // The shards are summarized as they are, so no store's transactions are moved into another
+    write_summary(&shard_ledgers, format, args.output.as_deref(), &summary_options)?;
+    let mut metrics = Metrics::default();
+    for shard in &shard_ledgers {
+        metrics.merge(&shard.metrics());
+    }
+    let clients: usize = shard_ledgers.iter().map(|shard| shard.clients().count()).sum();
+
+    if let Some(path) = &args.stats {
+        let stats = RunStats::new(&manifest.inputs, &metrics, clients_before, clients as u64, started.elapsed());
+        match path.to_str() {
+            Some("-") => stats.write(std::io::stderr())?,
+            _ => stats.write(BufWriter::new(File::create(path)?))?,
+        }
+    }
+    if let Some(path) = &args.metrics {
+        write_metrics(&metrics, path)?;
+    }
+    if let Some(cache) = metrics.tx_cache {
+        tracing::info!("Transaction cache hit rate {:.1}% ({} hits, {} misses)", cache.hit_rate() * 100.0, cache.hits, cache.misses);
+    }
+
+    if let Some(path) = &args.manifest {
+        if let Some(config_path) = &args.config {
+            manifest.config = Some(FileProvenance::of(config_path)?);
+        }
+        for input in manifest.inputs.iter_mut().filter(|i| i.path != "-" && !i.path.starts_with(KAFKA_INPUT) && i.checksum.is_none()) {
+            input.checksum = Some(Checksum::of(&input.path)?);
+        }
+        manifest.clients = clients;
+        manifest.latency = Some(latency.lock().map_err(|_| "latency tracker poisoned")?.summary());
+        let mut out = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut out, &manifest)?;
+        writeln!(out)?;
+        out.flush()?;
+    }
 
-        handles.push(handle);
+    let tripped = manifest.inputs.iter().filter(|i| i.tripped.is_some()).count();
+
+    if shadow_config.is_some() {
+        write_shadow_report(&shadow_diffs, args.shadow_report.as_deref())?;
+    }
+
+    if tripped > 0 {
+        return Err(format!("{} input(s) stopped by the circuit breaker", tripped).into());
+    }
+    Ok(())
+}
+
+// Builds the shards for `serve` and `serve-grpc` and runs `serve` on them until Ctrl-C, then lets the
+// shards finish their queues and makes the store and journal durable
+#[cfg(any(feature = "server", feature = "grpc"))]
+async fn run_service<F, Fut>(args: ServeArgs, default_listen: &str, serve: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(tokio::net::TcpListener, ShardedLedger, Arc<Enricher>, Option<Arc<payments_processor::reload::Reloader>>, Shutdown) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn Error>>>,
+{
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let shards = match (&args.store, args.shards) {
+        (Some(_), _) => 1,
+        (None, Some(n)) => usize::from(n),
+        (None, None) => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let _lock = lock_run(&[&args.store, &args.journal], args.force)?;
+    let journal = args.journal.as_ref().map(|path| open_journal(path, &config)).transpose()?;
+    check_retention(&config, args.store.as_deref())?;
+    let mut ledgers = vec![];
+    let clients = args.accounts.load()?;
+    for index in 0..shards {
+        let mut ledger = build_ledger(&config, args.store.as_deref().map(|path| (path, args.store_cache)), None)?;
+        ledger.set_idempotent(args.idempotent);
+        ledger.set_admin_ops(args.allow_admin_ops);
+        ledger.set_shard(index, shards);
+        args.accounts.configure(&mut ledger, &clients);
+        if !config.notifications.is_empty() {
+            ledger.add_hook(Box::new(NotificationHook::new(config.notifications.clone())));
+        }
+        if let Some(journal) = &journal {
+            ledger.add_hook(Box::new(Journal::hook(journal)));
+        }
+        ledgers.push(ledger);
     }
+    let ledger = ShardedLedger::spawn(ledgers)?;
+    let enricher = Arc::new(Enricher::load(&config.reference)?);
+    let reloader = args.config.clone().map(|path| Arc::new(payments_processor::reload::Reloader::new(path, &config, ledger.clone(), journal.clone())));
 
-    for handle in handles {
-        handle.await?;
+    let listener = tokio::net::TcpListener::bind(args.listen.as_deref().unwrap_or(default_listen)).await?;
+    tracing::info!("Listening on {}", listener.local_addr()?);
+    let shutdown = Box::pin(async {
+        let _ = tokio::signal::ctrl_c().await;
+    });
+    serve(listener, ledger.clone(), enricher, reloader, shutdown).await?;
+
+    for mut shard in ledger.shutdown().await? {
+        shard.flush()?;
+    }
+    if let Some(journal) = &journal {
+        let mut journal = journal.lock().map_err(|_| "journal poisoned")?;
+        journal.sync()?;
+        journal.wait_for_retention();
     }
+    Ok(())
+}
+
+#[cfg(any(feature = "server", feature = "grpc"))]
+type Shutdown = std::pin::Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[cfg(feature = "server")]
+async fn run_serve(args: ServeArgs) -> Result<(), Box<dyn Error>> {
+    run_service(args, "127.0.0.1:8080", |listener, ledger, enricher, reloader, shutdown| async move {
+        Ok(payments_processor::server::serve(listener, ledger, enricher, reloader, shutdown).await?)
+    })
+    .await
+}
 
-    let ledger = ledger.lock().await;
-    ledger.print_summary()?;
+#[cfg(not(feature = "server"))]
+async fn run_serve(_args: ServeArgs) -> Result<(), Box<dyn Error>> {
+    Err("serve needs a build with the `server` feature".into())
+}
+
+#[cfg(feature = "grpc")]
+async fn run_serve_grpc(args: ServeArgs) -> Result<(), Box<dyn Error>> {
+    // Reloading the config is only offered over HTTP
+    run_service(args, "127.0.0.1:50051", |listener, ledger, enricher, _reloader, shutdown| async move {
+        Ok(payments_processor::grpc::serve(listener, ledger, enricher, shutdown).await?)
+    })
+    .await
+}
 
+#[cfg(not(feature = "grpc"))]
+async fn run_serve_grpc(_args: ServeArgs) -> Result<(), Box<dyn Error>> {
+    Err("serve-grpc needs a build with the `grpc` feature".into())
+}
+
+// Applies the files dropped into `dir` until Ctrl-C, writing the summary every `--summary-every`, and
+// returns them with the paths they were moved to. A file fails if it can't be opened or any of its records
+// can't be read, or under --strict at its first bad record; ledger rejections only go to --rejects.
+async fn watch_folder(
+    dir: &Path,
+    args: &ProcessArgs,
+    config: &Config,
+    ledger: &ShardedLedger,
+    enricher: &Arc<Enricher>,
+    rejects: Option<&Rejects>,
+) -> Result<Vec<InputProvenance>, Box<dyn Error>> {
+    let mut folder = DropFolder::open(dir)?;
+    let late_out: Box<dyn Write + Send> = match &args.late_records {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::sink()),
+    };
+    let mut late = LateRecords::new(config.late_records, late_out);
+    let options = args.summary_options(config)?;
+    tracing::info!("Watching {}", dir.display());
+    let mut inputs = vec![];
+    let every = Duration::from_secs(args.summary_every);
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        let path = tokio::select! {
+            _ = &mut ctrl_c => {
+                late.flush()?;
+                return Ok(inputs);
+            }
+            _ = ticks.tick() => {
+                let snapshot = ledger.snapshot().await?;
+                match &args.output {
+                    // Replaced in one step, so whoever reads the summary never sees half of it
+                    Some(path) => {
+                        let mut file = AtomicFile::create(path)?;
+                        snapshot.write_summary(summary::writer_for(args.format, &mut file, &options).as_mut(), &options)?;
+                        file.commit()?;
+                    }
+                    None => snapshot.write_summary(summary::writer_for(args.format, std::io::stdout(), &options).as_mut(), &options)?,
+                }
+                late.reported();
+                if let Some(path) = &args.metrics {
+                    write_metrics(&ledger.metrics().await?, path)?;
+                }
+                continue;
+            }
+            path = folder.next_file() => path?,
+        };
+        let name = path.display().to_string();
+        let span = tracing::info_span!("input", file = %name);
+        let mut input = InputProvenance::new(name.clone());
+        let mut ok = true;
+        match open_input(&name, args.strict_schema, args.csv.format()).await {
+            Ok(source) => {
+                let reader = Reader { keep_raw: rejects.is_some() || args.late_records.is_some(), ..Reader::default() };
+                let mut chunks = reader.spawn(source, Arc::clone(enricher), Arc::new(AtomicU64::new(0)), Arc::new(AtomicBool::new(false)));
+                'file: while let Some(chunk) = chunks.recv().await {
+                    for Parsed { result, line, raw, seq } in chunk {
+                        input.records += 1;
+                        let error = match result {
+                            Ok(tx) => {
+                                if late.admit(&name, line, seq, raw.clone())? {
+                                    let tx_span = span.in_scope(|| logging::tx_span(&tx));
+                                    ledger.apply(tx).instrument(tx_span).await.err().map(|e| e.to_string())
+                                } else {
+                                    // Held for review: neither applied nor rejected
+                                    None
+                                }
+                            }
+                            Err(SourceError::UnknownRecord(record)) => {
+                                *input.unknown_types.entry(record.tx_type.clone()).or_default() += 1;
+                                ledger.handle_unknown(record).instrument(span.clone()).await.err().map(|e| e.to_string())
+                            }
+                            Err(e) => {
+                                ok = false;
+                                input.unreadable += 1;
+                                Some(e.to_string())
+                            }
+                        };
+                        if let Some(error) = error {
+                            tracing::warn!(parent: &span, line, "{}", error);
+                            input.rejected += 1;
+                            write_reject(rejects, &Reject { input: name.clone(), line, record: raw, error });
+                            if args.strict {
+                                ok = false;
+                                break 'file;
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::error!(parent: &span, "Failed to open {}: {}", name, e);
+                write_reject(rejects, &Reject { input: name.clone(), line: None, record: None, error: e.to_string() });
+                ok = false;
+            }
+        }
+        if let Some(rejects) = rejects {
+            rejects.lock().map_err(|_| "rejects writer poisoned")?.flush()?;
+        }
+        late.flush()?;
+        let moved = folder.finish(&path, ok)?;
+        tracing::info!(parent: &span, "{} {} ({} records, {} rejected)", if ok { "Processed" } else { "Failed" }, name, input.records, input.rejected);
+        input.path = moved.display().to_string();
+        if args.manifest.is_some() {
+            let path = input.path.clone();
+            input.checksum = Some(tokio::task::spawn_blocking(move || Checksum::of(&path)).await??);
+        }
+        inputs.push(input);
+    }
+}
+
+// On the blocking pool: opening reads the first bytes to detect compression, which can take a while on a
+// network filesystem
+async fn open_input(path: &str, strict_schema: bool, format: CsvFormat) -> Result<BoxedSource, SourceError> {
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || source::open(&path, strict_schema, format))
+        .await
+        .unwrap_or_else(|e| Err(SourceError::Io(std::io::Error::other(e))))
+}
+
+// Replaced in one step, so a collector reading the file (e.g. node_exporter's textfile one) never sees half of it
+fn write_metrics(metrics: &Metrics, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut file = AtomicFile::create(path)?;
+    metrics.write_prometheus(&mut file)?;
+    file.commit()?;
+    Ok(())
+}
+
+type Rejects = StdMutex<RejectsWriter<BufWriter<File>>>;
+
+fn write_reject(rejects: Option<&Rejects>, reject: &Reject) {
+    if let Some(rejects) = rejects {
+        let written = rejects.lock().map_err(|_| "rejects writer poisoned".into()).and_then(|mut r| r.write(reject));
+        if let Err(e) = written {
+            tracing::error!("Failed to write reject from {}: {}", reject.input, e);
+        }
+    }
+}
+
+// Prefix of the input name of a `--kafka` topic
+const KAFKA_INPUT: &str = "kafka:";
+
+type BoxedSource = Box<dyn source::TransactionSource + Send>;
+
+// The `--kafka` consumer as an input named after its topic, reading until `stop` is set
+#[cfg(feature = "kafka")]
+fn open_kafka(args: &[String], stop: &Arc<AtomicBool>) -> Result<Option<(String, BoxedSource)>, Box<dyn Error>> {
+    if args.is_empty() {
+        return Ok(None);
+    }
+    let options = payments_processor::kafka::KafkaOptions::parse(args)?;
+    let consumer = payments_processor::kafka::KafkaSource::connect(&options, Arc::clone(stop))?;
+    Ok(Some((format!("{}{}", KAFKA_INPUT, options.topic), Box::new(consumer))))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn open_kafka(args: &[String], _stop: &Arc<AtomicBool>) -> Result<Option<(String, BoxedSource)>, Box<dyn Error>> {
+    match args.is_empty() {
+        true => Ok(None),
+        false => Err("--kafka needs a build with the `kafka` feature".into()),
+    }
+}
+
+// One input of `process` as its records are applied
+struct InputRun {
+    index: usize,
+    input: InputProvenance,
+    breaker: Option<CircuitBreaker>,
+    // Shared with the checkpointer
+    position: Arc<AtomicU64>,
+    span: tracing::Span,
+    // Under --strict, where and why this input stopped
+    abort: Option<Reject>,
+}
+
+// Applies the records of `process` inputs, from a task per input or from the one merging them
+#[derive(Clone)]
+struct Applier {
+    ledger: ShardedLedger,
+    checkpointer: Option<Arc<Checkpointer>>,
+    rejects: Option<Arc<Rejects>>,
+    // Set by the first input to fail under --strict, so the others stop too
+    stop: Arc<AtomicBool>,
+    strict: bool,
+}
+
+impl Applier {
+    // Applies one record of `run`'s input; false once that input should not be read any further
+    async fn apply(&self, run: &mut InputRun, parsed: Parsed) -> bool {
+        let span = run.span.clone();
+        self.apply_in_span(run, parsed).instrument(span).await
+    }
+
+    async fn apply_in_span(&self, run: &mut InputRun, Parsed { result, line, raw, .. }: Parsed) -> bool {
+        if self.stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        // Held while the record is applied, so a checkpoint never sees half of one
+        let applying = match &self.checkpointer {
+            Some(c) => Some(c.gate.read().await),
+            None => None,
+        };
+        run.input.records += 1;
+        run.position.fetch_add(1, Ordering::Relaxed);
+        // Identifies a failing record for the circuit breaker
+        let failure = match result {
+            Ok(tx) => {
+                let key = format!("{:?}", tx);
+                let span = logging::tx_span(&tx);
+                match self.ledger.apply(tx).instrument(span.clone()).await {
+                    Ok(()) => None,
+                    Err(e) => {
+                        tracing::warn!(parent: &span, line, "Rejected: {}", e);
+                        Some((key, e.to_string()))
+                    }
+                }
+            }
+            Err(SourceError::UnknownRecord(record)) => {
+                *run.input.unknown_types.entry(record.tx_type.clone()).or_default() += 1;
+                let raw = record.raw.clone();
+                match self.ledger.handle_unknown(record).await {
+                    Ok(()) => None,
+                    Err(e) => {
+                        tracing::warn!(line, "Rejected record: {}", e);
+                        Some((raw, e.to_string()))
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::warn!(line, "Unreadable record: {}", e);
+                run.input.unreadable += 1;
+                Some((e.to_string(), e.to_string()))
+            }
+        };
+        run.input.rejected += failure.is_some() as u64;
+
+        if let Some((_, error)) = &failure {
+            let reject = Reject { input: run.input.path.clone(), line, record: raw, error: error.clone() };
+            write_reject(self.rejects.as_deref(), &reject);
+            if self.strict {
+                run.abort = Some(reject);
+                self.stop.store(true, Ordering::Relaxed);
+                return false;
+            }
+        }
+        let failure = failure.map(|(key, _)| key);
+        if let Some(trip) = run.breaker.as_mut().and_then(|b| b.record(failure)) {
+            tracing::error!("ALERT: circuit breaker stopped reading {}: {}. Fix the input and rerun to resume", run.input.path, trip);
+            run.input.tripped = Some(trip.to_string());
+            return false;
+        }
+        drop(applying);
+        if let Some(checkpointer) = &self.checkpointer {
+            checkpointer.record_done(&self.ledger).await;
+        }
+        true
+    }
+}
+
+// Writes `--checkpoint` every `every` records over all inputs. Inputs hold `gate` for reading while
+// they apply a record, so taking it for writing stops them between records, with `positions`
+// matching the ledger state.
+struct Checkpointer {
+    path: PathBuf,
+    every: u64,
+    records: AtomicU64,
+    gate: RwLock<()>,
+    positions: Vec<(String, Arc<AtomicU64>)>,
+    // Carried over from the resumed checkpoint, with `checksums` (by input, like `positions`) added
+    // for the inputs that got as far as a record
+    applied: AppliedInputs,
+    checksums: Vec<Option<String>>,
+    // Its last sequence number goes into the checkpoint, for `verify`
+    journal: Option<Arc<StdMutex<Journal>>>,
+}
+
+impl Checkpointer {
+    async fn record_done(&self, ledger: &ShardedLedger) {
+        if !(self.records.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(self.every) {
+            return;
+        }
+        let _paused = self.gate.write().await;
+        let offsets = self.positions.iter().map(|(path, n)| (path.clone(), n.load(Ordering::Relaxed))).collect();
+        let journal_seq = self.journal.as_ref().and_then(|journal| journal.lock().ok()).map(|journal| journal.last_seq());
+        let mut inputs = self.applied.clone();
+        for ((path, position), sha256) in self.positions.iter().zip(&self.checksums) {
+            if let Some(sha256) = sha256
+                && position.load(Ordering::Relaxed) > 0
+            {
+                inputs.entry(sha256.clone()).or_insert_with(|| path.clone());
+            }
+        }
+        if let Err(e) = ledger.checkpoint(&self.path, &offsets, journal_seq, &inputs).await {
+            tracing::error!("Failed to write checkpoint {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// The account summary to stdout, or to the given file, which is only replaced once the summary is complete
+fn write_summary(ledgers: &[Ledger], format: OutputFormat, path: Option<&Path>, options: &SummaryOptions) -> Result<(), Box<dyn Error>> {
+    let Some(path) = path else {
+        let mut out = summary::writer_for(format, std::io::stdout(), options);
+        return summary::write_ledgers(ledgers, out.as_mut(), options);
+    };
+    let mut file = AtomicFile::create(path)?;
+    let mut out = summary::writer_for(format, &mut file, options);
+    summary::write_ledgers(ledgers, out.as_mut(), options)?;
+    drop(out);
+    file.commit()?;
+    Ok(())
+}
+
+// One JSON object per difference, to the given file or stderr
+fn write_shadow_report(report: &[ShadowDiff], path: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let mut out: Box<dyn Write> = match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stderr()),
+    };
+    for diff in report {
+        serde_json::to_writer(&mut out, diff)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn open_journal(path: &PathBuf, config: &Config) -> Result<Arc<StdMutex<Journal>>, JournalError> {
+    let (journal, _) = Journal::open(path)?;
+    journal.lock().unwrap().set_retention(config.journal.clone());
+    Ok(journal)
+}
+
+// The ids of transactions the retention drops are only kept in memory and checkpoints, so a store
+// reopened by a later run wouldn't recognize them
+fn check_retention(config: &Config, store: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    match (store, config.retention) {
+        (Some(_), retention) if retention != Retention::All => {
+            Err("--store needs retention = \"all\" in the config, or a later run would apply replayed withdrawals again".into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// In memory, with transactions past `max_tx_memory` spilling to disk
+fn memory_ledger(max_tx_memory: Option<usize>) -> Result<Ledger, StoreError> {
+    match max_tx_memory {
+        Some(max) => Ledger::with_store(Box::new(SpillStore::create(max)?)),
+        None => Ok(Ledger::new()),
+    }
+}
+
+// Locks the files the run writes, exiting with EXIT_LOCKED if another run has them
+fn lock_run(paths: &[&Option<PathBuf>], force: bool) -> Result<RunLock, Box<dyn Error>> {
+    let paths: Vec<_> = paths.iter().copied().flatten().collect();
+    match RunLock::acquire(&paths, force) {
+        Err(e @ LockError::Held { .. }) => {
+            tracing::error!("{}", e);
+            std::process::exit(EXIT_LOCKED);
+        }
+        result => Ok(result?),
+    }
+}
+
+// `store` is the --store database with the size of the cache in front of it
+fn build_ledger(config: &Config, store: Option<(&Path, usize)>, max_tx_memory: Option<usize>) -> Result<Ledger, Box<dyn Error>> {
+    let mut ledger = match store {
+        #[cfg(feature = "sqlite")]
+        Some((path, cache)) => {
+            let store = payments_processor::store::SqliteStore::open(path)?;
+            match std::num::NonZeroUsize::new(cache) {
+                Some(capacity) => Ledger::with_store(Box::new(payments_processor::cache::CachedStore::new(store, capacity)))?,
+                None => Ledger::with_store(Box::new(store))?,
+            }
+        }
+        #[cfg(not(feature = "sqlite"))]
+        Some(_) => return Err("--store needs a build with the `sqlite` feature".into()),
+        None => memory_ledger(max_tx_memory)?,
+    };
+    ledger.set_config(config.ledger_config());
+    for rules in config.rules()? {
+        ledger.add_rules(rules);
+    }
+    Ok(ledger)
+}
+
+async fn run_replay(path: &Path, config: Option<&Path>, format: OutputFormat, output: Option<&Path>, operator: bool) -> Result<(), Box<dyn Error>> {
+    let config = match config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mut ledger = build_ledger(&config, None, None)?;
+    // Admin records only made it into the journal if the original run allowed them
+    ledger.set_admin_ops(true);
+    let report = journal::replay(path, &mut ledger)?;
+    tracing::info!("Replayed {} transactions ({} journaled as rejected)", report.applied, report.skipped_rejected);
+    for (seq, e) in &report.diverged {
+        tracing::warn!("Journal entry {} was accepted originally but rejected on replay: {}", seq, e);
+    }
+    write_summary(std::slice::from_ref(&ledger), format, output, &SummaryOptions { operator, ..SummaryOptions::default() })?;
+    if !report.diverged.is_empty() {
+        return Err(format!("{} journal entries diverged on replay", report.diverged.len()).into());
+    }
+    Ok(())
+}
+
+fn run_inspect(client: u16, checkpoint: Option<&Path>, journal: Option<&Path>, config: Option<&Path>, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    let config = match config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mut ledger = build_ledger(&config, None, None)?;
+    match (checkpoint, journal) {
+        (Some(path), _) => {
+            ledger.restore(path)?;
+        }
+        (None, Some(path)) => {
+            ledger.set_admin_ops(true);
+            let report = journal::replay(path, &mut ledger)?;
+            if !report.diverged.is_empty() {
+                tracing::warn!("{} journal entries diverged on replay", report.diverged.len());
+            }
+        }
+        (None, None) => unreachable!("clap requires --checkpoint or --journal"),
+    }
+    let report = ClientReport::of(&ledger, client)?.ok_or_else(|| format!("No client {}", client))?;
+    report.write(format, std::io::stdout().lock())
+}
+
+// Inputs are applied one after the other to a single ledger, so the sequence numbers follow the input order
+fn run_export_history(inputs: &[String], csv: CsvFormat, config: Option<&Path>, format: OutputFormat, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let config = match config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mut ledger = build_ledger(&config, None, None)?;
+    ledger.set_history(true);
+    let enricher = Enricher::load(&config.reference)?;
+    for input in inputs {
+        let _span = tracing::info_span!("input", file = %input).entered();
+        let mut source = source::open(input, false, csv)?;
+        while let Some(result) = source.next() {
+            match result {
+                Ok(mut tx) => {
+                    enricher.enrich(&mut tx);
+                    ledger.apply(&tx);
+                }
+                Err(SourceError::UnknownRecord(record)) => {
+                    if let Err(e) = ledger.handle_unknown(&record) {
+                        tracing::warn!(line = source.line(), "Rejected record: {}", e);
+                    }
+                }
+                Err(e) => tracing::warn!(line = source.line(), "Unreadable record: {}", e),
+            }
+        }
+    }
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    ledger.export_history(format, out)
+}
+
+fn run_validate(args: &ValidateArgs) -> Result<(), Box<dyn Error>> {
+    let ValidateArgs { inputs, csv, output, idempotent, accounts, .. } = args;
+    let output = output.as_deref();
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    // Only the rules: no store, journal or notifications
+    let mut ledger = build_ledger(&config, None, None)?;
+    ledger.set_idempotent(*idempotent);
+    ledger.set_admin_ops(args.allow_admin_ops);
+    accounts.configure(&mut ledger, &accounts.load()?);
+    let enricher = Enricher::load(&config.reference)?;
+    let mut problems = vec![];
+    let mut records = 0;
+    for input in inputs {
+        match source::open(input, args.strict_schema, csv.format()) {
+            Ok(mut source) => records += validate::validate_source(&mut ledger, &enricher, input, source.as_mut(), &mut problems),
+            Err(e) => problems.push(Reject { input: input.clone(), line: None, record: None, error: e.to_string() }),
+        }
+    }
+
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut report = RejectsWriter::named(out, output);
+    for problem in &problems {
+        report.write(problem)?;
+    }
+    report.flush()?;
+    tracing::info!("Validated {} records in {} input(s): {} problem(s)", records, inputs.len(), problems.len());
+    if !problems.is_empty() {
+        std::process::exit(EXIT_BAD_INPUT);
+    }
+    Ok(())
+}
+
+fn run_diff(format: OutputFormat, old: &Path, new: &Path) -> Result<(), Box<dyn Error>> {
+    let old = diff::load(old)?;
+    let new = diff::load(new)?;
+    diff::write_deltas(&diff::diff(&old, &new), format, std::io::stdout().lock())
+}
+
+fn run_generate(options: &GenerateOptions, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let report = generate::generate(options, out)?;
+    tracing::info!(
+        "Generated {} deposits, {} withdrawals, {} disputes, {} resolves, {} chargebacks and {} malformed rows",
+        report.deposits, report.withdrawals, report.disputes, report.resolves, report.chargebacks, report.malformed
+    );
+    Ok(())
+}
+
+fn run_verify(checkpoint: &Path, journal: Option<&Path>, config: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let config = match config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let report = verify::verify(checkpoint, journal, build_ledger(&config, None, None)?, build_ledger(&config, None, None)?)?;
+    println!("{}: {} clients", checkpoint.display(), report.clients);
+    if let Some(applied) = report.replayed {
+        match report.journal_seq {
+            Some(seq) => println!("journal: {} transactions applied, up to entry {}", applied, seq),
+            None => println!("journal: {} transactions applied, all of it (the checkpoint doesn't say how far it goes)", applied),
+        }
+    }
+    match report.finding {
+        Some(finding) => {
+            println!("FAILED: {}", finding);
+            std::process::exit(EXIT_BAD_INPUT);
+        }
+        None => println!("OK"),
+    }
+    Ok(())
+}
+
+fn run_migrate(path: &Path, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let version = checkpoint::migrate(path, output.unwrap_or(path))?;
+    if version == checkpoint::VERSION {
+        tracing::info!("{} is already at version {}", path.display(), version);
+    } else {
+        tracing::info!("Migrated {} from version {} to {}", path.display(), version, checkpoint::VERSION);
+    }
+    Ok(())
+}
+
+fn run_retention_status(journal: &Path, config: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let config = match config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let status = segments::status(journal, &config.journal, SystemTime::now())?;
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    Ok(())
+}
+
+fn run_schema(path: &Path, csv: CsvFormat) -> Result<(), Box<dyn Error>> {
+    let report = schema::check(source::open_reader(path)?, csv.for_path(path))?;
+    print!("{}", report);
+    if report.anomaly_count > 0 {
+        return Err(format!("{}: {} schema anomalies", path.display(), report.anomaly_count).into());
+    }
     Ok(())
 }
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::cache::CacheStats;
+use crate::ledger::LedgerError;
+use crate::transaction::TxType;
+
+// Upper bounds of the apply latency histogram's buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.1, 1.0];
+
+// Counters a `Ledger` keeps as it applies transactions, in the Prometheus text format through
+// `write_prometheus`. Shards each keep their own, summed with `merge`. The gauges are as of the last
+// `Ledger::metrics`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    // Every transaction that reached the rules, accepted or not, by record type
+    pub processed: BTreeMap<&'static str, u64>,
+    // Rejections by `LedgerError::kind`, including unknown records
+    pub errors: BTreeMap<&'static str, u64>,
+    // Kept by the ledger as disputes open and end, which a resolve or chargeback of part of one doesn't
+    pub disputes_open: u64,
+    pub accounts_locked: u64,
+    // Transaction lookups answered by the cache in front of the store, if there is one
+    pub tx_cache: Option<CacheStats>,
+    // Per bucket of LATENCY_BUCKETS, the last one for anything slower; not cumulative
+    latency_buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    latency_sum: f64,
+    latency_count: u64,
+}
+
+impl Metrics {
+    pub(crate) fn record(&mut self, tx_type: &TxType, result: &Result<(), LedgerError>, elapsed: Duration) {
+        *self.processed.entry(tx_type.name()).or_default() += 1;
+        if let Err(e) = result {
+            self.record_error(e);
+        }
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|le| seconds <= *le).unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket] += 1;
+        self.latency_sum += seconds;
+        self.latency_count += 1;
+    }
+
+    pub(crate) fn record_error(&mut self, error: &LedgerError) {
+        *self.errors.entry(error.kind()).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: &Metrics) {
+        for (name, n) in &other.processed {
+            *self.processed.entry(name).or_default() += n;
+        }
+        for (kind, n) in &other.errors {
+            *self.errors.entry(kind).or_default() += n;
+        }
+        self.disputes_open += other.disputes_open;
+        self.accounts_locked += other.accounts_locked;
+        if let Some(other) = other.tx_cache {
+            let cache = self.tx_cache.get_or_insert_default();
+            cache.hits += other.hits;
+            cache.misses += other.misses;
+        }
+        for (bucket, n) in self.latency_buckets.iter_mut().zip(other.latency_buckets) {
+            *bucket += n;
+        }
+        self.latency_sum += other.latency_sum;
+        self.latency_count += other.latency_count;
+    }
+
+    pub fn write_prometheus<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "# HELP payments_transactions_total Transactions processed, accepted or rejected, by record type")?;
+        writeln!(out, "# TYPE payments_transactions_total counter")?;
+        for (name, n) in &self.processed {
+            writeln!(out, "payments_transactions_total{{type=\"{}\"}} {}", name, n)?;
+        }
+        writeln!(out, "# HELP payments_errors_total Rejected transactions and records, by error")?;
+        writeln!(out, "# TYPE payments_errors_total counter")?;
+        for (kind, n) in &self.errors {
+            writeln!(out, "payments_errors_total{{kind=\"{}\"}} {}", kind, n)?;
+        }
+        writeln!(out, "# HELP payments_disputes_open Transactions under dispute")?;
+        writeln!(out, "# TYPE payments_disputes_open gauge")?;
+        writeln!(out, "payments_disputes_open {}", self.disputes_open)?;
+        writeln!(out, "# HELP payments_accounts_locked Locked client accounts")?;
+        writeln!(out, "# TYPE payments_accounts_locked gauge")?;
+        writeln!(out, "payments_accounts_locked {}", self.accounts_locked)?;
+        if let Some(cache) = self.tx_cache {
+            writeln!(out, "# HELP payments_tx_cache_lookups_total Lookups of stored transactions, by whether the cache in front of the store had them")?;
+            writeln!(out, "# TYPE payments_tx_cache_lookups_total counter")?;
+            writeln!(out, "payments_tx_cache_lookups_total{{result=\"hit\"}} {}", cache.hits)?;
+            writeln!(out, "payments_tx_cache_lookups_total{{result=\"miss\"}} {}", cache.misses)?;
+        }
+        writeln!(out, "# HELP payments_apply_seconds Time to apply a transaction, hooks included")?;
+        writeln!(out, "# TYPE payments_apply_seconds histogram")?;
+        let mut cumulative = 0;
+        for (le, n) in LATENCY_BUCKETS.iter().zip(self.latency_buckets) {
+            cumulative += n;
+            writeln!(out, "payments_apply_seconds_bucket{{le=\"{}\"}} {}", le, cumulative)?;
+        }
+        writeln!(out, "payments_apply_seconds_bucket{{le=\"+Inf\"}} {}", self.latency_count)?;
+        writeln!(out, "payments_apply_seconds_sum {}", self.latency_sum)?;
+        writeln!(out, "payments_apply_seconds_count {}", self.latency_count)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_metrics_count_types_errors_disputes_and_locks() {
+        let mut ledger = Ledger::new();
+        for tx in [
+            TxBuilder::deposit(1, 1, 10.0).build(),
+            TxBuilder::deposit(2, 2, 10.0).build(),
+            TxBuilder::withdrawal(1, 3, 50.0).build(),
+            TxBuilder::dispute(1, 1).build(),
+            TxBuilder::dispute(2, 2).build(),
+            TxBuilder::chargeback(2, 2).build(),
+        ] {
+            let _ = ledger.process_transaction(&tx);
+        }
+        // Simulated transactions leave no trace
+        ledger.simulate(&[TxBuilder::deposit(1, 4, 1.0).build()]).unwrap();
+
+        let metrics = ledger.metrics();
+        assert_eq!(metrics.processed, BTreeMap::from([("chargeback", 1), ("deposit", 2), ("dispute", 2), ("withdrawal", 1)]));
+        assert_eq!(metrics.errors, BTreeMap::from([("not_enough_funds", 1)]));
+        assert_eq!((metrics.disputes_open, metrics.accounts_locked), (1, 1));
+
+        let mut out = Vec::new();
+        metrics.write_prometheus(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("payments_transactions_total{type=\"deposit\"} 2\n"));
+        assert!(out.contains("payments_errors_total{kind=\"not_enough_funds\"} 1\n"));
+        assert!(out.contains("payments_disputes_open 1\n"));
+        assert!(out.contains("payments_accounts_locked 1\n"));
+        assert!(out.contains("payments_apply_seconds_bucket{le=\"+Inf\"} 6\n"));
+        assert!(out.contains("payments_apply_seconds_count 6\n"));
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+
+use crate::client::{Client, Currency};
+use crate::clock::{Clock, SystemClock};
+use crate::hooks::LedgerHook;
+use crate::transaction::{Transaction, TxType};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+// Events waiting for the webhook thread; more than this and new ones are dropped rather than wait
+const WEBHOOK_QUEUE: usize = 1024;
+
+// One `[[notifications]]` entry in the config file, e.g.
+//
+//   [[notifications]]
+//   when = { balance_below = 10.0 }
+//   action = { webhook = "https://alerts.example.com/payments" }
+//   clients = [1, 7]    # optional; every client when omitted
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct NotificationRule {
+    pub when: Condition,
+    pub action: Action,
+    #[serde(default)]
+    pub clients: Option<Vec<u16>>,
+}
+
+impl NotificationRule {
+    fn covers(&self, client: u16) -> bool {
+        self.clients.as_ref().is_none_or(|c| c.contains(&client))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Chargeback,
+    // Fires when a client's available balance drops below the threshold, not again until it recovers
+    BalanceBelow(f64),
+    // Fires once for each dispute that is still open after this many days (wall-clock time)
+    DisputeOpenDays(u64),
+    // Fires once when a client's held funds have stayed above `amount` for `hours` (wall-clock
+    // time), not again until they drop back
+    HeldAbove { amount: f64, hours: u64 },
+    // Account-level changes only, for consumers that don't want every transaction: client_created,
+    // first_deposit, locked and unlocked
+    Lifecycle,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Stdout,
+    File(PathBuf),
+    Webhook(String),
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Event {
+    pub event: &'static str,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub held: Option<f64>,
+    // The currency of `available` and `held`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+}
+
+// Posts webhook events from a thread of its own, so a slow or unreachable endpoint never holds up
+// the ledger task the hook runs on. Dropping it waits for the events already queued to go out.
+struct WebhookSender {
+    queue: Option<SyncSender<(String, String)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WebhookSender {
+    fn spawn() -> Self {
+        let (queue, events) = mpsc::sync_channel::<(String, String)>(WEBHOOK_QUEUE);
+        let thread = std::thread::spawn(move || {
+            let config = ureq::Agent::config_builder().timeout_global(Some(WEBHOOK_TIMEOUT)).build();
+            let agent = ureq::Agent::new_with_config(config);
+            for (url, line) in events {
+                if let Err(e) = agent.post(&url).header("Content-Type", "application/json").send(&line) {
+                    tracing::warn!("Failed to deliver webhook notification: {}: {}", url, e);
+                }
+            }
+        });
+        WebhookSender { queue: Some(queue), thread: Some(thread) }
+    }
+
+    fn send(&self, url: &str, line: String) -> Result<(), String> {
+        let Some(queue) = &self.queue else { return Ok(()) };
+        match queue.try_send((url.to_string(), line)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(format!("{}: {} events already waiting, dropped", url, WEBHOOK_QUEUE)),
+            Err(TrySendError::Disconnected(_)) => Err(format!("{}: webhook thread stopped", url)),
+        }
+    }
+}
+
+impl Drop for WebhookSender {
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// A `LedgerHook` that evaluates the configured rules after each applied transaction.
+// Delivery failures are logged and never block processing.
+pub struct NotificationHook {
+    rules: Vec<NotificationRule>,
+    // Only started when a rule has a webhook action
+    webhooks: Option<WebhookSender>,
+    clock: Box<dyn Clock>,
+    // (rule index, client, currency) balances currently below their threshold
+    below: HashSet<(usize, u16, Currency)>,
+    // open disputes by tx id: (client, opened at)
+    open_disputes: HashMap<u32, (u16, SystemTime)>,
+    // (rule index, tx) pairs already reported as open too long
+    reported_disputes: HashSet<(usize, u32)>,
+    // (rule index, client, currency) -> when held first went above the rule's amount
+    held_since: HashMap<(usize, u16, Currency), SystemTime>,
+    // (rule index, client, currency) balances already reported for the current stretch above the amount
+    reported_held: HashSet<(usize, u16, Currency)>,
+    // Lifecycle state seen so far, to report each transition once
+    known: HashSet<u16>,
+    funded: HashSet<u16>,
+    locked: HashSet<u16>,
+}
+
+impl NotificationHook {
+    pub fn new(rules: Vec<NotificationRule>) -> Self {
+        Self::with_clock(rules, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(rules: Vec<NotificationRule>, clock: Box<dyn Clock>) -> Self {
+        let webhooks = rules.iter().any(|r| matches!(r.action, Action::Webhook(_))).then(WebhookSender::spawn);
+        Self {
+            rules,
+            webhooks,
+            clock,
+            below: HashSet::new(),
+            open_disputes: HashMap::new(),
+            reported_disputes: HashSet::new(),
+            held_since: HashMap::new(),
+            reported_held: HashSet::new(),
+            known: HashSet::new(),
+            funded: HashSet::new(),
+            locked: HashSet::new(),
+        }
+    }
+
+    // Account-level transitions caused by this transaction, in the order they happened
+    fn lifecycle_changes(&mut self, tx: &Transaction, client: Option<&Client>) -> Vec<&'static str> {
+        let Some(client) = client else {
+            return vec![];
+        };
+        let mut changes = vec![];
+        if self.known.insert(client.id) {
+            changes.push("client_created");
+        }
+        if tx.tx_type == TxType::Deposit && self.funded.insert(client.id) {
+            changes.push("first_deposit");
+        }
+        if client.locked && self.locked.insert(client.id) {
+            changes.push("locked");
+        } else if !client.locked && self.locked.remove(&client.id) {
+            changes.push("unlocked");
+        }
+        changes
+    }
+
+    fn events_for(&mut self, tx: &Transaction, client: Option<&Client>) -> Vec<(usize, Event)> {
+        let now = self.clock.now();
+        match tx.tx_type {
+            TxType::Dispute => {
+                self.open_disputes.insert(tx.tx_id, (tx.client_id, now));
+            }
+            TxType::Resolve | TxType::Chargeback => {
+                self.open_disputes.remove(&tx.tx_id);
+            }
+            _ => {}
+        }
+
+        // Balance rules look at the transaction's currency; a record without one counts as the default
+        let currency = tx.currency.unwrap_or_default();
+        let balance_event = |event| Event {
+            event,
+            client: tx.client_id,
+            tx: tx.tx_id,
+            available: client.map(|c| c.balance(currency).available),
+            held: client.map(|c| c.balance(currency).held),
+            currency: client.map(|_| currency),
+        };
+
+        let lifecycle = self.lifecycle_changes(tx, client);
+
+        let mut events = vec![];
+        for (i, rule) in self.rules.iter().enumerate() {
+            match rule.when {
+                Condition::Lifecycle => {
+                    if rule.covers(tx.client_id) {
+                        events.extend(lifecycle.iter().map(|&change| (i, balance_event(change))));
+                    }
+                }
+                Condition::Chargeback => {
+                    if tx.tx_type == TxType::Chargeback && rule.covers(tx.client_id) {
+                        events.push((i, balance_event("chargeback")));
+                    }
+                }
+                Condition::BalanceBelow(threshold) => {
+                    let Some(client) = client.filter(|c| rule.covers(c.id)) else { continue };
+                    if client.balance(currency).available < threshold {
+                        if self.below.insert((i, client.id, currency)) {
+                            events.push((i, balance_event("balance_below")));
+                        }
+                    } else {
+                        self.below.remove(&(i, client.id, currency));
+                    }
+                }
+                Condition::DisputeOpenDays(days) => {
+                    let limit = DAY * days as u32;
+                    for (&tx_id, &(client_id, opened)) in &self.open_disputes {
+                        let overdue = rule.covers(client_id) && now.duration_since(opened).is_ok_and(|age| age > limit);
+                        if overdue && self.reported_disputes.insert((i, tx_id)) {
+                            events.push((i, Event {
+                                event: "dispute_open_too_long",
+                                client: client_id,
+                                tx: tx_id,
+                                available: None,
+                                held: None,
+                                currency: None,
+                            }));
+                        }
+                    }
+                }
+                Condition::HeldAbove { amount, hours } => {
+                    if let Some(client) = client.filter(|c| rule.covers(c.id)) {
+                        let key = (i, client.id, currency);
+                        if client.balance(currency).held > amount {
+                            self.held_since.entry(key).or_insert(now);
+                        } else {
+                            self.held_since.remove(&key);
+                            self.reported_held.remove(&key);
+                        }
+                    }
+                    // Checked for every tracked client, so a stretch is noticed on any later transaction
+                    let limit = HOUR * hours as u32;
+                    for (&(rule_index, client_id, held_currency), &since) in &self.held_since {
+                        let overdue = rule_index == i && now.duration_since(since).is_ok_and(|age| age >= limit);
+                        if overdue && self.reported_held.insert((i, client_id, held_currency)) {
+                            let current = client.filter(|c| c.id == client_id).map(|c| c.balance(held_currency));
+                            events.push((i, Event {
+                                event: "held_above",
+                                client: client_id,
+                                tx: tx.tx_id,
+                                available: current.map(|b| b.available),
+                                held: current.map(|b| b.held),
+                                currency: Some(held_currency),
+                            }));
+                        }
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    fn dispatch(&self, action: &Action, event: &Event) -> Result<(), String> {
+        let line = serde_json::to_string(event).map_err(|e| e.to_string())?;
+        match action {
+            Action::Stdout => {
+                println!("{}", line);
+                Ok(())
+            }
+            Action::File(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut f| writeln!(f, "{}", line))
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            // Delivered (or not) later; only a full queue is reported here
+            Action::Webhook(url) => match &self.webhooks {
+                Some(webhooks) => webhooks.send(url, line),
+                None => Err(format!("{}: no webhook thread", url)),
+            },
+        }
+    }
+}
+
+impl LedgerHook for NotificationHook {
+    fn after_apply(&mut self, tx: &Transaction, client: Option<&Client>) {
+        for (i, event) in self.events_for(tx, client) {
+            if let Err(e) = self.dispatch(&self.rules[i].action, &event) {
+                tracing::warn!("Failed to deliver {} notification: {}", event.event, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+    use crate::test_util::TxBuilder;
+
+    fn client(id: u16, available: f64) -> Client {
+        let mut client = Client::new(id);
+        client.balance_mut(Currency::Usd).available = available;
+        client
+    }
+
+    fn event_names(events: Vec<(usize, Event)>) -> Vec<&'static str> {
+        events.into_iter().map(|(_, e)| e.event).collect()
+    }
+
+    #[test]
+    fn test_balance_below_fires_once_until_recovered() {
+        let mut hook = NotificationHook::new(vec![NotificationRule {
+            when: Condition::BalanceBelow(5.0),
+            action: Action::Stdout,
+            clients: None,
+        }]);
+        let tx = TxBuilder::withdrawal(1, 1, 1.0).build();
+
+        assert_eq!(event_names(hook.events_for(&tx, Some(&client(1, 3.0)))), vec!["balance_below"]);
+        assert!(hook.events_for(&tx, Some(&client(1, 2.0))).is_empty());
+        assert!(hook.events_for(&tx, Some(&client(1, 6.0))).is_empty());
+        assert_eq!(event_names(hook.events_for(&tx, Some(&client(1, 1.0)))), vec!["balance_below"]);
+    }
+
+    #[test]
+    fn test_chargeback_and_overdue_dispute_rules() {
+        let clock = TestClock::new(SystemTime::UNIX_EPOCH);
+        let mut hook = NotificationHook::with_clock(vec![
+            NotificationRule { when: Condition::Chargeback, action: Action::Stdout, clients: None },
+            NotificationRule { when: Condition::DisputeOpenDays(2), action: Action::Stdout, clients: None },
+        ], Box::new(clock.clone()));
+
+        assert!(hook.events_for(&TxBuilder::dispute(1, 10).build(), None).is_empty());
+        assert!(hook.events_for(&TxBuilder::dispute(2, 11).build(), None).is_empty());
+        assert!(hook.events_for(&TxBuilder::resolve(2, 11).build(), None).is_empty());
+
+        clock.advance(DAY * 3);
+        let events = hook.events_for(&TxBuilder::deposit(3, 12, 1.0).build(), None);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].1.event, events[0].1.tx), ("dispute_open_too_long", 10));
+        // Reported only once
+        assert!(hook.events_for(&TxBuilder::deposit(3, 13, 1.0).build(), None).is_empty());
+
+        let events = hook.events_for(&TxBuilder::chargeback(1, 10).build(), Some(&client(1, 0.0)));
+        assert_eq!(event_names(events), vec!["chargeback"]);
+    }
+
+    #[test]
+    fn test_held_above_monitor_is_scoped_and_time_based() {
+        let clock = TestClock::new(SystemTime::UNIX_EPOCH);
+        let mut hook = NotificationHook::with_clock(vec![NotificationRule {
+            when: Condition::HeldAbove { amount: 50.0, hours: 24 },
+            action: Action::Stdout,
+            clients: Some(vec![1]),
+        }], Box::new(clock.clone()));
+        let held = |id, held| {
+            let mut c = Client::new(id);
+            c.balance_mut(Currency::Usd).held = held;
+            c
+        };
+
+        assert!(hook.events_for(&TxBuilder::dispute(1, 1).build(), Some(&held(1, 80.0))).is_empty());
+        assert!(hook.events_for(&TxBuilder::dispute(2, 2).build(), Some(&held(2, 80.0))).is_empty());
+
+        clock.advance(DAY);
+        let events = hook.events_for(&TxBuilder::deposit(3, 3, 1.0).build(), None);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].1.event, events[0].1.client), ("held_above", 1));
+        assert!(hook.events_for(&TxBuilder::deposit(3, 4, 1.0).build(), None).is_empty());
+
+        // Dropping back below the amount starts a new stretch
+        assert!(hook.events_for(&TxBuilder::resolve(1, 1).build(), Some(&held(1, 0.0))).is_empty());
+        assert!(hook.events_for(&TxBuilder::dispute(1, 1).build(), Some(&held(1, 80.0))).is_empty());
+    }
+
+    #[test]
+    fn test_lifecycle_reports_each_transition_once() {
+        let mut hook = NotificationHook::new(vec![NotificationRule { when: Condition::Lifecycle, action: Action::Stdout, clients: None }]);
+        let deposit = TxBuilder::deposit(1, 1, 5.0).build();
+        let mut locked = client(1, 0.0);
+        locked.locked = true;
+
+        assert_eq!(event_names(hook.events_for(&deposit, Some(&client(1, 5.0)))), vec!["client_created", "first_deposit"]);
+        assert!(hook.events_for(&TxBuilder::deposit(1, 2, 5.0).build(), Some(&client(1, 10.0))).is_empty());
+        assert_eq!(event_names(hook.events_for(&TxBuilder::chargeback(1, 1).build(), Some(&locked))), vec!["locked"]);
+        assert!(hook.events_for(&TxBuilder::chargeback(1, 2).build(), Some(&locked)).is_empty());
+        assert_eq!(event_names(hook.events_for(&TxBuilder::resolve(1, 2).build(), Some(&client(1, 0.0)))), vec!["unlocked"]);
+    }
+
+    #[test]
+    fn test_file_action_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("payments_processor_notify_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let hook = NotificationHook::new(vec![]);
+        let event = Event { event: "chargeback", client: 1, tx: 2, available: Some(0.0), held: None, currency: Some(Currency::Eur) };
+
+        hook.dispatch(&Action::File(path.clone()), &event).unwrap();
+        hook.dispatch(&Action::File(path.clone()), &event).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert_eq!(contents.lines().next().unwrap(), r#"{"event":"chargeback","client":1,"tx":2,"available":0.0,"currency":"EUR"}"#);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_webhook_is_posted_off_the_calling_thread() {
+        use std::io::Read;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        let action = Action::Webhook(url);
+        let hook = NotificationHook::new(vec![NotificationRule { when: Condition::Chargeback, action: action.clone(), clients: None }]);
+        let event = Event { event: "chargeback", client: 1, tx: 2, available: None, held: None, currency: None };
+
+        // Returns before the endpoint has read the request, let alone answered it
+        hook.dispatch(&action, &event).unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).contains("\"tx\":2") {
+            let n = conn.read(&mut buf).unwrap();
+            assert!(n > 0, "{}", String::from_utf8_lossy(&request));
+            request.extend_from_slice(&buf[..n]);
+        }
+        assert!(String::from_utf8_lossy(&request).starts_with("POST /alerts"));
+        conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+        drop(hook);
+    }
+
+    #[test]
+    fn test_rules_parse_from_toml() {
+        let config = crate::config::Config::parse(r#"
+            [[notifications]]
+            when = "chargeback"
+            action = "stdout"
+
+            [[notifications]]
+            when = { balance_below = 10.0 }
+            action = { webhook = "http://localhost/alerts" }
+
+            [[notifications]]
+            when = { dispute_open_days = 3 }
+            action = { file = "alerts.jsonl" }
+
+            [[notifications]]
+            when = { held_above = { amount = 100.0, hours = 48 } }
+            action = "stdout"
+            clients = [4, 5]
+
+            [[notifications]]
+            when = "lifecycle"
+            action = { file = "lifecycle.jsonl" }
+        "#).unwrap();
+
+        assert_eq!(config.notifications, vec![
+            NotificationRule { when: Condition::Chargeback, action: Action::Stdout, clients: None },
+            NotificationRule {
+                when: Condition::BalanceBelow(10.0),
+                action: Action::Webhook("http://localhost/alerts".to_string()),
+                clients: None,
+            },
+            NotificationRule { when: Condition::DisputeOpenDays(3), action: Action::File("alerts.jsonl".into()), clients: None },
+            NotificationRule {
+                when: Condition::HeldAbove { amount: 100.0, hours: 48 },
+                action: Action::Stdout,
+                clients: Some(vec![4, 5]),
+            },
+            NotificationRule { when: Condition::Lifecycle, action: Action::File("lifecycle.jsonl".into()), clients: None },
+        ]);
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// A file that replaces `path` only once it is complete: everything goes to `<path>.tmp`, which
+// `commit` syncs and renames over `path`, so readers see the previous file or the new one but never
+// half of it. Dropped without a commit, the temporary file is removed and `path` left as it was.
+pub struct AtomicFile {
+    out: BufWriter<File>,
+    tmp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        Ok(AtomicFile { out: BufWriter::new(File::create(&tmp)?), tmp, path, committed: false })
+    }
+
+    pub fn commit(mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.out.get_ref().sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use crate::summary::SummaryOptions;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_atomic_file_replaces_the_target_only_on_commit() {
+        let path = std::env::temp_dir().join(format!("payments_processor_output_{}.csv", std::process::id()));
+        fs::write(&path, "previous run\n").unwrap();
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(&TxBuilder::deposit(1, 1, 2.5).build()).unwrap();
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        ledger.write_summary(&mut file, &SummaryOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "previous run\n");
+        file.commit().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "client,available,held,total,locked,tier,operator_held,currency\n1,2.5000,0.0000,2.5000,false,basic,0.0000,USD\n"
+        );
+
+        // A run that fails half way leaves the last complete summary in place
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"client,avail").unwrap();
+        drop(file);
+        assert!(fs::read_to_string(&path).unwrap().starts_with("client,available"));
+        assert!(!path.with_extension("csv.tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::enrichment::Enricher;
+use crate::source::{SourceError, TransactionSource};
+use crate::transaction::Transaction;
+
+// Records per message for file inputs, so the channel is paid for once per chunk rather than per record
+pub const CHUNK: usize = 512;
+// Chunks a reader may get ahead of the ledger before it waits, so a fast parser can't fill memory
+const DEPTH: usize = 8;
+
+// A record as the reader parsed it, with where it was in the input for logs and rejects
+#[derive(Debug)]
+pub struct Parsed {
+    pub result: Result<Transaction, SourceError>,
+    pub line: Option<u64>,
+    // Only with `Reader::keep_raw`, since it copies every record
+    pub raw: Option<String>,
+    pub seq: Option<u64>,
+}
+
+// Parses an input on a thread of tokio's blocking pool and sends the transactions, enriched, over a
+// bounded channel to the task applying them in order. File reads (slow ones on a network filesystem
+// included) and parsing thus never hold up the runtime's workers. Each input gets its own reader, so
+// parsing runs on as many cores as there are inputs while the ledger's shards apply what was parsed before.
+#[derive(Clone, Copy, Debug)]
+pub struct Reader {
+    // Records an earlier run already applied, read past without being sent
+    pub skip: u64,
+    // Records per message
+    pub chunk: usize,
+    pub keep_raw: bool,
+}
+
+impl Default for Reader {
+    fn default() -> Self {
+        Reader { skip: 0, chunk: CHUNK, keep_raw: false }
+    }
+}
+
+fn parse_next(source: &mut dyn TransactionSource, enricher: &Enricher, keep_raw: bool) -> Option<Parsed> {
+    let mut result = source.next()?;
+    if let Ok(tx) = &mut result {
+        enricher.enrich(tx);
+    }
+    let raw = if keep_raw { source.raw() } else { None };
+    Some(Parsed { result, line: source.line(), raw, seq: source.seq() })
+}
+
+impl Reader {
+    // The reader stops at the end of the input, once `stop` is set, or once the receiver is dropped.
+    // Skipped records are counted in `position`, the ones sent are left for the receiver to count as it
+    // applies them.
+    pub fn spawn(
+        self,
+        mut source: Box<dyn TransactionSource + Send>,
+        enricher: Arc<Enricher>,
+        position: Arc<AtomicU64>,
+        stop: Arc<AtomicBool>,
+    ) -> mpsc::Receiver<Vec<Parsed>> {
+        let (sender, receiver) = mpsc::channel(DEPTH);
+        let chunk = self.chunk.max(1);
+        task::spawn_blocking(move || {
+            while position.load(Ordering::Relaxed) < self.skip && source.next().is_some() {
+                position.fetch_add(1, Ordering::Relaxed);
+            }
+            let mut parsed = Vec::with_capacity(chunk);
+            while let Some(next) = parse_next(source.as_mut(), &enricher, self.keep_raw) {
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                parsed.push(next);
+                if parsed.len() == chunk && sender.blocking_send(mem::replace(&mut parsed, Vec::with_capacity(chunk))).is_err() {
+                    return;
+                }
+            }
+            if !parsed.is_empty() {
+                let _ = sender.blocking_send(parsed);
+            }
+        });
+        receiver
+    }
+}
+
+// Where the task applying an input gets its records from
+pub enum Feed {
+    Reader(mpsc::Receiver<Vec<Parsed>>),
+    // One record at a time, read only once the previous one was applied, for live sources that take
+    // reading the next record as having applied the last (the Kafka source stores offsets that way).
+    // The read blocks the worker it runs on, with `block_in_place` letting the runtime move its other
+    // tasks elsewhere.
+    Live { source: Box<dyn TransactionSource + Send>, enricher: Arc<Enricher>, keep_raw: bool },
+}
+
+impl Feed {
+    pub async fn next_chunk(&mut self) -> Option<Vec<Parsed>> {
+        match self {
+            Feed::Reader(chunks) => chunks.recv().await,
+            Feed::Live { source, enricher, keep_raw } => task::block_in_place(|| parse_next(source.as_mut(), enricher, *keep_raw)).map(|parsed| vec![parsed]),
+        }
+    }
+
+    // Stops a reader; a live source is just no longer read
+    fn close(&mut self) {
+        if let Feed::Reader(chunks) = self {
+            chunks.close();
+        }
+    }
+}
+
+// How records of several inputs are interleaved
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    // As they arrive: each input is applied by its own task, so the interleaving changes from run to run
+    #[default]
+    Arrival,
+    // By each record's seq or timestamp, merged over all inputs (`SequencedMerge`), so every run applies
+    // them in the same order
+    Sequenced,
+}
+
+#[derive(Debug)]
+pub struct UnknownOrder(String);
+
+impl fmt::Display for UnknownOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown order '{}', expected arrival or sequenced", self.0)
+    }
+}
+
+impl std::error::Error for UnknownOrder {}
+
+impl FromStr for Order {
+    type Err = UnknownOrder;
+
+    fn from_str(s: &str) -> Result<Order, UnknownOrder> {
+        match s.trim().to_lowercase().as_str() {
+            "arrival" => Ok(Order::Arrival),
+            "sequenced" => Ok(Order::Sequenced),
+            other => Err(UnknownOrder(other.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Order::Arrival => "arrival",
+            Order::Sequenced => "sequenced",
+        })
+    }
+}
+
+struct MergeInput {
+    feed: Feed,
+    buffered: VecDeque<Parsed>,
+    // The input's next record in the merge, whose seq is on the heap
+    head: Option<Parsed>,
+    last: Option<u64>,
+    closed: bool,
+}
+
+impl MergeInput {
+    async fn read(&mut self) -> Option<Parsed> {
+        while !self.closed {
+            if let Some(parsed) = self.buffered.pop_front() {
+                return Some(parsed);
+            }
+            self.buffered = self.feed.next_chunk().await?.into();
+        }
+        None
+    }
+}
+
+// K-way merge of the readers of several inputs into one sequence ordered by seq, ties going to the
+// input given first. Each input must be in seq order itself: a record without a seq, or with a lower
+// one than the record before it, comes out as soon as it is read, with `SourceError::OutOfSequence`
+// instead of its transaction, as do records that couldn't be read at all.
+pub struct SequencedMerge {
+    inputs: Vec<MergeInput>,
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+    // Rejected records waiting to come out, ahead of the heap
+    pending: VecDeque<(usize, Parsed)>,
+    started: bool,
+}
+
+impl SequencedMerge {
+    pub fn new(feeds: Vec<Feed>) -> Self {
+        let inputs = feeds.into_iter().map(|feed| MergeInput { feed, buffered: VecDeque::new(), head: None, last: None, closed: false }).collect();
+        SequencedMerge { inputs, heap: BinaryHeap::new(), pending: VecDeque::new(), started: false }
+    }
+
+    // The next record in the global order, with the index of the input it came from
+    pub async fn next(&mut self) -> Option<(usize, Parsed)> {
+        if !self.started {
+            self.started = true;
+            for index in 0..self.inputs.len() {
+                self.advance(index).await;
+            }
+        }
+        loop {
+            if let Some(next) = self.pending.pop_front() {
+                return Some(next);
+            }
+            let Reverse((_, index)) = self.heap.pop()?;
+            // None when the input was closed in the meantime
+            if let Some(head) = self.inputs[index].head.take() {
+                self.advance(index).await;
+                return Some((index, head));
+            }
+        }
+    }
+
+    // Takes no more records from the input, e.g. once its circuit breaker trips
+    pub fn close(&mut self, index: usize) {
+        let input = &mut self.inputs[index];
+        (input.closed, input.head) = (true, None);
+        input.buffered.clear();
+        input.feed.close();
+        self.pending.retain(|(i, _)| *i != index);
+    }
+
+    // Reads the input's next record in order onto the heap, queueing the ones that can't be ordered
+    async fn advance(&mut self, index: usize) {
+        let input = &mut self.inputs[index];
+        while let Some(parsed) = input.read().await {
+            let readable = matches!(parsed.result, Ok(_) | Err(SourceError::UnknownRecord(_)));
+            match parsed.seq {
+                Some(seq) if readable && input.last.is_none_or(|last| seq >= last) => {
+                    input.last = Some(seq);
+                    input.head = Some(parsed);
+                    self.heap.push(Reverse((seq, index)));
+                    return;
+                }
+                _ if !readable => self.pending.push_back((index, parsed)),
+                seq => {
+                    let result = Err(SourceError::OutOfSequence { seq, previous: input.last });
+                    self.pending.push_back((index, Parsed { result, ..parsed }));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::CsvSource;
+
+    #[tokio::test]
+    async fn test_reader_sends_records_in_order_in_chunks_after_the_skipped_ones() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1\ndeposit,1,2,1\ndeposit,1,3,1\ndeposit,1\ndeposit,1,5,1\ndeposit,1,6,1\n";
+        let position = Arc::new(AtomicU64::new(0));
+        let reader = Reader { skip: 1, chunk: 2, keep_raw: true };
+        let source = Box::new(CsvSource::from_reader(data.as_bytes()));
+        let mut chunks = reader.spawn(source, Arc::new(Enricher::default()), Arc::clone(&position), Arc::new(AtomicBool::new(false)));
+
+        let mut sizes = vec![];
+        let mut records = vec![];
+        while let Some(chunk) = chunks.recv().await {
+            sizes.push(chunk.len());
+            records.extend(chunk.into_iter().map(|p| (p.line, p.raw, p.result.map(|tx| tx.tx_id).ok())));
+        }
+        assert_eq!(sizes, vec![2, 2, 1]);
+        assert_eq!(position.load(Ordering::Relaxed), 1);
+        assert_eq!(records[0], (Some(3), Some("deposit,1,2,1".to_string()), Some(2)));
+        assert_eq!(records[2], (Some(5), Some("deposit,1".to_string()), None));
+        assert_eq!(records.iter().map(|r| r.0).collect::<Vec<_>>(), vec![Some(3), Some(4), Some(5), Some(6), Some(7)]);
+    }
+
+    #[tokio::test]
+    async fn test_sequenced_merge_orders_records_across_inputs_by_seq() {
+        let inputs = [
+            "type,client,tx,amount,seq\ndispute,1,1,,5\nwithdrawal,1,4,1,2\nresolve,1,1,,9\n",
+            "type,client,tx,amount,seq\ndeposit,1,1,10,1\ndeposit,1,2,1,5\ndeposit,1,3,1,\n",
+        ];
+        let readers = inputs.iter().map(|data| {
+            let source = Box::new(CsvSource::from_reader(data.as_bytes()));
+            let chunks = Reader::default().spawn(source, Arc::new(Enricher::default()), Arc::new(AtomicU64::new(0)), Arc::new(AtomicBool::new(false)));
+            Feed::Reader(chunks)
+        });
+        let mut merge = SequencedMerge::new(readers.collect());
+
+        let mut order = vec![];
+        while let Some((index, parsed)) = merge.next().await {
+            let outcome = match parsed.result {
+                Ok(tx) => tx.tx_id.to_string(),
+                Err(SourceError::OutOfSequence { seq, .. }) => format!("out of sequence {:?}", seq),
+                Err(e) => e.to_string(),
+            };
+            order.push((index, parsed.line.unwrap(), outcome));
+        }
+        // The dispute waits for the deposit; a tie on seq 5 goes to the input given first
+        let expected = [
+            (1, 2, "1"),
+            (0, 2, "1"),
+            (0, 3, "out of sequence Some(2)"),
+            (1, 3, "2"),
+            (1, 4, "out of sequence None"),
+            (0, 4, "1"),
+        ];
+        assert_eq!(order, expected.map(|(i, line, outcome)| (i, line, outcome.to_string())));
+    }
+}
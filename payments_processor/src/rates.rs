@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::client::{self, Client, Currency};
+
+// `[reporting]` in the config: the currency of record for consolidated reporting, and what one unit
+// of each other currency is worth in it, e.g. `currency = "USD"` with `rates = { EUR = 1.08 }`.
+// The rates are fixed for the run; balances are kept in their own currencies either way.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RateTable {
+    pub currency: Currency,
+    #[serde(default, deserialize_with = "positive_rates")]
+    pub rates: BTreeMap<Currency, f64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MissingRate {
+    pub from: Currency,
+    pub to: Currency,
+}
+
+impl fmt::Display for MissingRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No [reporting] rate from {} to {}", self.from, self.to)
+    }
+}
+
+impl std::error::Error for MissingRate {}
+
+// A client's balances in every currency converted to the currency of record and added up
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConsolidatedRow {
+    pub client: u16,
+    #[serde(serialize_with = "client::four_decimals")]
+    pub available: f64,
+    #[serde(serialize_with = "client::four_decimals")]
+    pub held: f64,
+    #[serde(serialize_with = "client::four_decimals")]
+    pub total: f64,
+    pub currency: Currency,
+}
+
+impl RateTable {
+    pub fn rate(&self, from: Currency) -> Result<f64, MissingRate> {
+        match from == self.currency {
+            true => Ok(1.0),
+            false => self.rates.get(&from).copied().ok_or(MissingRate { from, to: self.currency }),
+        }
+    }
+
+    pub fn consolidate(&self, client: &Client) -> Result<ConsolidatedRow, MissingRate> {
+        let mut row = ConsolidatedRow { client: client.id, available: 0.0, held: 0.0, total: 0.0, currency: self.currency };
+        // In currency order, so the sums come out the same every run
+        let mut balances: Vec<_> = client.balances.iter().collect();
+        balances.sort_by_key(|(currency, _)| **currency);
+        for (currency, balance) in balances {
+            let rate = self.rate(*currency)?;
+            row.available += balance.available * rate;
+            row.held += balance.held * rate;
+            row.total += balance.total * rate;
+        }
+        Ok(row)
+    }
+}
+
+fn positive_rates<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<Currency, f64>, D::Error> {
+    let rates = BTreeMap::<Currency, f64>::deserialize(deserializer)?;
+    match rates.iter().find(|(_, rate)| !(rate.is_finite() && **rate > 0.0)) {
+        Some((currency, rate)) => Err(serde::de::Error::custom(format!("rate for {} must be a positive number, not {}", currency, rate))),
+        None => Ok(rates),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_balances_are_converted_to_the_currency_of_record() {
+        let config = Config::parse("[reporting]\ncurrency = \"USD\"\nrates = { EUR = 1.5, GBP = 2.0 }\n").unwrap();
+        let rates = config.reporting.unwrap();
+        let mut client = Client::new(7);
+        client.balance_mut(Currency::Usd).available = 1.0;
+        let eur = client.balance_mut(Currency::Eur);
+        (eur.available, eur.held, eur.total) = (2.0, 4.0, 6.0);
+        assert_eq!(rates.consolidate(&client).unwrap(), ConsolidatedRow { client: 7, available: 4.0, held: 6.0, total: 9.0, currency: Currency::Usd });
+
+        let rates = RateTable { currency: Currency::Eur, rates: BTreeMap::new() };
+        assert_eq!(rates.consolidate(&client), Err(MissingRate { from: Currency::Usd, to: Currency::Eur }));
+        assert!(Config::parse("[reporting]\ncurrency = \"USD\"\nrates = { EUR = 0 }\n").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use serde::Serialize;
+
+// A record that couldn't be read or was rejected by the ledger, kept so it can be inspected and
+// replayed instead of getting lost in stderr
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Reject {
+    pub input: String,
+    pub line: Option<u64>,
+    // The record as it appears in the input; None when it couldn't be read at all
+    pub record: Option<String>,
+    pub error: String,
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} line {}: {}", self.input, line, self.error)?,
+            None => write!(f, "{}: {}", self.input, self.error)?,
+        }
+        match &self.record {
+            Some(record) => write!(f, "\n  {}", record),
+            None => Ok(()),
+        }
+    }
+}
+
+// The quarantine file behind `--rejects`: CSV with an input,line,record,error header, or one JSON
+// object per reject
+pub enum RejectsWriter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(W),
+}
+
+impl RejectsWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        Ok(RejectsWriter::named(BufWriter::new(File::create(path)?), Some(path)))
+    }
+}
+
+impl<W: Write> RejectsWriter<W> {
+    // JSON Lines for .jsonl/.ndjson paths, CSV otherwise (and without a path)
+    pub fn named(out: W, path: Option<&Path>) -> Self {
+        match path.and_then(|p| p.extension()).and_then(|e| e.to_str()) {
+            Some("jsonl") | Some("ndjson") => RejectsWriter::JsonLines(out),
+            _ => RejectsWriter::Csv(Box::new(csv::Writer::from_writer(out))),
+        }
+    }
+
+    pub fn write(&mut self, reject: &Reject) -> Result<(), Box<dyn Error>> {
+        match self {
+            RejectsWriter::Csv(out) => out.serialize(reject)?,
+            RejectsWriter::JsonLines(out) => {
+                serde_json::to_writer(&mut *out, reject)?;
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        match self {
+            RejectsWriter::Csv(out) => out.flush()?,
+            RejectsWriter::JsonLines(out) => out.flush()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_are_written_as_csv_or_json_lines() {
+        let rejects = [
+            Reject { input: "a.csv".to_string(), line: Some(3), record: Some("withdrawal,1,2,5".to_string()), error: "insufficient funds".to_string() },
+            Reject { input: "a.csv".to_string(), line: Some(4), record: None, error: "CSV error".to_string() },
+        ];
+
+        let mut csv = RejectsWriter::Csv(Box::new(csv::Writer::from_writer(vec![])));
+        let mut jsonl = RejectsWriter::JsonLines(vec![]);
+        for reject in &rejects {
+            csv.write(reject).unwrap();
+            jsonl.write(reject).unwrap();
+        }
+        let RejectsWriter::Csv(csv) = csv else { unreachable!() };
+        assert_eq!(
+            String::from_utf8(csv.into_inner().unwrap()).unwrap(),
+            "input,line,record,error\na.csv,3,\"withdrawal,1,2,5\",insufficient funds\na.csv,4,,CSV error\n",
+        );
+        let RejectsWriter::JsonLines(jsonl) = jsonl else { unreachable!() };
+        let first = String::from_utf8(jsonl).unwrap().lines().next().unwrap().to_string();
+        assert_eq!(first, r#"{"input":"a.csv","line":3,"record":"withdrawal,1,2,5","error":"insufficient funds"}"#);
+        assert_eq!(rejects[0].to_string(), "a.csv line 3: insufficient funds\n  withdrawal,1,2,5");
+    }
+}
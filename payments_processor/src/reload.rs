@@ -0,0 +1,124 @@
+// Config hot-reload for `serve`: re-reads the config file the service was started with and swaps its
+// policies, tier limits and business rules into the running shards, keeping every balance. The
+// notifications, reference data, latency budget and circuit breaker are only read at start.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use sha2::{Digest, Sha256};
+
+use crate::config::{Config, ConfigError};
+use crate::handle::HandleError;
+use crate::journal::Journal;
+use crate::ledger::Retention;
+use crate::shard::ShardedLedger;
+
+#[derive(Debug)]
+pub enum ReloadError {
+    Config(ConfigError),
+    // A change the running ledger can't take without a restart
+    Refused(String),
+    Journal(io::Error),
+    Handle(HandleError),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::Config(e) => write!(f, "{}", e),
+            ReloadError::Refused(e) => write!(f, "Config not reloaded: {}", e),
+            ReloadError::Journal(e) => write!(f, "Config reloaded, but recording it in the journal failed: {}", e),
+            ReloadError::Handle(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {}
+
+impl From<ConfigError> for ReloadError {
+    fn from(e: ConfigError) -> Self {
+        ReloadError::Config(e)
+    }
+}
+
+impl From<HandleError> for ReloadError {
+    fn from(e: HandleError) -> Self {
+        ReloadError::Handle(e)
+    }
+}
+
+pub struct Reloader {
+    path: PathBuf,
+    // The retention the ledger started with; the transactions already dropped can't come back
+    retention: Retention,
+    ledger: ShardedLedger,
+    journal: Option<Arc<Mutex<Journal>>>,
+    // One reload at a time, so two can't leave the shards on different configs
+    running: tokio::sync::Mutex<()>,
+}
+
+impl Reloader {
+    pub fn new(path: PathBuf, started_with: &Config, ledger: ShardedLedger, journal: Option<Arc<Mutex<Journal>>>) -> Reloader {
+        Reloader { path, retention: started_with.retention, ledger, journal, running: tokio::sync::Mutex::new(()) }
+    }
+
+    // Applies the file as it is now and returns its sha256. A file that doesn't load, or changes the
+    // retention, leaves the running config alone. A reload is recorded in the journal, if there is one,
+    // as the audit trail of which entries were applied under which config.
+    pub async fn reload(&self) -> Result<String, ReloadError> {
+        let _running = self.running.lock().await;
+        let text = fs::read_to_string(&self.path).map_err(|source| ConfigError::Io { path: self.path.clone(), source })?;
+        let sha256 = format!("{:x}", Sha256::digest(text.as_bytes()));
+        let config = Config::parse(&text).map_err(|source| ConfigError::Parse { path: self.path.clone(), source })?;
+        if config.retention != self.retention {
+            return Err(ReloadError::Refused("retention can't change while serving; restart to change it".to_string()));
+        }
+        let rules = (0..self.ledger.shard_count()).map(|_| config.rules()).collect::<Result<Vec<_>, _>>()?;
+        self.ledger.reload(&config.ledger_config(), rules).await?;
+
+        if let Some(journal) = &self.journal {
+            let mut journal = journal.lock().map_err(|_| ReloadError::Journal(io::Error::other("journal poisoned")))?;
+            journal.record_reload(&self.path, &sha256).map_err(ReloadError::Journal)?;
+        }
+        tracing::info!("Reloaded config {} (sha256 {})", self.path.display(), sha256);
+        Ok(sha256)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::{Ledger, LedgerError};
+    use crate::transaction::Transaction;
+
+    #[tokio::test]
+    async fn test_reload_applies_new_limits_to_the_running_ledger() {
+        let dir = std::env::temp_dir();
+        let (path, journal_path) = (dir.join(format!("payments_processor_reload_{}.toml", std::process::id())), dir.join(format!("payments_processor_reload_{}.jsonl", std::process::id())));
+        let _ = fs::remove_file(&journal_path);
+        fs::write(&path, "").unwrap();
+        let (journal, hook) = Journal::open(&journal_path).unwrap();
+        let mut ledger = Ledger::new();
+        ledger.add_hook(Box::new(hook));
+        let ledger = ShardedLedger::spawn(vec![ledger]).unwrap();
+        let reloader = Reloader::new(path.clone(), &Config::default(), ledger.clone(), Some(Arc::clone(&journal)));
+
+        ledger.apply(Transaction::deposit(1, 1, 100.0).unwrap()).await.unwrap();
+        fs::write(&path, "[tiers.basic]\nmax_withdrawal = 10.0\n").unwrap();
+        let sha256 = reloader.reload().await.unwrap();
+        assert!(matches!(
+            ledger.apply(Transaction::withdrawal(1, 2, 50.0).unwrap()).await,
+            Err(HandleError::Ledger(LedgerError::TierLimit { .. }))
+        ));
+        assert_eq!(ledger.shard(1).client(1).await.unwrap().unwrap().balance(Default::default()).available, 100.0);
+        let recorded = fs::read_to_string(&journal_path).unwrap();
+        assert!(recorded.contains(&format!("\"sha256\":\"{}\",\"after\":1", sha256)), "{}", recorded);
+
+        fs::write(&path, "retention = \"deposits\"\n").unwrap();
+        assert!(matches!(reloader.reload().await, Err(ReloadError::Refused(_))));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&journal_path).unwrap();
+    }
+}
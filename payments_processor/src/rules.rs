@@ -0,0 +1,19 @@
+use crate::client::Client;
+use crate::transaction::{Transaction, UnknownRecord};
+
+// Partner-supplied business rules consulted by `Ledger` for every transaction, after hooks have run.
+// `validate_transaction` can veto a transaction; `compute_fee` returns a fee charged on top of a
+// deposit or withdrawal (ignored for dispute/resolve/chargeback).
+pub trait BusinessRules: Send {
+    fn validate_transaction(&mut self, tx: &Transaction, client: Option<&Client>) -> Result<(), String>;
+
+    fn compute_fee(&mut self, _tx: &Transaction, _client: Option<&Client>) -> Result<f64, String> {
+        Ok(0.0)
+    }
+
+    // Offered records of a type this build doesn't know when the unknown-record policy is `plugin`.
+    // Ok(true) takes the record, Ok(false) leaves it to the next rules, Err rejects it.
+    fn handle_unknown(&mut self, _record: &UnknownRecord) -> Result<bool, String> {
+        Ok(false)
+    }
+}
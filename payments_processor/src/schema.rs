@@ -0,0 +1,255 @@
+use std::fmt;
+use std::io::Read;
+use csv::{ReaderBuilder, StringRecord};
+
+use crate::source::CsvFormat;
+
+// The CSV header the parser expects, in order. Fields are trimmed the same way the parser trims them.
+pub const EXPECTED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+// Allowed after the expected columns; only transfers use it
+pub const DESTINATION_COLUMN: &str = "destination";
+// Names of the optional column ordering records across inputs under `--order sequenced`: a sequence
+// number, or an integer timestamp such as Unix epoch milliseconds. Allowed last.
+pub const SEQUENCE_COLUMNS: [&str; 2] = ["seq", "timestamp"];
+
+// Anomalies kept for the report; the rest are only counted
+const MAX_ANOMALIES: usize = 100;
+
+#[derive(Debug, PartialEq)]
+pub enum SchemaError {
+    HeaderMismatch(Vec<String>),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::HeaderMismatch(found) =>
+                write!(f, "Header {} does not match the expected {}", found.join(","), EXPECTED_COLUMNS.join(",")),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+// Used by `--strict-schema`: the header must be exactly the expected columns, in order, optionally
+// followed by the destination column and then a sequence column
+pub fn check_header(header: &StringRecord) -> Result<(), SchemaError> {
+    let mut columns: Vec<&str> = header.iter().map(str::trim).collect();
+    if columns.last().is_some_and(|c| SEQUENCE_COLUMNS.contains(c)) {
+        columns.pop();
+    }
+    if columns == EXPECTED_COLUMNS || columns.iter().copied().eq(EXPECTED_COLUMNS.into_iter().chain([DESTINATION_COLUMN])) {
+        Ok(())
+    } else {
+        Err(SchemaError::HeaderMismatch(header.iter().map(|f| f.trim().to_string()).collect()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnType {
+    Empty,
+    Integer,
+    Decimal,
+    Text,
+}
+
+impl ColumnType {
+    fn of(value: &str) -> ColumnType {
+        if value.is_empty() {
+            ColumnType::Empty
+        } else if value.parse::<i64>().is_ok() {
+            ColumnType::Integer
+        } else if value.parse::<f64>().is_ok() {
+            ColumnType::Decimal
+        } else {
+            ColumnType::Text
+        }
+    }
+
+    // The narrowest type covering both; empty values don't widen a column
+    fn widen(self, other: ColumnType) -> ColumnType {
+        use ColumnType::*;
+        match (self, other) {
+            (a, Empty) => a,
+            (Empty, b) => b,
+            (Text, _) | (_, Text) => Text,
+            (Decimal, _) | (_, Decimal) => Decimal,
+            (Integer, Integer) => Integer,
+        }
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColumnType::Empty => "empty",
+            ColumnType::Integer => "integer",
+            ColumnType::Decimal => "decimal",
+            ColumnType::Text => "text",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ColumnReport {
+    pub name: String,
+    pub column_type: ColumnType,
+    pub empty: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Anomaly {
+    MissingColumn(&'static str),
+    ExtraColumn(String),
+    // The expected columns are all there, but not in the expected order
+    ColumnOrder,
+    FieldCount { line: u64, found: usize },
+    MissingAmount { line: u64, tx_type: String },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::MissingColumn(name) => write!(f, "missing column {}", name),
+            Anomaly::ExtraColumn(name) => write!(f, "extra column {}", name),
+            Anomaly::ColumnOrder => write!(f, "columns are not in the order {}", EXPECTED_COLUMNS.join(",")),
+            Anomaly::FieldCount { line, found } => write!(f, "line {}: {} fields, header has a different count", line, found),
+            Anomaly::MissingAmount { line, tx_type } => write!(f, "line {}: {} without an amount", line, tx_type),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SchemaReport {
+    pub rows: u64,
+    pub columns: Vec<ColumnReport>,
+    // The first anomalies found; `anomaly_count` has the total
+    pub anomalies: Vec<Anomaly>,
+    pub anomaly_count: u64,
+}
+
+impl SchemaReport {
+    fn push(&mut self, anomaly: Anomaly) {
+        self.anomaly_count += 1;
+        if self.anomalies.len() < MAX_ANOMALIES {
+            self.anomalies.push(anomaly);
+        }
+    }
+}
+
+impl fmt::Display for SchemaReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rows: {}", self.rows)?;
+        writeln!(f, "columns:")?;
+        for column in &self.columns {
+            writeln!(f, "  {:<10} {:<8} {} empty", column.name, column.column_type, column.empty)?;
+        }
+        writeln!(f, "anomalies: {}", self.anomaly_count)?;
+        for anomaly in &self.anomalies {
+            writeln!(f, "  {}", anomaly)?;
+        }
+        if self.anomaly_count > self.anomalies.len() as u64 {
+            writeln!(f, "  ... and {} more", self.anomaly_count - self.anomalies.len() as u64)?;
+        }
+        Ok(())
+    }
+}
+
+// Reads a whole CSV input and reports its columns with their detected types, plus anything the
+// parser would trip over or silently accept: missing/extra columns, rows with a different field
+// count, and deposits or withdrawals without an amount. The input is laid out as `format` says (comma
+// separated unless it names a delimiter), so amounts with its decimal separator count as decimals.
+pub fn check<R: Read>(reader: R, format: CsvFormat) -> Result<SchemaReport, csv::Error> {
+    let mut reader = ReaderBuilder::new().flexible(true).delimiter(format.delimiter.unwrap_or(b',')).from_reader(reader);
+    let header: Vec<String> = reader.headers()?.iter().map(|f| f.trim().to_string()).collect();
+
+    let mut report = SchemaReport {
+        rows: 0,
+        columns: header.iter().map(|name| ColumnReport { name: name.clone(), column_type: ColumnType::Empty, empty: 0 }).collect(),
+        anomalies: vec![],
+        anomaly_count: 0,
+    };
+    for expected in EXPECTED_COLUMNS {
+        if !header.iter().any(|name| name == expected) {
+            report.push(Anomaly::MissingColumn(expected));
+        }
+    }
+    for name in header.iter().filter(|name| !EXPECTED_COLUMNS.contains(&name.as_str()) && name.as_str() != DESTINATION_COLUMN) {
+        report.push(Anomaly::ExtraColumn(name.clone()));
+    }
+    let present: Vec<&str> = header.iter().map(String::as_str).filter(|name| EXPECTED_COLUMNS.contains(name)).collect();
+    if present.len() == EXPECTED_COLUMNS.len() && present != EXPECTED_COLUMNS {
+        report.push(Anomaly::ColumnOrder);
+    }
+
+    let position = |name: &str| header.iter().position(|h| h == name);
+    let (type_at, amount_at) = (position("type"), position("amount"));
+
+    let mut record = StringRecord::new();
+    while reader.read_record(&mut record)? {
+        report.rows += 1;
+        let line = record.position().map_or(0, |p| p.line());
+        if record.len() != header.len() {
+            report.push(Anomaly::FieldCount { line, found: record.len() });
+        }
+        for (i, (column, value)) in report.columns.iter_mut().zip(record.iter().map(str::trim)).enumerate() {
+            let localized = if Some(i) == amount_at { format.decimal_point(value) } else { None };
+            let value_type = ColumnType::of(localized.as_deref().unwrap_or(value));
+            column.empty += (value_type == ColumnType::Empty) as u64;
+            column.column_type = column.column_type.widen(value_type);
+        }
+
+        let tx_type = type_at.and_then(|i| record.get(i)).map(str::trim).unwrap_or_default();
+        let amount = amount_at.and_then(|i| record.get(i)).map(str::trim).unwrap_or_default();
+        if matches!(tx_type, "deposit" | "withdrawal") && amount.is_empty() {
+            report.push(Anomaly::MissingAmount { line, tx_type: tx_type.to_string() });
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_reports_types_and_anomalies() {
+        let data = "type, client, tx, amount, note\ndeposit, 1, 1, 1.5, a\ndeposit, 1, 2, ,\ndispute, 1, 1\nwithdrawal, 2, 3, 2, b\n";
+        let report = check(data.as_bytes(), CsvFormat::default()).unwrap();
+
+        assert_eq!(report.rows, 4);
+        let types: Vec<(&str, ColumnType, u64)> =
+            report.columns.iter().map(|c| (c.name.as_str(), c.column_type, c.empty)).collect();
+        assert_eq!(types, vec![
+            ("type", ColumnType::Text, 0),
+            ("client", ColumnType::Integer, 0),
+            ("tx", ColumnType::Integer, 0),
+            ("amount", ColumnType::Decimal, 1),
+            ("note", ColumnType::Text, 1),
+        ]);
+        assert_eq!(report.anomalies, vec![
+            Anomaly::ExtraColumn("note".to_string()),
+            Anomaly::MissingAmount { line: 3, tx_type: "deposit".to_string() },
+            Anomaly::FieldCount { line: 4, found: 3 },
+        ]);
+    }
+
+    #[test]
+    fn test_check_reads_amounts_with_the_decimal_separator() {
+        let data = "type;client;tx;amount\ndeposit;1;1;12,50\nwithdrawal;1;2;3\n";
+        let report = check(data.as_bytes(), CsvFormat { delimiter: Some(b';'), decimal_separator: ',' }).unwrap();
+        assert_eq!(report.columns[3].column_type, ColumnType::Decimal);
+        assert_eq!(report.anomaly_count, 0);
+    }
+
+    #[test]
+    fn test_strict_header_must_match_exactly() {
+        assert_eq!(check_header(&StringRecord::from(vec!["type", " client", "tx", "amount"])), Ok(()));
+        assert!(check_header(&StringRecord::from(vec!["client", "type", "tx", "amount"])).is_err());
+        assert_eq!(check_header(&StringRecord::from(vec!["type", "client", "tx", "amount", "destination"])), Ok(()));
+        assert_eq!(check_header(&StringRecord::from(vec!["type", "client", "tx", "amount", "seq"])), Ok(()));
+        assert!(check_header(&StringRecord::from(vec!["type", "client", "tx", "seq", "amount"])).is_err());
+        assert!(check_header(&StringRecord::from(vec!["type", "client", "tx"])).is_err());
+    }
+}
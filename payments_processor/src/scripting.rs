@@ -0,0 +1,227 @@
+// Business rules written as Rhai scripts. A script may define either or both of:
+//
+//   fn validate(tx, client)  - return true (or nothing) to accept, false or a reason string to reject
+//   fn fee(tx, client)       - return the fee to charge, as a float or integer
+//   fn unknown(record)       - under the `plugin` unknown-record policy, gets records of types this
+//                              build doesn't know as a map with `type` and `raw`; return true to take
+//                              the record, false (or nothing) to pass, or a reason string to reject it
+//
+// `tx` is a map with `type`, `client`, `tx`, `amount` (() when absent), `currency` (e.g. "EUR"; the
+// default for records without one) and `attributes` (the enriched reference-data fields, e.g.
+// `tx.attributes.country`); `client` is a map with `available`, `held`, `total`, `locked` and
+// `operator_held` in the transaction's currency, or () if the client doesn't exist yet.
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use rhai::{AST, Dynamic, Engine, Map, Scope};
+
+use crate::client::Client;
+use crate::rules::BusinessRules;
+use crate::transaction::{Transaction, UnknownRecord};
+
+const MAX_OPERATIONS: u64 = 100_000;
+
+pub struct ScriptRules {
+    name: String,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    engine: Engine,
+    ast: AST,
+}
+
+impl ScriptRules {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let mut rules = Self::from_source(&path.display().to_string(), &fs::read_to_string(path)?)?;
+        rules.path = Some(path.to_path_buf());
+        rules.modified = fs::metadata(path)?.modified().ok();
+        Ok(rules)
+    }
+
+    pub fn from_source(name: &str, source: &str) -> Result<Self, Box<dyn Error>> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile(source)?;
+        Ok(Self { name: name.to_string(), path: None, modified: None, engine, ast })
+    }
+
+    // Recompiles the script if its file changed on disk. On a compile error the previous
+    // version stays active and the error is returned.
+    pub fn reload_if_changed(&mut self) -> Result<bool, Box<dyn Error>> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let modified = fs::metadata(path)?.modified().ok();
+        if modified == self.modified {
+            return Ok(false);
+        }
+        self.ast = self.engine.compile(fs::read_to_string(path)?)?;
+        self.modified = modified;
+        Ok(true)
+    }
+
+    fn has_fn(&self, name: &str, arity: usize) -> bool {
+        self.ast.iter_functions().any(|f| f.name == name && f.params.len() == arity)
+    }
+
+    fn call(&self, name: &str, tx: &Transaction, client: Option<&Client>) -> Result<Dynamic, String> {
+        let mut scope = Scope::new();
+        self.engine
+            .call_fn::<Dynamic>(&mut scope, &self.ast, name, (tx_to_map(tx), client_to_dynamic(tx, client)))
+            .map_err(|e| format!("script {} failed in {}: {}", self.name, name, e))
+    }
+}
+
+fn tx_to_map(tx: &Transaction) -> Map {
+    let mut map = Map::new();
+    map.insert("type".into(), tx.tx_type.name().into());
+    map.insert("client".into(), (tx.client_id as i64).into());
+    map.insert("tx".into(), (tx.tx_id as i64).into());
+    map.insert("amount".into(), tx.amount.map_or(Dynamic::UNIT, Dynamic::from_float));
+    map.insert("currency".into(), tx.currency.unwrap_or_default().to_string().into());
+    let attributes: Map = tx.attributes.iter().map(|(k, v)| (k.as_str().into(), v.clone().into())).collect();
+    map.insert("attributes".into(), attributes.into());
+    map
+}
+
+fn client_to_dynamic(tx: &Transaction, client: Option<&Client>) -> Dynamic {
+    let Some(client) = client else {
+        return Dynamic::UNIT;
+    };
+    let balance = client.balance(tx.currency.unwrap_or_default());
+    let mut map = Map::new();
+    map.insert("available".into(), balance.available.into());
+    map.insert("held".into(), balance.held.into());
+    map.insert("total".into(), balance.total.into());
+    map.insert("locked".into(), client.locked.into());
+    map.insert("operator_held".into(), balance.operator_held.into());
+    map.into()
+}
+
+impl BusinessRules for ScriptRules {
+    fn validate_transaction(&mut self, tx: &Transaction, client: Option<&Client>) -> Result<(), String> {
+        if !self.has_fn("validate", 2) {
+            return Ok(());
+        }
+        let verdict = self.call("validate", tx, client)?;
+        if verdict.is_unit() {
+            return Ok(());
+        }
+        if let Ok(accepted) = verdict.as_bool() {
+            return if accepted { Ok(()) } else { Err(format!("script {} rejected", self.name)) };
+        }
+        match verdict.into_string() {
+            Ok(reason) => Err(reason),
+            Err(other) => Err(format!("script {} validate returned {}", self.name, other)),
+        }
+    }
+
+    fn compute_fee(&mut self, tx: &Transaction, client: Option<&Client>) -> Result<f64, String> {
+        if !self.has_fn("fee", 2) {
+            return Ok(0.0);
+        }
+        let fee = self.call("fee", tx, client)?;
+        fee.as_float()
+            .or_else(|_| fee.as_int().map(|i| i as f64))
+            .map_err(|other| format!("script {} fee returned {}", self.name, other))
+    }
+
+    fn handle_unknown(&mut self, record: &UnknownRecord) -> Result<bool, String> {
+        if !self.has_fn("unknown", 1) {
+            return Ok(false);
+        }
+        let mut map = Map::new();
+        map.insert("type".into(), record.tx_type.clone().into());
+        map.insert("raw".into(), record.raw.clone().into());
+        let verdict = self.engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, "unknown", (map,))
+            .map_err(|e| format!("script {} failed in unknown: {}", self.name, e))?;
+        if verdict.is_unit() {
+            return Ok(false);
+        }
+        if let Ok(taken) = verdict.as_bool() {
+            return Ok(taken);
+        }
+        match verdict.into_string() {
+            Ok(reason) => Err(reason),
+            Err(other) => Err(format!("script {} unknown returned {}", self.name, other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Currency;
+    use crate::ledger::{Ledger, LedgerError};
+    use crate::test_util::TxBuilder;
+
+    const SCRIPT: &str = r#"
+        fn validate(tx, client) {
+            if tx.type == "withdrawal" && client != () && client.held > 0.0 {
+                return "withdrawals blocked while funds are held";
+            }
+            true
+        }
+
+        fn fee(tx, client) {
+            if tx.type == "withdrawal" { 0.25 } else { 0 }
+        }
+    "#;
+
+    #[test]
+    fn test_script_rules_validate_and_charge_fees() {
+        let mut ledger = Ledger::new();
+        ledger.add_rules(Box::new(ScriptRules::from_source("test", SCRIPT).unwrap()));
+
+        ledger.process_transaction(&TxBuilder::deposit(1, 1, 10.0).build()).unwrap();
+        ledger.process_transaction(&TxBuilder::withdrawal(1, 2, 1.0).build()).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, 8.75);
+
+        ledger.process_transaction(&TxBuilder::deposit(1, 3, 1.0).build()).unwrap();
+        ledger.process_transaction(&TxBuilder::dispute(1, 3).build()).unwrap();
+        let res = ledger.process_transaction(&TxBuilder::withdrawal(1, 4, 1.0).build());
+        assert_eq!(res, Err(LedgerError::RejectedByRule {
+            tx: 4,
+            reason: "withdrawals blocked while funds are held".to_string(),
+        }));
+    }
+
+    #[test]
+    fn test_script_runaway_loop_is_stopped() {
+        let mut rules = ScriptRules::from_source("loop", "fn validate(tx, client) { loop {} }").unwrap();
+        assert!(rules.validate_transaction(&TxBuilder::deposit(1, 1, 1.0).build(), None).is_err());
+    }
+
+    #[test]
+    fn test_script_reloads_when_file_changes() {
+        let path = std::env::temp_dir().join(format!("payments_processor_reload_{}.rhai", std::process::id()));
+        fs::write(&path, "fn fee(tx, client) { 1.0 }").unwrap();
+        let mut rules = ScriptRules::from_file(&path).unwrap();
+        let tx = TxBuilder::deposit(1, 1, 5.0).build();
+        assert_eq!(rules.compute_fee(&tx, None), Ok(1.0));
+
+        // Force a different mtime so the change is picked up even on coarse-grained filesystems
+        rules.modified = Some(SystemTime::UNIX_EPOCH);
+        fs::write(&path, "fn fee(tx, client) { 2 }").unwrap();
+        assert!(rules.reload_if_changed().unwrap());
+        assert_eq!(rules.compute_fee(&tx, None), Ok(2.0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_script_handles_unknown_records() {
+        let mut rules = ScriptRules::from_source("unknown", r#"
+            fn unknown(record) {
+                if record.type == "refund" { return true; }
+                if record.type == "payout" { return "payouts are not supported"; }
+            }
+        "#).unwrap();
+        let record = |tx_type: &str| UnknownRecord { tx_type: tx_type.to_string(), raw: String::new() };
+        assert_eq!(rules.handle_unknown(&record("refund")), Ok(true));
+        assert_eq!(rules.handle_unknown(&record("payout")), Err("payouts are not supported".to_string()));
+        assert_eq!(rules.handle_unknown(&record("other")), Ok(false));
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use serde::Serialize;
+
+use crate::client::Client;
+
+// Query words this long or longer also match names one typo away
+const FUZZY_MIN_LEN: usize = 4;
+
+// How a client matched a search, best first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    // The query is the client id
+    Id,
+    // The query starts the client id, or every query word starts a word of the name
+    Prefix,
+    // Some query word is one edit away from a word of the name
+    Fuzzy,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SearchHit {
+    pub client: u16,
+    pub name: Option<String>,
+    #[serde(rename = "match")]
+    pub matched: MatchKind,
+}
+
+// The lowercased words of the client names, each with the clients whose name has it. `Clients` keeps
+// it in step with the names, so a search goes through the words rather than every client.
+#[derive(Debug, Default)]
+pub struct NameIndex {
+    words: BTreeMap<String, BTreeSet<u16>>,
+}
+
+impl NameIndex {
+    pub fn insert(&mut self, client: u16, name: &str) {
+        for word in words(name) {
+            self.words.entry(word).or_default().insert(client);
+        }
+    }
+
+    pub fn remove(&mut self, client: u16, name: &str) {
+        for word in words(name) {
+            if let Some(clients) = self.words.get_mut(&word) {
+                clients.remove(&client);
+                if clients.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    // The clients whose name has a word matching every word of `query`, with the worst of those matches
+    fn matches(&self, query: &str) -> HashMap<u16, MatchKind> {
+        let mut matched: Option<HashMap<u16, MatchKind>> = None;
+        for word in words(query) {
+            let mut clients: HashMap<u16, MatchKind> = HashMap::new();
+            for (_, ids) in self.words.range(word.clone()..).take_while(|(name, _)| name.starts_with(&word)) {
+                clients.extend(ids.iter().map(|&id| (id, MatchKind::Prefix)));
+            }
+            if word.chars().count() >= FUZZY_MIN_LEN {
+                for (_, ids) in self.words.iter().filter(|(name, _)| within_one_edit(&word, name)) {
+                    for &id in ids {
+                        clients.entry(id).or_insert(MatchKind::Fuzzy);
+                    }
+                }
+            }
+            matched = Some(match matched {
+                None => clients,
+                Some(so_far) => so_far.into_iter()
+                    .filter_map(|(id, kind)| clients.get(&id).map(|&other| (id, kind.max(other))))
+                    .collect(),
+            });
+        }
+        matched.unwrap_or_default()
+    }
+}
+
+// Clients matching `query` by id or name, best matches first and then by id
+pub fn search(clients: &HashMap<u16, Client>, index: &NameIndex, query: &str) -> Vec<SearchHit> {
+    let query = query.trim();
+    let mut matched = index.matches(query);
+    if !query.is_empty() && query.bytes().all(|b| b.is_ascii_digit()) {
+        for id in clients.keys() {
+            let digits = id.to_string();
+            if digits == query {
+                matched.insert(*id, MatchKind::Id);
+            } else if digits.starts_with(query) {
+                matched.entry(*id).or_insert(MatchKind::Prefix);
+            }
+        }
+    }
+    let mut hits: Vec<SearchHit> = matched.into_iter()
+        .filter_map(|(id, matched)| clients.get(&id).map(|client| SearchHit { client: id, name: client.name.clone(), matched }))
+        .collect();
+    hits.sort_by_key(|hit| (hit.matched, hit.client));
+    hits
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).map(str::to_lowercase)
+}
+
+// One character inserted, removed or replaced, or none
+fn within_one_edit(a: &str, b: &str) -> bool {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if long.len() - short.len() > 1 {
+        return false;
+    }
+    let prefix = short.iter().zip(&long).take_while(|(x, y)| x == y).count();
+    if short.len() == long.len() {
+        short[prefix..].iter().skip(1).eq(long[prefix..].iter().skip(1))
+    } else {
+        short[prefix..] == long[prefix + 1..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Clients;
+
+    #[test]
+    fn test_search_matches_ids_prefixes_and_typos() {
+        let mut clients = Clients::new();
+        for (id, name) in [(1, "Acme Ltd"), (12, "Acme Trading"), (120, "Beta Corp"), (3, "Zenith")] {
+            clients.set_name(id, Some(name.to_string()));
+        }
+        let found = |clients: &Clients, query: &str| -> Vec<(u16, MatchKind)> {
+            clients.search(query).iter().map(|hit| (hit.client, hit.matched)).collect()
+        };
+
+        assert_eq!(found(&clients, "12"), [(12, MatchKind::Id), (120, MatchKind::Prefix)]);
+        assert_eq!(found(&clients, "acme"), [(1, MatchKind::Prefix), (12, MatchKind::Prefix)]);
+        assert_eq!(found(&clients, "ACME tr"), [(12, MatchKind::Prefix)]);
+        assert_eq!(found(&clients, "zenth"), [(3, MatchKind::Fuzzy)]);
+        assert!(found(&clients, "").is_empty());
+
+        // Renaming keeps the index in step
+        clients.set_name(3, Some("Omega".to_string()));
+        assert!(found(&clients, "zenith").is_empty());
+        assert_eq!(found(&clients, "ome"), [(3, MatchKind::Prefix)]);
+    }
+}
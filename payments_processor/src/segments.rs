@@ -0,0 +1,211 @@
+// Journal segments: once `[journal] segment_bytes` is set, the journal file rolls over to
+// `<journal>.<last seq>` (zero-padded, so the names sort) when it grows past that size, and a new file
+// is started at the journal's path. Rolled-over segments are compressed and deleted by age or total
+// size in the background, so a long-running `serve` doesn't fill the disk. The journal is read as its
+// segments in order followed by the file at its path.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+const ZSTD_LEVEL: i32 = 3;
+
+// `[journal]` in the config. Without `segment_bytes` the journal is one file that keeps growing, and
+// nothing is compressed or deleted.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct JournalRetention {
+    // Rolls the journal over to a new segment once its file is this big
+    pub segment_bytes: Option<u64>,
+    // zstd-compresses segments once they are rolled over
+    pub compress: bool,
+    // Deletes segments rolled over longer ago than this
+    pub max_age_days: Option<u64>,
+    // Deletes the oldest segments while all of them together take more than this
+    pub max_total_bytes: Option<u64>,
+}
+
+impl JournalRetention {
+    pub fn rotates(&self) -> bool {
+        self.segment_bytes.is_some()
+    }
+}
+
+// A rolled-over segment, holding the entries up to `last_seq`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub path: PathBuf,
+    pub last_seq: u64,
+    pub bytes: u64,
+    pub compressed: bool,
+    // When it was rolled over (its modification time)
+    pub rotated: SystemTime,
+}
+
+impl Segment {
+    pub fn open(&self) -> io::Result<Box<dyn BufRead>> {
+        let file = File::open(&self.path)?;
+        Ok(match self.compressed {
+            true => Box::new(BufReader::new(zstd::stream::read::Decoder::new(file)?)),
+            false => Box::new(BufReader::new(file)),
+        })
+    }
+}
+
+// Where the active file of the journal at `path` goes when it rolls over after entry `last_seq`
+pub fn segment_path(path: &Path, last_seq: u64) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{:020}", last_seq));
+    path.with_file_name(name)
+}
+
+// The rolled-over segments of the journal at `path`, oldest first. A segment found both plain and
+// compressed is one whose compression was interrupted, and the plain file is the complete one.
+pub fn segments(path: &Path) -> io::Result<Vec<Segment>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Some(prefix) = path.file_name().and_then(|name| name.to_str()).map(|name| format!("{}.", name)) else {
+        return Ok(vec![]);
+    };
+    let mut segments: Vec<Segment> = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(rest) = name.to_str().and_then(|name| name.strip_prefix(&prefix)) else { continue };
+        let (seq, compressed) = match rest.strip_suffix(".zst") {
+            Some(seq) => (seq, true),
+            None => (rest, false),
+        };
+        let Some(last_seq) = seq.parse().ok().filter(|_| seq.bytes().all(|b| b.is_ascii_digit())) else { continue };
+        let metadata = entry.metadata()?;
+        segments.push(Segment { path: path.with_file_name(&name), last_seq, bytes: metadata.len(), compressed, rotated: metadata.modified()? });
+    }
+    segments.sort_by_key(|s| (s.last_seq, s.compressed));
+    segments.dedup_by_key(|s| s.last_seq);
+    Ok(segments)
+}
+
+// The segments the retention deletes at `now`: those past the age, then the oldest of the rest while
+// the segments together are over the total size
+pub fn expired<'a>(segments: &'a [Segment], retention: &JournalRetention, now: SystemTime) -> Vec<&'a Segment> {
+    let max_age = retention.max_age_days.map(|days| DAY * days as u32);
+    let mut total: u64 = segments.iter().map(|s| s.bytes).sum();
+    let mut expired = vec![];
+    for segment in segments {
+        let too_old = max_age.is_some_and(|max| now.duration_since(segment.rotated).is_ok_and(|age| age > max));
+        let over_size = retention.max_total_bytes.is_some_and(|max| total > max);
+        if !too_old && !over_size {
+            break;
+        }
+        total -= segment.bytes;
+        expired.push(segment);
+    }
+    expired
+}
+
+// Compresses the segments that aren't yet, if the retention asks for it, and deletes the expired
+// ones; returns the deleted paths. Safe to run again after an interruption.
+pub fn enforce(path: &Path, retention: &JournalRetention, now: SystemTime) -> io::Result<Vec<PathBuf>> {
+    if retention.compress {
+        for segment in segments(path)?.into_iter().filter(|s| !s.compressed) {
+            compress(&segment.path)?;
+        }
+    }
+    let segments = segments(path)?;
+    let mut deleted = vec![];
+    for segment in expired(&segments, retention, now) {
+        fs::remove_file(&segment.path)?;
+        deleted.push(segment.path.clone());
+    }
+    Ok(deleted)
+}
+
+// Writes `<segment>.zst` by way of a temporary file and only then removes the plain segment, so
+// there is always a complete copy
+fn compress(segment: &Path) -> io::Result<()> {
+    let mut name = segment.as_os_str().to_os_string();
+    name.push(".zst");
+    let compressed = PathBuf::from(name);
+    let mut tmp = compressed.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    {
+        let mut out = File::create(&tmp)?;
+        zstd::stream::copy_encode(File::open(segment)?, &mut out, ZSTD_LEVEL)?;
+        out.sync_all()?;
+    }
+    // Keeps the rotation time, which the age limit goes by
+    File::options().write(true).open(&tmp)?.set_modified(fs::metadata(segment)?.modified()?)?;
+    fs::rename(&tmp, &compressed)?;
+    fs::remove_file(segment)
+}
+
+// What `retention status` prints: the journal's files and which segments the retention would delete
+#[derive(Debug, Serialize)]
+pub struct RetentionStatus {
+    pub journal: String,
+    pub active_bytes: u64,
+    pub segments: Vec<SegmentStatus>,
+    pub segment_bytes: u64,
+    pub expired: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentStatus {
+    pub path: String,
+    pub last_seq: u64,
+    pub bytes: u64,
+    pub compressed: bool,
+    pub age_secs: u64,
+    pub expired: bool,
+}
+
+pub fn status(path: &Path, retention: &JournalRetention, now: SystemTime) -> io::Result<RetentionStatus> {
+    let segments = segments(path)?;
+    let expired = expired(&segments, retention, now);
+    let active_bytes = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    Ok(RetentionStatus {
+        journal: path.display().to_string(),
+        active_bytes,
+        segment_bytes: segments.iter().map(|s| s.bytes).sum(),
+        expired: expired.len(),
+        segments: segments.iter().map(|s| SegmentStatus {
+            path: s.path.display().to_string(),
+            last_seq: s.last_seq,
+            bytes: s.bytes,
+            compressed: s.compressed,
+            age_secs: now.duration_since(s.rotated).map_or(0, |age| age.as_secs()),
+            expired: expired.iter().any(|e| e.path == s.path),
+        }).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(last_seq: u64, bytes: u64, age_days: u32, now: SystemTime) -> Segment {
+        Segment { path: PathBuf::from(format!("j.{}", last_seq)), last_seq, bytes, compressed: false, rotated: now - DAY * age_days }
+    }
+
+    #[test]
+    fn test_expired_goes_by_age_then_total_size_oldest_first() {
+        let now = SystemTime::UNIX_EPOCH + DAY * 100;
+        let segments = [segment(10, 50, 40, now), segment(20, 50, 20, now), segment(30, 50, 10, now), segment(40, 50, 1, now)];
+        let last_seqs = |retention: &JournalRetention| -> Vec<u64> { expired(&segments, retention, now).iter().map(|s| s.last_seq).collect() };
+
+        assert!(last_seqs(&JournalRetention::default()).is_empty());
+        assert_eq!(last_seqs(&JournalRetention { max_age_days: Some(30), ..Default::default() }), [10]);
+        assert_eq!(last_seqs(&JournalRetention { max_total_bytes: Some(100), ..Default::default() }), [10, 20]);
+        assert_eq!(last_seqs(&JournalRetention { max_age_days: Some(15), max_total_bytes: Some(150), ..Default::default() }), [10, 20]);
+    }
+}
@@ -0,0 +1,352 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use axum::{Json, Router};
+use axum::extract::{Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::TcpListener;
+
+use crate::enrichment::Enricher;
+use crate::handle::HandleError;
+use crate::reload::{ReloadError, Reloader};
+use crate::shard::ShardedLedger;
+use crate::source::{self, SourceError};
+use crate::summary::{self, OutputFormat, SummaryOptions};
+
+#[derive(Clone)]
+struct AppState {
+    ledger: ShardedLedger,
+    enricher: Arc<Enricher>,
+    // None when serve was started without --config
+    reloader: Option<Arc<Reloader>>,
+}
+
+// The HTTP front end of `serve`:
+//   POST /transactions  one record in the JSON Lines input format, e.g. {"type":"deposit","client":1,"tx":1,"amount":1.5}
+//   POST /validate      a batch of records in that format, one per line, checked against the current balances
+//                       without applying any; answers with a verdict per record
+//   GET  /clients/{id}  that client's balances, one row per currency
+//   GET  /clients/search?q=  the clients whose id or name matches, by prefix or with a typo, best matches
+//                       first; &limit=N (20 by default) caps how many
+//   GET  /summary       all clients as ?format=csv|json|jsonl (json by default), &totals=true adds the totals and
+//                       &operator=true the operator section
+//   GET  /metrics       counters, gauges and the apply latency histogram in the Prometheus text format
+//   POST /admin/reload  re-reads the config file and applies its policies, tier limits and rules from then on
+pub fn router(ledger: ShardedLedger, enricher: Arc<Enricher>, reloader: Option<Arc<Reloader>>) -> Router {
+    Router::new()
+        .route("/transactions", post(apply))
+        .route("/validate", post(validate))
+        .route("/clients/search", get(search))
+        .route("/clients/{id}", get(client))
+        .route("/summary", get(summary))
+        .route("/metrics", get(metrics))
+        .route("/admin/reload", post(reload))
+        .with_state(AppState { ledger, enricher, reloader })
+}
+
+// Serves until `shutdown` resolves, letting requests in flight finish
+pub async fn serve(
+    listener: TcpListener,
+    ledger: ShardedLedger,
+    enricher: Arc<Enricher>,
+    reloader: Option<Arc<Reloader>>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    axum::serve(listener, router(ledger, enricher, reloader)).with_graceful_shutdown(shutdown).await
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+fn handle_error(e: HandleError) -> Response {
+    match e {
+        HandleError::Closed => error(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+        e => error(StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
+    }
+}
+
+// 400 for a body that isn't a valid record, 422 when the ledger rejects it
+async fn apply(State(state): State<AppState>, body: String) -> Response {
+    let result = match source::parse_json_line(&body) {
+        Ok(mut tx) => {
+            state.enricher.enrich(&mut tx);
+            state.ledger.apply(tx).await
+        }
+        Err(SourceError::UnknownRecord(record)) => state.ledger.handle_unknown(record).await,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    match result {
+        Ok(()) => Json(json!({ "status": "applied" })).into_response(),
+        Err(e) => handle_error(e),
+    }
+}
+
+// What applying one record of a `POST /validate` batch now would do
+#[derive(Serialize)]
+struct Verdict {
+    line: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx: Option<u32>,
+    // "accepted", "rejected" by the ledger, "unreadable", or "unknown" for a record type the
+    // unknown-records policy decides about, which isn't dry-run
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Always 200 once the batch is read; the verdicts say what is wrong with it. Later records are
+// checked against the balances the earlier ones would leave.
+async fn validate(State(state): State<AppState>, body: String) -> Response {
+    let mut verdicts = vec![];
+    let mut txs = vec![];
+    // The verdict of each transaction in `txs`
+    let mut parsed = vec![];
+    for (n, line) in body.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let mut verdict = Verdict { line: n as u64 + 1, tx: None, status: "accepted", error: None };
+        match source::parse_json_line(line) {
+            Ok(mut tx) => {
+                state.enricher.enrich(&mut tx);
+                verdict.tx = Some(tx.tx_id);
+                parsed.push(verdicts.len());
+                txs.push(tx);
+            }
+            Err(e @ SourceError::UnknownRecord(_)) => (verdict.status, verdict.error) = ("unknown", Some(e.to_string())),
+            Err(e) => (verdict.status, verdict.error) = ("unreadable", Some(e.to_string())),
+        }
+        verdicts.push(verdict);
+    }
+    let result = match state.ledger.simulate(txs).await {
+        Ok(result) => result,
+        Err(e) => return handle_error(e),
+    };
+    for (index, _, e) in result.rejections {
+        let verdict = &mut verdicts[parsed[index]];
+        (verdict.status, verdict.error) = ("rejected", Some(e.to_string()));
+    }
+    let problems = verdicts.iter().filter(|v| v.status != "accepted").count();
+    Json(json!({ "records": verdicts.len(), "problems": problems, "verdicts": verdicts })).into_response()
+}
+
+async fn client(State(state): State<AppState>, Path(id): Path<u16>) -> Response {
+    match state.ledger.shard(id).client(id).await {
+        Ok(Some(client)) => Json(client.rows()).into_response(),
+        Ok(None) => error(StatusCode::NOT_FOUND, format!("Client {} not found", id)),
+        Err(e) => handle_error(e),
+    }
+}
+
+const SEARCH_LIMIT: usize = 20;
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    limit: Option<usize>,
+}
+
+// 400 without a query
+async fn search(State(state): State<AppState>, Query(query): Query<SearchQuery>) -> Response {
+    if query.q.trim().is_empty() {
+        return error(StatusCode::BAD_REQUEST, "q is required".to_string());
+    }
+    match state.ledger.search(&query.q).await {
+        Ok(mut hits) => {
+            hits.truncate(query.limit.unwrap_or(SEARCH_LIMIT));
+            Json(hits).into_response()
+        }
+        Err(e) => handle_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct SummaryQuery {
+    format: Option<String>,
+    #[serde(default)]
+    totals: bool,
+    #[serde(default)]
+    operator: bool,
+}
+
+// The summary writers own their output, so they write into a buffer shared with the handler
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().map_err(|_| io::Error::other("summary buffer poisoned"))?.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn summary(State(state): State<AppState>, Query(query): Query<SummaryQuery>) -> Response {
+    let format = match query.format.as_deref().unwrap_or("json").parse::<OutputFormat>() {
+        Ok(format) => format,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let snapshot = match state.ledger.snapshot().await {
+        Ok(snapshot) => snapshot,
+        Err(e) => return handle_error(e),
+    };
+    let buffer = Buffer::default();
+    let options = SummaryOptions { totals: query.totals, operator: query.operator, ..SummaryOptions::default() };
+    let mut out = summary::writer_for(format, buffer.clone(), &options);
+    if let Err(e) = snapshot.write_summary(out.as_mut(), &options) {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+    drop(out);
+    let body = std::mem::take(&mut *buffer.0.lock().unwrap_or_else(|e| e.into_inner()));
+    let content_type = match format {
+        OutputFormat::Csv => "text/csv",
+        OutputFormat::Json => "application/json",
+        OutputFormat::Jsonl => "application/x-ndjson",
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => "application/vnd.apache.parquet",
+    };
+    ([(header::CONTENT_TYPE, content_type)], body).into_response()
+}
+
+async fn metrics(State(state): State<AppState>) -> Response {
+    let metrics = match state.ledger.metrics().await {
+        Ok(metrics) => metrics,
+        Err(e) => return handle_error(e),
+    };
+    let mut body = Vec::new();
+    if let Err(e) = metrics.write_prometheus(&mut body) {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+// 409 when there is no config file to reload, 422 when the file doesn't load or can't be applied
+async fn reload(State(state): State<AppState>) -> Response {
+    let Some(reloader) = &state.reloader else {
+        return error(StatusCode::CONFLICT, "serve was started without --config; there is nothing to reload".to_string());
+    };
+    let result = reloader.reload().await;
+    if let Err(e) = &result {
+        tracing::warn!("{}", e);
+    }
+    match result {
+        Ok(sha256) => Json(json!({ "status": "reloaded", "sha256": sha256 })).into_response(),
+        Err(ReloadError::Handle(e)) => handle_error(e),
+        Err(e @ ReloadError::Journal(_)) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        Err(e) => error(StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+
+    #[tokio::test]
+    async fn test_transactions_are_applied_and_balances_served() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let ledger = ShardedLedger::spawn(vec![Ledger::new(), Ledger::new()]).unwrap();
+        let enricher = Arc::new(Enricher::load(&[]).unwrap());
+        tokio::spawn(serve(listener, ledger, enricher, None, std::future::pending()));
+
+        let responses = tokio::task::spawn_blocking(move || {
+            let config = ureq::Agent::config_builder().http_status_as_error(false).build();
+            let agent = ureq::Agent::new_with_config(config);
+            let post = |body: &str| agent.post(format!("{}/transactions", url)).send(body).unwrap().status().as_u16();
+            let get = |path: &str| {
+                let mut response = agent.get(format!("{}{}", url, path)).call().unwrap();
+                (response.status().as_u16(), response.body_mut().read_to_string().unwrap())
+            };
+            vec![
+                (post(r#"{"type":"deposit","client":1,"tx":1,"amount":2.5}"#), String::new()),
+                (post(r#"{"type":"withdrawal","client":1,"tx":2,"amount":5.0}"#), String::new()),
+                (post("not json"), String::new()),
+                get("/clients/1"),
+                get("/clients/2"),
+                get("/summary?format=csv"),
+                get("/metrics"),
+                (agent.post(format!("{}/admin/reload", url)).send("").unwrap().status().as_u16(), String::new()),
+            ]
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(responses.iter().map(|(status, _)| *status).collect::<Vec<_>>(), vec![200, 422, 400, 200, 404, 200, 200, 409]);
+        assert_eq!(responses[3].1, r#"[{"client":1,"available":2.5,"held":0.0,"total":2.5,"locked":false,"tier":"basic","operator_held":0.0,"currency":"USD"}]"#);
+        assert_eq!(responses[5].1, "client,available,held,total,locked,tier,operator_held,currency\n1,2.5000,0.0000,2.5000,false,basic,0.0000,USD\n");
+        assert!(responses[6].1.contains("payments_transactions_total{type=\"withdrawal\"} 1\n"));
+        assert!(responses[6].1.contains("payments_errors_total{kind=\"not_enough_funds\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_clients_are_searched_across_shards_by_name_and_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let mut ledgers = vec![Ledger::new(), Ledger::new()];
+        for (id, name) in [(1, "Acme Ltd"), (2, "Acme Trading"), (12, "Zenith")] {
+            let settings = crate::clients_file::ClientSettings { client: id, name: Some(name.to_string()), ..Default::default() };
+            ledgers[usize::from(id) % 2].configure_client(&settings);
+        }
+        let ledger = ShardedLedger::spawn(ledgers).unwrap();
+        let enricher = Arc::new(Enricher::load(&[]).unwrap());
+        tokio::spawn(serve(listener, ledger, enricher, None, std::future::pending()));
+
+        let responses = tokio::task::spawn_blocking(move || {
+            let config = ureq::Agent::config_builder().http_status_as_error(false).build();
+            let agent = ureq::Agent::new_with_config(config);
+            ["acme", "acme&limit=1", "zenit", "1", ""].map(|q| {
+                let mut response = agent.get(format!("{}/clients/search?q={}", url, q)).call().unwrap();
+                (response.status().as_u16(), response.body_mut().read_to_string().unwrap())
+            })
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(responses.iter().map(|(status, _)| *status).collect::<Vec<_>>(), [200, 200, 200, 200, 400]);
+        assert_eq!(responses[0].1, r#"[{"client":1,"name":"Acme Ltd","match":"prefix"},{"client":2,"name":"Acme Trading","match":"prefix"}]"#);
+        assert_eq!(responses[1].1, r#"[{"client":1,"name":"Acme Ltd","match":"prefix"}]"#);
+        assert_eq!(responses[2].1, r#"[{"client":12,"name":"Zenith","match":"prefix"}]"#);
+        assert_eq!(responses[3].1, r#"[{"client":1,"name":"Acme Ltd","match":"id"},{"client":12,"name":"Zenith","match":"prefix"}]"#);
+    }
+
+    #[tokio::test]
+    async fn test_validate_gives_a_verdict_per_record_and_applies_none() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let ledger = ShardedLedger::spawn(vec![Ledger::new(), Ledger::new()]).unwrap();
+        ledger.apply(crate::Transaction::deposit(2, 1, 5.0).unwrap()).await.unwrap();
+        let enricher = Arc::new(Enricher::load(&[]).unwrap());
+        tokio::spawn(serve(listener, ledger.clone(), enricher, None, std::future::pending()));
+
+        let batch = [
+            r#"{"type":"deposit","client":1,"tx":2,"amount":10.0}"#,
+            r#"{"type":"withdrawal","client":1,"tx":3,"amount":4.0}"#,
+            "",
+            r#"{"type":"withdrawal","client":2,"tx":4,"amount":6.0}"#,
+            r#"{"type":"deposit","client":1}"#,
+            r#"{"type":"dispute","client":1,"tx":2}"#,
+        ]
+        .join("\n");
+        let body = tokio::task::spawn_blocking(move || {
+            let mut response = ureq::post(format!("{}/validate", url)).send(batch).unwrap();
+            response.body_mut().read_to_string().unwrap()
+        })
+        .await
+        .unwrap();
+
+        let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let verdicts: Vec<(u64, &str)> = response["verdicts"].as_array().unwrap().iter()
+            .map(|v| (v["line"].as_u64().unwrap(), v["status"].as_str().unwrap()))
+            .collect();
+        assert_eq!(verdicts, [(1, "accepted"), (2, "accepted"), (4, "rejected"), (5, "unreadable"), (6, "accepted")]);
+        assert_eq!(response["problems"], 2);
+        assert_eq!(ledger.shard(1).client(1).await.unwrap(), None);
+        assert_eq!(ledger.shard(2).client(2).await.unwrap().unwrap().balance(Default::default()).available, 5.0);
+    }
+}
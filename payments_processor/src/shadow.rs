@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+
+use crate::client::{AccountRow, Client, Currency};
+use crate::hooks::LedgerHook;
+use crate::ledger::{Ledger, LedgerError};
+use crate::transaction::{Transaction, TxType};
+
+// Shadow mode: every transaction the primary ledger sees is replayed into a second ledger built
+// from a different policy configuration, and the two outcomes are compared. The primary output
+// is unaffected; the differences are reported at the end of the run.
+pub struct ShadowComparison {
+    shadow: Ledger,
+    rejections: Vec<ShadowDiff>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ShadowDiff {
+    Balance { client: u16, currency: Currency, primary: Option<Balances>, shadow: Option<Balances> },
+    Rejection { tx: u32, client: u16, tx_type: TxType, primary: Option<String>, shadow: Option<String> },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Balances {
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+}
+
+impl From<&AccountRow> for Balances {
+    fn from(row: &AccountRow) -> Self {
+        Balances { available: row.available, held: row.held, total: row.total, locked: row.locked }
+    }
+}
+
+fn balances(ledger: &Ledger) -> BTreeMap<(u16, Currency), Balances> {
+    ledger.clients().flat_map(Client::rows).map(|row| ((row.client, row.currency), Balances::from(&row))).collect()
+}
+
+impl ShadowComparison {
+    // Returns the shared comparison state plus the hook to register on the primary ledger
+    pub fn new(shadow: Ledger) -> (Arc<Mutex<ShadowComparison>>, ShadowHook) {
+        let state = Arc::new(Mutex::new(ShadowComparison { shadow, rejections: vec![] }));
+        let hook = ShadowHook(Arc::clone(&state));
+        (state, hook)
+    }
+
+    fn mirror(&mut self, tx: &Transaction, primary: Result<(), &LedgerError>) {
+        let shadow = self.shadow.process_transaction(tx);
+        if primary.err() != shadow.as_ref().err() {
+            self.rejections.push(ShadowDiff::Rejection {
+                tx: tx.tx_id,
+                client: tx.client_id,
+                tx_type: tx.tx_type.clone(),
+                primary: primary.err().map(|e| e.to_string()),
+                shadow: shadow.err().map(|e| e.to_string()),
+            });
+        }
+    }
+
+    // Rejection differences in arrival order, followed by balance differences by client id and currency
+    pub fn report(&self, primary: &Ledger) -> Vec<ShadowDiff> {
+        let (mut primary, mut shadow) = (balances(primary), balances(&self.shadow));
+        let keys: BTreeSet<(u16, Currency)> = primary.keys().chain(shadow.keys()).copied().collect();
+
+        let balances = keys.into_iter().filter_map(|(client, currency)| {
+            let a = primary.remove(&(client, currency));
+            let b = shadow.remove(&(client, currency));
+            (a != b).then_some(ShadowDiff::Balance { client, currency, primary: a, shadow: b })
+        });
+
+        self.rejections.iter().cloned().chain(balances).collect()
+    }
+}
+
+pub struct ShadowHook(Arc<Mutex<ShadowComparison>>);
+
+impl LedgerHook for ShadowHook {
+    fn after_apply(&mut self, tx: &Transaction, _client: Option<&Client>) {
+        if let Ok(mut state) = self.0.lock() {
+            state.mirror(tx, Ok(()));
+        }
+    }
+
+    fn on_reject(&mut self, tx: &Transaction, error: &LedgerError) {
+        if let Ok(mut state) = self.0.lock() {
+            state.mirror(tx, Err(error));
+        }
+    }
+
+    // The shadow is sharded like its primary, so it gets the destination side the same way
+    fn after_credit(&mut self, tx: &Transaction, _client: Option<&Client>) {
+        if let Ok(mut state) = self.0.lock() {
+            let _ = state.shadow.credit_transfer(tx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::BusinessRules;
+    use crate::test_util::TxBuilder;
+
+    struct MaxWithdrawal(f64);
+
+    impl BusinessRules for MaxWithdrawal {
+        fn validate_transaction(&mut self, tx: &Transaction, _client: Option<&Client>) -> Result<(), String> {
+            match (&tx.tx_type, tx.amount) {
+                (TxType::Withdrawal, Some(amount)) if amount > self.0 => Err("too large".to_string()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_shadow_reports_rejection_and_balance_differences() {
+        let mut stricter = Ledger::new();
+        stricter.add_rules(Box::new(MaxWithdrawal(5.0)));
+        let (state, hook) = ShadowComparison::new(stricter);
+
+        let mut primary = Ledger::new();
+        primary.add_hook(Box::new(hook));
+        for tx in [
+            TxBuilder::deposit(1, 1, 20.0),
+            TxBuilder::withdrawal(1, 2, 10.0),
+            TxBuilder::deposit(2, 3, 1.0),
+            TxBuilder::withdrawal(2, 4, 3.0),
+        ] {
+            let _ = primary.process_transaction(&tx.build());
+        }
+
+        let report = state.lock().unwrap().report(&primary);
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0], ShadowDiff::Rejection {
+            tx: 2,
+            client: 1,
+            tx_type: TxType::Withdrawal,
+            primary: None,
+            shadow: Some("Tx 2 rejected by business rules: too large".to_string()),
+        });
+        // Client 2's withdrawal fails identically in both, so only client 1 differs
+        assert!(matches!(&report[1], ShadowDiff::Balance { client: 1, currency: Currency::Usd, primary: Some(a), shadow: Some(b) }
+            if a.available == 10.0 && b.available == 20.0));
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use crate::checkpoint::{AppliedInputs, CheckpointError, CheckpointWriter, Offsets};
+use crate::handle::{HandleError, LedgerHandle};
+use tokio::sync::oneshot;
+use tracing::Instrument;
+
+use crate::ledger::{Ledger, LedgerConfig, LedgerError, LedgerSnapshot, SimulationResult};
+use crate::metrics::Metrics;
+use crate::rules::BusinessRules;
+use crate::search::SearchHit;
+use crate::store::StoreError;
+use crate::transaction::{Transaction, TxType, UnknownRecord};
+
+// The tx ids held by a set of ledgers, shared between them so a deposit, withdrawal, hold or transfer
+// is caught as a duplicate even when the earlier record with its id went to another shard. Each ledger
+// claims an id as it stores the transaction (or, under a retention that doesn't keep it, retires it)
+// and gives it back when a released hold leaves its store, so the shards together catch the same
+// duplicates as a single ledger would.
+#[derive(Clone, Debug, Default)]
+pub struct TxIds(Arc<Mutex<HashSet<u32>>>);
+
+impl TxIds {
+    pub fn contains(&self, tx_id: u32) -> bool {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).contains(&tx_id)
+    }
+
+    // False if another ledger holds the id already
+    pub(crate) fn claim(&self, tx_id: u32) -> bool {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).insert(tx_id)
+    }
+
+    pub(crate) fn release(&self, tx_id: u32) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).remove(&tx_id);
+    }
+}
+
+// Splits the clients over several ledgers, each owned by its own task (see `LedgerHandle`), so
+// transactions for different clients are applied in parallel instead of queueing on one lock.
+// Transactions are routed by client id, so every client's history lives in a single shard and is
+// applied in the order it was sent. A transfer between clients of different shards takes three steps:
+// the destination's shard makes room for the credit (or refuses it, over the tier's max balance), the
+// source's shard applies the transfer, and the destination's shard then credits it or gives the room
+// back. A snapshot taken in between sees the amount in neither client. With more than one shard the
+// ledgers share a `TxIds`, so tx ids are unique across all of them.
+#[derive(Clone)]
+pub struct ShardedLedger {
+    shards: Vec<LedgerHandle>,
+}
+
+impl ShardedLedger {
+    // One shard per ledger. The ledgers should be configured alike (rules, hooks, limits), since
+    // which one a client lands on is arbitrary. Fails only if a store can't be read for the tx ids the
+    // ledgers already hold, e.g. after restoring a checkpoint.
+    pub fn spawn(mut ledgers: Vec<Ledger>) -> Result<ShardedLedger, StoreError> {
+        assert!(!ledgers.is_empty(), "ShardedLedger needs at least one ledger");
+        let count = ledgers.len();
+        let ids = TxIds::default();
+        for (index, ledger) in ledgers.iter_mut().enumerate() {
+            ledger.set_shard(index, count);
+            if count > 1 {
+                ledger.share_tx_ids(ids.clone())?;
+            }
+        }
+        Ok(ShardedLedger { shards: ledgers.into_iter().map(LedgerHandle::spawn).collect() })
+    }
+
+    pub fn shard(&self, client: u16) -> &LedgerHandle {
+        &self.shards[self.index(client)]
+    }
+
+    fn index(&self, client: u16) -> usize {
+        client as usize % self.shards.len()
+    }
+
+    pub async fn apply(&self, tx: Transaction) -> Result<(), HandleError> {
+        match tx.tx_type {
+            TxType::Transfer(destination) if self.index(destination) != self.index(tx.client_id) => {
+                // On a task of its own, so the steps run to the end even when the caller stops waiting
+                // halfway (e.g. a `serve` client disconnecting) and the debit never goes without its credit
+                let shards = self.clone();
+                tokio::spawn(async move { shards.transfer_between_shards(tx, destination).await }.in_current_span())
+                    .await
+                    .map_err(|_| HandleError::Closed)?
+            }
+            _ => self.shard(tx.client_id).apply(tx).await,
+        }
+    }
+
+    async fn transfer_between_shards(&self, tx: Transaction, destination: u16) -> Result<(), HandleError> {
+        let credit = match self.shard(destination).reserve_credit(tx.clone()).await {
+            Ok(()) => Ok(()),
+            Err(HandleError::Ledger(e)) => Err(e),
+            Err(e) => return Err(e),
+        };
+        let reserved = credit.is_ok();
+        let result = self.shard(tx.client_id).apply_transfer(tx.clone(), credit).await;
+        match (&result, reserved) {
+            (Ok(()), _) => self.shard(destination).credit_transfer(tx).await?,
+            (Err(_), true) => self.shard(destination).cancel_credit(tx).await?,
+            (Err(_), false) => {}
+        }
+        result
+    }
+
+    // Unknown records have no parsed client, so the first shard's policy and rules handle them
+    pub async fn handle_unknown(&self, record: UnknownRecord) -> Result<(), HandleError> {
+        self.shards[0].handle_unknown(record).await
+    }
+
+    // A consistent summary across all shards while ingestion continues. Each shard copies its clients
+    // and then holds its queue until every shard has made its copy, so a caller that waits for each
+    // transaction before sending the next (as main.rs does per input) is seen up to the same point
+    // in all shards. Ingestion is only held for the time the copies take.
+    pub async fn snapshot(&self) -> Result<LedgerSnapshot, HandleError> {
+        let mut resumes = Vec::with_capacity(self.shards.len());
+        let mut pending = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            let (resume, paused) = oneshot::channel();
+            resumes.push(resume);
+            pending.push(shard.snapshot_paused(paused).await?);
+        }
+        let mut snapshots = Vec::with_capacity(pending.len());
+        for response in pending {
+            snapshots.push(response.await.map_err(|_| HandleError::Closed)?);
+        }
+        for resume in resumes {
+            let _ = resume.send(());
+        }
+        Ok(LedgerSnapshot::merge(snapshots))
+    }
+
+    // What-if run of `txs`, each shard simulating the records of its clients in order; see
+    // `Ledger::simulate`. The rejections are by index into `txs`. A transfer to a client of another
+    // shard is only checked on the sending side.
+    pub async fn simulate(&self, txs: Vec<Transaction>) -> Result<SimulationResult, HandleError> {
+        let mut batches: Vec<(Vec<usize>, Vec<Transaction>)> = self.shards.iter().map(|_| (vec![], vec![])).collect();
+        for (index, tx) in txs.into_iter().enumerate() {
+            let (indexes, txs) = &mut batches[self.index(tx.client_id)];
+            indexes.push(index);
+            txs.push(tx);
+        }
+        let mut result = SimulationResult::default();
+        for (shard, (indexes, txs)) in self.shards.iter().zip(batches) {
+            if txs.is_empty() {
+                continue;
+            }
+            let simulated = shard.simulate(txs).await?;
+            result.clients.extend(simulated.clients);
+            result.rejections.extend(simulated.rejections.into_iter().map(|(index, tx, e)| (indexes[index], tx, e)));
+        }
+        result.clients.sort_by_key(|c| c.id);
+        result.rejections.sort_by_key(|(index, ..)| *index);
+        Ok(result)
+    }
+
+    // The matching clients of all shards, best matches first and then by id
+    pub async fn search(&self, query: &str) -> Result<Vec<SearchHit>, HandleError> {
+        let mut hits = vec![];
+        for shard in &self.shards {
+            hits.extend(shard.search(query).await?);
+        }
+        hits.sort_by_key(|hit| (hit.matched, hit.client));
+        Ok(hits)
+    }
+
+    // The metrics of all shards added up, each as of when it got the request
+    pub async fn metrics(&self) -> Result<Metrics, HandleError> {
+        let mut metrics = Metrics::default();
+        for shard in &self.shards {
+            metrics.merge(&shard.metrics().await?);
+        }
+        Ok(metrics)
+    }
+
+    // Writes all shards to one checkpoint. Unlike `snapshot` this doesn't pause the shards together,
+    // so the caller has to hold back new transactions until it returns (main.rs does so per record)
+    // for the offsets to match the state.
+    pub async fn checkpoint<P: AsRef<Path>>(&self, path: P, offsets: &Offsets, journal_seq: Option<u64>, inputs: &AppliedInputs) -> Result<(), CheckpointError> {
+        let mut out = CheckpointWriter::create(path, offsets, journal_seq, inputs)?;
+        for shard in &self.shards {
+            out = shard.write_checkpoint(out).await?;
+        }
+        out.finish()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    // Swaps the config into every shard, with one set of rules per shard in shard order; see
+    // `Ledger::reload`. The shards switch one after another rather than at one cut.
+    pub async fn reload(&self, config: &LedgerConfig, rules: Vec<Vec<Box<dyn BusinessRules>>>) -> Result<(), HandleError> {
+        assert_eq!(rules.len(), self.shards.len(), "one set of rules per shard");
+        for (shard, rules) in self.shards.iter().zip(rules) {
+            shard.reload(config.clone(), rules).await?;
+        }
+        Ok(())
+    }
+
+    // Stops every shard once its queued commands are applied and returns the ledgers in shard order
+    pub async fn shutdown(self) -> Result<Vec<Ledger>, HandleError> {
+        let mut ledgers = Vec::with_capacity(self.shards.len());
+        for shard in self.shards {
+            ledgers.push(shard.shutdown().await?);
+        }
+        Ok(ledgers)
+    }
+
+    // Stops the shards and merges them into one ledger, e.g. for writing the summary
+    pub async fn into_ledger(self) -> Result<Ledger, HandleError> {
+        let mut merged = Ledger::new();
+        for ledger in self.shutdown().await? {
+            merged.merge(ledger).map_err(|e| HandleError::Ledger(LedgerError::Store(e)))?;
+        }
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Currency, Tier, TierLimits};
+    use crate::summary::SummaryOptions;
+    use crate::test_util::TxBuilder;
+
+    #[tokio::test]
+    async fn test_shards_keep_each_client_in_order_and_merge() {
+        let sharded = ShardedLedger::spawn((0..4).map(|_| Ledger::new()).collect()).unwrap();
+        let mut tasks = vec![];
+        for client in 0..8u16 {
+            let sharded = sharded.clone();
+            tasks.push(tokio::spawn(async move {
+                let base = u32::from(client) * 10;
+                sharded.apply(TxBuilder::deposit(client, base, 10.0).build()).await?;
+                sharded.apply(TxBuilder::withdrawal(client, base + 1, 4.0).build()).await?;
+                sharded.apply(TxBuilder::dispute(client, base).build()).await
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        assert_eq!(
+            sharded.apply(TxBuilder::withdrawal(3, 99, 1.0).build()).await,
+            Err(HandleError::Ledger(LedgerError::NotEnoughFunds { client: 3, requested: 1.0, available: -4.0 })),
+        );
+
+        let ledger = sharded.into_ledger().await.unwrap();
+        assert_eq!(ledger.clients().count(), 8);
+        for client in ledger.clients() {
+            let balance = client.balance(Currency::Usd);
+            assert_eq!((balance.available, balance.held, balance.total), (-4.0, 10.0, 6.0));
+        }
+        assert_eq!(ledger.transaction(70).unwrap().unwrap().amount, Some(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_transfers_between_shards_credit_the_destination_once() {
+        let sharded = ShardedLedger::spawn((0..2).map(|_| Ledger::new()).collect()).unwrap();
+        sharded.apply(TxBuilder::deposit(1, 1, 10.0).build()).await.unwrap();
+        sharded.apply(TxBuilder::transfer(1, 2, 2, 4.0).build()).await.unwrap();
+        sharded.apply(TxBuilder::transfer(1, 3, 3, 1.0).build()).await.unwrap();
+        // A rejected transfer credits nobody
+        assert!(matches!(
+            sharded.apply(TxBuilder::transfer(1, 4, 2, 6.0).build()).await,
+            Err(HandleError::Ledger(LedgerError::NotEnoughFunds { .. }))
+        ));
+
+        assert!(sharded.shard(1).client(2).await.unwrap().is_none());
+        let ledger = sharded.into_ledger().await.unwrap();
+        let totals: Vec<(u16, f64)> = ledger.snapshot().clients.iter().map(|c| (c.id, c.balance(Currency::Usd).total)).collect();
+        assert_eq!(totals, vec![(1, 5.0), (2, 4.0), (3, 1.0)]);
+        assert_eq!(ledger.transaction(2).unwrap().unwrap().tx_type, TxType::Transfer(2));
+    }
+
+    #[tokio::test]
+    async fn test_transfers_between_shards_respect_the_destinations_max_balance() {
+        let limited = || {
+            let mut ledger = Ledger::new();
+            ledger.set_tier_limits(Tier::Basic, TierLimits { max_balance: Some(20.0), ..TierLimits::default() });
+            ledger
+        };
+        let sharded = ShardedLedger::spawn(vec![limited(), limited()]).unwrap();
+        sharded.apply(TxBuilder::deposit(1, 1, 20.0).build()).await.unwrap();
+        sharded.apply(TxBuilder::deposit(2, 2, 15.0).build()).await.unwrap();
+
+        assert_eq!(
+            sharded.apply(TxBuilder::transfer(1, 3, 2, 15.0).build()).await,
+            Err(HandleError::Ledger(LedgerError::TierLimit { client: 2, tier: Tier::Basic, limit: "max balance" }))
+        );
+        // The refused transfer gave the room back, so a smaller one still fits
+        sharded.apply(TxBuilder::transfer(1, 4, 2, 5.0).build()).await.unwrap();
+        let ledger = sharded.into_ledger().await.unwrap();
+        let totals: Vec<f64> = ledger.snapshot().clients.iter().map(|c| c.balance(Currency::Usd).total).collect();
+        assert_eq!(totals, [15.0, 20.0]);
+        assert!(ledger.transaction(3).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_tx_ids_are_caught_whatever_the_shard_count() {
+        let input = [
+            TxBuilder::deposit(1, 5, 10.0).build(),
+            TxBuilder::deposit(2, 5, 20.0).build(),
+            TxBuilder::dispute(2, 5).build(),
+            TxBuilder::deposit(3, 7, 4.0).build(),
+            TxBuilder::hold(3, 8, 1.0).build(),
+            TxBuilder::release(3, 8).build(),
+            // The released hold's id is free again, on whichever shard
+            TxBuilder::deposit(4, 8, 2.0).build(),
+            TxBuilder::withdrawal(4, 7, 1.0).build(),
+        ];
+        let mut summaries = vec![];
+        for shards in [1, 2, 3] {
+            let admin = LedgerConfig { admin_ops: true, ..LedgerConfig::default() };
+            let sharded = ShardedLedger::spawn((0..shards).map(|_| Ledger::with_config(admin.clone())).collect()).unwrap();
+            let mut outcomes = vec![];
+            for tx in &input {
+                outcomes.push(sharded.apply(tx.clone()).await.is_ok());
+            }
+            assert_eq!(outcomes, [true, false, false, true, true, true, true, false], "{} shards", shards);
+            let mut summary = vec![];
+            sharded.into_ledger().await.unwrap().write_summary(&mut summary, &SummaryOptions::default()).unwrap();
+            summaries.push(String::from_utf8(summary).unwrap());
+        }
+        assert!(summaries.iter().all(|s| *s == summaries[0]), "{:#?}", summaries);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_while_ingesting_sees_a_prefix_of_each_input() {
+        let sharded = ShardedLedger::spawn((0..3).map(|_| Ledger::new()).collect()).unwrap();
+        // One input depositing 1.0 into clients 0..6 in turn, so any consistent cut has balances
+        // that never increase with client id and differ by at most 1.0
+        let feeder = {
+            let sharded = sharded.clone();
+            tokio::spawn(async move {
+                for tx in 0..600u32 {
+                    sharded.apply(TxBuilder::deposit((tx % 6) as u16, tx, 1.0).build()).await.unwrap();
+                }
+            })
+        };
+        for _ in 0..20 {
+            let snapshot = sharded.snapshot().await.unwrap();
+            let totals: Vec<f64> = snapshot.clients.iter().map(|c| c.balance(Currency::Usd).total).collect();
+            assert!(totals.windows(2).all(|w| w[0] >= w[1] && w[0] - w[1] <= 1.0), "{:?}", totals);
+            tokio::task::yield_now().await;
+        }
+        feeder.await.unwrap();
+
+        let snapshot = sharded.snapshot().await.unwrap();
+        assert_eq!(snapshot.clients.len(), 6);
+        assert!(snapshot.clients.iter().all(|c| c.balance(Currency::Usd).total == 100.0));
+    }
+}
@@ -0,0 +1,238 @@
+// Model-based simulation of the ledger: seeded random transaction sequences are applied to both the
+// real `Ledger` and a small reference model, and balances and dispute statuses must agree after
+// every step, and `Ledger::check_invariants` must hold throughout. The model keeps amounts in integer
+// ten-thousandths so it can't drift.
+
+use std::collections::HashMap;
+
+use crate::client::Currency;
+use crate::generate::Rng;
+use crate::invariants::InvariantViolation;
+use crate::ledger::{DisputeFundsPolicy, Ledger, LedgerConfig};
+use crate::test_util::TxBuilder;
+use crate::transaction::{PaymentStatus, Transaction};
+
+const CLIENTS: u16 = 5;
+const STEPS: usize = 400;
+const SEEDS: u64 = 64;
+
+impl Rng {
+    fn pick<T: Copy>(&mut self, items: &[T]) -> Option<T> {
+        if items.is_empty() { None } else { Some(items[self.below(items.len() as u64) as usize]) }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Posted,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+struct ModelTx {
+    client: u16,
+    amount: i64,
+    deposit: bool,
+    state: State,
+    // What a dispute holds, less than `amount` when capped
+    held: i64,
+}
+
+#[derive(Default)]
+struct ModelClient {
+    available: i64,
+    held: i64,
+    locked: bool,
+}
+
+#[derive(Default)]
+struct Model {
+    clients: HashMap<u16, ModelClient>,
+    txs: HashMap<u32, ModelTx>,
+    dispute_funds: DisputeFundsPolicy,
+}
+
+impl Model {
+    // Returns whether the model accepts the transaction
+    fn apply(&mut self, tx: &Transaction, kind: Op) -> bool {
+        match kind {
+            Op::Deposit | Op::Withdrawal => {
+                let deposit = matches!(kind, Op::Deposit);
+                let amount = to_units(tx.amount.unwrap());
+                let client = self.clients.entry(tx.client_id).or_default();
+                if client.locked || (!deposit && client.available < amount) {
+                    return false;
+                }
+                client.available += if deposit { amount } else { -amount };
+                self.txs.insert(tx.tx_id, ModelTx { client: tx.client_id, amount, deposit, state: State::Posted, held: 0 });
+                true
+            }
+            Op::Dispute | Op::Resolve | Op::Chargeback => {
+                let Some(target) = self.txs.get_mut(&tx.tx_id) else {
+                    return false;
+                };
+                let expected = match kind {
+                    Op::Dispute => State::Posted,
+                    _ => State::Disputed,
+                };
+                if target.client != tx.client_id || !target.deposit || target.state != expected {
+                    return false;
+                }
+                let client = self.clients.get_mut(&tx.client_id).unwrap();
+                match kind {
+                    Op::Dispute => {
+                        target.held = match self.dispute_funds {
+                            DisputeFundsPolicy::Allow => target.amount,
+                            _ if client.available >= target.amount => target.amount,
+                            DisputeFundsPolicy::Reject => return false,
+                            DisputeFundsPolicy::Cap => client.available.max(0),
+                        };
+                        client.available -= target.held;
+                        client.held += target.held;
+                        target.state = State::Disputed;
+                    }
+                    Op::Resolve => {
+                        client.available += target.held;
+                        client.held -= target.held;
+                        target.state = State::Resolved;
+                    }
+                    _ => {
+                        client.held -= target.held;
+                        client.locked = true;
+                        target.state = State::ChargedBack;
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+fn to_units(amount: f64) -> i64 {
+    (amount * 10_000.0).round() as i64
+}
+
+// Draws the next transaction. With `valid_only`, disputes only ever target the client's own
+// deposits in the right state and locked clients are left alone; otherwise any earlier tx id
+// and client can be referenced.
+fn generate(rng: &mut Rng, model: &Model, next_id: &mut u32, valid_only: bool) -> (Transaction, Op) {
+    let unlocked: Vec<u16> = (1..=CLIENTS)
+        .filter(|id| !valid_only || !model.clients.get(id).is_some_and(|c| c.locked))
+        .collect();
+    let client = rng.pick(&unlocked).unwrap_or(1);
+    let referable: Vec<u32> = {
+        let mut ids: Vec<u32> = model.txs.iter()
+            .filter(|(_, t)| !valid_only || (t.client == client && t.deposit && matches!(t.state, State::Posted | State::Disputed)))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    };
+
+    let op = match rng.below(10) {
+        0..=3 => Op::Deposit,
+        4..=5 => Op::Withdrawal,
+        6..=7 => Op::Dispute,
+        8 => Op::Resolve,
+        _ => Op::Chargeback,
+    };
+    let target = |rng: &mut Rng, state: State| {
+        let candidates: Vec<u32> = referable.iter().copied()
+            .filter(|id| !valid_only || model.txs[id].state == state)
+            .collect();
+        rng.pick(&candidates)
+    };
+    let referenced = match op {
+        Op::Dispute => target(rng, State::Posted),
+        Op::Resolve | Op::Chargeback => target(rng, State::Disputed),
+        _ => None,
+    };
+
+    let amount = (rng.below(1_000_000) + 1) as f64 / 10_000.0;
+    let tx = match (op, referenced) {
+        (Op::Dispute, Some(id)) => TxBuilder::dispute(client, id),
+        (Op::Resolve, Some(id)) => TxBuilder::resolve(client, id),
+        (Op::Chargeback, Some(id)) => TxBuilder::chargeback(client, id),
+        (Op::Withdrawal, _) => {
+            *next_id += 1;
+            TxBuilder::withdrawal(client, *next_id, amount)
+        }
+        // Nothing to reference yet, so fall back to a deposit
+        _ => {
+            *next_id += 1;
+            return (TxBuilder::deposit(client, *next_id, amount).build(), Op::Deposit);
+        }
+    };
+    (tx.build(), op)
+}
+
+fn run(seed: u64, valid_only: bool, dispute_funds: DisputeFundsPolicy) {
+    let mut rng = Rng(seed);
+    let mut ledger = Ledger::with_config(LedgerConfig { dispute_funds, ..LedgerConfig::default() });
+    let mut model = Model { dispute_funds, ..Model::default() };
+    let mut next_id = 0;
+
+    for step in 0..STEPS {
+        let (tx, op) = generate(&mut rng, &model, &mut next_id, valid_only);
+        let expected = model.apply(&tx, op);
+        let accepted = ledger.process_transaction(&tx).is_ok();
+        assert_eq!(accepted, expected, "seed {} step {}: {:?} {:?}", seed, step, op, tx);
+        match ledger.check_invariants() {
+            // Disputing a deposit whose funds were withdrawn since takes available below zero, which
+            // `DisputeFundsPolicy::Allow` lets through
+            Ok(()) => {}
+            Err(InvariantViolation::Overdrawn { .. }) if dispute_funds == DisputeFundsPolicy::Allow => {}
+            Err(violation) => panic!("seed {} step {}: {} after {:?}", seed, step, violation, tx),
+        }
+
+        for (id, client) in &model.clients {
+            let real = ledger.client(*id).unwrap();
+            let balance = real.balance(Currency::Usd);
+            let got = (to_units(balance.available), to_units(balance.held), to_units(balance.total), real.locked);
+            let want = (client.available, client.held, client.available + client.held, client.locked);
+            assert_eq!(got, want, "seed {} step {}: client {} after {:?}", seed, step, id, tx);
+        }
+        for (id, t) in &model.txs {
+            let status = ledger.transaction(*id).unwrap().unwrap().status;
+            let expected = match t.state {
+                State::Posted => PaymentStatus::Posted,
+                State::Disputed => PaymentStatus::Disputed,
+                State::Resolved => PaymentStatus::Resolved,
+                State::ChargedBack => PaymentStatus::ChargedBack,
+            };
+            assert_eq!(status, expected, "seed {} step {}: tx {}", seed, step, id);
+        }
+    }
+}
+
+#[test]
+fn test_simulation_valid_sequences_match_model() {
+    for seed in 0..SEEDS {
+        run(seed, true, DisputeFundsPolicy::Allow);
+    }
+}
+
+#[test]
+fn test_simulation_arbitrary_sequences_match_model() {
+    for seed in 0..SEEDS {
+        run(seed, false, DisputeFundsPolicy::Allow);
+    }
+}
+
+#[test]
+fn test_simulation_rejected_and_capped_disputes_match_model() {
+    for seed in 0..SEEDS {
+        run(seed, false, DisputeFundsPolicy::Reject);
+        run(seed, false, DisputeFundsPolicy::Cap);
+    }
+}
@@ -0,0 +1,588 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Stdin};
+use std::path::Path;
+use flate2::read::MultiGzDecoder;
+use csv::{ReaderBuilder, StringRecord, StringRecordsIntoIter, Trim};
+use serde::Deserialize;
+
+use crate::schema::{self, SchemaError};
+use crate::transaction::{self, PaymentStatus, RawTransaction, Transaction, TransactionError, TxType, UnknownRecord};
+
+#[derive(Debug)]
+pub enum SourceError {
+    Io(io::Error),
+    Csv(csv::Error),
+    Json(serde_json::Error),
+    Transaction(TransactionError),
+    UnknownRecord(UnknownRecord),
+    Schema(SchemaError),
+    // Under sequenced order, a record without a sequence number or with a lower one than the record
+    // before it in the same input
+    OutOfSequence { seq: Option<u64>, previous: Option<u64> },
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Io(e) => write!(f, "I/O error: {}", e),
+            SourceError::Csv(e) => write!(f, "CSV error: {}", e),
+            SourceError::Json(e) => write!(f, "JSON error: {}", e),
+            SourceError::Transaction(e) => write!(f, "{}", e),
+            SourceError::UnknownRecord(r) => write!(f, "Unknown transaction type: {}", r.tx_type),
+            SourceError::Schema(e) => write!(f, "{}", e),
+            SourceError::OutOfSequence { seq: None, .. } => write!(f, "Record has no seq or timestamp, which sequenced order needs"),
+            SourceError::OutOfSequence { seq: Some(seq), previous } =>
+                write!(f, "Record seq {} comes after {} in its input, out of order", seq, previous.map_or("a later one".to_string(), |p| p.to_string())),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<TransactionError> for SourceError {
+    fn from(e: TransactionError) -> Self {
+        SourceError::Transaction(e)
+    }
+}
+
+// Unknown types are reported separately from malformed records so the caller can apply its policy
+fn classify(e: TransactionError, raw: impl FnOnce() -> String) -> SourceError {
+    match e {
+        TransactionError::UnknownTxType(tx_type) => SourceError::UnknownRecord(UnknownRecord { tx_type, raw: raw() }),
+        e => SourceError::Transaction(e),
+    }
+}
+
+// Anything that can hand transactions to the ledger one at a time
+pub trait TransactionSource {
+    fn next(&mut self) -> Option<Result<Transaction, SourceError>>;
+
+    // Line of the input the last record came from, for sources that know it
+    fn line(&self) -> Option<u64> {
+        None
+    }
+
+    // The last record as it appears in the input (CSV fields re-joined with commas, or the JSON line),
+    // when it could be read at all
+    fn raw(&self) -> Option<String> {
+        None
+    }
+
+    // The last record's place in the order across inputs, from its `seq` or `timestamp` column (see
+    // `schema::SEQUENCE_COLUMNS`), for sources that have one
+    fn seq(&self) -> Option<u64> {
+        None
+    }
+}
+
+// Rows are mapped by column name when the first row is a header naming a `type` column, and read
+// positionally (type, client, tx, amount, destination, currency) otherwise, so headerless files lose no rows.
+pub struct CsvSource<R: Read> {
+    records: StringRecordsIntoIter<R>,
+    format: CsvFormat,
+    // None until the first row is read; then the header, or an empty record for headerless input
+    headers: Option<StringRecord>,
+    line: u64,
+    // The last row read, empty after a CSV error
+    record: StringRecord,
+    // Index of the header's sequence column, if it has one
+    seq_column: Option<usize>,
+    // Index of the amount column: by header, or the fourth field of headerless rows
+    amount_column: Option<usize>,
+}
+
+// How a CSV input is laid out, for exports that aren't comma-separated with decimal points, e.g.
+// `deposit;1;1;12,50` from European banks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CsvFormat {
+    // None picks by extension in `open`: tabs for .tsv, commas otherwise
+    pub delimiter: Option<u8>,
+    pub decimal_separator: char,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        CsvFormat { delimiter: None, decimal_separator: '.' }
+    }
+}
+
+impl CsvFormat {
+    // The format of the input at `path`: tab-separated for .tsv unless a delimiter was given
+    pub fn for_path(self, path: &Path) -> CsvFormat {
+        let tsv = format_extension(path) == Some("tsv");
+        CsvFormat { delimiter: self.delimiter.or(tsv.then_some(b'\t')), ..self }
+    }
+
+    // A decimal separator that also separates the fields would split every amount written with it in two
+    pub fn separators_clash(&self) -> bool {
+        self.decimal_separator != '.' && self.delimiter.unwrap_or(b',') as char == self.decimal_separator
+    }
+
+    fn reader_builder(&self) -> ReaderBuilder {
+        let mut builder = reader_builder();
+        builder.delimiter(self.delimiter.unwrap_or(b','));
+        builder
+    }
+
+    // The amount with a decimal point instead of the separator, if it is written with one; anything
+    // else (tier names, annulment reasons, malformed amounts) is left for the parser
+    pub(crate) fn decimal_point(&self, amount: &str) -> Option<String> {
+        let separator = self.decimal_separator;
+        let numeric = amount.chars().all(|c| c == separator || c.is_ascii_digit() || c == '-' || c == '+');
+        (separator != '.' && numeric && amount.contains(separator)).then(|| amount.replacen(separator, ".", 1))
+    }
+}
+
+fn reader_builder() -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder.flexible(true).has_headers(false).trim(Trim::All);
+    builder
+}
+
+fn is_header(record: &StringRecord) -> bool {
+    record.iter().any(|field| field.eq_ignore_ascii_case("type"))
+}
+
+// Column names in lowercase, as rows are deserialized by name: `Type,Client,Tx,Amount` is a header too
+fn header(record: &StringRecord) -> StringRecord {
+    record.iter().map(str::to_lowercase).collect()
+}
+
+fn amount_column(header: &StringRecord) -> Option<usize> {
+    header.iter().position(|field| field == "amount")
+}
+
+fn seq_column(header: &StringRecord) -> Option<usize> {
+    header.iter().position(|field| schema::SEQUENCE_COLUMNS.iter().any(|c| field.eq_ignore_ascii_case(c)))
+}
+
+impl<R: Read> CsvSource<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self::with_format(reader, CsvFormat::default())
+    }
+
+    pub fn with_format(reader: R, format: CsvFormat) -> Self {
+        let records = format.reader_builder().from_reader(reader).into_records();
+        Self { records, format, headers: None, line: 0, record: StringRecord::new(), seq_column: None, amount_column: None }
+    }
+
+    // Fails up front unless the header is exactly `schema::EXPECTED_COLUMNS`
+    pub fn with_strict_schema(reader: R, format: CsvFormat) -> Result<Self, SourceError> {
+        let mut records = format.reader_builder().from_reader(reader).into_records();
+        let header = match records.next() {
+            Some(header) => header.map_err(SourceError::Csv)?,
+            None => StringRecord::new(),
+        };
+        schema::check_header(&header).map_err(SourceError::Schema)?;
+        let (seq_column, amount_column) = (seq_column(&header), amount_column(&header));
+        Ok(Self { records, format, headers: Some(header), line: 1, record: StringRecord::new(), seq_column, amount_column })
+    }
+
+    fn parse(&self, record: &StringRecord) -> Result<Transaction, SourceError> {
+        let amount = self.amount_column.and_then(|i| record.get(i)).and_then(|amount| self.format.decimal_point(amount));
+        let localized: StringRecord;
+        let record = match amount {
+            Some(amount) => {
+                let column = self.amount_column;
+                localized = record.iter().enumerate().map(|(i, field)| if Some(i) == column { amount.as_str() } else { field }).collect();
+                &localized
+            }
+            None => record,
+        };
+        let raw = || record.iter().collect::<Vec<_>>().join(",");
+        match &self.headers {
+            Some(headers) if !headers.is_empty() => {
+                let row: RawTransaction = record.deserialize(Some(headers)).map_err(SourceError::Csv)?;
+                Transaction::try_from(row).map_err(|e| classify(e, raw))
+            }
+            _ => Transaction::create_transaction(record).map_err(|e| classify(e, raw)),
+        }
+    }
+}
+
+impl CsvSource<File> {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, SourceError> {
+        Ok(Self::from_reader(File::open(path).map_err(SourceError::Io)?))
+    }
+}
+
+impl CsvSource<Stdin> {
+    pub fn stdin() -> Self {
+        Self::from_reader(io::stdin())
+    }
+}
+
+impl<R: Read> TransactionSource for CsvSource<R> {
+    fn next(&mut self) -> Option<Result<Transaction, SourceError>> {
+        self.record = match self.records.next()? {
+            Ok(record) => record,
+            Err(e) => {
+                self.line = e.position().map_or(self.line + 1, |p| p.line());
+                self.record.clear();
+                return Some(Err(SourceError::Csv(e)));
+            }
+        };
+        self.line = self.record.position().map_or(self.line + 1, |p| p.line());
+        if self.headers.is_none() {
+            if is_header(&self.record) {
+                let header = header(&self.record);
+                self.seq_column = seq_column(&header);
+                self.amount_column = amount_column(&header);
+                self.headers = Some(header);
+                return self.next();
+            }
+            self.headers = Some(StringRecord::new());
+            self.amount_column = Some(3);
+        }
+        Some(self.parse(&self.record))
+    }
+
+    fn line(&self) -> Option<u64> {
+        Some(self.line)
+    }
+
+    fn raw(&self) -> Option<String> {
+        (!self.record.is_empty()).then(|| self.record.iter().collect::<Vec<_>>().join(","))
+    }
+
+    fn seq(&self) -> Option<u64> {
+        self.record.get(self.seq_column?)?.parse().ok()
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonRecord {
+    #[serde(rename = "type")]
+    tx_type: String,
+    client: u16,
+    tx: u32,
+    amount: Option<f64>,
+    tier: Option<String>,
+    reason: Option<String>,
+    destination: Option<u16>,
+    currency: Option<String>,
+    #[serde(alias = "timestamp")]
+    seq: Option<u64>,
+}
+
+// One JSON object per line: {"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}
+// Tier admin records carry the tier in its own field: {"type": "tier", "client": 1, "tx": 2, "tier": "premium"},
+// and annul records their reason: {"type": "annul", "client": 1, "tx": 1, "reason": "duplicate upstream file"}
+// Transfers name the receiving client: {"type": "transfer", "client": 1, "tx": 3, "amount": 2.0, "destination": 2}
+// Any record can name its currency: {"type": "deposit", "client": 1, "tx": 4, "amount": 1.5, "currency": "EUR"}
+// and its place in the order across inputs: {"type": "deposit", "client": 1, "tx": 5, "amount": 1.5, "seq": 42}
+pub struct JsonLinesSource<R: BufRead> {
+    lines: io::Lines<R>,
+    line: u64,
+    last: Option<String>,
+    seq: Option<u64>,
+}
+
+impl<R: BufRead> JsonLinesSource<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self { lines: reader.lines(), line: 0, last: None, seq: None }
+    }
+}
+
+impl JsonLinesSource<BufReader<File>> {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, SourceError> {
+        let file = File::open(path).map_err(SourceError::Io)?;
+        Ok(Self::from_reader(BufReader::new(file)))
+    }
+}
+
+impl<R: BufRead> TransactionSource for JsonLinesSource<R> {
+    fn next(&mut self) -> Option<Result<Transaction, SourceError>> {
+        loop {
+            self.line += 1;
+            self.seq = None;
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => {
+                    self.last = None;
+                    return Some(Err(SourceError::Io(e)));
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line = self.last.insert(line);
+            let record: JsonRecord = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(e) => return Some(Err(SourceError::Json(e))),
+            };
+            self.seq = record.seq;
+            return Some(json_transaction(record, line));
+        }
+    }
+
+    fn line(&self) -> Option<u64> {
+        Some(self.line)
+    }
+
+    fn raw(&self) -> Option<String> {
+        self.last.clone()
+    }
+
+    fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+}
+
+pub(crate) fn parse_json_line(line: &str) -> Result<Transaction, SourceError> {
+    json_transaction(serde_json::from_str(line).map_err(SourceError::Json)?, line)
+}
+
+fn json_transaction(record: JsonRecord, line: &str) -> Result<Transaction, SourceError> {
+    let destination = record.destination.map(|d| d.to_string());
+    let tx = Transaction {
+        tx_type: TxType::parse_input(&record.tx_type, record.tier.as_deref().or(record.reason.as_deref()), destination.as_deref())
+            .map_err(|e| classify(e, || line.to_string()))?,
+        client_id: record.client,
+        tx_id: record.tx,
+        amount: record.amount,
+        currency: transaction::parse_currency(record.currency.as_deref())?,
+        status: PaymentStatus::Posted,
+        disputed: None,
+        shortfall: None,
+        attributes: BTreeMap::new(),
+    };
+    Ok(tx.validate()?)
+}
+
+// A record on its own, e.g. a message from a queue: a JSON object as in JSON Lines, or a
+// headerless `type,client,tx,amount` CSV line
+pub fn parse_record(record: &str) -> Result<Transaction, SourceError> {
+    if record.trim_start().starts_with('{') {
+        return parse_json_line(record);
+    }
+    let fields = match reader_builder().from_reader(record.as_bytes()).records().next() {
+        Some(fields) => fields.map_err(SourceError::Csv)?,
+        None => StringRecord::new(),
+    };
+    Transaction::create_transaction(&fields).map_err(|e| classify(e, || record.trim().to_string()))
+}
+
+pub struct VecSource {
+    txs: VecDeque<Transaction>,
+}
+
+impl From<Vec<Transaction>> for VecSource {
+    fn from(txs: Vec<Transaction>) -> Self {
+        Self { txs: txs.into() }
+    }
+}
+
+impl TransactionSource for VecSource {
+    fn next(&mut self) -> Option<Result<Transaction, SourceError>> {
+        self.txs.pop_front().map(Ok)
+    }
+}
+
+// Any iterator of transactions as a source, for feeding the ledger from a queue of one's own: e.g.
+// `IterSource(receiver.into_iter())` over a `std::sync::mpsc` receiver, or
+// `IterSource(std::iter::from_fn(|| receiver.blocking_recv()))` over a tokio one, ending once every
+// sender is dropped. Blocking in `next` is fine, sources are read off the async runtime.
+pub struct IterSource<I>(pub I);
+
+impl<I: Iterator<Item = Result<Transaction, SourceError>>> TransactionSource for IterSource<I> {
+    fn next(&mut self) -> Option<Result<Transaction, SourceError>> {
+        self.0.next()
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// Wraps input starting with the gzip or zstd magic bytes in its decoder, so compressed dumps are
+// streamed without decompressing them to disk first. Multi-member gzip (concatenated .gz files) is
+// read to the end, as are multi-frame zstd files.
+pub fn decompressed<R: Read + Send + 'static>(reader: R) -> Result<Box<dyn Read + Send>, SourceError> {
+    let mut reader = BufReader::new(reader);
+    let head = reader.fill_buf().map_err(SourceError::Io)?;
+    if head.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else if head.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(zstd::Decoder::with_buffer(reader).map_err(SourceError::Io)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+// The file or stdin ("-") behind an input path, decompressed if needed
+pub fn open_reader<P: AsRef<Path>>(path: P) -> Result<Box<dyn Read + Send>, SourceError> {
+    let path = path.as_ref();
+    if path == Path::new("-") {
+        return decompressed(io::stdin());
+    }
+    decompressed(File::open(path).map_err(SourceError::Io)?)
+}
+
+// The extension naming the input format, looking past a .gz/.zst suffix
+fn format_extension(path: &Path) -> Option<&str> {
+    match path.extension()?.to_str()? {
+        "gz" | "zst" => Path::new(path.file_stem()?).extension()?.to_str(),
+        ext => Some(ext),
+    }
+}
+
+// Whether the path names a JSON Lines input rather than CSV
+pub fn is_json_lines(path: &Path) -> bool {
+    matches!(format_extension(path), Some("jsonl") | Some("ndjson"))
+}
+
+// Picks a source from the path: "-" reads CSV from stdin, .jsonl/.ndjson are JSON Lines, anything else is CSV
+// in `format` (tab-separated for .tsv unless it names a delimiter). Any of them may be gzip or zstd compressed
+// (e.g. dump.jsonl.gz). With `strict_schema`, CSV inputs whose header isn't exactly the expected one are refused.
+pub fn open(path: &str, strict_schema: bool, format: CsvFormat) -> Result<Box<dyn TransactionSource + Send>, SourceError> {
+    let reader = open_reader(path)?;
+    let format = format.for_path(Path::new(path));
+    match format_extension(Path::new(path)) {
+        Some("jsonl") | Some("ndjson") => Ok(Box::new(JsonLinesSource::from_reader(BufReader::new(reader)))),
+        _ if strict_schema => Ok(Box::new(CsvSource::with_strict_schema(reader, format)?)),
+        _ => Ok(Box::new(CsvSource::with_format(reader, format))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect<S: TransactionSource>(source: &mut S) -> Vec<Result<Transaction, SourceError>> {
+        let mut out = vec![];
+        while let Some(result) = source.next() {
+            out.push(result);
+        }
+        out
+    }
+
+    #[test]
+    fn test_csv_source_skips_header_and_parses_rows() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.5\nbogus,1,2,1.0\n";
+        let results = collect(&mut CsvSource::from_reader(data.as_bytes()));
+
+        assert_eq!(results.len(), 2);
+        let tx = results[0].as_ref().unwrap();
+        assert_eq!(tx.tx_type, TxType::Deposit);
+        assert_eq!(tx.amount, Some(1.5));
+        assert!(matches!(&results[1], Err(SourceError::UnknownRecord(r)) if r.tx_type == "bogus" && r.raw == "bogus,1,2,1.0"));
+    }
+
+    #[test]
+    fn test_csv_header_names_are_matched_in_any_case() {
+        let data = "Type,Client,TX,Amount,Seq\nDeposit,1,1,1.5,7\n";
+        let mut source = CsvSource::from_reader(data.as_bytes());
+        let tx = source.next().unwrap().unwrap();
+        assert_eq!((tx.tx_type, tx.client_id, tx.tx_id, tx.amount), (TxType::Deposit, 1, 1, Some(1.5)));
+        assert_eq!(source.seq(), Some(7));
+    }
+
+    #[test]
+    fn test_sources_report_the_line_and_text_of_the_last_record() {
+        let mut csv = CsvSource::from_reader("type,client,tx,amount\ndeposit,1,1,1.5\nwithdrawal, 1, 2, x\n".as_bytes());
+        csv.next();
+        assert_eq!(csv.line(), Some(2));
+        assert!(csv.next().unwrap().is_err());
+        assert_eq!((csv.line(), csv.raw().as_deref()), (Some(3), Some("withdrawal,1,2,x")));
+
+        let mut json = JsonLinesSource::from_reader("\n{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1}\n".as_bytes());
+        json.next();
+        assert_eq!(json.line(), Some(2));
+    }
+
+    #[test]
+    fn test_csv_source_maps_columns_by_header_or_position() {
+        let reordered = "client, tx, note, type, amount\n3, 9, x, withdrawal, 2.5\n3, 9, , dispute\n";
+        let results = collect(&mut CsvSource::from_reader(reordered.as_bytes()));
+        let tx = results[0].as_ref().unwrap();
+        assert_eq!((&tx.tx_type, tx.client_id, tx.tx_id, tx.amount), (&TxType::Withdrawal, 3, 9, Some(2.5)));
+        assert_eq!(results[1].as_ref().unwrap().amount, None);
+
+        let headerless = "deposit,1,1,1.0\ntier,1,2,premium\n";
+        let results = collect(&mut CsvSource::from_reader(headerless.as_bytes()));
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].as_ref().unwrap().tx_type, TxType::SetTier(crate::client::Tier::Premium));
+    }
+
+    #[test]
+    fn test_csv_source_reads_other_delimiters_and_decimal_separators() {
+        let format = CsvFormat { delimiter: Some(b';'), decimal_separator: ',' };
+        let data = "type;client;tx;amount\ndeposit;1;1;12,50\nannul;1;1;late, duplicate\nwithdrawal;1;2;1,2,3\n";
+        let results = collect(&mut CsvSource::with_format(data.as_bytes(), format));
+        assert_eq!(results[0].as_ref().unwrap().amount, Some(12.5));
+        assert_eq!(results[1].as_ref().unwrap().tx_type, TxType::Annul("late, duplicate".to_string()));
+        assert!(results[2].is_err());
+
+        let tabs = CsvFormat { delimiter: Some(b'\t'), ..CsvFormat::default() };
+        let results = collect(&mut CsvSource::with_format("deposit\t1\t1\t0.5\n".as_bytes(), tabs));
+        assert_eq!(results[0].as_ref().unwrap().amount, Some(0.5));
+    }
+
+    #[test]
+    fn test_compressed_input_is_detected_by_magic_bytes() {
+        use flate2::{Compression, write::GzEncoder};
+        use std::io::Write;
+
+        let data = "type,client,tx,amount\ndeposit,1,1,1.5\n";
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(data.as_bytes()).unwrap();
+        let gz = gz.finish().unwrap();
+        let zst = zstd::encode_all(data.as_bytes(), 0).unwrap();
+
+        for bytes in [gz, zst, data.as_bytes().to_vec()] {
+            let results = collect(&mut CsvSource::from_reader(decompressed(io::Cursor::new(bytes)).unwrap()));
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].as_ref().unwrap().amount, Some(1.5));
+        }
+        assert_eq!(format_extension(Path::new("dump.jsonl.gz")), Some("jsonl"));
+        assert_eq!(format_extension(Path::new("dump.zst")), None);
+    }
+
+    #[test]
+    fn test_json_lines_source_parses_records() {
+        let data = "{\"type\":\"deposit\",\"client\":2,\"tx\":5,\"amount\":3.0}\n\n{\"type\":\"dispute\",\"client\":2,\"tx\":5}\nnot json\n";
+        let results = collect(&mut JsonLinesSource::from_reader(data.as_bytes()));
+
+        assert_eq!(results.len(), 3);
+        let deposit = results[0].as_ref().unwrap();
+        assert_eq!((deposit.client_id, deposit.tx_id, deposit.amount), (2, 5, Some(3.0)));
+        let dispute = results[1].as_ref().unwrap();
+        assert_eq!(dispute.tx_type, TxType::Dispute);
+        assert_eq!(dispute.amount, None);
+        assert!(matches!(results[2], Err(SourceError::Json(_))));
+    }
+
+    #[test]
+    fn test_single_records_are_parsed_as_json_or_csv() {
+        let json = parse_record(r#" {"type":"deposit","client":2,"tx":5,"amount":3.0}"#).unwrap();
+        let csv = parse_record("deposit, 2, 5, 3.0\n").unwrap();
+        assert_eq!((json.client_id, json.tx_id, json.amount), (csv.client_id, csv.tx_id, csv.amount));
+        assert!(matches!(parse_record("bogus,1,2,1.0"), Err(SourceError::UnknownRecord(r)) if r.raw == "bogus,1,2,1.0"));
+        assert!(matches!(parse_record(""), Err(SourceError::Transaction(TransactionError::TooFewFields(_)))));
+    }
+
+    #[test]
+    fn test_vec_source_yields_in_order() {
+        let record = StringRecord::from(vec!["deposit", "1", "7", "2.0"]);
+        let tx = Transaction::create_transaction(&record).unwrap();
+        let results = collect(&mut VecSource::from(vec![tx.clone(), tx]));
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.as_ref().unwrap().tx_id == 7));
+    }
+
+    #[test]
+    fn test_iter_source_drains_a_channel_until_its_senders_are_gone() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let producer = std::thread::spawn(move || {
+            for tx in 1..=3 {
+                sender.send(Transaction::deposit(1, tx, 1.0).map_err(SourceError::from)).unwrap();
+            }
+        });
+        let mut ledger = crate::ledger::Ledger::new();
+        ledger.process_source(&mut IterSource(receiver.into_iter()));
+        producer.join().unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(crate::client::Currency::Usd).total, 3.0);
+    }
+}
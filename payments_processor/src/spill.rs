@@ -0,0 +1,233 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::client::{Client, OperatorAccount};
+use crate::store::{LedgerStore, MemoryStore, StoreError, StoredTx};
+use crate::transaction::Transaction;
+
+// Bytes per index slot: where the transaction's line starts in the data file, plus one so that a
+// slot never written (a hole in the sparse file) reads as not spilled
+const SLOT: u64 = 8;
+
+// Spill directories created by this process, for unique names
+static SPILLS: AtomicU64 = AtomicU64::new(0);
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError(e.to_string())
+    }
+}
+
+// A memory store that keeps at most `max_in_memory` transactions in memory. Past that, the oldest
+// ones spill to a data file of JSON lines, found again through an index file with a slot per
+// possible tx id. The index is a sparse file, so it only takes disk space around the ids that
+// spilled, and neither file is read into memory: a lookup is two seeks. Clients and the operator
+// account stay in memory, as in `MemoryStore`. Both files are deleted with the store.
+pub struct SpillStore {
+    memory: MemoryStore,
+    hot: HashMap<u32, Transaction>,
+    // Ids in the order they came into memory, oldest first; may still hold ids removed since
+    order: VecDeque<u32>,
+    max_in_memory: usize,
+    dir: PathBuf,
+    data: File,
+    index: File,
+    // Where the next spilled line goes; lines superseded by a later version of the same
+    // transaction stay behind until `move_transactions`
+    end: u64,
+}
+
+impl SpillStore {
+    // Spills into a new directory under the system's temporary directory
+    pub fn create(max_in_memory: usize) -> Result<Self, StoreError> {
+        let name = format!("payments_processor_spill_{}_{}", std::process::id(), SPILLS.fetch_add(1, Ordering::Relaxed));
+        Self::create_in(std::env::temp_dir().join(name), max_in_memory)
+    }
+
+    pub fn create_in<P: AsRef<Path>>(dir: P, max_in_memory: usize) -> Result<Self, StoreError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let open = |name| OpenOptions::new().read(true).write(true).create(true).truncate(true).open(dir.join(name));
+        let (data, index) = (open("transactions.jsonl")?, open("index")?);
+        Ok(SpillStore { memory: MemoryStore::new(), hot: HashMap::new(), order: VecDeque::new(), max_in_memory, dir, data, index, end: 0 })
+    }
+
+    fn slot(&self, tx_id: u32) -> Result<Option<u64>, StoreError> {
+        let mut index = &self.index;
+        index.seek(SeekFrom::Start(u64::from(tx_id) * SLOT))?;
+        let mut slot = [0; SLOT as usize];
+        match index.read_exact(&mut slot) {
+            Ok(()) => Ok(u64::from_le_bytes(slot).checked_sub(1)),
+            // Past the end of the index: no id this high spilled yet
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_slot(&self, tx_id: u32, offset: Option<u64>) -> Result<(), StoreError> {
+        let mut index = &self.index;
+        index.seek(SeekFrom::Start(u64::from(tx_id) * SLOT))?;
+        index.write_all(&offset.map_or(0, |offset| offset + 1).to_le_bytes())?;
+        Ok(())
+    }
+
+    fn read_spilled(&self, offset: u64) -> Result<Transaction, StoreError> {
+        let mut data = &self.data;
+        data.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        BufReader::new(data).read_line(&mut line)?;
+        parse(&line)
+    }
+
+    // Writes out the oldest transactions until the rest fit in memory
+    fn spill(&mut self) -> Result<(), StoreError> {
+        while self.hot.len() > self.max_in_memory {
+            let Some(tx_id) = self.order.pop_front() else { break };
+            let Some(tx) = self.hot.remove(&tx_id) else { continue };
+            let mut line = serde_json::to_vec(&StoredTx::from(&tx)).map_err(|e| StoreError(e.to_string()))?;
+            line.push(b'\n');
+            let mut data = &self.data;
+            data.seek(SeekFrom::Start(self.end))?;
+            data.write_all(&line)?;
+            self.set_slot(tx_id, Some(self.end))?;
+            self.end += line.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+fn parse(line: &str) -> Result<Transaction, StoreError> {
+    let stored: StoredTx = serde_json::from_str(line).map_err(|e| StoreError(format!("corrupt spill file: {}", e)))?;
+    stored.into_transaction().map_err(|e| StoreError(format!("corrupt spill file: {}", e)))
+}
+
+impl Drop for SpillStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl LedgerStore for SpillStore {
+    fn get_tx(&self, tx_id: u32) -> Result<Option<Transaction>, StoreError> {
+        if let Some(tx) = self.hot.get(&tx_id) {
+            return Ok(Some(tx.clone()));
+        }
+        self.slot(tx_id)?.map(|offset| self.read_spilled(offset)).transpose()
+    }
+
+    fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError> {
+        Ok(self.hot.contains_key(&tx_id) || self.slot(tx_id)?.is_some())
+    }
+
+    // A spilled transaction that is updated (e.g. disputed) comes back into memory, where it
+    // shadows its spilled line until it spills again
+    fn put_tx(&mut self, tx: &Transaction) -> Result<(), StoreError> {
+        if self.hot.insert(tx.tx_id, tx.clone()).is_none() {
+            self.order.push_back(tx.tx_id);
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn remove_tx(&mut self, tx_id: u32) -> Result<(), StoreError> {
+        self.hot.remove(&tx_id);
+        if self.slot(tx_id)?.is_some() {
+            self.set_slot(tx_id, None)?;
+        }
+        Ok(())
+    }
+
+    fn get_client(&self, client_id: u16) -> Result<Option<Client>, StoreError> {
+        self.memory.get_client(client_id)
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), StoreError> {
+        self.memory.put_client(client)
+    }
+
+    fn clients(&self) -> Result<Vec<Client>, StoreError> {
+        self.memory.clients()
+    }
+
+    fn operator(&self) -> Result<OperatorAccount, StoreError> {
+        self.memory.operator()
+    }
+
+    fn put_operator(&mut self, operator: &OperatorAccount) -> Result<(), StoreError> {
+        self.memory.put_operator(operator)
+    }
+
+    // Reads through the data file, skipping lines that a later version or the one in memory supersedes
+    fn for_each_tx(&self, f: &mut dyn FnMut(&Transaction) -> Result<(), StoreError>) -> Result<(), StoreError> {
+        self.hot.values().try_for_each(&mut *f)?;
+        let mut data = &self.data;
+        data.seek(SeekFrom::Start(0))?;
+        let mut lines = BufReader::new(data);
+        let (mut offset, mut line) = (0, String::new());
+        while offset < self.end {
+            line.clear();
+            let read = lines.read_line(&mut line)? as u64;
+            if read == 0 {
+                break;
+            }
+            let tx = parse(&line)?;
+            if !self.hot.contains_key(&tx.tx_id) && self.slot(tx.tx_id)? == Some(offset) {
+                f(&tx)?;
+            }
+            offset += read;
+        }
+        Ok(())
+    }
+
+    fn move_transactions(&mut self, other: &mut dyn LedgerStore) -> Result<(), StoreError> {
+        self.for_each_tx(&mut |tx| other.put_tx(tx))?;
+        self.hot.clear();
+        self.order.clear();
+        self.data.set_len(0)?;
+        self.index.set_len(0)?;
+        self.end = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use crate::client::Currency;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_spill_store_keeps_transactions_past_its_memory_cap_on_disk() {
+        let dir = std::env::temp_dir().join(format!("payments_processor_spill_test_{}", std::process::id()));
+        let mut ledger = Ledger::with_store(Box::new(SpillStore::create_in(&dir, 2).unwrap())).unwrap();
+        for tx in 1..=5 {
+            ledger.process_transaction(&TxBuilder::deposit(1, tx, 1.0).build()).unwrap();
+        }
+        assert!(fs::metadata(dir.join("transactions.jsonl")).unwrap().len() > 0);
+        // Tx 1 spilled: it is still found for the dispute, a duplicate and the resolve
+        ledger.process_transaction(&TxBuilder::dispute(1, 1).build()).unwrap();
+        ledger.process_transaction(&TxBuilder::deposit(1, 1, 1.0).build()).unwrap_err();
+        for tx in 6..=8 {
+            ledger.process_transaction(&TxBuilder::deposit(1, tx, 1.0).build()).unwrap();
+        }
+        ledger.process_transaction(&TxBuilder::resolve(1, 1).build()).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, 8.0);
+
+        let mut ids = vec![];
+        ledger.for_each_transaction(&mut |tx| {
+            ids.push(tx.tx_id);
+            Ok(())
+        }).unwrap();
+        ids.sort();
+        assert_eq!(ids, (1..=8).collect::<Vec<_>>());
+
+        let mut merged = Ledger::new();
+        merged.merge(ledger).unwrap();
+        assert!(merged.transaction(1).unwrap().is_some());
+        assert!(!dir.exists());
+    }
+}
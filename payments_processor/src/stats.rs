@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::time::Duration;
+use serde::Serialize;
+
+use crate::manifest::InputProvenance;
+use crate::metrics::Metrics;
+
+// Counts of one run for `--stats`, for reconciling it against what upstream sent: every record read
+// was either accepted or rejected, and the rejections add up by error
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RunStats {
+    pub records: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    // By `LedgerError::kind`, plus "unreadable" for records that couldn't be read at all
+    pub rejected_by_error: BTreeMap<&'static str, u64>,
+    // Records that reached the ledger, accepted or not, by type
+    pub transactions_by_type: BTreeMap<&'static str, u64>,
+    pub clients_created: u64,
+    pub duration_secs: f64,
+    pub records_per_sec: f64,
+}
+
+impl RunStats {
+    // From the run's inputs and the metrics of the ledger it ran on, which went from `clients_before` to
+    // `clients_after` clients
+    pub fn new(inputs: &[InputProvenance], metrics: &Metrics, clients_before: u64, clients_after: u64, duration: Duration) -> RunStats {
+        let records: u64 = inputs.iter().map(|i| i.records).sum();
+        let rejected: u64 = inputs.iter().map(|i| i.rejected).sum();
+        let unreadable: u64 = inputs.iter().map(|i| i.unreadable).sum();
+        let mut rejected_by_error = metrics.errors.clone();
+        if unreadable > 0 {
+            rejected_by_error.insert("unreadable", unreadable);
+        }
+        let secs = duration.as_secs_f64();
+        RunStats {
+            records,
+            accepted: records - rejected,
+            rejected,
+            rejected_by_error,
+            transactions_by_type: metrics.processed.clone(),
+            clients_created: clients_after.saturating_sub(clients_before),
+            duration_secs: secs,
+            records_per_sec: if secs > 0.0 { records as f64 / secs } else { 0.0 },
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer_pretty(&mut out, self)?;
+        writeln!(out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_stats_reconcile_records_with_accepted_and_rejected() {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(&TxBuilder::deposit(1, 1, 10.0).build()).unwrap();
+        ledger.process_transaction(&TxBuilder::deposit(2, 2, 10.0).build()).unwrap();
+        assert!(ledger.process_transaction(&TxBuilder::withdrawal(2, 3, 50.0).build()).is_err());
+        let mut input = InputProvenance::new("in.csv".to_string());
+        (input.records, input.rejected, input.unreadable) = (4, 2, 1);
+
+        let stats = RunStats::new(&[input], &ledger.metrics(), 1, ledger.clients().count() as u64, Duration::from_secs(2));
+        assert_eq!((stats.records, stats.accepted, stats.rejected), (4, 2, 2));
+        assert_eq!(stats.rejected_by_error, BTreeMap::from([("not_enough_funds", 1), ("unreadable", 1)]));
+        assert_eq!(stats.transactions_by_type, BTreeMap::from([("deposit", 2), ("withdrawal", 1)]));
+        assert_eq!((stats.clients_created, stats.records_per_sec), (1, 2.0));
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::CacheStats;
+use crate::client::{Client, Currency, OperatorAccount};
+use crate::transaction::{PaymentStatus, Transaction, TxType};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoreError(pub String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Storage error: {}", self.0)
+    }
+}
+
+impl Error for StoreError {}
+
+// Where a ledger keeps its state. The transaction history lives only in the store, so it can grow
+// past RAM with a persistent backend. Clients (at most 65536) are worked on in memory and written
+// back on `Ledger::flush`, together with the operator account, before `commit`; a persistent store
+// reopened after a crash comes back as of its last commit.
+pub trait LedgerStore: Send {
+    fn get_tx(&self, tx_id: u32) -> Result<Option<Transaction>, StoreError>;
+
+    fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError> {
+        Ok(self.get_tx(tx_id)?.is_some())
+    }
+
+    // Inserts the transaction, or updates the stored one with the same id
+    fn put_tx(&mut self, tx: &Transaction) -> Result<(), StoreError>;
+
+    fn remove_tx(&mut self, tx_id: u32) -> Result<(), StoreError>;
+
+    fn get_client(&self, client_id: u16) -> Result<Option<Client>, StoreError>;
+
+    fn put_client(&mut self, client: &Client) -> Result<(), StoreError>;
+
+    fn clients(&self) -> Result<Vec<Client>, StoreError>;
+
+    fn operator(&self) -> Result<OperatorAccount, StoreError>;
+
+    fn put_operator(&mut self, operator: &OperatorAccount) -> Result<(), StoreError>;
+
+    // Makes everything written since the last commit durable at once
+    fn commit(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    // Calls `f` with every stored transaction, in no particular order
+    fn for_each_tx(&self, f: &mut dyn FnMut(&Transaction) -> Result<(), StoreError>) -> Result<(), StoreError>;
+
+    // Moves every transaction into `other`, e.g. when merging shards
+    fn move_transactions(&mut self, other: &mut dyn LedgerStore) -> Result<(), StoreError>;
+
+    // How the transaction cache in front of the store has done, for stores behind a `CachedStore`
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+// A transaction as checkpoints and `SpillStore` write it, one JSON line each
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StoredTx {
+    tx_type: String,
+    client_id: u16,
+    tx_id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    amount: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disputed: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shortfall: Option<f64>,
+    // Why it was annulled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: BTreeMap<String, String>,
+}
+
+impl From<&Transaction> for StoredTx {
+    fn from(tx: &Transaction) -> Self {
+        let reason = match &tx.status {
+            PaymentStatus::Annulled(reason) => Some(reason.clone()),
+            _ => None,
+        };
+        StoredTx {
+            tx_type: tx.tx_type.name().to_string(),
+            client_id: tx.client_id,
+            tx_id: tx.tx_id,
+            amount: tx.amount,
+            value: tx.tx_type.value(),
+            currency: tx.currency,
+            status: tx.status.name().to_string(),
+            disputed: tx.disputed,
+            shortfall: tx.shortfall,
+            reason,
+            attributes: tx.attributes.clone(),
+        }
+    }
+}
+
+impl StoredTx {
+    pub(crate) fn into_transaction(self) -> Result<Transaction, String> {
+        let StoredTx { tx_type, client_id, tx_id, amount, value, currency, status, disputed, shortfall, reason, attributes } = self;
+        let tx_type = TxType::parse(&tx_type, value.as_deref()).map_err(|e| e.to_string())?;
+        let status = PaymentStatus::from_name(&status, reason).ok_or_else(|| format!("unknown status {}", status))?;
+        Ok(Transaction { tx_type, tx_id, client_id, amount, currency, status, disputed, shortfall, attributes })
+    }
+}
+
+// The default store: everything in hash maps, gone with the process
+#[derive(Default)]
+pub struct MemoryStore {
+    transactions: HashMap<u32, Transaction>,
+    clients: HashMap<u16, Client>,
+    operator: OperatorAccount,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LedgerStore for MemoryStore {
+    fn get_tx(&self, tx_id: u32) -> Result<Option<Transaction>, StoreError> {
+        Ok(self.transactions.get(&tx_id).cloned())
+    }
+
+    fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError> {
+        Ok(self.transactions.contains_key(&tx_id))
+    }
+
+    fn put_tx(&mut self, tx: &Transaction) -> Result<(), StoreError> {
+        self.transactions.insert(tx.tx_id, tx.clone());
+        Ok(())
+    }
+
+    fn remove_tx(&mut self, tx_id: u32) -> Result<(), StoreError> {
+        self.transactions.remove(&tx_id);
+        Ok(())
+    }
+
+    fn get_client(&self, client_id: u16) -> Result<Option<Client>, StoreError> {
+        Ok(self.clients.get(&client_id).cloned())
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), StoreError> {
+        self.clients.insert(client.id, client.clone());
+        Ok(())
+    }
+
+    fn clients(&self) -> Result<Vec<Client>, StoreError> {
+        Ok(self.clients.values().cloned().collect())
+    }
+
+    fn operator(&self) -> Result<OperatorAccount, StoreError> {
+        Ok(self.operator.clone())
+    }
+
+    fn put_operator(&mut self, operator: &OperatorAccount) -> Result<(), StoreError> {
+        self.operator = operator.clone();
+        Ok(())
+    }
+
+    fn for_each_tx(&self, f: &mut dyn FnMut(&Transaction) -> Result<(), StoreError>) -> Result<(), StoreError> {
+        self.transactions.values().try_for_each(f)
+    }
+
+    fn move_transactions(&mut self, other: &mut dyn LedgerStore) -> Result<(), StoreError> {
+        for (_, tx) in self.transactions.drain() {
+            other.put_tx(&tx)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use rusqlite::{Connection, OptionalExtension, Row, params};
+
+    use super::{LedgerStore, StoreError};
+    use crate::client::{Balance, Client, Currency, OperatorAccount};
+    use crate::transaction::{PaymentStatus, Transaction, TxType};
+
+    // Transactions read per query by `for_each_tx`
+    const PAGE_SIZE: u32 = 10_000;
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS transactions (
+            tx_id INTEGER PRIMARY KEY,
+            client_id INTEGER NOT NULL,
+            tx_type TEXT NOT NULL,
+            value TEXT,
+            amount REAL,
+            status TEXT NOT NULL,
+            reason TEXT,
+            attributes TEXT NOT NULL,
+            currency TEXT,
+            disputed REAL,
+            shortfall REAL
+        );
+        CREATE TABLE IF NOT EXISTS clients (
+            client_id INTEGER PRIMARY KEY,
+            locked INTEGER NOT NULL,
+            tier TEXT NOT NULL,
+            overdraft_limit REAL,
+            name TEXT
+        );
+        CREATE TABLE IF NOT EXISTS balances (
+            client_id INTEGER NOT NULL,
+            currency TEXT NOT NULL,
+            available REAL NOT NULL,
+            held REAL NOT NULL,
+            total REAL NOT NULL,
+            operator_held REAL NOT NULL,
+            PRIMARY KEY (client_id, currency)
+        );
+        CREATE TABLE IF NOT EXISTS operator (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            fees_earned REAL NOT NULL,
+            chargeback_losses REAL NOT NULL
+        );
+    ";
+
+    // Databases written before currencies kept the balances in the clients table; they were all USD
+    const MIGRATE_BALANCES: &str = "
+        INSERT INTO balances SELECT client_id, 'USD', available, held, total, operator_held FROM clients;
+        ALTER TABLE clients DROP COLUMN available;
+        ALTER TABLE clients DROP COLUMN held;
+        ALTER TABLE clients DROP COLUMN total;
+        ALTER TABLE clients DROP COLUMN operator_held;
+    ";
+
+    const TX_COLUMNS: &str = "tx_id, client_id, tx_type, value, amount, status, reason, attributes, currency, disputed, shortfall";
+
+    impl From<rusqlite::Error> for StoreError {
+        fn from(e: rusqlite::Error) -> Self {
+            StoreError(e.to_string())
+        }
+    }
+
+    // A single SQLite file. Writes go into an open SQL transaction that `commit` closes (and reopens),
+    // so a crash loses only the work since the last commit and never leaves clients and
+    // transactions out of step.
+    pub struct SqliteStore {
+        conn: Connection,
+    }
+
+    impl SqliteStore {
+        // Opens the database, creating it if needed; an existing one is picked up where it was left
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+            let conn = Connection::open(path)?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.pragma_update(None, "synchronous", "NORMAL")?;
+            conn.execute_batch(SCHEMA)?;
+            conn.execute_batch("BEGIN")?;
+            if has_column(&conn, "clients", "available")? {
+                conn.execute_batch(MIGRATE_BALANCES)?;
+            }
+            if !has_column(&conn, "transactions", "currency")? {
+                conn.execute_batch("ALTER TABLE transactions ADD COLUMN currency TEXT")?;
+            }
+            if !has_column(&conn, "clients", "overdraft_limit")? {
+                conn.execute_batch("ALTER TABLE clients ADD COLUMN overdraft_limit REAL")?;
+            }
+            if !has_column(&conn, "clients", "name")? {
+                conn.execute_batch("ALTER TABLE clients ADD COLUMN name TEXT")?;
+            }
+            if !has_column(&conn, "transactions", "disputed")? {
+                conn.execute_batch("ALTER TABLE transactions ADD COLUMN disputed REAL")?;
+            }
+            if !has_column(&conn, "transactions", "shortfall")? {
+                conn.execute_batch("ALTER TABLE transactions ADD COLUMN shortfall REAL")?;
+            }
+            conn.execute_batch("COMMIT; BEGIN")?;
+            Ok(Self { conn })
+        }
+
+        fn balances(&self, client: &mut Client) -> Result<(), StoreError> {
+            let mut stmt = self.conn.prepare_cached(
+                "SELECT currency, available, held, total, operator_held FROM balances WHERE client_id = ?1",
+            )?;
+            let rows = stmt.query_map([client.id], balance_from_row)?;
+            for row in rows {
+                let (currency, balance) = row??;
+                client.balances.insert(currency, balance);
+            }
+            Ok(())
+        }
+    }
+
+    fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, StoreError> {
+        let mut stmt = conn.prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?;
+        Ok(stmt.exists([table, column])?)
+    }
+
+    fn parse_currency(currency: &str) -> Result<Currency, StoreError> {
+        currency.parse().map_err(|c| StoreError(format!("unknown currency {}", c)))
+    }
+
+    fn tx_from_row(row: &Row) -> rusqlite::Result<Result<Transaction, StoreError>> {
+        let tx_type: String = row.get(2)?;
+        let value: Option<String> = row.get(3)?;
+        let status: String = row.get(5)?;
+        let reason: Option<String> = row.get(6)?;
+        let attributes: String = row.get(7)?;
+        let currency: Option<String> = row.get(8)?;
+        let (tx_id, client_id, amount, disputed, shortfall) = (row.get(0)?, row.get(1)?, row.get(4)?, row.get(9)?, row.get(10)?);
+        Ok((|| {
+            let tx_type = TxType::parse(&tx_type, value.as_deref()).map_err(|e| StoreError(e.to_string()))?;
+            let status = PaymentStatus::from_name(&status, reason)
+                .ok_or_else(|| StoreError(format!("unknown status {} for tx {}", status, tx_id)))?;
+            let attributes: BTreeMap<String, String> =
+                serde_json::from_str(&attributes).map_err(|e| StoreError(e.to_string()))?;
+            let currency = currency.as_deref().map(parse_currency).transpose()?;
+            Ok(Transaction { tx_type, tx_id, client_id, amount, currency, status, disputed, shortfall, attributes })
+        })())
+    }
+
+    // Without balances, which live in their own table
+    fn client_from_row(row: &Row) -> rusqlite::Result<Result<Client, StoreError>> {
+        let mut client = Client::new(row.get(0)?);
+        client.locked = row.get(1)?;
+        client.overdraft_limit = row.get(3)?;
+        client.name = row.get(4)?;
+        let tier: String = row.get(2)?;
+        Ok(tier.parse().map(|tier| Client { tier, ..client }).map_err(|t| StoreError(format!("unknown tier {}", t))))
+    }
+
+    fn balance_from_row(row: &Row) -> rusqlite::Result<Result<(Currency, Balance), StoreError>> {
+        let currency: String = row.get(0)?;
+        let balance = Balance { available: row.get(1)?, held: row.get(2)?, total: row.get(3)?, operator_held: row.get(4)? };
+        Ok(parse_currency(&currency).map(|currency| (currency, balance)))
+    }
+
+    impl LedgerStore for SqliteStore {
+        fn get_tx(&self, tx_id: u32) -> Result<Option<Transaction>, StoreError> {
+            let sql = format!("SELECT {} FROM transactions WHERE tx_id = ?1", TX_COLUMNS);
+            self.conn.prepare_cached(&sql)?.query_row([tx_id], tx_from_row).optional()?.transpose()
+        }
+
+        fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError> {
+            let mut stmt = self.conn.prepare_cached("SELECT 1 FROM transactions WHERE tx_id = ?1")?;
+            Ok(stmt.exists([tx_id])?)
+        }
+
+        fn put_tx(&mut self, tx: &Transaction) -> Result<(), StoreError> {
+            let reason = match &tx.status {
+                PaymentStatus::Annulled(reason) => Some(reason.as_str()),
+                _ => None,
+            };
+            let status = tx.status.name();
+            let attributes = serde_json::to_string(&tx.attributes).map_err(|e| StoreError(e.to_string()))?;
+            let currency = tx.currency.map(|c| c.to_string());
+            let sql = format!("INSERT OR REPLACE INTO transactions ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)", TX_COLUMNS);
+            self.conn.prepare_cached(&sql)?.execute(params![
+                tx.tx_id, tx.client_id, tx.tx_type.name(), tx.tx_type.value(), tx.amount, status, reason, attributes, currency, tx.disputed, tx.shortfall,
+            ])?;
+            Ok(())
+        }
+
+        fn remove_tx(&mut self, tx_id: u32) -> Result<(), StoreError> {
+            self.conn.prepare_cached("DELETE FROM transactions WHERE tx_id = ?1")?.execute([tx_id])?;
+            Ok(())
+        }
+
+        fn get_client(&self, client_id: u16) -> Result<Option<Client>, StoreError> {
+            let mut stmt = self.conn.prepare_cached("SELECT client_id, locked, tier, overdraft_limit, name FROM clients WHERE client_id = ?1")?;
+            let Some(mut client) = stmt.query_row([client_id], client_from_row).optional()?.transpose()? else {
+                return Ok(None);
+            };
+            self.balances(&mut client)?;
+            Ok(Some(client))
+        }
+
+        // A client never loses a currency once it has a balance in it, so balances are only upserted
+        fn put_client(&mut self, client: &Client) -> Result<(), StoreError> {
+            self.conn.prepare_cached("INSERT OR REPLACE INTO clients (client_id, locked, tier, overdraft_limit, name) VALUES (?1, ?2, ?3, ?4, ?5)")?
+                .execute(params![client.id, client.locked, client.tier.to_string(), client.overdraft_limit, client.name])?;
+            let mut stmt = self.conn.prepare_cached("INSERT OR REPLACE INTO balances VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+            for (currency, b) in &client.balances {
+                stmt.execute(params![client.id, currency.to_string(), b.available, b.held, b.total, b.operator_held])?;
+            }
+            Ok(())
+        }
+
+        fn clients(&self) -> Result<Vec<Client>, StoreError> {
+            let mut stmt = self.conn.prepare_cached("SELECT client_id, locked, tier, overdraft_limit, name FROM clients ORDER BY client_id")?;
+            let mut clients: Vec<Client> = stmt.query_map([], client_from_row)?.map(|row| row?).collect::<Result<_, _>>()?;
+            for client in &mut clients {
+                self.balances(client)?;
+            }
+            Ok(clients)
+        }
+
+        fn operator(&self) -> Result<OperatorAccount, StoreError> {
+            let mut stmt = self.conn.prepare_cached("SELECT fees_earned, chargeback_losses FROM operator WHERE id = 0")?;
+            let operator = stmt.query_row([], |row| Ok(OperatorAccount { fees_earned: row.get(0)?, chargeback_losses: row.get(1)? }));
+            Ok(operator.optional()?.unwrap_or_default())
+        }
+
+        fn put_operator(&mut self, operator: &OperatorAccount) -> Result<(), StoreError> {
+            self.conn.prepare_cached("INSERT OR REPLACE INTO operator VALUES (0, ?1, ?2)")?
+                .execute(params![operator.fees_earned, operator.chargeback_losses])?;
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<(), StoreError> {
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+            Ok(())
+        }
+
+        // Pages through the table, so the history never has to fit in memory
+        fn for_each_tx(&self, f: &mut dyn FnMut(&Transaction) -> Result<(), StoreError>) -> Result<(), StoreError> {
+            let sql = format!("SELECT {} FROM transactions WHERE tx_id > ?1 ORDER BY tx_id LIMIT ?2", TX_COLUMNS);
+            let mut after = -1i64;
+            loop {
+                let page: Vec<Transaction> = self.conn.prepare_cached(&sql)?
+                    .query_map(params![after, PAGE_SIZE], tx_from_row)?
+                    .map(|row| row?)
+                    .collect::<Result<_, _>>()?;
+                let Some(last) = page.last() else { break };
+                after = i64::from(last.tx_id);
+                page.iter().try_for_each(&mut *f)?;
+            }
+            Ok(())
+        }
+
+        fn move_transactions(&mut self, other: &mut dyn LedgerStore) -> Result<(), StoreError> {
+            self.for_each_tx(&mut |tx| other.put_tx(tx))?;
+            self.conn.execute("DELETE FROM transactions", [])?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_memory_store_round_trips_and_moves_transactions() {
+        let mut store = MemoryStore::new();
+        let mut tx = TxBuilder::deposit(1, 1, 2.5).build();
+        store.put_tx(&tx).unwrap();
+        tx.status = crate::transaction::PaymentStatus::Disputed;
+        store.put_tx(&tx).unwrap();
+        assert_eq!(store.get_tx(1).unwrap().unwrap().status, tx.status);
+        assert!(!store.contains_tx(2).unwrap());
+
+        let mut other = MemoryStore::new();
+        store.move_transactions(&mut other).unwrap();
+        assert!(store.get_tx(1).unwrap().is_none());
+        assert!(other.contains_tx(1).unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store_keeps_committed_state_across_reopens() {
+        use crate::client::{Currency, Tier};
+        use crate::ledger::Ledger;
+
+        let path = std::env::temp_dir().join(format!("payments_processor_store_{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut ledger = Ledger::with_store(Box::new(SqliteStore::open(&path).unwrap())).unwrap();
+            ledger.set_admin_ops(true);
+            ledger.process_transaction(&TxBuilder::deposit(1, 1, 10.0).currency(Currency::Gbp).build()).unwrap();
+            ledger.process_transaction(&TxBuilder::dispute(1, 1).build()).unwrap();
+            ledger.process_transaction(&TxBuilder::set_tier(2, 2, Tier::Premium).build()).unwrap();
+            ledger.process_transaction(&TxBuilder::annul(2, 3, "wrong file").build()).unwrap_err();
+            ledger.flush().unwrap();
+            // Not committed, so lost when the store is dropped
+            ledger.process_transaction(&TxBuilder::deposit(3, 4, 1.0).build()).unwrap();
+        }
+
+        let mut ledger = Ledger::with_store(Box::new(SqliteStore::open(&path).unwrap())).unwrap();
+        let balance = ledger.client(1).unwrap().balance(Currency::Gbp).clone();
+        assert_eq!((balance.available, balance.held, balance.total), (0.0, 10.0, 10.0));
+        assert_eq!(ledger.client(2).unwrap().tier, Tier::Premium);
+        assert!(ledger.client(3).is_none());
+        ledger.process_transaction(&TxBuilder::resolve(1, 1).build()).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Gbp).available, 10.0);
+        assert!(ledger.transaction(4).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,714 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use csv::{Writer, WriterBuilder};
+use serde::Serialize;
+
+use crate::client::{self, Client, Currency, OperatorAccount};
+use crate::ledger::Ledger;
+use crate::rates::{ConsolidatedRow, RateTable};
+
+
+pub trait SummaryWriter {
+    fn write_header(&mut self) -> Result<(), Box<dyn Error>>;
+    // One row per currency the client holds, see `Client::rows`
+    fn write_client(&mut self, client: &Client) -> Result<(), Box<dyn Error>>;
+    // Called after the last client when the operator section is requested; formats without a
+    // place for it ignore it
+    fn write_operator(&mut self, _operator: &OperatorAccount) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    // Called after the last client when totals are requested, one row per currency; formats without
+    // a place for them ignore them
+    fn write_totals(&mut self, _totals: &[TotalsRow]) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    // Called after the totals when a consolidated section is requested, one row per client in the
+    // currency of record; formats without a place for it ignore it
+    fn write_consolidated(&mut self, _rows: &[ConsolidatedRow]) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+// What goes into a summary besides the client rows
+#[derive(Clone, Debug, PartialEq)]
+pub struct SummaryOptions {
+    // The CSV header row; the other formats name their fields anyway
+    pub header: bool,
+    pub totals: bool,
+    pub operator: bool,
+    // Each client's balances converted to the currency of record, after the rows in their own currencies
+    pub consolidated: Option<RateTable>,
+}
+
+impl Default for SummaryOptions {
+    fn default() -> Self {
+        SummaryOptions { header: true, totals: false, operator: false, consolidated: None }
+    }
+}
+
+// The sum of every client's balances in one currency
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TotalsRow {
+    pub currency: Currency,
+    #[serde(serialize_with = "client::four_decimals")]
+    pub available: f64,
+    #[serde(serialize_with = "client::four_decimals")]
+    pub held: f64,
+    #[serde(serialize_with = "client::four_decimals")]
+    pub total: f64,
+    #[serde(serialize_with = "client::four_decimals")]
+    pub operator_held: f64,
+}
+
+// Adds up the clients as they are written. They come in id order, so the sums are the same on every run.
+#[derive(Debug, Default)]
+pub struct Totals(BTreeMap<Currency, TotalsRow>);
+
+impl Totals {
+    pub fn add(&mut self, client: &Client) {
+        for row in client.rows() {
+            let totals = self.0.entry(row.currency).or_insert_with(|| TotalsRow { currency: row.currency, ..TotalsRow::default() });
+            totals.available += row.available;
+            totals.held += row.held;
+            totals.total += row.total;
+            totals.operator_held += row.operator_held;
+        }
+    }
+
+    // Ordered by currency
+    pub fn rows(&self) -> Vec<TotalsRow> {
+        self.0.values().cloned().collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+    // One JSON object per line
+    Jsonl,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = UnknownFormat;
+
+    fn from_str(s: &str) -> Result<OutputFormat, UnknownFormat> {
+        match s.trim().to_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" | "ndjson" => Ok(OutputFormat::Jsonl),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(OutputFormat::Parquet),
+            other => Err(UnknownFormat(other.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Jsonl => write!(f, "jsonl"),
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => write!(f, "parquet"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownFormat(pub String);
+
+impl fmt::Display for UnknownFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown output format: {}", self.0)
+    }
+}
+
+impl Error for UnknownFormat {}
+
+pub fn writer_for<'a, W: Write + Send + 'a>(format: OutputFormat, out: W, options: &SummaryOptions) -> Box<dyn SummaryWriter + 'a> {
+    match format {
+        OutputFormat::Csv => Box::new(CsvSummaryWriter::new(out).header(options.header)),
+        OutputFormat::Json => Box::new(JsonSummaryWriter::new(out)),
+        OutputFormat::Jsonl => Box::new(JsonLinesSummaryWriter::new(out)),
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => Box::new(parquet_writer::ParquetSummaryWriter::new(out)),
+    }
+}
+
+// The one place a summary is put together: the clients, which must come in id order, then the totals,
+// consolidated and operator sections the options ask for
+pub fn write<'a>(
+    out: &mut dyn SummaryWriter,
+    clients: impl IntoIterator<Item = &'a Client>,
+    operator: &OperatorAccount,
+    options: &SummaryOptions,
+) -> Result<(), Box<dyn Error>> {
+    out.write_header()?;
+    let mut totals = Totals::default();
+    let mut consolidated = vec![];
+    for client in clients {
+        out.write_client(client)?;
+        totals.add(client);
+        if let Some(rates) = &options.consolidated {
+            consolidated.push(rates.consolidate(client)?);
+        }
+    }
+    if options.totals {
+        out.write_totals(&totals.rows())?;
+    }
+    if options.consolidated.is_some() {
+        out.write_consolidated(&consolidated)?;
+    }
+    if options.operator {
+        out.write_operator(operator)?;
+    }
+    out.finish()
+}
+
+// One summary of ledgers holding disjoint sets of clients, e.g. the shards of a run, without merging
+// them: each ledger's clients are put in id order by reference and a k-way merge takes the lowest id
+// of the shards next, so no client or stored transaction is copied
+pub fn write_ledgers(ledgers: &[Ledger], out: &mut dyn SummaryWriter, options: &SummaryOptions) -> Result<(), Box<dyn Error>> {
+    let mut operator = OperatorAccount::default();
+    let mut shards = Vec::with_capacity(ledgers.len());
+    for ledger in ledgers {
+        operator.fees_earned += ledger.operator().fees_earned;
+        operator.chargeback_losses += ledger.operator().chargeback_losses;
+        let mut clients: Vec<&Client> = ledger.clients().collect();
+        clients.sort_unstable_by_key(|c| c.id);
+        shards.push(clients.into_iter().peekable());
+    }
+    let merged = std::iter::from_fn(|| {
+        let (_, next) = shards.iter_mut().enumerate().filter_map(|(i, shard)| shard.peek().map(|c| (c.id, i))).min()?;
+        shards[next].next()
+    });
+    write(out, merged, &operator, options)
+}
+
+pub struct CsvSummaryWriter<W: Write> {
+    wtr: Writer<W>,
+    header: bool,
+}
+
+impl<W: Write> CsvSummaryWriter<W> {
+    pub fn new(out: W) -> Self {
+        // Flexible so the operator section can follow the client rows with its own columns
+        Self { wtr: WriterBuilder::new().flexible(true).from_writer(out), header: true }
+    }
+
+    // Without a header row, e.g. for appending to an earlier summary
+    pub fn header(mut self, header: bool) -> Self {
+        self.header = header;
+        self
+    }
+}
+
+impl<W: Write> SummaryWriter for CsvSummaryWriter<W> {
+    fn write_header(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.header {
+            self.wtr.write_record(["client", "available", "held", "total", "locked", "tier", "operator_held", "currency"])?;
+        }
+        Ok(())
+    }
+
+    fn write_client(&mut self, client: &Client) -> Result<(), Box<dyn Error>> {
+        for row in client.rows() {
+            self.wtr.write_record(&[
+                row.client.to_string(),
+                format!("{:.4}", row.available),
+                format!("{:.4}", row.held),
+                format!("{:.4}", row.total),
+                row.locked.to_string(),
+                row.tier.to_string(),
+                format!("{:.4}", row.operator_held),
+                row.currency.to_string(),
+            ])?;
+        }
+        Ok(())
+    }
+
+    // In the client columns, with "totals" for the client id and nothing for locked and tier
+    fn write_totals(&mut self, totals: &[TotalsRow]) -> Result<(), Box<dyn Error>> {
+        for row in totals {
+            self.wtr.write_record(&[
+                "totals".to_string(),
+                format!("{:.4}", row.available),
+                format!("{:.4}", row.held),
+                format!("{:.4}", row.total),
+                String::new(),
+                String::new(),
+                format!("{:.4}", row.operator_held),
+                row.currency.to_string(),
+            ])?;
+        }
+        Ok(())
+    }
+
+    // Its own header (unless headers are off) and rows led by "consolidated", which can't be a client id
+    fn write_consolidated(&mut self, rows: &[ConsolidatedRow]) -> Result<(), Box<dyn Error>> {
+        if self.header {
+            self.wtr.write_record(["consolidated", "client", "available", "held", "total", "currency"])?;
+        }
+        for row in rows {
+            self.wtr.write_record(&[
+                "consolidated".to_string(),
+                row.client.to_string(),
+                format!("{:.4}", row.available),
+                format!("{:.4}", row.held),
+                format!("{:.4}", row.total),
+                row.currency.to_string(),
+            ])?;
+        }
+        Ok(())
+    }
+
+    // Its own header (unless headers are off) and row after the clients; the leading "operator" can't
+    // be a client id
+    fn write_operator(&mut self, operator: &OperatorAccount) -> Result<(), Box<dyn Error>> {
+        if self.header {
+            self.wtr.write_record(["operator", "fees_earned", "chargeback_losses", "net"])?;
+        }
+        self.wtr.write_record(&[
+            "operator".to_string(),
+            format!("{:.4}", operator.fees_earned),
+            format!("{:.4}", operator.chargeback_losses),
+            format!("{:.4}", operator.net()),
+        ])?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+// Streams clients as a single JSON array so the whole summary never has to sit in memory
+pub struct JsonSummaryWriter<W: Write> {
+    out: W,
+    first: bool,
+}
+
+impl<W: Write> JsonSummaryWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, first: true }
+    }
+}
+
+impl<W: Write> SummaryWriter for JsonSummaryWriter<W> {
+    fn write_header(&mut self) -> Result<(), Box<dyn Error>> {
+        self.out.write_all(b"[")?;
+        Ok(())
+    }
+
+    fn write_client(&mut self, client: &Client) -> Result<(), Box<dyn Error>> {
+        for row in client.rows() {
+            if !self.first {
+                self.out.write_all(b",")?;
+            }
+            self.first = false;
+            serde_json::to_writer(&mut self.out, &row)?;
+        }
+        Ok(())
+    }
+
+    // Told apart from client rows by its `totals` key
+    fn write_totals(&mut self, totals: &[TotalsRow]) -> Result<(), Box<dyn Error>> {
+        if !self.first {
+            self.out.write_all(b",")?;
+        }
+        self.first = false;
+        self.out.write_all(b"{\"totals\":")?;
+        serde_json::to_writer(&mut self.out, totals)?;
+        self.out.write_all(b"}")?;
+        Ok(())
+    }
+
+    // Told apart from client rows by its `consolidated` key
+    fn write_consolidated(&mut self, rows: &[ConsolidatedRow]) -> Result<(), Box<dyn Error>> {
+        if !self.first {
+            self.out.write_all(b",")?;
+        }
+        self.first = false;
+        self.out.write_all(b"{\"consolidated\":")?;
+        serde_json::to_writer(&mut self.out, rows)?;
+        self.out.write_all(b"}")?;
+        Ok(())
+    }
+
+    // Last element of the array, told apart from client rows by its `operator` key
+    fn write_operator(&mut self, operator: &OperatorAccount) -> Result<(), Box<dyn Error>> {
+        if !self.first {
+            self.out.write_all(b",")?;
+        }
+        self.first = false;
+        self.out.write_all(b"{\"operator\":")?;
+        serde_json::to_writer(&mut self.out, operator)?;
+        self.out.write_all(b"}")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.out.write_all(b"]\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// One client object per line, for consumers that process the summary as a stream
+pub struct JsonLinesSummaryWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonLinesSummaryWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write> SummaryWriter for JsonLinesSummaryWriter<W> {
+    fn write_header(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn write_client(&mut self, client: &Client) -> Result<(), Box<dyn Error>> {
+        for row in client.rows() {
+            serde_json::to_writer(&mut self.out, &row)?;
+            self.out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    // A line with a `totals` key, as in the JSON array
+    fn write_totals(&mut self, totals: &[TotalsRow]) -> Result<(), Box<dyn Error>> {
+        self.out.write_all(b"{\"totals\":")?;
+        serde_json::to_writer(&mut self.out, totals)?;
+        self.out.write_all(b"}\n")?;
+        Ok(())
+    }
+
+    // A line with a `consolidated` key, as in the JSON array
+    fn write_consolidated(&mut self, rows: &[ConsolidatedRow]) -> Result<(), Box<dyn Error>> {
+        self.out.write_all(b"{\"consolidated\":")?;
+        serde_json::to_writer(&mut self.out, rows)?;
+        self.out.write_all(b"}\n")?;
+        Ok(())
+    }
+
+    // A last line with an `operator` key, as in the JSON array
+    fn write_operator(&mut self, operator: &OperatorAccount) -> Result<(), Box<dyn Error>> {
+        self.out.write_all(b"{\"operator\":")?;
+        serde_json::to_writer(&mut self.out, operator)?;
+        self.out.write_all(b"}\n")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use std::error::Error;
+    use std::io::Write;
+    use std::sync::Arc;
+    use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int32Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    use super::SummaryWriter;
+    use crate::client::Client;
+
+    const SCHEMA: &str = "
+        message summary {
+            REQUIRED INT32 client (INTEGER(16, false));
+            REQUIRED DOUBLE available;
+            REQUIRED DOUBLE held;
+            REQUIRED DOUBLE total;
+            REQUIRED BOOLEAN locked;
+            REQUIRED BYTE_ARRAY tier (STRING);
+            REQUIRED DOUBLE operator_held;
+            REQUIRED BYTE_ARRAY currency (STRING);
+        }
+    ";
+
+    // Rows written between flushes; bounds memory to one row group's worth of columns
+    const ROW_GROUP_ROWS: usize = 64 * 1024;
+
+    // Parquet is columnar, so rows are buffered and written out a row group at a time
+    pub struct ParquetSummaryWriter<W: Write + Send> {
+        out: Option<W>,
+        writer: Option<SerializedFileWriter<W>>,
+        ids: Vec<i32>,
+        available: Vec<f64>,
+        held: Vec<f64>,
+        total: Vec<f64>,
+        locked: Vec<bool>,
+        tiers: Vec<ByteArray>,
+        operator_held: Vec<f64>,
+        currencies: Vec<ByteArray>,
+    }
+
+    impl<W: Write + Send> ParquetSummaryWriter<W> {
+        pub fn new(out: W) -> Self {
+            Self {
+                out: Some(out),
+                writer: None,
+                ids: vec![],
+                available: vec![],
+                held: vec![],
+                total: vec![],
+                locked: vec![],
+                tiers: vec![],
+                operator_held: vec![],
+                currencies: vec![],
+            }
+        }
+
+        fn flush_row_group(&mut self) -> Result<(), Box<dyn Error>> {
+            let writer = self.writer.as_mut().ok_or("parquet summary not started")?;
+            let mut row_group = writer.next_row_group()?;
+            if let Some(mut col) = row_group.next_column()? {
+                col.typed::<Int32Type>().write_batch(&self.ids, None, None)?;
+                col.close()?;
+            }
+            for values in [&self.available, &self.held, &self.total] {
+                if let Some(mut col) = row_group.next_column()? {
+                    col.typed::<DoubleType>().write_batch(values, None, None)?;
+                    col.close()?;
+                }
+            }
+            if let Some(mut col) = row_group.next_column()? {
+                col.typed::<BoolType>().write_batch(&self.locked, None, None)?;
+                col.close()?;
+            }
+            if let Some(mut col) = row_group.next_column()? {
+                col.typed::<ByteArrayType>().write_batch(&self.tiers, None, None)?;
+                col.close()?;
+            }
+            if let Some(mut col) = row_group.next_column()? {
+                col.typed::<DoubleType>().write_batch(&self.operator_held, None, None)?;
+                col.close()?;
+            }
+            if let Some(mut col) = row_group.next_column()? {
+                col.typed::<ByteArrayType>().write_batch(&self.currencies, None, None)?;
+                col.close()?;
+            }
+            row_group.close()?;
+
+            self.ids.clear();
+            self.available.clear();
+            self.held.clear();
+            self.total.clear();
+            self.locked.clear();
+            self.tiers.clear();
+            self.operator_held.clear();
+            self.currencies.clear();
+            Ok(())
+        }
+    }
+
+    impl<W: Write + Send> SummaryWriter for ParquetSummaryWriter<W> {
+        fn write_header(&mut self) -> Result<(), Box<dyn Error>> {
+            let out = self.out.take().ok_or("parquet summary already started")?;
+            let schema = Arc::new(parse_message_type(SCHEMA)?);
+            let props = Arc::new(WriterProperties::builder().build());
+            self.writer = Some(SerializedFileWriter::new(out, schema, props)?);
+            Ok(())
+        }
+
+        fn write_client(&mut self, client: &Client) -> Result<(), Box<dyn Error>> {
+            for row in client.rows() {
+                self.ids.push(row.client as i32);
+                self.available.push(row.available);
+                self.held.push(row.held);
+                self.total.push(row.total);
+                self.locked.push(row.locked);
+                self.tiers.push(row.tier.to_string().as_str().into());
+                self.operator_held.push(row.operator_held);
+                self.currencies.push(row.currency.to_string().as_str().into());
+                if self.ids.len() >= ROW_GROUP_ROWS {
+                    self.flush_row_group()?;
+                }
+            }
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+            if !self.ids.is_empty() {
+                self.flush_row_group()?;
+            }
+            let writer = self.writer.take().ok_or("parquet summary not started")?;
+            writer.close()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Balance, Currency};
+
+    fn sample_client() -> Client {
+        let mut client = Client::new(7);
+        *client.balance_mut(Currency::Usd) = Balance { available: 1.5, held: 0.25, total: 1.75, operator_held: 0.0 };
+        client
+    }
+
+    fn render(format: OutputFormat) -> String {
+        let mut buf = Vec::new();
+        {
+            let mut writer: Box<dyn SummaryWriter> = match format {
+                OutputFormat::Csv => Box::new(CsvSummaryWriter::new(&mut buf)),
+                OutputFormat::Jsonl => Box::new(JsonLinesSummaryWriter::new(&mut buf)),
+                _ => Box::new(JsonSummaryWriter::new(&mut buf)),
+            };
+            writer.write_header().unwrap();
+            writer.write_client(&sample_client()).unwrap();
+            writer.write_client(&Client::new(8)).unwrap();
+            writer.finish().unwrap();
+        }
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_csv_summary_writer_formats_four_decimals() {
+        assert_eq!(
+            render(OutputFormat::Csv),
+            "client,available,held,total,locked,tier,operator_held,currency\n7,1.5000,0.2500,1.7500,false,basic,0.0000,USD\n8,0.0000,0.0000,0.0000,false,basic,0.0000,USD\n"
+        );
+    }
+
+    #[test]
+    fn test_json_summary_writer_emits_array() {
+        let parsed: serde_json::Value = serde_json::from_str(&render(OutputFormat::Json)).unwrap();
+        let rows = parsed.as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["client"], 7);
+        assert_eq!(rows[0]["available"], 1.5);
+        assert_eq!(rows[0]["locked"], false);
+    }
+
+    #[test]
+    fn test_json_lines_summary_writer_emits_one_object_per_line() {
+        let out = render(OutputFormat::Jsonl);
+        let rows: Vec<serde_json::Value> = out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["held"], 0.25);
+        assert_eq!(rows[1]["client"], 8);
+    }
+
+    #[test]
+    fn test_summary_has_a_row_per_currency() {
+        let mut client = sample_client();
+        client.balance_mut(Currency::Eur).available = 2.0;
+        let mut buf = Vec::new();
+        {
+            let mut writer = JsonLinesSummaryWriter::new(&mut buf);
+            writer.write_client(&client).unwrap();
+        }
+        let rows: Vec<serde_json::Value> = String::from_utf8(buf).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(rows.iter().map(|r| (r["currency"].as_str().unwrap(), r["available"].as_f64().unwrap())).collect::<Vec<_>>(), vec![("USD", 1.5), ("EUR", 2.0)]);
+    }
+
+    #[test]
+    fn test_output_format_from_str() {
+        assert_eq!("JSON".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_write_ledgers_merges_the_shards_in_id_order() {
+        let mut shards = [Ledger::new(), Ledger::new()];
+        for (shard, id) in [(0, u16::MAX), (1, 3), (0, 0), (1, 5000)] {
+            shards[shard].process_transaction(&crate::test_util::TxBuilder::deposit(id, id as u32 + 1, 1.0).build()).unwrap();
+        }
+
+        let mut buf = Vec::new();
+        write_ledgers(&shards, &mut CsvSummaryWriter::new(&mut buf), &SummaryOptions::default()).unwrap();
+        let ids: Vec<String> = String::from_utf8(buf).unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().to_string())
+            .collect();
+        assert_eq!(ids, ["0", "3", "5000", "65535"]);
+    }
+
+    #[test]
+    fn test_summary_without_header_and_with_totals() {
+        let mut ledger = Ledger::new();
+        for (client, tx, amount) in [(2, 1, 1.5), (1, 2, 0.1), (1, 3, 0.2)] {
+            ledger.process_transaction(&crate::test_util::TxBuilder::deposit(client, tx, amount).build()).unwrap();
+        }
+        let options = SummaryOptions { header: false, totals: true, ..SummaryOptions::default() };
+        let mut snapshot = Vec::new();
+        ledger.snapshot().write_summary(&mut CsvSummaryWriter::new(&mut snapshot).header(false), &options).unwrap();
+        let ledger = [ledger];
+
+        let mut buf = Vec::new();
+        write_ledgers(&ledger, &mut CsvSummaryWriter::new(&mut buf).header(false), &options).unwrap();
+        let rows = "1,0.3000,0.0000,0.3000,false,basic,0.0000,USD\n2,1.5000,0.0000,1.5000,false,basic,0.0000,USD\n";
+        assert_eq!(String::from_utf8(buf.clone()).unwrap(), format!("{}totals,1.8000,0.0000,1.8000,,,0.0000,USD\n", rows));
+        assert_eq!(snapshot, buf);
+        let mut direct = Vec::new();
+        ledger[0].write_summary(&mut direct, &options).unwrap();
+        assert_eq!(direct, buf);
+        let mut buf = Vec::new();
+        write_ledgers(&ledger, &mut JsonLinesSummaryWriter::new(&mut buf), &options).unwrap();
+        assert!(String::from_utf8(buf).unwrap().ends_with(
+            "{\"totals\":[{\"currency\":\"USD\",\"available\":1.8,\"held\":0.0,\"total\":1.8,\"operator_held\":0.0}]}\n"
+        ));
+    }
+
+    #[test]
+    fn test_consolidated_section_follows_the_rows_in_their_own_currencies() {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(&crate::test_util::TxBuilder::deposit(1, 1, 2.0).currency(Currency::Eur).build()).unwrap();
+        ledger.process_transaction(&crate::test_util::TxBuilder::deposit(1, 2, 1.0).build()).unwrap();
+        let rates = RateTable { currency: Currency::Usd, rates: BTreeMap::from([(Currency::Eur, 1.25)]) };
+        let options = SummaryOptions { consolidated: Some(rates), ..SummaryOptions::default() };
+
+        let mut buf = Vec::new();
+        write_ledgers(&[ledger], &mut CsvSummaryWriter::new(&mut buf), &options).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "client,available,held,total,locked,tier,operator_held,currency\n\
+            1,1.0000,0.0000,1.0000,false,basic,0.0000,USD\n1,2.0000,0.0000,2.0000,false,basic,0.0000,EUR\n\
+            consolidated,client,available,held,total,currency\nconsolidated,1,3.5000,0.0000,3.5000,USD\n");
+    }
+
+    #[test]
+    fn test_csv_summary_writer_appends_operator_section() {
+        let mut buf = Vec::new();
+        {
+            let mut writer = CsvSummaryWriter::new(&mut buf);
+            writer.write_header().unwrap();
+            writer.write_client(&Client::new(8)).unwrap();
+            writer.write_operator(&OperatorAccount { fees_earned: 1.5, chargeback_losses: 0.25 }).unwrap();
+            writer.finish().unwrap();
+        }
+        assert!(String::from_utf8(buf).unwrap().ends_with(
+            "8,0.0000,0.0000,0.0000,false,basic,0.0000,USD\noperator,fees_earned,chargeback_losses,net\noperator,1.5000,0.2500,1.2500\n"
+        ));
+
+        // Without headers the section is the row alone
+        let mut buf = Vec::new();
+        {
+            let mut writer = CsvSummaryWriter::new(&mut buf).header(false);
+            writer.write_header().unwrap();
+            writer.write_operator(&OperatorAccount { fees_earned: 1.5, chargeback_losses: 0.25 }).unwrap();
+            writer.finish().unwrap();
+        }
+        assert_eq!(String::from_utf8(buf).unwrap(), "operator,1.5000,0.2500,1.2500\n");
+    }
+}
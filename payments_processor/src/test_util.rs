@@ -0,0 +1,191 @@
+//! Fixtures for putting a `Ledger` into a known state without replaying CSV strings.
+//! Enabled in downstream crates with the `test-util` feature.
+
+use std::collections::BTreeMap;
+
+use crate::client::{Currency, Tier};
+use crate::ledger::Ledger;
+use crate::transaction::{PaymentStatus, Transaction, TxType};
+
+pub struct TxBuilder {
+    tx: Transaction,
+}
+
+impl TxBuilder {
+    pub fn new(tx_type: TxType, client_id: u16, tx_id: u32) -> Self {
+        Self {
+            tx: Transaction {
+                tx_type,
+                client_id,
+                tx_id,
+                amount: None,
+                currency: None,
+                status: PaymentStatus::Posted,
+                disputed: None,
+                shortfall: None,
+                attributes: BTreeMap::new(),
+            },
+        }
+    }
+
+    pub fn deposit(client_id: u16, tx_id: u32, amount: f64) -> Self {
+        Self::new(TxType::Deposit, client_id, tx_id).amount(amount)
+    }
+
+    pub fn withdrawal(client_id: u16, tx_id: u32, amount: f64) -> Self {
+        Self::new(TxType::Withdrawal, client_id, tx_id).amount(amount)
+    }
+
+    pub fn dispute(client_id: u16, tx_id: u32) -> Self {
+        Self::new(TxType::Dispute, client_id, tx_id)
+    }
+
+    pub fn resolve(client_id: u16, tx_id: u32) -> Self {
+        Self::new(TxType::Resolve, client_id, tx_id)
+    }
+
+    pub fn chargeback(client_id: u16, tx_id: u32) -> Self {
+        Self::new(TxType::Chargeback, client_id, tx_id)
+    }
+
+    pub fn set_tier(client_id: u16, tx_id: u32, tier: Tier) -> Self {
+        Self::new(TxType::SetTier(tier), client_id, tx_id)
+    }
+
+    pub fn hold(client_id: u16, tx_id: u32, amount: f64) -> Self {
+        Self::new(TxType::Hold, client_id, tx_id).amount(amount)
+    }
+
+    pub fn release(client_id: u16, tx_id: u32) -> Self {
+        Self::new(TxType::Release, client_id, tx_id)
+    }
+
+    pub fn annul(client_id: u16, tx_id: u32, reason: &str) -> Self {
+        Self::new(TxType::Annul(reason.to_string()), client_id, tx_id)
+    }
+
+    pub fn transfer(client_id: u16, tx_id: u32, destination: u16, amount: f64) -> Self {
+        Self::new(TxType::Transfer(destination), client_id, tx_id).amount(amount)
+    }
+
+    pub fn lock(client_id: u16, tx_id: u32) -> Self {
+        Self::new(TxType::Lock, client_id, tx_id)
+    }
+
+    pub fn unlock(client_id: u16, tx_id: u32) -> Self {
+        Self::new(TxType::Unlock, client_id, tx_id)
+    }
+
+    pub fn amount(mut self, amount: f64) -> Self {
+        self.tx.amount = Some(amount);
+        self
+    }
+
+    pub fn currency(mut self, currency: Currency) -> Self {
+        self.tx.currency = Some(currency);
+        self
+    }
+
+    pub fn status(mut self, status: PaymentStatus) -> Self {
+        self.tx.status = status;
+        self
+    }
+
+    pub fn build(self) -> Transaction {
+        self.tx
+    }
+}
+
+pub struct LedgerBuilder {
+    ledger: Ledger,
+    // Generated tx ids count down from u32::MAX so they don't clash with ids passed to `apply`
+    next_tx_id: u32,
+}
+
+impl Default for LedgerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LedgerBuilder {
+    pub fn new() -> Self {
+        Self { ledger: Ledger::new(), next_tx_id: u32::MAX }
+    }
+
+    /// Applies a transaction through the normal ledger rules, panicking if it is rejected.
+    pub fn apply(mut self, tx: impl Into<Transaction>) -> Self {
+        let tx = tx.into();
+        if let Err(e) = self.ledger.process_transaction(&tx) {
+            panic!("LedgerBuilder: transaction {} rejected: {}", tx.tx_id, e);
+        }
+        self
+    }
+
+    pub fn funded_client(mut self, client_id: u16, amount: f64) -> Self {
+        let tx_id = self.next_id();
+        self.apply(TxBuilder::deposit(client_id, tx_id, amount))
+    }
+
+    /// Deposits `amount` for the client and immediately disputes it.
+    pub fn open_dispute(mut self, client_id: u16, amount: f64) -> Self {
+        let tx_id = self.next_id();
+        self.apply(TxBuilder::deposit(client_id, tx_id, amount))
+            .apply(TxBuilder::dispute(client_id, tx_id))
+    }
+
+    /// Marks the client as locked without touching its balances.
+    pub fn locked_client(mut self, client_id: u16) -> Self {
+        self.ledger.clients_mut().add_client(client_id).locked = true;
+        self
+    }
+
+    pub fn build(self) -> Ledger {
+        self.ledger
+    }
+
+    fn next_id(&mut self) -> u32 {
+        let id = self.next_tx_id;
+        self.next_tx_id -= 1;
+        id
+    }
+}
+
+impl From<TxBuilder> for Transaction {
+    fn from(builder: TxBuilder) -> Self {
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_sets_up_funded_disputed_and_locked_clients() {
+        let ledger = LedgerBuilder::new()
+            .funded_client(1, 10.0)
+            .open_dispute(2, 5.0)
+            .locked_client(3)
+            .apply(TxBuilder::deposit(4, 1, 2.5))
+            .build();
+
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, 10.0);
+
+        let disputed = ledger.client(2).unwrap().balance(Currency::Usd);
+        assert_eq!(disputed.available, 0.0);
+        assert_eq!(disputed.held, 5.0);
+
+        let locked = ledger.client(3).unwrap();
+        assert!(locked.locked);
+        assert_eq!(locked.balance(Currency::Usd).total, 0.0);
+
+        assert_eq!(ledger.transaction(1).unwrap().unwrap().amount, Some(2.5));
+    }
+
+    #[test]
+    #[should_panic(expected = "rejected")]
+    fn test_builder_panics_on_rejected_transaction() {
+        LedgerBuilder::new().apply(TxBuilder::withdrawal(1, 1, 5.0));
+    }
+}
@@ -1,33 +1,172 @@
+use std::collections::BTreeMap;
 use std::fmt;
 use std::error::Error;
 use csv::StringRecord;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, PartialEq, Debug)]
+use crate::client::{Currency, Tier};
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TxType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    // Admin records (see `is_admin`) are only applied by ledgers that allow admin operations.
+    // Admin record: `tier,<client>,<tx>,<basic|verified|premium>`
+    #[serde(rename = "tier")]
+    SetTier(Tier),
+    // Admin records: `hold,<client>,<tx>,<amount>` ring-fences part of the available balance, and
+    // `release,<client>,<tx>` gives back the hold with that tx id
+    Hold,
+    Release,
+    // Admin record: `annul,<client>,<tx>,<reason>` reverses a deposit or withdrawal that should never
+    // have been applied (e.g. upstream sent a wrong file), keeping it in the ledger marked as annulled
+    Annul(String),
+    // `transfer,<client>,<tx>,<amount>,<destination>` moves funds from the client to the destination client
+    Transfer(u16),
+    // Admin records: `lock,<client>,<tx>` freezes an account by hand and `unlock,<client>,<tx>` lifts
+    // a lock, including one set by a chargeback
+    Lock,
+    Unlock,
 }
 
 impl TxType {
-    fn from_str(s: &str) -> Result<TxType, TransactionError> {
+    pub(crate) fn from_str(s: &str) -> Result<TxType, TransactionError> {
         match s.trim().to_lowercase().as_str() {
             "deposit" => Ok(TxType::Deposit),
             "withdrawal" => Ok(TxType::Withdrawal),
             "dispute" => Ok(TxType::Dispute),
             "resolve" => Ok(TxType::Resolve),
             "chargeback" => Ok(TxType::Chargeback),
+            "hold" => Ok(TxType::Hold),
+            "release" => Ok(TxType::Release),
+            "lock" => Ok(TxType::Lock),
+            "unlock" => Ok(TxType::Unlock),
             other => Err(TransactionError::UnknownTxType(other.to_string())),
         }
     }
+
+    // The record type as written in the input
+    pub fn name(&self) -> &'static str {
+        match self {
+            TxType::Deposit => "deposit",
+            TxType::Withdrawal => "withdrawal",
+            TxType::Dispute => "dispute",
+            TxType::Resolve => "resolve",
+            TxType::Chargeback => "chargeback",
+            TxType::SetTier(_) => "tier",
+            TxType::Hold => "hold",
+            TxType::Release => "release",
+            TxType::Annul(_) => "annul",
+            TxType::Transfer(_) => "transfer",
+            TxType::Lock => "lock",
+            TxType::Unlock => "unlock",
+        }
+    }
+
+    // Records only an operator may send: a client's own feed could otherwise annul its withdrawals,
+    // move itself to a tier with higher limits or release a hold
+    pub fn is_admin(&self) -> bool {
+        matches!(self, TxType::SetTier(_) | TxType::Hold | TxType::Release | TxType::Annul(_) | TxType::Lock | TxType::Unlock)
+    }
+
+    // Admin types whose amount column holds a value of their own rather than an amount
+    pub(crate) fn carries_value(&self) -> bool {
+        matches!(self, TxType::SetTier(_) | TxType::Annul(_))
+    }
+
+    // What `carries_value` types hold instead of an amount, as written in the input
+    pub(crate) fn value(&self) -> Option<String> {
+        match self {
+            TxType::SetTier(tier) => Some(tier.to_string()),
+            TxType::Annul(reason) => Some(reason.clone()),
+            TxType::Transfer(destination) => Some(destination.to_string()),
+            _ => None,
+        }
+    }
+
+    // Types whose amount moves funds; any amount on the other types is ignored by the ledger, except
+    // on disputes (see `is_dispute`)
+    pub(crate) fn moves_funds(&self) -> bool {
+        matches!(self, TxType::Deposit | TxType::Withdrawal | TxType::Hold | TxType::Transfer(_))
+    }
+
+    // Dispute, resolve and chargeback, whose optional amount is the part of the referenced deposit
+    // they act on
+    pub(crate) fn is_dispute(&self) -> bool {
+        matches!(self, TxType::Dispute | TxType::Resolve | TxType::Chargeback)
+    }
+
+    // Like from_str, but also handles admin types that carry their value in the amount column
+    pub(crate) fn parse(s: &str, value: Option<&str>) -> Result<TxType, TransactionError> {
+        match s.trim().to_lowercase().as_str() {
+            "tier" => {
+                let value = value.unwrap_or("").trim();
+                let tier = value.parse().map_err(|_| TransactionError::UnknownTier(value.to_string()))?;
+                Ok(TxType::SetTier(tier))
+            }
+            "annul" => Ok(TxType::Annul(value.unwrap_or("").trim().to_string())),
+            "transfer" => {
+                let destination = value.unwrap_or("").trim().parse()
+                    .map_err(|e| TransactionError::ParseError { field: "destination".to_string(), source: Box::new(e) })?;
+                Ok(TxType::Transfer(destination))
+            }
+            other => TxType::from_str(other),
+        }
+    }
+
+    // Like parse, for an input record: transfers take their value from the destination column, the
+    // other types from the amount column
+    pub(crate) fn parse_input(s: &str, amount: Option<&str>, destination: Option<&str>) -> Result<TxType, TransactionError> {
+        let value = if s.trim().eq_ignore_ascii_case("transfer") { destination } else { amount };
+        TxType::parse(s, value)
+    }
 }
 
+// Where a transaction is in the dispute lifecycle: Posted -> Disputed -> Resolved | ChargedBack. Resolved
+// and ChargedBack are final, so a transaction can be disputed once. Only deposits are ever disputed.
 #[derive(Clone, PartialEq, Debug)]
 pub enum PaymentStatus {
+    Posted,
     Disputed,
-    Undisputed,
+    Resolved,
+    ChargedBack,
+    // Reversed by an annul record, with its reason; kept for audit but no longer disputable
+    Annulled(String),
+}
+
+impl PaymentStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PaymentStatus::Posted => "posted",
+            PaymentStatus::Disputed => "disputed",
+            PaymentStatus::Resolved => "resolved",
+            PaymentStatus::ChargedBack => "charged_back",
+            PaymentStatus::Annulled(_) => "annulled",
+        }
+    }
+
+    // From `name`, with the reason of an annulment; "undisputed" is what stores written before
+    // resolutions were final call a posted transaction
+    pub(crate) fn from_name(name: &str, reason: Option<String>) -> Option<PaymentStatus> {
+        match name {
+            "posted" | "undisputed" => Some(PaymentStatus::Posted),
+            "disputed" => Some(PaymentStatus::Disputed),
+            "resolved" => Some(PaymentStatus::Resolved),
+            "charged_back" => Some(PaymentStatus::ChargedBack),
+            "annulled" => Some(PaymentStatus::Annulled(reason.unwrap_or_default())),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PaymentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -36,14 +175,73 @@ pub struct Transaction {
     pub tx_id: u32,
     pub client_id: u16,
     pub amount: Option<f64>,
+    // None for records without a currency: the default currency for those that create a transaction,
+    // and the referenced transaction's for disputes, resolves, chargebacks, releases and annulments
+    pub currency: Option<Currency>,
     pub status: PaymentStatus,
+    // The part of a disputed deposit still under dispute; None when it isn't disputed, or when all of
+    // it is (as in stores and checkpoints written before partial disputes)
+    pub disputed: Option<f64>,
+    // The part of an open dispute the ledger couldn't hold under `DisputeFundsPolicy::Cap`, the client's
+    // available funds being short; a chargeback books it as an operator loss
+    pub shortfall: Option<f64>,
+    // Reference-data fields added at ingest by `enrichment::Enricher`, e.g. "country"
+    pub attributes: BTreeMap<String, String>,
+}
+
+// One CSV row mapped by header name, so column order and extra columns don't matter. `amount` stays a
+// string because admin records carry their value there (e.g. the tier name).
+#[derive(Debug, Deserialize)]
+pub struct RawTransaction {
+    #[serde(rename = "type")]
+    pub tx_type: String,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(default)]
+    pub amount: Option<String>,
+    #[serde(default)]
+    pub destination: Option<String>,
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+impl TryFrom<RawTransaction> for Transaction {
+    type Error = TransactionError;
+
+    fn try_from(raw: RawTransaction) -> Result<Transaction, TransactionError> {
+        let tx_type = TxType::parse_input(&raw.tx_type, raw.amount.as_deref(), raw.destination.as_deref())?;
+        let amount = match (&tx_type, raw.amount.as_deref().map(str::trim)) {
+            (tx_type, _) if tx_type.carries_value() => None,
+            (_, None | Some("")) => None,
+            (_, Some(amount)) => Some(amount.parse()
+                .map_err(|e| TransactionError::ParseError { field: "amount".to_string(), source: Box::new(e) })?),
+        };
+        let mut tx = Transaction::new(tx_type, raw.client, raw.tx, amount);
+        tx.currency = parse_currency(raw.currency.as_deref())?;
+        tx.validate()
+    }
+}
+
+// A record whose type this build doesn't know, kept verbatim (CSV fields re-joined with commas, or the
+// JSON line) so a plugin can interpret it under the `plugin` unknown-record policy
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnknownRecord {
+    pub tx_type: String,
+    pub raw: String,
 }
 
 #[derive(Debug)]
 pub enum TransactionError {
     TooFewFields(Vec<String>),
     UnknownTxType(String),
-    ParseError { field: String, source: Box<dyn Error> },
+    UnknownTier(String),
+    UnknownCurrency(String),
+    InvalidAmount(f64),
+    NegativeAmount(f64),
+    ZeroAmount,
+    // More than the 4 decimal places balances are kept to
+    TooPrecise(f64),
+    ParseError { field: String, source: Box<dyn Error + Send + Sync> },
 }
 
 impl fmt::Display for TransactionError {
@@ -51,6 +249,12 @@ impl fmt::Display for TransactionError {
         match self {
             TransactionError::TooFewFields(fields) => write!(f, "Too few fields: {:?}", fields),
             TransactionError::UnknownTxType(s) => write!(f, "Unknown transaction type: {}", s),
+            TransactionError::UnknownTier(s) => write!(f, "Unknown client tier: {:?}", s),
+            TransactionError::UnknownCurrency(s) => write!(f, "Unknown currency: {:?}", s),
+            TransactionError::InvalidAmount(amount) => write!(f, "Invalid amount: {} (must be a finite number)", amount),
+            TransactionError::NegativeAmount(amount) => write!(f, "Negative amount: {}", amount),
+            TransactionError::ZeroAmount => write!(f, "Amount must not be zero"),
+            TransactionError::TooPrecise(amount) => write!(f, "Amount {} has more than 4 decimal places", amount),
             TransactionError::ParseError { field, source } => write!(f, "Failed to parse {}: {}", field, source),
         }
     }
@@ -59,6 +263,81 @@ impl fmt::Display for TransactionError {
 impl Error for TransactionError {}
 
 impl Transaction {
+    // Typed constructors for library users; amounts are checked here rather than when the ledger
+    // applies the transaction
+    pub fn deposit(client_id: u16, tx_id: u32, amount: f64) -> Result<Transaction, TransactionError> {
+        Ok(Transaction::new(TxType::Deposit, client_id, tx_id, Some(valid_amount(amount)?)))
+    }
+
+    pub fn withdrawal(client_id: u16, tx_id: u32, amount: f64) -> Result<Transaction, TransactionError> {
+        Ok(Transaction::new(TxType::Withdrawal, client_id, tx_id, Some(valid_amount(amount)?)))
+    }
+
+    pub fn dispute(client_id: u16, tx_id: u32) -> Transaction {
+        Transaction::new(TxType::Dispute, client_id, tx_id, None)
+    }
+
+    pub fn resolve(client_id: u16, tx_id: u32) -> Transaction {
+        Transaction::new(TxType::Resolve, client_id, tx_id, None)
+    }
+
+    pub fn chargeback(client_id: u16, tx_id: u32) -> Transaction {
+        Transaction::new(TxType::Chargeback, client_id, tx_id, None)
+    }
+
+    pub fn set_tier(client_id: u16, tx_id: u32, tier: Tier) -> Transaction {
+        Transaction::new(TxType::SetTier(tier), client_id, tx_id, None)
+    }
+
+    pub fn hold(client_id: u16, tx_id: u32, amount: f64) -> Result<Transaction, TransactionError> {
+        Ok(Transaction::new(TxType::Hold, client_id, tx_id, Some(valid_amount(amount)?)))
+    }
+
+    pub fn release(client_id: u16, tx_id: u32) -> Transaction {
+        Transaction::new(TxType::Release, client_id, tx_id, None)
+    }
+
+    pub fn annul(client_id: u16, tx_id: u32, reason: &str) -> Transaction {
+        Transaction::new(TxType::Annul(reason.to_string()), client_id, tx_id, None)
+    }
+
+    pub fn transfer(client_id: u16, tx_id: u32, destination: u16, amount: f64) -> Result<Transaction, TransactionError> {
+        Ok(Transaction::new(TxType::Transfer(destination), client_id, tx_id, Some(valid_amount(amount)?)))
+    }
+
+    pub fn lock(client_id: u16, tx_id: u32) -> Transaction {
+        Transaction::new(TxType::Lock, client_id, tx_id, None)
+    }
+
+    pub fn unlock(client_id: u16, tx_id: u32) -> Transaction {
+        Transaction::new(TxType::Unlock, client_id, tx_id, None)
+    }
+
+    // Limits a dispute, resolve or chargeback to part of the deposit, e.g. 30 of a 100 deposit
+    pub fn for_amount(mut self, amount: f64) -> Result<Transaction, TransactionError> {
+        self.amount = Some(valid_amount(amount)?);
+        Ok(self)
+    }
+
+    // The typed constructors create transactions in the default currency
+    pub fn in_currency(mut self, currency: Currency) -> Transaction {
+        self.currency = Some(currency);
+        self
+    }
+
+    pub(crate) fn new(tx_type: TxType, client_id: u16, tx_id: u32, amount: Option<f64>) -> Transaction {
+        Transaction { tx_type, client_id, tx_id, amount, currency: None, status: PaymentStatus::Posted, disputed: None, shortfall: None, attributes: BTreeMap::new() }
+    }
+
+    // Checks the amount of a parsed record the same way the typed constructors do, so a negative
+    // deposit or a sub-cent fraction never reaches the ledger
+    pub fn validate(self) -> Result<Transaction, TransactionError> {
+        if let (true, Some(amount)) = (self.tx_type.moves_funds() || self.tx_type.is_dispute(), self.amount) {
+            valid_amount(amount)?;
+        }
+        Ok(self)
+    }
+
     pub fn create_transaction(record: &StringRecord) -> Result<Transaction, TransactionError> {
         let fields: Vec<String> = record.iter().map(|f| f.trim().to_string()).collect();
 
@@ -66,21 +345,52 @@ impl Transaction {
             return Err(TransactionError::TooFewFields(fields));
         }
 
-        let tx_type = TxType::from_str(&fields[0])?;
+        let tx_type = TxType::parse_input(&fields[0], fields.get(3).map(String::as_str), fields.get(4).map(String::as_str))?;
         let client_id = fields[1].parse()
             .map_err(|e| TransactionError::ParseError { field: "client_id".to_string(), source: Box::new(e) })?;
         let tx_id = fields[2].parse()
             .map_err(|e| TransactionError::ParseError { field: "tx_id".to_string(), source: Box::new(e) })?;
 
-        let amount = if fields.len() >= 4 && !fields[3].is_empty() {
+        let amount = if tx_type.carries_value() {
+            None
+        } else if fields.len() >= 4 && !fields[3].is_empty() {
             Some(fields[3].parse()
                 .map_err(|e| TransactionError::ParseError { field: "amount".to_string(), source: Box::new(e) })?)
         } else {
             None
         };
 
-        Ok(Transaction { tx_type, client_id, tx_id, amount, status: PaymentStatus::Undisputed })
+        let mut tx = Transaction::new(tx_type, client_id, tx_id, amount);
+        tx.currency = parse_currency(fields.get(5).map(String::as_str))?;
+        tx.validate()
+    }
+}
+
+// An empty or missing currency column leaves the currency unset
+pub(crate) fn parse_currency(value: Option<&str>) -> Result<Option<Currency>, TransactionError> {
+    match value.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(currency) => currency.parse().map(Some).map_err(TransactionError::UnknownCurrency),
+    }
+}
+
+fn valid_amount(amount: f64) -> Result<f64, TransactionError> {
+    if !amount.is_finite() {
+        return Err(TransactionError::InvalidAmount(amount));
+    }
+    if amount < 0.0 {
+        return Err(TransactionError::NegativeAmount(amount));
+    }
+    if amount == 0.0 {
+        return Err(TransactionError::ZeroAmount);
+    }
+    // Checked on the value rather than the text so JSON numbers and typed constructors are covered;
+    // the tolerance absorbs float noise such as 1.0001 * 10000 = 10001.000000000002
+    let scaled = amount * 10_000.0;
+    if (scaled - scaled.round()).abs() > 1e-6 {
+        return Err(TransactionError::TooPrecise(amount));
     }
+    Ok(amount)
 }
 
 #[cfg(test)]
@@ -130,4 +440,65 @@ mod tests {
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_create_transaction_tier_admin_record() {
+        let record = StringRecord::from(vec!["tier", "3", "10", "Premium"]);
+        let tx = Transaction::create_transaction(&record).unwrap();
+        assert_eq!(tx.tx_type, TxType::SetTier(Tier::Premium));
+        assert_eq!(tx.amount, None);
+
+        let record = StringRecord::from(vec!["tier", "3", "10", "gold"]);
+        let err = Transaction::create_transaction(&record).unwrap_err();
+        assert!(matches!(err, TransactionError::UnknownTier(s) if s == "gold"));
+
+        let record = StringRecord::from(vec!["annul", "3", "10", " duplicate file "]);
+        let tx = Transaction::create_transaction(&record).unwrap();
+        assert_eq!((tx.tx_type, tx.amount), (TxType::Annul("duplicate file".to_string()), None));
+    }
+
+    #[test]
+    fn test_create_transaction_transfer_reads_the_destination_column() {
+        let record = StringRecord::from(vec!["transfer", "1", "7", "2.5", "4"]);
+        let tx = Transaction::create_transaction(&record).unwrap();
+        assert_eq!((tx.tx_type, tx.client_id, tx.amount), (TxType::Transfer(4), 1, Some(2.5)));
+
+        let record = StringRecord::from(vec!["transfer", "1", "8", "2.5"]);
+        let err = Transaction::create_transaction(&record).unwrap_err();
+        assert!(matches!(err, TransactionError::ParseError { field, .. } if field == "destination"));
+        assert!(matches!(Transaction::transfer(1, 9, 4, -1.0), Err(TransactionError::NegativeAmount(_))));
+    }
+
+    #[test]
+    fn test_create_transaction_reads_an_optional_currency() {
+        let parse = |fields: Vec<&str>| Transaction::create_transaction(&StringRecord::from(fields));
+        assert_eq!(parse(vec!["deposit", "1", "1", "1.0", "", "eur"]).unwrap().currency, Some(Currency::Eur));
+        assert_eq!(parse(vec!["deposit", "1", "1", "1.0"]).unwrap().currency, None);
+        assert!(matches!(parse(vec!["dispute", "1", "1", "", "", "JPY"]), Err(TransactionError::UnknownCurrency(c)) if c == "JPY"));
+    }
+
+    #[test]
+    fn test_typed_constructors_validate_amounts() {
+        let tx = Transaction::deposit(3, 9, 12.5).unwrap();
+        assert_eq!((tx.tx_type, tx.client_id, tx.tx_id, tx.amount), (TxType::Deposit, 3, 9, Some(12.5)));
+        assert_eq!(tx.status, PaymentStatus::Posted);
+
+        assert!(matches!(Transaction::withdrawal(3, 10, -1.0), Err(TransactionError::NegativeAmount(_))));
+        assert!(matches!(Transaction::deposit(3, 11, f64::NAN), Err(TransactionError::InvalidAmount(_))));
+        assert_eq!(Transaction::chargeback(3, 9).amount, None);
+    }
+
+    #[test]
+    fn test_parsed_amounts_are_validated() {
+        let parse = |fields: Vec<&str>| Transaction::create_transaction(&StringRecord::from(fields));
+        assert!(matches!(parse(vec!["deposit", "1", "1", "-5.0"]), Err(TransactionError::NegativeAmount(_))));
+        assert!(matches!(parse(vec!["withdrawal", "1", "2", "0"]), Err(TransactionError::ZeroAmount)));
+        assert!(matches!(parse(vec!["hold", "1", "3", "1.00001"]), Err(TransactionError::TooPrecise(_))));
+        assert_eq!(parse(vec!["deposit", "1", "4", "1.0001"]).unwrap().amount, Some(1.0001));
+        assert_eq!(parse(vec!["deposit", "1", "5", "12345678.9999"]).unwrap().amount, Some(12345678.9999));
+        // Disputes may name the part of the deposit they act on, checked like any amount
+        assert!(matches!(parse(vec!["dispute", "1", "1", "0"]), Err(TransactionError::ZeroAmount)));
+        assert_eq!(parse(vec!["resolve", "1", "1", "30"]).unwrap().amount, Some(30.0));
+        // Amounts on other records that don't move funds are ignored, as before
+        assert!(parse(vec!["lock", "1", "1", "0"]).is_ok());
+    }
+}
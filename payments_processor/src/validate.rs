@@ -0,0 +1,48 @@
+use crate::enrichment::Enricher;
+use crate::ledger::Ledger;
+use crate::rejects::Reject;
+use crate::source::{SourceError, TransactionSource};
+
+// Dry run behind `validate`: applies one input's records to `ledger` the way `process` would and adds
+// every record that couldn't be read or would be rejected to `problems`, with its line. Returns the
+// number of records read. Meant for a throwaway ledger without hooks, so nothing is notified,
+// journaled or stored; later records are checked against the balances the earlier ones left.
+pub fn validate_source<S: TransactionSource + ?Sized>(ledger: &mut Ledger, enricher: &Enricher, input: &str, source: &mut S, problems: &mut Vec<Reject>) -> u64 {
+    let mut records = 0;
+    while let Some(result) = source.next() {
+        records += 1;
+        let error = match result {
+            Ok(mut tx) => {
+                enricher.enrich(&mut tx);
+                ledger.process_transaction(&tx).err().map(|e| e.to_string())
+            }
+            Err(SourceError::UnknownRecord(record)) => ledger.handle_unknown(&record).err().map(|e| e.to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(error) = error {
+            problems.push(Reject { input: input.to_string(), line: source.line(), record: source.raw(), error });
+        }
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::CsvSource;
+
+    #[test]
+    fn test_validate_reports_unreadable_and_rejected_records_with_lines() {
+        let data = "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,50\ndeposit,1\ndispute,1,9,\nwithdrawal,1,3,4\n";
+        let mut ledger = Ledger::new();
+        let mut problems = vec![];
+        let records = validate_source(&mut ledger, &Enricher::default(), "in.csv", &mut CsvSource::from_reader(data.as_bytes()), &mut problems);
+
+        assert_eq!(records, 5);
+        let found: Vec<(Option<u64>, Option<&str>)> = problems.iter().map(|p| (p.line, p.record.as_deref())).collect();
+        assert_eq!(found, vec![(Some(3), Some("withdrawal,1,2,50")), (Some(4), Some("deposit,1")), (Some(5), Some("dispute,1,9,"))]);
+        assert!(problems[0].error.contains("insufficient funds"));
+        // The accepted records were applied, so the last withdrawal was checked against them
+        assert_eq!(ledger.client(1).unwrap().balance(crate::client::Currency::Usd).available, 6.0);
+    }
+}
@@ -0,0 +1,130 @@
+// `verify`: checks a checkpoint on its own (every line readable, invariants hold) and, given the journal
+// of the run, against the state the journal's entries up to the checkpoint rebuild independently
+
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use crate::checkpoint::{self, CheckpointError};
+use crate::diff::{self, Change, ClientDelta};
+use crate::invariants::{self, InvariantViolation};
+use crate::journal::{self, JournalError};
+use crate::ledger::{DisputeFundsPolicy, Ledger, LedgerError};
+
+// The first problem `verify` ran into
+#[derive(Debug)]
+pub enum Finding {
+    Corrupt(String),
+    Invariant(InvariantViolation),
+    // A journal entry recorded as accepted that the rebuilt ledger rejects
+    Diverged { seq: u64, error: LedgerError },
+    // A balance of the checkpoint that differs from the rebuilt one; the delta is rebuilt - checkpoint
+    Balance(ClientDelta),
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Corrupt(e) => write!(f, "{}", e),
+            Finding::Invariant(e) => write!(f, "Invariant violated: {}", e),
+            Finding::Diverged { seq, error } => write!(f, "Journal entry {} was accepted but is rejected on replay: {}", seq, error),
+            Finding::Balance(d) if d.change == Change::Added => write!(f, "Client {} {} is in the journal but not in the checkpoint", d.client, d.currency),
+            Finding::Balance(d) if d.change == Change::Removed => write!(f, "Client {} {} is in the checkpoint but not in the journal", d.client, d.currency),
+            Finding::Balance(d) => write!(
+                f,
+                "Client {} {}: the journal gives available {:+}, held {:+}, total {:+} against the checkpoint{}",
+                d.client,
+                d.currency,
+                d.available,
+                d.held,
+                d.total,
+                if d.newly_locked { ", and a lock it doesn't have" } else { "" }
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub clients: usize,
+    // Journal entries applied, when a journal was given
+    pub replayed: Option<u64>,
+    // The last journal entry the checkpoint includes; None if it doesn't say, and the whole journal was replayed
+    pub journal_seq: Option<u64>,
+    pub finding: Option<Finding>,
+}
+
+// `restored` and `rebuilt` are empty ledgers configured like the run's. Only I/O errors are errors;
+// everything wrong with the files themselves is the report's finding.
+pub fn verify(checkpoint: &Path, journal: Option<&Path>, mut restored: Ledger, mut rebuilt: Ledger) -> Result<VerifyReport, Box<dyn Error>> {
+    let mut report = VerifyReport::default();
+    match restored.restore(checkpoint) {
+        Ok(_) => {}
+        Err(e @ CheckpointError::Corrupt { .. }) => return Ok(VerifyReport { finding: Some(Finding::Corrupt(e.to_string())), ..report }),
+        Err(e) => return Err(e.into()),
+    }
+    report.clients = restored.clients().count();
+    match invariants::check(&restored) {
+        // Disputing funds that were already withdrawn does this under the default policy
+        Err(InvariantViolation::Overdrawn { .. }) if restored.config().dispute_funds == DisputeFundsPolicy::Allow => {}
+        Err(e) => {
+            report.finding = Some(Finding::Invariant(e));
+            return Ok(report);
+        }
+        Ok(()) => {}
+    }
+    let Some(journal) = journal else { return Ok(report) };
+
+    report.journal_seq = checkpoint::journal_seq(checkpoint)?;
+    // Admin records only made it into the journal if the original run allowed them
+    rebuilt.set_admin_ops(true);
+    let replay = match journal::replay_to(journal, &mut rebuilt, report.journal_seq) {
+        Ok(replay) => replay,
+        Err(e @ JournalError::Corrupt { .. }) => return Ok(VerifyReport { finding: Some(Finding::Corrupt(e.to_string())), ..report }),
+        Err(e) => return Err(e.into()),
+    };
+    report.replayed = Some(replay.applied);
+    if let Some((seq, error)) = replay.diverged.into_iter().next() {
+        report.finding = Some(Finding::Diverged { seq, error });
+        return Ok(report);
+    }
+    report.finding = diff::diff(&diff::rows(&restored), &diff::rows(&rebuilt)).into_iter().next().map(Finding::Balance);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::Journal;
+    use crate::test_util::TxBuilder;
+
+    #[test]
+    fn test_verify_reports_the_first_balance_the_journal_disagrees_with() {
+        let dir = std::env::temp_dir();
+        let (checkpoint, journal) = (dir.join(format!("payments_processor_verify_{}.jsonl", std::process::id())), dir.join(format!("payments_processor_verify_journal_{}.jsonl", std::process::id())));
+        let _ = std::fs::remove_file(&journal);
+        let mut ledger = Ledger::new();
+        let (state, hook) = Journal::open(&journal).unwrap();
+        ledger.add_hook(Box::new(hook));
+        for tx in [TxBuilder::deposit(1, 1, 10.0).build(), TxBuilder::deposit(2, 2, 5.0).build(), TxBuilder::withdrawal(2, 3, 9.0).build()] {
+            let _ = ledger.process_transaction(&tx);
+        }
+        state.lock().unwrap().sync().unwrap();
+        ledger.checkpoint(&checkpoint, &Default::default()).unwrap();
+
+        let report = verify(&checkpoint, Some(&journal), Ledger::new(), Ledger::new()).unwrap();
+        assert!(report.finding.is_none(), "{:?}", report.finding);
+        assert_eq!((report.clients, report.replayed), (2, Some(2)));
+
+        // Someone edits client 2's balance in the checkpoint
+        let edited = std::fs::read_to_string(&checkpoint).unwrap().replace("\"available\":5.0,\"held\":0.0,\"total\":5.0", "\"available\":7.0,\"held\":0.0,\"total\":7.0");
+        std::fs::write(&checkpoint, edited).unwrap();
+        let report = verify(&checkpoint, Some(&journal), Ledger::new(), Ledger::new()).unwrap();
+        match report.finding {
+            Some(Finding::Balance(d)) => assert_eq!((d.client, d.available), (2, -2.0)),
+            other => panic!("expected a balance finding, got {:?}", other),
+        }
+        std::fs::remove_file(&checkpoint).unwrap();
+        std::fs::remove_file(&journal).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+// Business rules loaded from a sandboxed WASM module. The module gets no imports (so no host
+// access at all) and a fuel budget per call, and may export either or both of:
+//
+//   validate_transaction(tx_type: i32, client: i32, tx: i64, amount: f64, available: f64, held: f64) -> i32
+//       0 accepts the transaction, any other value rejects it with that code
+//   compute_fee(tx_type: i32, client: i32, tx: i64, amount: f64) -> f64
+//
+// tx_type is 0 deposit, 1 withdrawal, 2 dispute, 3 resolve, 4 chargeback, 5 tier change, 6 hold,
+// 7 release, 8 annul, 9 transfer, 10 lock, 11 unlock; a missing amount is 0. available and held are the client's balance in
+// the transaction's currency.
+
+use std::error::Error;
+use std::path::Path;
+use wasmtime::{Config, Engine, Instance, Module, Store, TypedFunc};
+
+use crate::client::Client;
+use crate::rules::BusinessRules;
+use crate::transaction::{Transaction, TxType};
+
+const FUEL_PER_CALL: u64 = 1_000_000;
+
+type ValidateFn = TypedFunc<(i32, i32, i64, f64, f64, f64), i32>;
+type FeeFn = TypedFunc<(i32, i32, i64, f64), f64>;
+
+pub struct WasmPlugin {
+    name: String,
+    store: Store<()>,
+    validate: Option<ValidateFn>,
+    fee: Option<FeeFn>,
+}
+
+impl WasmPlugin {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        Self::from_bytes(&path.display().to_string(), &bytes)
+    }
+
+    // Accepts binary modules as well as the WAT text format
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, bytes)?;
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[])?;
+
+        let validate = instance.get_typed_func(&mut store, "validate_transaction").ok();
+        let fee = instance.get_typed_func(&mut store, "compute_fee").ok();
+        if validate.is_none() && fee.is_none() {
+            return Err(format!("{} exports neither validate_transaction nor compute_fee", name).into());
+        }
+
+        Ok(Self { name: name.to_string(), store, validate, fee })
+    }
+}
+
+fn tx_type_code(tx_type: &TxType) -> i32 {
+    match tx_type {
+        TxType::Deposit => 0,
+        TxType::Withdrawal => 1,
+        TxType::Dispute => 2,
+        TxType::Resolve => 3,
+        TxType::Chargeback => 4,
+        TxType::SetTier(_) => 5,
+        TxType::Hold => 6,
+        TxType::Release => 7,
+        TxType::Annul(_) => 8,
+        TxType::Transfer(_) => 9,
+        TxType::Lock => 10,
+        TxType::Unlock => 11,
+    }
+}
+
+impl BusinessRules for WasmPlugin {
+    fn validate_transaction(&mut self, tx: &Transaction, client: Option<&Client>) -> Result<(), String> {
+        let Some(validate) = &self.validate else {
+            return Ok(());
+        };
+        self.store.set_fuel(FUEL_PER_CALL).map_err(|e| e.to_string())?;
+        let args = (
+            tx_type_code(&tx.tx_type),
+            tx.client_id as i32,
+            tx.tx_id as i64,
+            tx.amount.unwrap_or(0.0),
+            client.map_or(0.0, |c| c.balance(tx.currency.unwrap_or_default()).available),
+            client.map_or(0.0, |c| c.balance(tx.currency.unwrap_or_default()).held),
+        );
+        match validate.call(&mut self.store, args) {
+            Ok(0) => Ok(()),
+            Ok(code) => Err(format!("plugin {} rejected with code {}", self.name, code)),
+            Err(e) => Err(format!("plugin {} failed: {}", self.name, e)),
+        }
+    }
+
+    fn compute_fee(&mut self, tx: &Transaction, _client: Option<&Client>) -> Result<f64, String> {
+        let Some(fee) = &self.fee else {
+            return Ok(0.0);
+        };
+        self.store.set_fuel(FUEL_PER_CALL).map_err(|e| e.to_string())?;
+        let args = (tx_type_code(&tx.tx_type), tx.client_id as i32, tx.tx_id as i64, tx.amount.unwrap_or(0.0));
+        fee.call(&mut self.store, args)
+            .map_err(|e| format!("plugin {} failed: {}", self.name, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Currency;
+    use crate::ledger::{Ledger, LedgerError};
+    use crate::test_util::TxBuilder;
+
+    // Rejects withdrawals over 50 and charges a flat 0.5 fee on deposits
+    const PLUGIN: &str = r#"
+        (module
+          (func (export "validate_transaction")
+                (param $type i32) (param $client i32) (param $tx i64)
+                (param $amount f64) (param $available f64) (param $held f64) (result i32)
+            (if (result i32)
+              (i32.and (i32.eq (local.get $type) (i32.const 1))
+                       (f64.gt (local.get $amount) (f64.const 50)))
+              (then (i32.const 7))
+              (else (i32.const 0))))
+          (func (export "compute_fee")
+                (param $type i32) (param $client i32) (param $tx i64) (param $amount f64) (result f64)
+            (if (result f64) (i32.eqz (local.get $type))
+              (then (f64.const 0.5))
+              (else (f64.const 0)))))
+    "#;
+
+    const LOOPING_PLUGIN: &str = r#"
+        (module
+          (func (export "validate_transaction")
+                (param i32 i32 i64 f64 f64 f64) (result i32)
+            (loop $forever (br $forever))
+            (i32.const 0)))
+    "#;
+
+    #[test]
+    fn test_wasm_plugin_validates_and_charges_fees() {
+        let mut ledger = Ledger::new();
+        ledger.add_rules(Box::new(WasmPlugin::from_bytes("test", PLUGIN.as_bytes()).unwrap()));
+
+        ledger.process_transaction(&TxBuilder::deposit(1, 1, 100.0).build()).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).available, 99.5);
+
+        let res = ledger.process_transaction(&TxBuilder::withdrawal(1, 2, 60.0).build());
+        assert!(matches!(res, Err(LedgerError::RejectedByRule { tx: 2, .. })));
+
+        ledger.process_transaction(&TxBuilder::withdrawal(1, 3, 40.0).build()).unwrap();
+        assert_eq!(ledger.client(1).unwrap().balance(Currency::Usd).total, 59.5);
+    }
+
+    #[test]
+    fn test_wasm_plugin_runaway_loop_is_stopped_by_fuel() {
+        let mut plugin = WasmPlugin::from_bytes("loop", LOOPING_PLUGIN.as_bytes()).unwrap();
+        let tx = TxBuilder::deposit(1, 1, 1.0).build();
+        assert!(plugin.validate_transaction(&tx, None).is_err());
+    }
+
+    #[test]
+    fn test_wasm_plugin_without_known_exports_fails_to_load() {
+        assert!(WasmPlugin::from_bytes("empty", b"(module)").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+// How long a file must go without changes before it is taken, so one still being written isn't read half way
+const SETTLE: Duration = Duration::from_millis(500);
+
+pub const PROCESSED: &str = "processed";
+pub const FAILED: &str = "failed";
+
+#[derive(Debug)]
+pub enum WatchError {
+    Io(io::Error),
+    Notify(notify::Error),
+    Closed,
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchError::Io(e) => write!(f, "Drop folder I/O error: {}", e),
+            WatchError::Notify(e) => write!(f, "Failed to watch drop folder: {}", e),
+            WatchError::Closed => write!(f, "Drop folder watcher stopped"),
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
+
+impl From<io::Error> for WatchError {
+    fn from(e: io::Error) -> Self {
+        WatchError::Io(e)
+    }
+}
+
+impl From<notify::Error> for WatchError {
+    fn from(e: notify::Error) -> Self {
+        WatchError::Notify(e)
+    }
+}
+
+// A folder that input files are dropped into. Files already there when it is opened and those that
+// arrive later come out of `next_file` once they have stopped changing; after processing, `finish`
+// moves each to the `processed/` or `failed/` subfolder. Hidden files (a leading `.`) are left alone,
+// so a writer can create `.name.csv` and rename it when done.
+pub struct DropFolder {
+    dir: PathBuf,
+    events: mpsc::UnboundedReceiver<notify::Result<Event>>,
+    _watcher: RecommendedWatcher,
+    // Files not taken yet, by when they last changed
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl DropFolder {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, WatchError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join(PROCESSED))?;
+        fs::create_dir_all(dir.join(FAILED))?;
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+        // Listed after the watch starts, so nothing dropped in between is missed
+        let mut folder = DropFolder { dir, events, _watcher: watcher, pending: HashMap::new() };
+        let settled = Instant::now().checked_sub(SETTLE).unwrap_or_else(Instant::now);
+        for entry in fs::read_dir(&folder.dir)? {
+            folder.seen(entry?.path(), settled);
+        }
+        Ok(folder)
+    }
+
+    fn seen(&mut self, path: PathBuf, at: Instant) {
+        let hidden = path.file_name().and_then(|n| n.to_str()).is_none_or(|n| n.starts_with('.'));
+        if path.parent() == Some(self.dir.as_path()) && path.is_file() && !hidden {
+            self.pending.insert(path, at);
+        } else {
+            // Removed, renamed away or not a file (any more)
+            self.pending.remove(&path);
+        }
+    }
+
+    // Waits for the next file that has settled, oldest first
+    pub async fn next_file(&mut self) -> Result<PathBuf, WatchError> {
+        loop {
+            let now = Instant::now();
+            let oldest = self.pending.iter().min_by_key(|(path, at)| (**at, (*path).clone()));
+            let wait = match oldest {
+                Some((path, at)) if now.duration_since(*at) >= SETTLE => {
+                    let path = path.clone();
+                    self.pending.remove(&path);
+                    return Ok(path);
+                }
+                Some((_, at)) => SETTLE - now.duration_since(*at),
+                None => Duration::MAX,
+            };
+            let event = match tokio::time::timeout(wait, self.events.recv()).await {
+                Ok(Some(event)) => event?,
+                Ok(None) => return Err(WatchError::Closed),
+                Err(_) => continue,
+            };
+            for path in event.paths {
+                self.seen(path, Instant::now());
+            }
+        }
+    }
+
+    // Moves a file taken from `next_file` to `processed/` or `failed/`, next to any earlier file of the
+    // same name, and returns where it went
+    pub fn finish(&self, path: &Path, ok: bool) -> io::Result<PathBuf> {
+        let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+        let target = self.dir.join(if ok { PROCESSED } else { FAILED });
+        let mut to = target.join(name);
+        let mut n = 1;
+        while to.exists() {
+            to = target.join(format!("{}.{}", name.to_string_lossy(), n));
+            n += 1;
+        }
+        fs::rename(path, &to)?;
+        Ok(to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_existing_and_new_files_are_picked_up_and_moved() {
+        let dir = std::env::temp_dir().join(format!("payments_processor_watch_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.csv"), "type,client,tx,amount\n").unwrap();
+        let mut folder = DropFolder::open(&dir).unwrap();
+        fs::write(dir.join(".b.csv"), "type,client,tx,amount\n").unwrap();
+        fs::rename(dir.join(".b.csv"), dir.join("b.csv")).unwrap();
+
+        let a = folder.next_file().await.unwrap();
+        assert_eq!(a, dir.join("a.csv"));
+        assert_eq!(folder.finish(&a, true).unwrap(), dir.join(PROCESSED).join("a.csv"));
+        let b = folder.next_file().await.unwrap();
+        assert_eq!(b, dir.join("b.csv"));
+        assert_eq!(folder.finish(&b, false).unwrap(), dir.join(FAILED).join("b.csv"));
+
+        fs::write(dir.join("a.csv"), "type,client,tx,amount\n").unwrap();
+        let a = folder.next_file().await.unwrap();
+        assert_eq!(folder.finish(&a, true).unwrap(), dir.join(PROCESSED).join("a.csv.1"));
+        assert!(tokio::time::timeout(SETTLE * 3, folder.next_file()).await.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}